@@ -0,0 +1,47 @@
+// Times the virtual machine against the interpreter on arithmetic loops.
+// Run with `cargo run --release --example vm_speed`.
+//
+// The interpreter runs without the JIT, so both sides only interpret. Measured speedups
+// are 6x to 8x for the loop in a function and 4x to 6x for the loop at the top level,
+// where variables live in the symbol map instead of slots. That is short of the 10x
+// the virtual machine was meant to reach.
+
+use fin::{Backend, Engine, OutputBuffer};
+use std::time::{Duration, Instant};
+
+const PROGRAMS: [(&str, &str); 2] = [
+    (
+        "function",
+        "tominto f(n) { muut i = 0; muut s = 0; kun i < n { muut s = s + i * 2; muut i = i + 1 }; palata s }; f(2000000)",
+    ),
+    (
+        "top level",
+        "muut i = 0; muut s = 0; kun i < 2000000 { muut s = s + i * 2; muut i = i + 1 }; s",
+    ),
+];
+
+fn time(text: &str, backend: Backend) -> Duration {
+    let mut engine = Engine::new();
+    engine.set_output(OutputBuffer::new());
+    engine.set_backend(backend);
+    engine.set_jit_threshold(None);
+    let started = Instant::now();
+    let value = engine.eval(text).unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(value.to_string(), "3999998000000");
+    elapsed
+}
+
+fn main() {
+    for (name, text) in PROGRAMS.iter() {
+        let interpreter = time(text, Backend::Interpreter);
+        let vm = time(text, Backend::Vm);
+        println!(
+            "{}: interpreter {:?}, vm {:?}, {:.1}x faster",
+            name,
+            interpreter,
+            vm,
+            interpreter.as_secs_f64() / vm.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,1829 @@
+use crate::ast::{self, identifier_name};
+use crate::builtins;
+use crate::elf;
+use crate::errors::{self, ErrorType, TypeError};
+use crate::limits::Limit;
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::types::Type;
+use crate::x86;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+// Lowers syntax trees to x86-64 assembly for programs of kok, liu and totuus values. Unlike
+// in the C backend the types are known when compiling: every variable and expression has
+// one of the three, and functions annotate their parameters. Values are kept in registers
+// while expressions are evaluated, and variables live in the frame of their function, or in
+// the data section for globals.
+//
+// The assembly reads with the GNU assembler, and x86.rs assembles it to build programs
+// without one.
+//
+// Errors show the same tracebacks as the interpreter. Their messages and arrows are made
+// here, and the runtime adds the frames, which the generated code keeps like fin_runtime.s
+// describes.
+
+/// The runtime generated programs are linked with
+pub const RUNTIME: &str = include_str!("fin_runtime.s");
+
+/// The name of the runtime's assembly file
+pub const RUNTIME_NAME: &str = "fin_runtime.s";
+
+/// The name of the runtime's object file
+pub const RUNTIME_OBJECT_NAME: &str = "fin_runtime.o";
+
+// The interpreter's default call depth limit
+const MAX_DEPTH: usize = 1000;
+
+// Registers that hold the values of expressions. Rax, rcx and rdx are left for dividing and
+// for moving values around, and xmm0 to xmm7 for arguments.
+const INT_REGISTERS: [&str; 6] = ["rsi", "rdi", "r8", "r9", "r10", "r11"];
+const INT_REGISTERS_32: [&str; 6] = ["esi", "edi", "r8d", "r9d", "r10d", "r11d"];
+const FLOAT_REGISTERS: [&str; 8] = [
+    "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+// Where the System V calling convention passes the first arguments
+const INT_ARGUMENTS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+const FLOAT_ARGUMENTS: [&str; 8] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+];
+
+// Bytes of a frame before the arguments: the descriptor, the call site and the tail calls
+const FRAME_HEADER: usize = 24;
+
+/// Generates the assembly of a program, to be linked with the runtime.
+///
+/// Programs can only use kok, liu and totuus values, and texts as arguments of `tulosta`.
+/// Functions are defined at the top of the program and annotate the types of their
+/// parameters, and of their result if they have one.
+pub fn generate(file_name: &str, node: &Node) -> Result<String, ErrorType> {
+    let statements = match node {
+        Node::StatementsNode(nodes) => nodes.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    let mut generator = Generator::default();
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, params, returns, _) = statement {
+            generator.declare(name_tok, params, returns)?;
+        }
+    }
+    generator.program(statements)?;
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, _, _, body) = statement {
+            generator.function(name_tok, body)?;
+        }
+    }
+    Ok(generator.finish(file_name))
+}
+
+/// Assembles a generated program and links it with the runtime into a static executable,
+/// without any tools besides finc
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub fn build(asm_source: &str, output: &Path) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let objects = [assemble(asm_source)?, assemble(RUNTIME)?];
+    let executable = elf::executable(&objects).map_err(invalid_data)?;
+    fs::write(output, executable)?;
+    fs::set_permissions(output, fs::Permissions::from_mode(0o755))
+}
+
+// The executables only run on x86-64 Linux
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub fn build(_asm_source: &str, _output: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Executables can only be built on x86-64 Linux, use --emit=asm or --emit=obj",
+    ))
+}
+
+/// Assembles a generated program, or the runtime, into a relocatable ELF object
+pub fn object(asm_source: &str) -> io::Result<Vec<u8>> {
+    Ok(elf::relocatable(&assemble(asm_source)?))
+}
+
+fn assemble(asm_source: &str) -> io::Result<x86::Object> {
+    x86::assemble(asm_source).map_err(invalid_data)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A function of the program, known before code is generated for any of them
+struct Function {
+    name: String,
+    params: Vec<(String, Type)>,
+    // `None` when the function returns nothing
+    returns: Option<Type>,
+    // Whether the program has defined it by the code being generated
+    defined: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Place {
+    Register(usize),
+    // In the slot of the value in the frame
+    Stack,
+}
+
+// The value of an expression being evaluated
+struct Temp {
+    type_: Type,
+    place: Place,
+}
+
+enum Variable {
+    Local(usize),
+    Global(usize),
+}
+
+// The code of the program or of one function
+struct Body {
+    code: String,
+    function: Option<usize>,
+    arity: usize,
+    // Variables in the frame, parameters first. Their types are known once they are set.
+    locals: Vec<(String, Option<Type>)>,
+    // Variables that are set on every path to the code being generated: locals in functions
+    // and globals in the program. Others are read after checking their flags.
+    set: Vec<String>,
+    // Locals whose flags are used, which are cleared when the function starts
+    flagged: Vec<bool>,
+    // Values of expressions being evaluated, the most recent last
+    temps: Vec<Temp>,
+    max_temps: usize,
+    // Where tail calls start over, and where the code returns
+    start: String,
+    end: String,
+    // Code that ends the program with an error: its label, and the labels of the
+    // location and report it shows
+    failures: Vec<(String, String, String)>,
+}
+
+#[derive(Default)]
+struct Generator {
+    bodies: Vec<Body>,
+    functions: Vec<Function>,
+    function_ids: HashMap<String, usize>,
+    globals: Vec<(String, Type)>,
+    global_ids: HashMap<String, usize>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+    floats: Vec<f64>,
+    labels: usize,
+    text: String,
+}
+
+impl Generator {
+    fn body(&mut self) -> &mut Body {
+        self.bodies
+            .last_mut()
+            .expect("Code is generated inside a body")
+    }
+
+    fn current(&self) -> &Body {
+        self.bodies.last().expect("Code is generated inside a body")
+    }
+
+    fn line(&mut self, line: String) {
+        let body = self.body();
+        body.code.push_str("    ");
+        body.code.push_str(&line);
+        body.code.push('\n');
+    }
+
+    fn place_label(&mut self, label: &str) {
+        let body = self.body();
+        body.code.push_str(label);
+        body.code.push_str(":\n");
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    fn new_body(&mut self, function: Option<usize>, locals: Vec<(String, Option<Type>)>) -> Body {
+        let params = match function {
+            Some(index) => self.functions[index].params.as_slice(),
+            None => &[],
+        };
+        Body {
+            code: String::new(),
+            function,
+            arity: params.len(),
+            set: params.iter().map(|(param, _)| param.clone()).collect(),
+            flagged: vec![false; locals.len()],
+            locals,
+            temps: Vec::new(),
+            max_temps: 0,
+            start: self.label(),
+            end: self.label(),
+            failures: Vec::new(),
+        }
+    }
+
+    // The label of a text in the read only data
+    fn string(&mut self, text: &str) -> String {
+        let id = match self.string_ids.get(text) {
+            Some(id) => *id,
+            None => {
+                self.strings.push(text.to_string());
+                self.string_ids
+                    .insert(text.to_string(), self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        format!("fin_str_{}", id)
+    }
+
+    // A jump target that ends the program with an error between two positions
+    fn failure(
+        &mut self,
+        error_name: &str,
+        message: &str,
+        start: Option<Position>,
+        end: Option<Position>,
+    ) -> String {
+        let location = self.string(&errors::frame_location(start.as_ref()));
+        let report = errors::error_report(error_name, message, start, end) + "\n";
+        let report = self.string(&report);
+        let label = self.label();
+        self.body().failures.push((label.clone(), location, report));
+        label
+    }
+
+    fn overflow(&mut self, start: Option<Position>, end: Option<Position>) -> String {
+        self.failure("Runtime Error", "kokonaisluvun ylivuoto", start, end)
+    }
+
+    fn division_by_zero(&mut self, start: Option<Position>, end: Option<Position>) -> String {
+        self.failure("DivisionByZero Error", "Division by Zero", start, end)
+    }
+
+    // Values of expressions
+
+    // The frame slot of a value of an expression
+    fn slot(&self, index: usize) -> String {
+        let body = self.current();
+        stack(FRAME_HEADER + 8 * (body.arity + 2 * body.locals.len() + index + 1))
+    }
+
+    // Where a value of an expression is, as an operand
+    fn operand(&self, index: usize) -> String {
+        let temp = &self.current().temps[index];
+        match temp.place {
+            Place::Register(register) => register_name(&temp.type_, register).to_string(),
+            Place::Stack => self.slot(index),
+        }
+    }
+
+    fn top(&self) -> usize {
+        self.current().temps.len() - 1
+    }
+
+    fn push_temp(&mut self, type_: Type) -> &'static str {
+        let register = self.free_register(&type_);
+        let body = self.body();
+        body.temps.push(Temp {
+            type_: type_.clone(),
+            place: Place::Register(register),
+        });
+        body.max_temps = body.max_temps.max(body.temps.len());
+        register_name(&type_, register)
+    }
+
+    fn pop_temp(&mut self) {
+        self.body().temps.pop();
+    }
+
+    // A register for a value of the type. When all are taken, the value that has been in
+    // one the longest moves to its slot.
+    fn free_register(&mut self, type_: &Type) -> usize {
+        let float = is_float(type_);
+        let count = match float {
+            true => FLOAT_REGISTERS.len(),
+            false => INT_REGISTERS.len(),
+        };
+        let taken: Vec<(usize, usize)> = self
+            .current()
+            .temps
+            .iter()
+            .enumerate()
+            .filter(|(_, temp)| is_float(&temp.type_) == float)
+            .filter_map(|(index, temp)| match temp.place {
+                Place::Register(register) => Some((index, register)),
+                Place::Stack => None,
+            })
+            .collect();
+        if let Some(free) = (0..count).find(|r| taken.iter().all(|(_, taken)| taken != r)) {
+            return free;
+        }
+        let (oldest, register) = taken[0];
+        self.spill(oldest);
+        register
+    }
+
+    fn spill(&mut self, index: usize) {
+        let temp = &self.current().temps[index];
+        if let Place::Register(register) = temp.place {
+            let line = format!(
+                "{} {}, {}",
+                mov(&temp.type_),
+                self.slot(index),
+                register_name(&temp.type_, register)
+            );
+            self.line(line);
+            self.body().temps[index].place = Place::Stack;
+        }
+    }
+
+    // Before calls, which can change any of the registers
+    fn spill_all(&mut self) {
+        for index in 0..self.current().temps.len() {
+            self.spill(index);
+        }
+    }
+
+    // The register of a value of an expression, loading it there if it is in its slot.
+    // Operands of the same instruction are asked for after this, as this can move them.
+    fn in_register(&mut self, index: usize) -> &'static str {
+        let type_ = self.current().temps[index].type_.clone();
+        if let Place::Register(register) = self.current().temps[index].place {
+            return register_name(&type_, register);
+        }
+        let register = self.free_register(&type_);
+        let name = register_name(&type_, register);
+        let line = format!("{} {}, {}", mov(&type_), name, self.slot(index));
+        self.line(line);
+        self.body().temps[index].place = Place::Register(register);
+        name
+    }
+
+    // Copies a value of an expression to a register or memory
+    fn load(&mut self, index: usize, destination: &str) {
+        let operand = self.operand(index);
+        if operand != destination {
+            let type_ = &self.current().temps[index].type_;
+            let line = format!("{} {}, {}", mov(type_), destination, operand);
+            self.line(line);
+        }
+    }
+
+    // Copies a value of an expression to memory
+    fn store(&mut self, index: usize, destination: &str) {
+        match self.current().temps[index].place {
+            Place::Register(_) => self.load(index, destination),
+            Place::Stack => {
+                let type_ = self.current().temps[index].type_.clone();
+                let scratch = scratch(&type_);
+                self.load(index, scratch);
+                self.line(format!("{} {}, {}", mov(&type_), destination, scratch));
+            }
+        }
+    }
+
+    // Variables
+
+    fn variable(&self, variable: &Variable) -> String {
+        match variable {
+            Variable::Local(index) => stack(FRAME_HEADER + 8 * (self.current().arity + index + 1)),
+            Variable::Global(index) => format!("qword ptr [rip + fin_global_{}]", index),
+        }
+    }
+
+    // Where a variable keeps whether it has been set, after the locals in the frame
+    fn flag(&self, variable: &Variable) -> String {
+        match variable {
+            Variable::Local(index) => {
+                let body = self.current();
+                stack(FRAME_HEADER + 8 * (body.arity + body.locals.len() + index + 1))
+            }
+            Variable::Global(index) => format!("qword ptr [rip + fin_set_{}]", index),
+        }
+    }
+
+    // Ends the program like the interpreter does when a variable is read before it is set.
+    // Functions can run before the program sets a global, so they always check.
+    fn check_set(&mut self, token: &Token, variable: &Variable) -> Result<(), ErrorType> {
+        let name = identifier_name(token)?;
+        if self.current().set.contains(&name) {
+            return Ok(());
+        }
+        if let Variable::Local(index) = variable {
+            if self.global_ids.contains_key(&name) {
+                return Err(unsupported(
+                    token,
+                    "reads of globals before setting a local of the same name",
+                ));
+            }
+            self.body().flagged[*index] = true;
+        }
+        let failure = self.failure(
+            "Runtime Error",
+            &format!("{} is not defined", name),
+            token.position_start(),
+            token.position_end(),
+        );
+        self.line(format!("cmp {}, 0", self.flag(variable)));
+        self.line(format!("je {}", failure));
+        Ok(())
+    }
+
+    // Sets the flag of a variable unless it is surely set already
+    fn mark_set(&mut self, name: String, variable: &Variable) {
+        if self.current().set.contains(&name) {
+            return;
+        }
+        if let Variable::Local(index) = variable {
+            self.body().flagged[*index] = true;
+        }
+        self.line(format!("mov {}, 1", self.flag(variable)));
+        self.body().set.push(name);
+    }
+
+    // Goes back to before a branch, keeping the variables set on every path so far that
+    // goes on after the branches
+    fn branch_end(&mut self, set: &mut Option<Vec<String>>, before: Vec<String>, goes_on: bool) {
+        let after = std::mem::replace(&mut self.body().set, before);
+        if !goes_on {
+            return;
+        }
+        *set = Some(match set.take() {
+            Some(set) => set
+                .into_iter()
+                .filter(|name| after.contains(name))
+                .collect(),
+            None => after,
+        });
+    }
+
+    fn lookup(&self, token: &Token) -> Result<(Variable, Type), ErrorType> {
+        let name = identifier_name(token)?;
+        let body = self.current();
+        if body.function.is_some() {
+            if let Some(index) = body.locals.iter().position(|(local, _)| *local == name) {
+                // Until the function sets the local, the name reads the global. Which one a
+                // read gets can change between the rounds of a loop, so the code cant tell.
+                return match &body.locals[index].1 {
+                    Some(type_) => Ok((Variable::Local(index), type_.clone())),
+                    None if self.global_ids.contains_key(&name) => Err(unsupported(
+                        token,
+                        "reads of globals before setting a local of the same name",
+                    )),
+                    None => Err(not_defined(token, &name)),
+                };
+            }
+        }
+        if let Some(index) = self.global_ids.get(&name) {
+            return Ok((Variable::Global(*index), self.globals[*index].1.clone()));
+        }
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(token, "functions as values"));
+        }
+        Err(not_defined(token, &name))
+    }
+
+    fn assign(
+        &mut self,
+        token: &Token,
+        annotation: &Option<Token>,
+        value: &Node,
+    ) -> Result<Type, ErrorType> {
+        let name = identifier_name(token)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(token, "variables named like functions"));
+        }
+        let type_ = self.expression(value)?;
+        if let Some(annotation) = annotation {
+            let expected = annotated_type(annotation)?;
+            if expected != type_ {
+                return Err(type_error(
+                    token,
+                    format!("{} is {}, not {}", name, expected, type_),
+                ));
+            }
+        }
+
+        // Variables keep the type of their first value
+        let variable = match self.current().function {
+            Some(_) => {
+                let body = self.body();
+                let index = body
+                    .locals
+                    .iter()
+                    .position(|(local, _)| *local == name)
+                    .expect("Locals are collected first");
+                let known = body.locals[index].1.get_or_insert(type_.clone()).clone();
+                check_variable(token, &name, &known, &type_)?;
+                Variable::Local(index)
+            }
+            None => {
+                let index = match self.global_ids.get(&name) {
+                    Some(index) => *index,
+                    None => {
+                        self.globals.push((name.clone(), type_.clone()));
+                        self.global_ids.insert(name.clone(), self.globals.len() - 1);
+                        self.globals.len() - 1
+                    }
+                };
+                check_variable(token, &name, &self.globals[index].1, &type_)?;
+                Variable::Global(index)
+            }
+        };
+        let destination = self.variable(&variable);
+        self.store(self.top(), &destination);
+        self.mark_set(name, &variable);
+        Ok(type_)
+    }
+
+    // Expressions
+
+    // Generates a node that leaves its value as the most recent value of an expression
+    fn expression(&mut self, node: &Node) -> Result<Type, ErrorType> {
+        match node {
+            Node::Value(token) => self.value(token),
+            Node::VarAccessNode(token) => {
+                let (variable, type_) = self.lookup(token)?;
+                self.check_set(token, &variable)?;
+                let operand = self.variable(&variable);
+                let register = self.push_temp(type_.clone());
+                self.line(format!("{} {}, {}", mov(&type_), register, operand));
+                Ok(type_)
+            }
+            Node::VarAssignNode(token, annotation, value) => self.assign(token, annotation, value),
+            Node::Binop(..) => self.binops(node),
+            Node::Unary(optok, operand) => self.unary(optok, operand),
+            Node::CallNode(name_tok, args) => match self.call(name_tok, args)? {
+                Some(type_) => Ok(type_),
+                None => Err(type_error(
+                    name_tok,
+                    format!("{} returns nothing", identifier_name(name_tok)?),
+                )),
+            },
+            Node::ListNode(..) => Err(unsupported_node(node, "lista values")),
+            Node::RecordNode(..) => Err(unsupported_node(node, "tietue values")),
+            Node::IndexNode(..) => Err(unsupported_node(node, "indexes")),
+            Node::FieldNode(..) => Err(unsupported_node(node, "fields")),
+            Node::FuncDefNode(..) => Err(unsupported_node(node, "functions inside other code")),
+            Node::IfNode(..) | Node::WhileNode(..) | Node::ReturnNode(..) => {
+                Err(unsupported_node(node, "statements as values"))
+            }
+            Node::StatementsNode(_) => Err(unsupported_node(node, "statements as values")),
+        }
+    }
+
+    fn value(&mut self, token: &Token) -> Result<Type, ErrorType> {
+        match token.type_() {
+            TokenType::Int(value) => {
+                let register = self.push_temp(Type::Integer);
+                let mov = match i32::try_from(value) {
+                    Ok(_) => "mov",
+                    Err(_) => "movabs",
+                };
+                self.line(format!("{} {}, {}", mov, register, value));
+                Ok(Type::Integer)
+            }
+            TokenType::Float(value) => {
+                self.floats.push(value);
+                let constant = self.floats.len() - 1;
+                let register = self.push_temp(Type::Float);
+                self.line(format!(
+                    "movsd {}, qword ptr [rip + fin_float_{}]",
+                    register, constant
+                ));
+                Ok(Type::Float)
+            }
+            TokenType::Keyword(keyword) if keyword == "tosi" || keyword == "epätosi" => {
+                let register = self.push_temp(Type::Boolean);
+                self.line(format!("mov {}, {}", register, (keyword == "tosi") as i32));
+                Ok(Type::Boolean)
+            }
+            TokenType::Keyword(keyword) if keyword == "tyhjä" => {
+                Err(unsupported(token, "tyhjä values"))
+            }
+            TokenType::String(_) => Err(unsupported(token, "teksti values")),
+            TokenType::BigInt(_) => Err(unsupported(token, "iso numbers")),
+            TokenType::Imaginary(_) => Err(unsupported(token, "kompleksi numbers")),
+            other => Err(type_error(
+                token,
+                format!(
+                    "Non Value Token {:?} found inside generate value function",
+                    other
+                ),
+            )),
+        }
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are generated in a loop
+    fn binops(&mut self, node: &Node) -> Result<Type, ErrorType> {
+        let operations = node.binop_chain();
+        let first = operations[0].0;
+        let mut left_type = self.expression(first)?;
+        for (i, (left, optok, right)) in operations.into_iter().enumerate() {
+            let start = match i {
+                0 => value_span(first).0,
+                _ => first.pos_start(),
+            };
+            left_type = self.binop(left_type, (start, left), optok, right)?;
+        }
+        Ok(left_type)
+    }
+
+    // The value of `left` is on top of the temporaries, and starts at the position with it
+    fn binop(
+        &mut self,
+        left_type: Type,
+        (start, left): (Option<Position>, &Node),
+        optok: &Token,
+        right: &Node,
+    ) -> Result<Type, ErrorType> {
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            self.truth(left, &left_type)?;
+            return self.logic(keyword == "ja", right);
+        }
+
+        let right_type = self.expression(right)?;
+        let (right_start, end) = value_span(right);
+        let mismatch = || {
+            type_error(
+                optok,
+                format!(
+                    "Cant use {} with {} and {}",
+                    optok.type_().symbol(),
+                    left_type,
+                    right_type
+                ),
+            )
+        };
+
+        let operator = optok.type_();
+        match (&operator, &left_type, &right_type) {
+            (TokenType::Pow, _, Type::Float) => Err(ErrorType::TypeError(TypeError::new(
+                start,
+                right.pos_end(),
+                "powers with liu exponents are not supported in assembly programs".to_string(),
+            ))),
+            (TokenType::Pow, Type::Integer | Type::Float, Type::Integer) => {
+                self.power(&left_type, start, end)?;
+                Ok(left_type)
+            }
+            (
+                TokenType::Plus | TokenType::Minus | TokenType::Multiply | TokenType::Divide,
+                Type::Integer,
+                Type::Integer,
+            ) => {
+                self.int_arithmetic(&operator, (start, end), (right_start, value_span(right).1));
+                Ok(Type::Integer)
+            }
+            (
+                TokenType::Plus | TokenType::Minus | TokenType::Multiply | TokenType::Divide,
+                Type::Float,
+                Type::Float,
+            ) => {
+                self.float_arithmetic(&operator, right_start, value_span(right).1);
+                Ok(Type::Float)
+            }
+            (
+                TokenType::EqualEqual | TokenType::NotEqual,
+                Type::Integer | Type::Boolean,
+                Type::Integer | Type::Boolean,
+            )
+            | (
+                TokenType::LessThan
+                | TokenType::GreaterThan
+                | TokenType::LessThanEqual
+                | TokenType::GreaterThanEqual,
+                Type::Integer,
+                Type::Integer,
+            ) if left_type == right_type => {
+                self.int_comparison(&operator);
+                Ok(Type::Boolean)
+            }
+            (
+                TokenType::EqualEqual
+                | TokenType::NotEqual
+                | TokenType::LessThan
+                | TokenType::GreaterThan
+                | TokenType::LessThanEqual
+                | TokenType::GreaterThanEqual,
+                Type::Float,
+                Type::Float,
+            ) => {
+                self.float_comparison(&operator);
+                Ok(Type::Boolean)
+            }
+            _ => Err(mismatch()),
+        }
+    }
+
+    fn int_arithmetic(
+        &mut self,
+        operator: &TokenType,
+        (start, end): (Option<Position>, Option<Position>),
+        (right_start, right_end): (Option<Position>, Option<Position>),
+    ) {
+        let overflow = self.overflow(start, end);
+        let right = self.top();
+        let left = right - 1;
+        if *operator == TokenType::Divide {
+            let zero = self.division_by_zero(right_start, right_end);
+            let divide = self.label();
+            let left = self.operand(left);
+            let right = self.operand(right);
+            self.line(format!("cmp {}, 0", right));
+            self.line(format!("je {}", zero));
+            // The smallest kok divided by -1 is too large
+            self.line(format!("cmp {}, -1", right));
+            self.line(format!("jne {}", divide));
+            self.line(format!("movabs rax, {}", i64::MIN));
+            self.line(format!("cmp {}, rax", left));
+            self.line(format!("je {}", overflow));
+            self.place_label(&divide);
+            self.line(format!("mov rax, {}", left));
+            self.line("cqo".to_string());
+            self.line(format!("idiv {}", right));
+            self.line(format!("mov {}, rax", left));
+        } else {
+            let instruction = match operator {
+                TokenType::Plus => "add",
+                TokenType::Minus => "sub",
+                _ => "imul",
+            };
+            let left = self.in_register(left);
+            let right = self.operand(right);
+            self.line(format!("{} {}, {}", instruction, left, right));
+            self.line(format!("jo {}", overflow));
+        }
+        self.pop_temp();
+    }
+
+    fn float_arithmetic(
+        &mut self,
+        operator: &TokenType,
+        right_start: Option<Position>,
+        right_end: Option<Position>,
+    ) {
+        let right = self.top();
+        let left = self.in_register(right - 1);
+        let right = self.operand(right);
+        let instruction = match operator {
+            TokenType::Plus => "addsd",
+            TokenType::Minus => "subsd",
+            TokenType::Multiply => "mulsd",
+            _ => {
+                let zero = self.division_by_zero(right_start, right_end);
+                let divide = self.label();
+                self.line("xorpd xmm0, xmm0".to_string());
+                self.line(format!("ucomisd xmm0, {}", right));
+                // NaN is not zero
+                self.line(format!("jp {}", divide));
+                self.line(format!("je {}", zero));
+                self.place_label(&divide);
+                "divsd"
+            }
+        };
+        self.line(format!("{} {}, {}", instruction, left, right));
+        self.pop_temp();
+    }
+
+    // Powers by squaring in the runtime. Errors span both numbers.
+    fn power(
+        &mut self,
+        base: &Type,
+        start: Option<Position>,
+        end: Option<Position>,
+    ) -> Result<(), ErrorType> {
+        let exponent = self.top();
+        self.spill_all();
+        self.load(exponent, "rsi");
+        let zero = self.division_by_zero(start.clone(), end.clone());
+        if *base == Type::Float {
+            self.load(exponent - 1, "xmm0");
+            self.line("mov rdi, rsi".to_string());
+            self.line("call fin_pow_float".to_string());
+            self.line("cmp edx, 2".to_string());
+            self.line(format!("je {}", zero));
+            self.pop_temp();
+            self.pop_temp();
+            let register = self.push_temp(Type::Float);
+            self.line(format!("movsd {}, xmm0", register));
+            return Ok(());
+        }
+
+        self.load(exponent - 1, "rdi");
+        let overflow = self.overflow(start.clone(), end.clone());
+        // Negative powers of kok are murto numbers
+        let murto = self.failure(
+            "Runtime Error",
+            "murto numbers are not supported in compiled programs",
+            start,
+            end,
+        );
+        self.line("call fin_pow_int".to_string());
+        for (status, failure) in [(1, overflow), (2, zero), (3, murto)] {
+            self.line(format!("cmp edx, {}", status));
+            self.line(format!("je {}", failure));
+        }
+        self.pop_temp();
+        self.pop_temp();
+        let register = self.push_temp(Type::Integer);
+        self.line(format!("mov {}, rax", register));
+        Ok(())
+    }
+
+    fn int_comparison(&mut self, operator: &TokenType) {
+        let right = self.top();
+        let left = self.in_register(right - 1);
+        let register = INT_REGISTERS
+            .iter()
+            .position(|register| *register == left)
+            .expect("Kok and totuus are in integer registers");
+        let right = self.operand(right);
+        let set = match operator {
+            TokenType::EqualEqual => "sete",
+            TokenType::NotEqual => "setne",
+            TokenType::LessThan => "setl",
+            TokenType::GreaterThan => "setg",
+            TokenType::LessThanEqual => "setle",
+            _ => "setge",
+        };
+        self.line(format!("cmp {}, {}", INT_REGISTERS[register], right));
+        self.line(format!("{} al", set));
+        self.line(format!("movzx {}, al", INT_REGISTERS_32[register]));
+        self.pop_temp();
+        let left = self.top();
+        self.body().temps[left].type_ = Type::Boolean;
+    }
+
+    // NaN is not equal, less or greater than any float
+    fn float_comparison(&mut self, operator: &TokenType) {
+        let right = self.top();
+        let left = right - 1;
+        match operator {
+            // Less is greater the other way around
+            TokenType::LessThan | TokenType::LessThanEqual => {
+                let right = self.in_register(right);
+                let left = self.operand(left);
+                self.line(format!("ucomisd {}, {}", right, left));
+            }
+            _ => {
+                let left = self.in_register(left);
+                let right = self.operand(right);
+                self.line(format!("ucomisd {}, {}", left, right));
+            }
+        }
+        match operator {
+            TokenType::EqualEqual => {
+                self.line("sete al".to_string());
+                self.line("setnp cl".to_string());
+                self.line("and al, cl".to_string());
+            }
+            TokenType::NotEqual => {
+                self.line("setne al".to_string());
+                self.line("setp cl".to_string());
+                self.line("or al, cl".to_string());
+            }
+            TokenType::LessThan | TokenType::GreaterThan => self.line("seta al".to_string()),
+            _ => self.line("setae al".to_string()),
+        }
+        self.pop_temp();
+        self.pop_temp();
+        let register = self.push_temp(Type::Boolean);
+        let register = INT_REGISTERS
+            .iter()
+            .position(|name| *name == register)
+            .expect("Totuus is in integer registers");
+        self.line(format!("movzx {}, al", INT_REGISTERS_32[register]));
+    }
+
+    // 'ja' and 'tai' only look at the right side when they need to
+    // The left side is on top of the temporaries
+    fn logic(&mut self, and: bool, right: &Node) -> Result<Type, ErrorType> {
+        // Values stay in their slots on both paths, so they are where the paths meet
+        self.spill_all();
+        let result = self.slot(self.top());
+        let end = self.label();
+        self.line(format!("cmp {}, 0", result));
+        self.line(format!("{} {}", if and { "je" } else { "jne" }, end));
+        let set = self.current().set.clone();
+        self.condition_value(right)?;
+        self.body().set = set;
+        self.store(self.top(), &result);
+        self.pop_temp();
+        self.place_label(&end);
+        Ok(Type::Boolean)
+    }
+
+    fn unary(&mut self, optok: &Token, operand: &Node) -> Result<Type, ErrorType> {
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            self.condition_value(operand)?;
+            let register = self.in_register(self.top());
+            self.line(format!("xor {}, 1", register));
+            return Ok(Type::Boolean);
+        }
+
+        let type_ = self.expression(operand)?;
+        match (optok.type_(), &type_) {
+            (TokenType::Plus, Type::Integer | Type::Float) => (),
+            (TokenType::Minus, Type::Integer) => {
+                let (start, end) = value_span(operand);
+                let overflow = self.overflow(start, end);
+                let register = self.in_register(self.top());
+                self.line(format!("neg {}", register));
+                self.line(format!("jo {}", overflow));
+            }
+            (TokenType::Minus, Type::Float) => {
+                let register = self.in_register(self.top());
+                self.line(format!("movq rax, {}", register));
+                self.line(format!("movabs rcx, {}", i64::MIN));
+                self.line("xor rax, rcx".to_string());
+                self.line(format!("movq {}, rax", register));
+            }
+            (operator, _) => {
+                return Err(type_error(
+                    optok,
+                    format!("Cant use {} with {}", operator.symbol(), type_),
+                ))
+            }
+        }
+        Ok(type_)
+    }
+
+    // Evaluates a totuus
+    fn condition_value(&mut self, node: &Node) -> Result<(), ErrorType> {
+        let type_ = self.expression(node)?;
+        self.truth(node, &type_)
+    }
+
+    // Checks that `node` gave a condition
+    fn truth(&self, node: &Node, type_: &Type) -> Result<(), ErrorType> {
+        if *type_ != Type::Boolean {
+            return Err(node_error(
+                node,
+                format!("Condition must be totuus, found {}", type_),
+            ));
+        }
+        Ok(())
+    }
+
+    // Jumps to `otherwise` unless a condition holds
+    fn condition(&mut self, node: &Node, otherwise: &str) -> Result<(), ErrorType> {
+        self.condition_value(node)?;
+        let value = self.operand(self.top());
+        self.line(format!("cmp {}, 0", value));
+        self.line(format!("je {}", otherwise));
+        self.pop_temp();
+        Ok(())
+    }
+
+    // Calls
+
+    // Generates a call and returns the type of its value, or `None` for nothing
+    fn call(&mut self, name_tok: &Token, args: &[Node]) -> Result<Option<Type>, ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if let Some(index) = self.function_ids.get(&name) {
+            return self.call_function(*index, name_tok, args);
+        }
+        match name.as_str() {
+            "tulosta" => {
+                self.print(args)?;
+                Ok(None)
+            }
+            "liu" => {
+                if args.len() != 1 {
+                    return Err(type_error(
+                        name_tok,
+                        format!("liu expects 1 arguments, got {}", args.len()),
+                    ));
+                }
+                match self.expression(&args[0])? {
+                    Type::Float => (),
+                    Type::Integer => {
+                        let value = self.operand(self.top());
+                        self.pop_temp();
+                        let register = self.push_temp(Type::Float);
+                        self.line(format!("cvtsi2sd {}, {}", register, value));
+                    }
+                    type_ => {
+                        return Err(node_error(
+                            &args[0],
+                            format!("liu expects a number, got {}", type_),
+                        ))
+                    }
+                }
+                Ok(Some(Type::Float))
+            }
+            _ if builtins::prelude().get(&name).is_some() => {
+                Err(unsupported(name_tok, &format!("calls to {}", name)))
+            }
+            _ => match self.lookup(name_tok) {
+                Ok((_, type_)) => Err(type_error(
+                    name_tok,
+                    format!("{} is a {}, not a function", name, type_),
+                )),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    // Evaluates the arguments of a function, checking their types
+    fn arguments(
+        &mut self,
+        index: usize,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<(), ErrorType> {
+        let function = &self.functions[index];
+        if args.len() != function.params.len() {
+            return Err(type_error(
+                name_tok,
+                format!(
+                    "{} expects {} arguments, got {}",
+                    function.name,
+                    function.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let params = function.params.clone();
+        for (arg, (param, expected)) in args.iter().zip(params) {
+            let type_ = self.expression(arg)?;
+            if type_ != expected {
+                return Err(node_error(
+                    arg,
+                    format!(
+                        "{} expects {} for {}, got {}",
+                        self.functions[index].name, expected, param, type_
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn call_function(
+        &mut self,
+        index: usize,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<Option<Type>, ErrorType> {
+        // The program defines functions in order, but they are all there when any runs
+        if self.current().function.is_none() && !self.functions[index].defined {
+            return Err(not_defined(name_tok, &self.functions[index].name));
+        }
+        self.arguments(index, name_tok, args)?;
+
+        self.spill_all();
+        let first = self.current().temps.len() - args.len();
+        let (mut ints, mut floats) = (0, 0);
+        for arg in first..first + args.len() {
+            if is_float(&self.current().temps[arg].type_) {
+                self.load(arg, FLOAT_ARGUMENTS[floats]);
+                floats += 1;
+            } else {
+                self.load(arg, INT_ARGUMENTS[ints]);
+                ints += 1;
+            }
+        }
+        let depth = self.failure(
+            &format!("{} Error", Limit::Depth),
+            &format!("Calls nested more than {} deep", MAX_DEPTH),
+            name_tok.position_start(),
+            name_tok.position_end(),
+        );
+        self.line(format!("cmp qword ptr [rip + fin_depth], {}", MAX_DEPTH));
+        self.line(format!("jg {}", depth));
+        let location = self.string(&errors::frame_location(name_tok.position_start().as_ref()));
+        self.line(format!("lea r10, [rip + {}]", location));
+        self.line(format!("call fin_fn_{}", index));
+        for _ in args {
+            self.pop_temp();
+        }
+
+        let returns = self.functions[index].returns.clone();
+        if let Some(type_) = &returns {
+            let register = self.push_temp(type_.clone());
+            self.line(format!("{} {}, {}", mov(type_), register, scratch(type_)));
+        }
+        Ok(returns)
+    }
+
+    // Prints the arguments after evaluating all of them, like the interpreter does
+    fn print(&mut self, args: &[Node]) -> Result<(), ErrorType> {
+        let mut texts = Vec::with_capacity(args.len());
+        for arg in args {
+            if let Node::Value(token) = arg {
+                if let TokenType::String(text) = token.type_() {
+                    texts.push(Some(self.string(&text)));
+                    continue;
+                }
+            }
+            self.expression(arg)?;
+            texts.push(None);
+        }
+
+        self.spill_all();
+        let values = texts.iter().filter(|text| text.is_none()).count();
+        let mut value = self.current().temps.len() - values;
+        for (i, text) in texts.iter().enumerate() {
+            if i > 0 {
+                self.line("lea rdi, [rip + fin_space_text]".to_string());
+                self.line("call fin_put_cstr".to_string());
+            }
+            if let Some(text) = text {
+                self.line(format!("lea rdi, [rip + {}]", text));
+                self.line("call fin_put_cstr".to_string());
+                continue;
+            }
+            let put = match self.current().temps[value].type_ {
+                Type::Float => "fin_put_float",
+                Type::Boolean => "fin_put_bool",
+                _ => "fin_put_int",
+            };
+            let register = match put {
+                "fin_put_float" => "xmm0",
+                _ => "rdi",
+            };
+            self.load(value, register);
+            self.line(format!("call {}", put));
+            value += 1;
+        }
+        self.line("lea rdi, [rip + fin_newline_text]".to_string());
+        self.line("call fin_put_cstr".to_string());
+        for _ in 0..values {
+            self.pop_temp();
+        }
+        Ok(())
+    }
+
+    // Statements
+
+    fn statement(&mut self, node: &Node) -> Result<(), ErrorType> {
+        match node {
+            Node::StatementsNode(nodes) => {
+                for node in nodes {
+                    self.statement(node)?;
+                }
+            }
+            Node::IfNode(_, cases, else_case) => {
+                let end = self.label();
+                let mut set = None;
+                for (condition, body) in cases {
+                    let next = self.label();
+                    self.condition(condition, &next)?;
+                    let before = self.current().set.clone();
+                    self.statement(body)?;
+                    self.branch_end(&mut set, before, !ast::ends_with_return(body));
+                    self.line(format!("jmp {}", end));
+                    self.place_label(&next);
+                }
+                let before = self.current().set.clone();
+                match else_case {
+                    Some(body) => {
+                        self.statement(body)?;
+                        self.branch_end(&mut set, before, !ast::ends_with_return(body));
+                    }
+                    None => self.branch_end(&mut set, before, true),
+                }
+                if let Some(set) = set {
+                    self.body().set = set;
+                }
+                self.place_label(&end);
+            }
+            Node::WhileNode(_, condition, body) => {
+                let start = self.label();
+                let end = self.label();
+                self.place_label(&start);
+                self.condition(condition, &end)?;
+                // The body might not run
+                let set = self.current().set.clone();
+                self.statement(body)?;
+                self.body().set = set;
+                self.line(format!("jmp {}", start));
+                self.place_label(&end);
+            }
+            Node::ReturnNode(token, value) => self.ret(token, value.as_deref())?,
+            Node::CallNode(name_tok, args) => {
+                if self.call(name_tok, args)?.is_some() {
+                    self.pop_temp();
+                }
+            }
+            node => {
+                self.expression(node)?;
+                self.pop_temp();
+            }
+        }
+        Ok(())
+    }
+
+    fn ret(&mut self, token: &Token, value: Option<&Node>) -> Result<(), ErrorType> {
+        let end = self.current().end.clone();
+        let index = match self.current().function {
+            Some(index) => index,
+            None => {
+                // The program ends by jumping past the rest of its code
+                if let Some(value) = value {
+                    self.statement(value)?;
+                }
+                self.line(format!("jmp {}", end));
+                return Ok(());
+            }
+        };
+
+        // A call to the running function runs in this same frame
+        if let Some(Node::CallNode(name_tok, args)) = value {
+            let function = &self.functions[index];
+            if identifier_name(name_tok)? == function.name && args.len() == function.params.len() {
+                return self.tail_call(index, name_tok, args);
+            }
+        }
+
+        let name = self.functions[index].name.clone();
+        match (value, self.functions[index].returns.clone()) {
+            (None, None) => (),
+            (Some(value), Some(returns)) => {
+                let type_ = self.expression(value)?;
+                if type_ != returns {
+                    return Err(node_error(
+                        value,
+                        format!("{} returns {}, not {}", name, returns, type_),
+                    ));
+                }
+                self.load(self.top(), scratch(&type_));
+                self.pop_temp();
+            }
+            (Some(Node::CallNode(name_tok, args)), None) => {
+                if self.call(name_tok, args)?.is_some() {
+                    return Err(unsupported(
+                        token,
+                        "values returned from functions without a return type",
+                    ));
+                }
+            }
+            (Some(_), None) => {
+                return Err(unsupported(
+                    token,
+                    "values returned from functions without a return type",
+                ))
+            }
+            (None, Some(returns)) => {
+                return Err(type_error(
+                    token,
+                    format!("{} returns {}, not tyhjä", name, returns),
+                ))
+            }
+        }
+        self.line(format!("jmp {}", end));
+        Ok(())
+    }
+
+    fn tail_call(
+        &mut self,
+        index: usize,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<(), ErrorType> {
+        self.arguments(index, name_tok, args)?;
+        let first = self.current().temps.len() - args.len();
+        let params = self.functions[index].params.clone();
+        for (i, (param, type_)) in params.iter().enumerate() {
+            let local = self
+                .current()
+                .locals
+                .iter()
+                .position(|(local, _)| local == param)
+                .expect("Parameters are locals");
+            let scratch = scratch(type_);
+            self.load(first + i, scratch);
+            let arg = stack(FRAME_HEADER + 8 * (i + 1));
+            let variable = self.variable(&Variable::Local(local));
+            self.line(format!("{} {}, {}", mov(type_), arg, scratch));
+            self.line(format!("{} {}, {}", mov(type_), variable, scratch));
+        }
+        for _ in args {
+            self.pop_temp();
+        }
+        self.line(format!("add {}, 1", stack(FRAME_HEADER)));
+        let start = self.current().start.clone();
+        self.line(format!("jmp {}", start));
+        Ok(())
+    }
+
+    // Functions and the program
+
+    fn declare(
+        &mut self,
+        name_tok: &Token,
+        params: &[(Token, Option<Token>)],
+        returns: &Option<Token>,
+    ) -> Result<(), ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(name_tok, "functions defined more than once"));
+        }
+        let mut typed_params = Vec::with_capacity(params.len());
+        let (mut ints, mut floats) = (0, 0);
+        for (param, annotation) in params {
+            let type_ = match annotation {
+                Some(annotation) => annotated_type(annotation)?,
+                None => {
+                    return Err(unsupported(
+                        param,
+                        "parameters without kok, liu or totuus types",
+                    ))
+                }
+            };
+            match is_float(&type_) {
+                true => floats += 1,
+                false => ints += 1,
+            }
+            if ints > INT_ARGUMENTS.len() || floats > FLOAT_ARGUMENTS.len() {
+                return Err(unsupported(
+                    param,
+                    "more than 6 kok and totuus or 8 liu parameters",
+                ));
+            }
+            typed_params.push((identifier_name(param)?, type_));
+        }
+        let returns = match returns {
+            Some(token) if token.type_() == TokenType::Keyword("tyhjä".to_string()) => None,
+            Some(token) => Some(annotated_type(token)?),
+            None => None,
+        };
+        self.functions.push(Function {
+            name: name.clone(),
+            params: typed_params,
+            returns,
+            defined: false,
+        });
+        self.function_ids.insert(name, self.functions.len() - 1);
+        Ok(())
+    }
+
+    fn program(&mut self, statements: &[Node]) -> Result<(), ErrorType> {
+        let body = self.new_body(None, Vec::new());
+        self.bodies.push(body);
+        for statement in statements {
+            match statement {
+                Node::FuncDefNode(name_tok, ..) => {
+                    let index = self.function_ids[&identifier_name(name_tok)?];
+                    self.functions[index].defined = true;
+                }
+                statement => self.statement(statement)?,
+            }
+        }
+        let body = self.bodies.pop().expect("The program body was pushed");
+
+        let mut code = String::from("fin_main:\n");
+        code.push_str(&prologue(&body));
+        writeln!(code, "    lea rax, [rip + fin_desc_program]").ok();
+        writeln!(code, "    mov {}, rax", stack(8)).ok();
+        writeln!(code, "    mov {}, 0", stack(16)).ok();
+        writeln!(code, "    mov {}, 0", stack(24)).ok();
+        writeln!(code, "    mov qword ptr [rip + fin_program_frame], rbp").ok();
+        writeln!(code, "    mov qword ptr [rip + fin_depth], 1").ok();
+        code.push_str(&body.code);
+        writeln!(code, "{}:\n    leave\n    ret", body.end).ok();
+        code.push_str(&failures(&body));
+        self.text.push_str(&code);
+        Ok(())
+    }
+
+    fn function(&mut self, name_tok: &Token, body: &Node) -> Result<(), ErrorType> {
+        let index = self.function_ids[&identifier_name(name_tok)?];
+        let params = &self.functions[index].params;
+        let mut names = Vec::with_capacity(params.len());
+        for (param, _) in params {
+            if self.function_ids.contains_key(param) {
+                return Err(unsupported(name_tok, "parameters named like functions"));
+            }
+            ast::push_unique(&mut names, param.clone());
+        }
+        ast::collect_locals(body, &mut names)?;
+        // Parameters have their types from the start
+        let locals = names
+            .into_iter()
+            .map(|name| {
+                let type_ = params.iter().find(|(param, _)| *param == name);
+                (name, type_.map(|(_, type_)| type_.clone()))
+            })
+            .collect();
+
+        let function = self.new_body(Some(index), locals);
+        self.bodies.push(function);
+        self.statement(body)?;
+        let body = self.bodies.pop().expect("The function body was pushed");
+
+        let function = &self.functions[index];
+        let mut code = format!("\n# tominto {}\nfin_fn_{}:\n", function.name, index);
+        code.push_str(&prologue(&body));
+        writeln!(code, "    lea rax, [rip + fin_desc_{}]", index).ok();
+        writeln!(code, "    mov {}, rax", stack(8)).ok();
+        writeln!(code, "    mov {}, r10", stack(16)).ok();
+        writeln!(code, "    mov {}, 0", stack(24)).ok();
+        writeln!(code, "    add qword ptr [rip + fin_depth], 1").ok();
+        let (mut ints, mut floats) = (0, 0);
+        for (i, (param, type_)) in function.params.iter().enumerate() {
+            let register = match is_float(type_) {
+                true => {
+                    floats += 1;
+                    FLOAT_ARGUMENTS[floats - 1]
+                }
+                false => {
+                    ints += 1;
+                    INT_ARGUMENTS[ints - 1]
+                }
+            };
+            let local = body
+                .locals
+                .iter()
+                .position(|(local, _)| local == param)
+                .expect("Parameters are locals");
+            let variable = stack(FRAME_HEADER + 8 * (body.arity + local + 1));
+            let arg = stack(FRAME_HEADER + 8 * (i + 1));
+            writeln!(code, "    {} {}, {}", mov(type_), arg, register).ok();
+            writeln!(code, "    {} {}, {}", mov(type_), variable, register).ok();
+        }
+        // Tail calls start over with the locals unset
+        writeln!(code, "{}:", body.start).ok();
+        for (local, flagged) in body.flagged.iter().enumerate() {
+            if *flagged {
+                let flag = FRAME_HEADER + 8 * (body.arity + body.locals.len() + local + 1);
+                writeln!(code, "    mov {}, 0", stack(flag)).ok();
+            }
+        }
+        code.push_str(&body.code);
+        writeln!(code, "{}:", body.end).ok();
+        writeln!(
+            code,
+            "    sub qword ptr [rip + fin_depth], 1\n    leave\n    ret"
+        )
+        .ok();
+        code.push_str(&failures(&body));
+        self.text.push_str(&code);
+        Ok(())
+    }
+
+    fn finish(&mut self, file_name: &str) -> String {
+        let mut asm = String::new();
+        writeln!(
+            asm,
+            "# Generated by finc from {}",
+            file_name.replace('\n', " ")
+        )
+        .ok();
+        writeln!(
+            asm,
+            "    .intel_syntax noprefix\n    .globl fin_main\n    .text"
+        )
+        .ok();
+        asm.push_str(&self.text);
+
+        // Descriptors of the frames, see fin_runtime.s
+        let mut data = String::new();
+        let program = self.string("Program");
+        writeln!(
+            data,
+            "fin_desc_program:\n    .quad {}\n    .quad -1",
+            program
+        )
+        .ok();
+        for index in 0..self.functions.len() {
+            let name = self.string(&format!("{}(", self.functions[index].name));
+            let arity = self.functions[index].params.len();
+            writeln!(
+                data,
+                "fin_desc_{}:\n    .quad {}\n    .quad {}",
+                index, name, arity
+            )
+            .ok();
+            for i in 0..arity {
+                let (param, type_) = self.functions[index].params[i].clone();
+                let separator = if i > 0 { ", " } else { "" };
+                let label = self.string(&format!("{}{} = ", separator, param));
+                let kind = match type_ {
+                    Type::Float => 1,
+                    Type::Boolean => 2,
+                    _ => 0,
+                };
+                writeln!(data, "    .quad {}\n    .quad {}", label, kind).ok();
+            }
+        }
+
+        writeln!(asm, "\n    .section .rodata\n    .balign 8").ok();
+        asm.push_str(&data);
+        for (i, value) in self.floats.iter().enumerate() {
+            writeln!(
+                asm,
+                "fin_float_{}:\n    .quad {} # {:?}",
+                i,
+                value.to_bits(),
+                value
+            )
+            .ok();
+        }
+        for (i, text) in self.strings.iter().enumerate() {
+            writeln!(asm, "fin_str_{}:\n    .asciz {}", i, asm_string(text)).ok();
+        }
+
+        if !self.globals.is_empty() {
+            writeln!(asm, "\n    .data\n    .balign 8").ok();
+            for (i, (name, _)) in self.globals.iter().enumerate() {
+                writeln!(asm, "fin_global_{}: # {}\n    .quad 0", i, name).ok();
+                writeln!(asm, "fin_set_{}:\n    .quad 0", i).ok();
+            }
+        }
+        asm
+    }
+}
+
+// Keeps the frame pointer and makes room for the frame, keeping the stack 16 byte aligned
+fn prologue(body: &Body) -> String {
+    let size = FRAME_HEADER + 8 * (body.arity + 2 * body.locals.len() + body.max_temps);
+    let size = size.div_ceil(16) * 16;
+    format!("    push rbp\n    mov rbp, rsp\n    sub rsp, {}\n", size)
+}
+
+fn failures(body: &Body) -> String {
+    let mut code = String::new();
+    for (label, location, report) in &body.failures {
+        writeln!(
+            code,
+            "{}:\n    lea rdi, [rip + {}]\n    lea rsi, [rip + {}]\n    mov rdx, rbp\n    call fin_fail",
+            label, location, report
+        )
+        .ok();
+    }
+    code
+}
+
+fn stack(offset: usize) -> String {
+    format!("qword ptr [rbp - {}]", offset)
+}
+
+fn is_float(type_: &Type) -> bool {
+    *type_ == Type::Float
+}
+
+fn mov(type_: &Type) -> &'static str {
+    match is_float(type_) {
+        true => "movsd",
+        false => "mov",
+    }
+}
+
+// Where values are returned, and moved through between memory
+fn scratch(type_: &Type) -> &'static str {
+    match is_float(type_) {
+        true => "xmm0",
+        false => "rax",
+    }
+}
+
+fn register_name(type_: &Type, register: usize) -> &'static str {
+    match is_float(type_) {
+        true => FLOAT_REGISTERS[register],
+        false => INT_REGISTERS[register],
+    }
+}
+
+// Where a value made by a node points in errors, like in the interpreter. Results of calls
+// and variables point at the name, and negated numbers start at the '-'.
+fn value_span(node: &Node) -> (Option<Position>, Option<Position>) {
+    match node {
+        Node::Value(token) | Node::VarAccessNode(token) | Node::CallNode(token, _) => {
+            (token.position_start(), token.position_end())
+        }
+        Node::Unary(optok, operand) => (optok.position_start(), value_span(operand).1),
+        Node::VarAssignNode(_, _, value) => value_span(value),
+        node => (node.pos_start(), node.pos_end()),
+    }
+}
+
+fn annotated_type(token: &Token) -> Result<Type, ErrorType> {
+    let name = match token.type_() {
+        TokenType::Identifier(name) | TokenType::Keyword(name) => name,
+        _ => String::new(),
+    };
+    match Type::from_name(&name) {
+        Some(type_ @ (Type::Integer | Type::Float | Type::Boolean)) => Ok(type_),
+        Some(type_) => Err(unsupported(token, &format!("{} values", type_))),
+        None => Err(type_error(token, format!("Unknown type {}", name))),
+    }
+}
+
+fn check_variable(token: &Token, name: &str, known: &Type, type_: &Type) -> Result<(), ErrorType> {
+    match known == type_ {
+        true => Ok(()),
+        false => Err(type_error(
+            token,
+            format!("{} is {}, not {}", name, known, type_),
+        )),
+    }
+}
+
+fn not_defined(token: &Token, name: &str) -> ErrorType {
+    type_error(token, format!("{} is not defined", name))
+}
+
+fn unsupported(token: &Token, what: &str) -> ErrorType {
+    type_error(
+        token,
+        format!("{} are not supported in assembly programs", what),
+    )
+}
+
+fn unsupported_node(node: &Node, what: &str) -> ErrorType {
+    node_error(
+        node,
+        format!("{} are not supported in assembly programs", what),
+    )
+}
+
+fn type_error(token: &Token, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(
+        token.position_start(),
+        token.position_end(),
+        message,
+    ))
+}
+
+fn node_error(node: &Node, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(node.pos_start(), node.pos_end(), message))
+}
+
+// A string for the GNU assembler. Bytes outside of printable ASCII are octal escapes.
+fn asm_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b' '..=b'~' => literal.push(byte as char),
+            byte => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::test_support::Target;
+    use std::fs;
+
+    const ASM: Target = Target {
+        name: "asm",
+        generate: Engine::to_asm,
+        build,
+    };
+
+    #[test]
+    fn test_same_as_interpeter() {
+        for text in [
+            "tulosta(1 + 2 * 3, 7 / 2, -7 / 2, 2 ^ 10, 2.0 ^ -2, 1.5 * 4.0, 0.1 + 0.2, 1.0 / 3.0)",
+            "tulosta(1000000000000000000000.0 * 1.0, 1.0 / 10000000.0, -0.0, liu(3), 0.000000000000000000000000000123 * 1.0)",
+            "tulosta(\"äö\", tosi, 1 < 2, 1.5 >= 2.5, 2 == 2, tosi != epätosi, ei tosi, 0.0 / 1.0 == -0.0)",
+            "muut n = 0.0 / 1.0 * 0.0; muut x = n / 1.0; tulosta(x < x, x == x, x != x, x >= 1.0)",
+            "tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; tulosta(fib(20))",
+            "tominto f(n: kok, s: kok): kok { kun n > 0 { muut s = s + n; muut n = n - 1 }; palata s }; tulosta(f(100000, 0))",
+            "tominto f(a: liu, b: kok, c: totuus, d: liu): liu { jos c { palata a * liu(b) + d }; palata d }; tulosta(f(1.5, 2, tosi, 0.25), f(1.5, 2, epätosi, 0.25))",
+            "tominto näytä(x: kok) { tulosta(\"x on\", x) }; näytä(3); näytä(-3)",
+            "muut x = 1; tominto f(): kok { palata x }; muut x = 2; tulosta(f())",
+            "jos 1 > 2 { tulosta(1) } muuten jos 1 == 1 ja ei epätosi { tulosta(2) } muuten { tulosta(3) }",
+            "tominto t(x: kok): totuus { tulosta(x); palata x > 1 }; tulosta(t(1) ja t(2), t(2) tai t(3), t(1) tai t(2) ja t(3))",
+            "muut a = 1; muut b = 2; tulosta(((a + b) * (a - b) + (a * b - (b - a) * (a + b))) * ((a + 1) * (b + 1) - (a + b) * (a - b)))",
+            "muut x = 1.5; tulosta(((x + x) * (x - 1.0) + (x * x - (x - 0.5) * (x + x))) * ((x + 1.0) * (x + 2.0) - (x + x) * (x - 2.0)))",
+            "tominto f(n: kok): kok { jos n > 0 { palata f(n - 1) }; palata 7 }; tulosta(f(50000))",
+            "muut a = 3; tulosta(a ^ 3, (-a) ^ 3, a ^ 0, 2.0 ^ 10, 0.5 ^ -2, -a, +a, -(1.5 * liu(a)))",
+            "palata 1; tulosta(2)",
+            "muut i = 0; kun i < 3 { muut x = i * 2; muut i = i + 1 }; tulosta(x)",
+            "tominto f(n: kok): kok { jos n > 0 { muut r = 1 } muuten { palata 2 }; palata r }; tulosta(f(1), f(0))",
+        ] {
+            ASM.assert_same(text);
+        }
+    }
+
+    #[test]
+    fn test_same_errors() {
+        for text in [
+            "tulosta(1); muut a = 9223372036854775807; a + 1",
+            "muut a = 0; tulosta(\"ennen\"); 1 / a",
+            "muut a = -9223372036854775807 - 1; tulosta(a / -1)",
+            "muut a = -9223372036854775807 - 1; -a",
+            "muut a = 0.0; 1.0 / a",
+            "tominto f(n: kok): kok { palata 10 / n }; tominto g(n: kok): kok { palata f(n - 1) }; g(1)",
+            "tominto f(n: kok): kok { palata 1 + f(n + 1) }; f(0)",
+            "tominto f(n: kok, x: liu, t: totuus): kok { jos n > 0 { palata f(n - 1, x * 2.0, ei t) }; palata 1 / n }; f(3, 0.1, tosi)",
+            "muut a = 0; a ^ -2",
+            "muut a = 2; a ^ 63",
+            "muut a = 0.0; a ^ -1",
+            "tulosta(1)\nmuut b = 4611686018427387904\nmuut c = b * 2",
+            "muut x = 5; jos x > 10 { muut y = 1 }; tulosta(y)",
+            "tominto f(n: kok): kok { jos n > 0 { muut r = 1 }; palata r }; tulosta(f(1)); f(0)",
+            "tominto f(): kok { palata g }; tulosta(f()); muut g = 1",
+            "tominto f(n: kok): kok { jos n > 1 { palata f(n - 1) }; jos n > 0 { muut r = n }; palata r }; tulosta(f(1)); f(2)",
+        ] {
+            ASM.assert_same(text);
+        }
+    }
+
+    #[test]
+    fn test_float_printing() {
+        // Shortest digits that read back as the same float, like Rust's Display
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut values = vec![
+            0.1,
+            0.3,
+            1e21,
+            1e-7,
+            123456.789,
+            5e-324,
+            2.2250738585072014e-308,
+            1e23,
+            9007199254740993.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            0.5,
+            2.0,
+            1.0 / 3.0,
+            100.0,
+            4.35,
+            0.000001,
+        ];
+        for _ in 0..200 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let value = f64::from_bits(state);
+            if value.is_finite() {
+                values.push(value);
+            }
+        }
+        // Literals are written out without exponents
+        let text: Vec<String> = values
+            .iter()
+            .map(|value| match value.fract() == 0.0 {
+                true => format!("tulosta({}.0 * 1.0)", value),
+                false => format!("tulosta({} * 1.0)", value),
+            })
+            .collect();
+        let expected: String = values.iter().map(|value| format!("{}\n", value)).collect();
+        assert_eq!(ASM.compile_and_run(&text.join("\n")).0, expected);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for (text, message) in [
+            ("muut a = 2i", "kompleksi numbers are not supported"),
+            ("muut a = [1]", "lista values are not supported"),
+            ("muut a = \"a\"", "teksti values are not supported"),
+            (
+                "tominto f(x) { palata x }",
+                "parameters without kok, liu or totuus types",
+            ),
+            (
+                "tulosta(pituus(\"a\"))",
+                "calls to pituus are not supported",
+            ),
+            (
+                "tominto f() { 1 }; muut g = f",
+                "functions as values are not supported",
+            ),
+            (
+                "muut a = 2.0; a ^ 0.5",
+                "powers with liu exponents are not supported",
+            ),
+            (
+                "muut g = 0; tominto f(n: kok): kok { muut g = g + n; palata g }; f(1)",
+                "reads of globals before setting a local of the same name are not supported",
+            ),
+        ] {
+            let error = ASM.unsupported_message(text);
+            assert!(error.contains(message), "{}: {}", text, error);
+        }
+
+        let (_, error) = ASM.compile_and_run("muut a = 2; a ^ -1");
+        assert!(
+            error.contains("murto numbers are not supported"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_fibonacci_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examplez/src/fibonacci_asm.fin");
+        ASM.assert_same(&fs::read_to_string(path).unwrap());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::errors::{ErrorType, TypeError};
+use crate::parser::Node;
+use crate::token::{Token, TokenType};
+
+// What the compilers need to know about syntax trees before they generate code for them
+
+/// The name of a variable or function token
+pub fn identifier_name(token: &Token) -> Result<String, ErrorType> {
+    match token.type_() {
+        TokenType::Identifier(name) => Ok(name),
+        _ => Err(ErrorType::TypeError(TypeError::new(
+            token.position_start(),
+            token.position_end(),
+            "Invalid Variable name".to_string(),
+        ))),
+    }
+}
+
+/// Every variable a node sets, in order of appearance, added to the ones already there.
+/// Functions start with their parameters.
+pub fn collect_locals(node: &Node, locals: &mut Vec<String>) -> Result<(), ErrorType> {
+    if let Node::VarAssignNode(token, _, _) = node {
+        push_unique(locals, identifier_name(token)?);
+    }
+    for child in node.children() {
+        collect_locals(child, locals)?;
+    }
+    Ok(())
+}
+
+/// Adds a name unless it is there already
+pub fn push_unique(names: &mut Vec<String>, name: String) {
+    if !names.contains(&name) {
+        names.push(name);
+    }
+}
+
+/// Whether a block always ends with `palata`, so the code after it does not run
+pub fn ends_with_return(node: &Node) -> bool {
+    match node {
+        Node::ReturnNode(..) => true,
+        Node::StatementsNode(nodes) => nodes.last().is_some_and(ends_with_return),
+        _ => false,
+    }
+}
+
+/// Whether a function is defined anywhere inside the node
+pub fn defines_function(node: &Node) -> bool {
+    matches!(node, Node::FuncDefNode(..)) || node.children().into_iter().any(defines_function)
+}
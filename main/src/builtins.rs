@@ -0,0 +1,291 @@
+use crate::errors::ErrorType;
+use crate::interpeter::Interpeter;
+use crate::native::{Arity, NativeFn, Registry};
+use crate::number::{Number, NumberType};
+use crate::value::Value;
+use num::complex::Complex64;
+use num::Signed;
+use std::cmp::Ordering;
+use std::num::Wrapping;
+
+type IntegerOperation = fn(i64, i64) -> i64;
+
+// Integer operations that wrap around or saturate instead of reporting overflow
+const INTEGER_BUILTINS: [(&str, IntegerOperation); 8] = [
+    ("wrapping_add", i64::wrapping_add),
+    ("wrapping_sub", i64::wrapping_sub),
+    ("wrapping_mul", i64::wrapping_mul),
+    ("wrapping_pow", wrapping_pow),
+    ("saturating_add", i64::saturating_add),
+    ("saturating_sub", i64::saturating_sub),
+    ("saturating_mul", i64::saturating_mul),
+    ("saturating_pow", saturating_pow),
+];
+
+fn wrapping_pow(base: i64, exponent: i64) -> i64 {
+    num::pow(Wrapping(base), exponent as usize).0
+}
+
+fn saturating_pow(base: i64, exponent: i64) -> i64 {
+    match num::checked_pow(base, exponent as usize) {
+        Some(val) => val,
+        None if base < 0 && exponent % 2 == 1 => i64::MIN,
+        None => i64::MAX,
+    }
+}
+
+// Absolute value and the complex plane accessors.
+// Real numbers act as complex numbers without an imaginary part.
+// Returns `None` when the result does not fit in the type.
+fn complex_builtin(name: &str, num: &NumberType) -> Option<NumberType> {
+    let complex = num.to_complex();
+    let result = match (name, num) {
+        ("abs", NumberType::Integer(num)) => {
+            let val = num.value().checked_abs()?;
+            NumberType::Integer(num.map(|_| val))
+        }
+        ("abs", NumberType::Float(num)) => NumberType::Float(num.map(|val| val.abs())),
+        ("abs", NumberType::BigInteger(num)) => NumberType::BigInteger(num.map(|val| val.abs())),
+        ("abs", NumberType::Fraction(num)) => NumberType::Fraction(num.map(|val| val.abs())),
+        ("abs", NumberType::Complex(_)) => NumberType::Float(complex.map(|val| val.norm())),
+        ("arg", _) => NumberType::Float(complex.map(|val| val.arg())),
+        ("conj", NumberType::Complex(_)) => NumberType::Complex(complex.map(|val| val.conj())),
+        ("conj", num) => num.clone(),
+        ("re", _) => NumberType::Float(complex.map(|val| val.re)),
+        _ => NumberType::Float(complex.map(|val| val.im)),
+    };
+    Some(result)
+}
+
+/// The builtins every program can use
+pub fn prelude() -> Registry {
+    let mut registry = Registry::new();
+
+    registry.register(NativeFn::new(
+        "tulosta",
+        Arity::at_least(0),
+        |interpeter, args| {
+            let texts: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            let output = interpeter.output();
+            let result = writeln!(output.borrow_mut(), "{}", texts.join(" "));
+            match result {
+                Ok(()) => Ok(Value::Nil),
+                Err(e) => Err(interpeter.native_error(format!("Cant print: {}", e))),
+            }
+        },
+    ));
+
+    registry.register(NativeFn::new(
+        "syöte",
+        Arity::between(0, 1),
+        |interpeter, args| {
+            if let Some(prompt) = args.first() {
+                let output = interpeter.output();
+                let mut output = output.borrow_mut();
+                write!(output, "{}", prompt)
+                    .and_then(|_| output.flush())
+                    .ok();
+            }
+            let mut line = String::new();
+            match interpeter.read_line(&mut line) {
+                Ok(0) => Ok(Value::Nil),
+                Ok(_) => Ok(Value::Text(
+                    line.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                )),
+                Err(e) => Err(interpeter.native_error(format!("Cant read input: {}", e))),
+            }
+        },
+    ));
+
+    registry.register(NativeFn::new("str", Arity::exactly(1), |_, args| {
+        Ok(Value::Text(args[0].to_string()))
+    }));
+
+    registry.register(NativeFn::new(
+        "pituus",
+        Arity::exactly(1),
+        |interpeter, args| {
+            let length = match &args[0] {
+                Value::Text(text) => text.chars().count(),
+                Value::List(values) => values.len(),
+                Value::Record(fields) => fields.len(),
+                value => {
+                    return Err(interpeter.native_error(format!(
+                        "pituus expects teksti, lista or tietue, got {}",
+                        value.type_name()
+                    )))
+                }
+            };
+            Ok(Value::Number(NumberType::Integer(Number::new_no_pos(
+                length as i64,
+            ))))
+        },
+    ));
+
+    for name in ["min", "max"] {
+        registry.register(NativeFn::new(
+            name,
+            Arity::at_least(1),
+            move |interpeter, args| extreme(interpeter, name, args),
+        ));
+    }
+
+    registry.register(NativeFn::new(
+        "sqrt",
+        Arity::exactly(1),
+        |interpeter, args| {
+            let num = number(interpeter, "sqrt", &args[0])?;
+            let result = match num {
+                NumberType::Complex(num) => NumberType::Complex(num.map(|val| val.sqrt())),
+                num => {
+                    let float = num.to_float();
+                    if float.value() < 0.0 {
+                        NumberType::Complex(float.map(|val| Complex64::new(*val, 0.0).sqrt()))
+                    } else {
+                        NumberType::Float(float.map(|val| val.sqrt()))
+                    }
+                }
+            };
+            Ok(Value::Number(result))
+        },
+    ));
+
+    for name in ["abs", "arg", "conj", "re", "im"] {
+        registry.register(NativeFn::new(
+            name,
+            Arity::exactly(1),
+            move |interpeter, args| {
+                let num = number(interpeter, name, &args[0])?;
+                match complex_builtin(name, &num) {
+                    Some(num) => Ok(Value::Number(num)),
+                    None => Err(interpeter.native_error("kokonaisluvun ylivuoto".to_string())),
+                }
+            },
+        ));
+    }
+
+    registry.register(NativeFn::new(
+        "iso",
+        Arity::exactly(1),
+        |interpeter, args| match &args[0] {
+            Value::Number(NumberType::Integer(num)) => {
+                Ok(Value::Number(NumberType::BigInteger(num.to_big())))
+            }
+            num @ Value::Number(NumberType::BigInteger(_)) => Ok(num.clone()),
+            _ => Err(interpeter.native_error("iso expects one integer".to_string())),
+        },
+    ));
+
+    registry.register(NativeFn::new(
+        "murto",
+        Arity::between(1, 2),
+        |interpeter, args| {
+            let whole = |value: &Value| match value {
+                Value::Number(NumberType::Integer(num)) => Some(num.to_big()),
+                Value::Number(NumberType::BigInteger(num)) => Some(num.clone()),
+                _ => None,
+            };
+            let fraction = match args.as_slice() {
+                [Value::Number(NumberType::Fraction(num))] => num.clone(),
+                [num] => match whole(num) {
+                    Some(num) => num.to_fraction(),
+                    None => {
+                        return Err(interpeter.native_error("murto expects integers".to_string()))
+                    }
+                },
+                [num1, num2] => match (whole(num1), whole(num2)) {
+                    (Some(num1), Some(num2)) => num1.over(num2)?,
+                    _ => return Err(interpeter.native_error("murto expects integers".to_string())),
+                },
+                _ => unreachable!("murto takes 1 or 2 arguments"),
+            };
+            Ok(Value::Number(NumberType::Fraction(fraction)))
+        },
+    ));
+
+    registry.register(NativeFn::new(
+        "liu",
+        Arity::exactly(1),
+        |interpeter, args| {
+            let num = number(interpeter, "liu", &args[0])?;
+            Ok(Value::Number(NumberType::Float(num.to_float())))
+        },
+    ));
+
+    for (name, function) in INTEGER_BUILTINS {
+        registry.register(NativeFn::new(name, Arity::exactly(2), move |interpeter, args| {
+            let (left, right) = match args.as_slice() {
+                [Value::Number(NumberType::Integer(left)), Value::Number(NumberType::Integer(right))] => {
+                    (left.value(), right.value())
+                }
+                _ => return Err(interpeter.native_error(format!("{} expects two integers", name))),
+            };
+            if name.ends_with("_pow") && right < 0 {
+                return Err(interpeter.native_error("Cant raise to Negative power".to_string()));
+            }
+            Ok(Value::Number(NumberType::Integer(Number::new_no_pos(
+                function(left, right),
+            ))))
+        }));
+    }
+
+    registry
+}
+
+fn number(interpeter: &Interpeter, name: &str, value: &Value) -> Result<NumberType, ErrorType> {
+    match value {
+        Value::Number(num) => Ok(num.clone()),
+        value => Err(interpeter.native_error(format!(
+            "{} expects numbers, got {}",
+            name,
+            value.type_name()
+        ))),
+    }
+}
+
+// The smallest or largest of the arguments, or of the items of a single list
+fn extreme(interpeter: &Interpeter, name: &str, args: Vec<Value>) -> Result<Value, ErrorType> {
+    let values = match args.as_slice() {
+        [Value::List(values)] if !values.is_empty() => values.clone(),
+        [Value::List(_)] => {
+            return Err(interpeter.native_error(format!("{} of an empty lista", name)))
+        }
+        _ => args,
+    };
+    let wanted = if name == "min" {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    };
+
+    let mut values = values.into_iter();
+    let mut best = values.next().unwrap_or(Value::Nil);
+    for value in values {
+        let ordering = match (&value, &best) {
+            (Value::Number(num1), Value::Number(num2)) => match NumberType::compare(num1, num2) {
+                Some(ordering)
+                    if !matches!(num1, NumberType::Complex(_))
+                        && !matches!(num2, NumberType::Complex(_)) =>
+                {
+                    ordering
+                }
+                _ => None,
+            },
+            (Value::Text(text1), Value::Text(text2)) => Some(text1.cmp(text2)),
+            _ => None,
+        };
+        match ordering {
+            Some(ordering) if ordering == wanted => best = value,
+            Some(_) => (),
+            None => {
+                return Err(interpeter.native_error(format!(
+                    "{} cant compare {} with {}",
+                    name,
+                    value.type_name(),
+                    best.type_name()
+                )))
+            }
+        }
+    }
+    Ok(best)
+}
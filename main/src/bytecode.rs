@@ -0,0 +1,332 @@
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+use std::fmt;
+use std::rc::Rc;
+
+/// Where an instruction came from in the source, for its errors
+pub type Span = (Option<Position>, Option<Position>);
+
+/// One instruction of the virtual machine.
+/// Operands are indexes into the tables of the chunk, or jump targets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// Pushes a constant
+    Constant(usize),
+    Nil,
+    Pop,
+    /// Pushes a variable found by name in the symbol map of the call or its parents
+    GetName(usize),
+    /// Sets a variable in the symbol map of the call, leaving the value on the stack
+    SetName(usize),
+    /// Sets a variable in the symbol map of the call and pops the value
+    StoreName(usize),
+    /// Pushes a local slot, or the variable of the same name around the function if it is unset
+    GetLocal(usize),
+    /// Sets a local slot, leaving the value on the stack
+    SetLocal(usize),
+    /// Sets a local slot and pops the value
+    StoreLocal(usize),
+    /// Applies the operator token to the two values on top of the stack
+    Binary(usize),
+    /// Applies the operator token to the value on top of the stack and a constant
+    BinaryConstant {
+        operator: usize,
+        constant: usize,
+    },
+    /// Compares the two values on top of the stack with the operator token,
+    /// and jumps if the comparison does not hold
+    CompareJump {
+        operator: usize,
+        target: usize,
+    },
+    /// Applies unary `+` or `-` with the operator token
+    Unary(usize),
+    /// `ei`
+    Not,
+    /// Pops a condition and jumps if it is `epätosi`
+    JumpIfFalse(usize),
+    /// For `ja` and `tai`. Pops a condition and jumps with it pushed back if it equals `when`.
+    ShortCircuit {
+        target: usize,
+        when: bool,
+    },
+    /// Checks that the value on top of the stack is a `totuus`
+    Truth,
+    Jump(usize),
+    /// Makes a list of that many values
+    List(usize),
+    /// Makes a record from a list of field names and their values
+    Record(usize),
+    Index,
+    /// Looks up the field named by the token
+    Field(usize),
+    /// Defines a function in the symbol map of the call and pushes it
+    Function(usize),
+    /// Calls the function on top of the stack with the arguments below it.
+    /// `name` is the token the function was called by.
+    Call {
+        argc: usize,
+        name: usize,
+    },
+    /// A call that is returned right away. Calls to the running function reuse its frame.
+    TailCall {
+        argc: usize,
+        name: usize,
+    },
+    Return,
+}
+
+/// A function as written in the source, to be made into a value by `Instruction::Function`
+#[derive(Debug, Clone)]
+pub struct FunctionTemplate {
+    pub name: String,
+    pub params: Vec<Token>,
+    pub body: Rc<Node>,
+}
+
+/// Compiled code of a program or a function, with the tables its instructions refer to
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    name: String,
+    code: Vec<Instruction>,
+    spans: Vec<Span>,
+    constants: Vec<Value>,
+    names: Vec<String>,
+    tokens: Vec<Token>,
+    records: Vec<Vec<String>>,
+    functions: Vec<FunctionTemplate>,
+    // Names of the local slots. Functions that define other functions keep their
+    // variables in a symbol map instead, and have no slots.
+    locals: Vec<String>,
+    uses_slots: bool,
+}
+
+impl Chunk {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn span(&self, offset: usize) -> Span {
+        self.spans[offset].clone()
+    }
+
+    pub fn constant(&self, index: usize) -> &Value {
+        &self.constants[index]
+    }
+
+    pub fn name_at(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    pub fn token(&self, index: usize) -> &Token {
+        &self.tokens[index]
+    }
+
+    pub fn record(&self, index: usize) -> &[String] {
+        &self.records[index]
+    }
+
+    pub fn function(&self, index: usize) -> &FunctionTemplate {
+        &self.functions[index]
+    }
+
+    pub fn functions(&self) -> &[FunctionTemplate] {
+        &self.functions
+    }
+
+    pub fn locals(&self) -> &[String] {
+        &self.locals
+    }
+
+    pub fn uses_slots(&self) -> bool {
+        self.uses_slots
+    }
+
+    /// Adds an instruction and returns its offset
+    pub fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.code.push(instruction);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `offset` to the next instruction
+    pub fn patch_jump(&mut self, offset: usize) {
+        let target = self.code.len();
+        match &mut self.code[offset] {
+            Instruction::Jump(to)
+            | Instruction::JumpIfFalse(to)
+            | Instruction::CompareJump { target: to, .. }
+            | Instruction::ShortCircuit { target: to, .. } => *to = target,
+            instruction => panic!("Cant patch {:?}, it is not a jump", instruction),
+        }
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn add_name(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        }
+    }
+
+    pub fn add_token(&mut self, token: Token) -> usize {
+        self.tokens.push(token);
+        self.tokens.len() - 1
+    }
+
+    pub fn add_record(&mut self, fields: Vec<String>) -> usize {
+        self.records.push(fields);
+        self.records.len() - 1
+    }
+
+    pub fn add_function(&mut self, function: FunctionTemplate) -> usize {
+        self.functions.push(function);
+        self.functions.len() - 1
+    }
+
+    pub fn set_locals(&mut self, locals: Vec<String>) {
+        self.locals = locals;
+        self.uses_slots = true;
+    }
+
+    pub fn local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().position(|local| local == name)
+    }
+
+    // What an operand stands for, shown next to it in listings
+    fn describe(&self, instruction: &Instruction) -> String {
+        match *instruction {
+            Instruction::Constant(index) => self.constants[index].representation(),
+            Instruction::GetName(index)
+            | Instruction::SetName(index)
+            | Instruction::StoreName(index) => self.names[index].clone(),
+            Instruction::GetLocal(slot)
+            | Instruction::SetLocal(slot)
+            | Instruction::StoreLocal(slot) => self.locals[slot].clone(),
+            Instruction::BinaryConstant { operator, constant } => format!(
+                "{} {}",
+                token_text(&self.tokens[operator]),
+                self.constants[constant].representation()
+            ),
+            Instruction::Binary(index)
+            | Instruction::CompareJump {
+                operator: index, ..
+            }
+            | Instruction::Unary(index)
+            | Instruction::Field(index)
+            | Instruction::Call { name: index, .. }
+            | Instruction::TailCall { name: index, .. } => token_text(&self.tokens[index]),
+            Instruction::Record(index) => self.records[index].join(", "),
+            Instruction::Function(index) => self.functions[index].name.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Lists the instructions with their source lines, one per line
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "== {} ==", self.name)?;
+        if self.uses_slots {
+            writeln!(f, "locals: {}", self.locals.join(", "))?;
+        }
+        let mut last_line = None;
+        for (offset, instruction) in self.code.iter().enumerate() {
+            let line = self.spans[offset].0.as_ref().map(|pos| pos.line() + 1);
+            let line_text = match line {
+                Some(line) if line != last_line.unwrap_or(-1) => format!("{:4}", line),
+                _ => "   |".to_string(),
+            };
+            if line.is_some() {
+                last_line = line;
+            }
+
+            let operation = match *instruction {
+                Instruction::Constant(index) => format!("CONSTANT {}", index),
+                Instruction::Nil => "NIL".to_string(),
+                Instruction::Pop => "POP".to_string(),
+                Instruction::GetName(index) => format!("GET_NAME {}", index),
+                Instruction::SetName(index) => format!("SET_NAME {}", index),
+                Instruction::StoreName(index) => format!("STORE_NAME {}", index),
+                Instruction::GetLocal(slot) => format!("GET_LOCAL {}", slot),
+                Instruction::SetLocal(slot) => format!("SET_LOCAL {}", slot),
+                Instruction::StoreLocal(slot) => format!("STORE_LOCAL {}", slot),
+                Instruction::Binary(_) => "BINARY".to_string(),
+                Instruction::BinaryConstant { constant, .. } => {
+                    format!("BINARY_CONSTANT {}", constant)
+                }
+                Instruction::CompareJump { target, .. } => format!("COMPARE_JUMP {}", target),
+                Instruction::Unary(_) => "UNARY".to_string(),
+                Instruction::Not => "NOT".to_string(),
+                Instruction::JumpIfFalse(target) => format!("JUMP_IF_FALSE {}", target),
+                Instruction::ShortCircuit { target, when } => {
+                    format!(
+                        "SHORT_CIRCUIT {} {}",
+                        target,
+                        if when { "tosi" } else { "epätosi" }
+                    )
+                }
+                Instruction::Truth => "TRUTH".to_string(),
+                Instruction::Jump(target) => format!("JUMP {}", target),
+                Instruction::List(count) => format!("LIST {}", count),
+                Instruction::Record(index) => format!("RECORD {}", index),
+                Instruction::Index => "INDEX".to_string(),
+                Instruction::Field(_) => "FIELD".to_string(),
+                Instruction::Function(index) => format!("FUNCTION {}", index),
+                Instruction::Call { argc, .. } => format!("CALL {}", argc),
+                Instruction::TailCall { argc, .. } => format!("TAIL_CALL {}", argc),
+                Instruction::Return => "RETURN".to_string(),
+            };
+            let description = self.describe(instruction);
+            if description.is_empty() {
+                writeln!(f, "{:04} {} {}", offset, line_text, operation)?;
+            } else {
+                writeln!(
+                    f,
+                    "{:04} {} {:<20} ; {}",
+                    offset, line_text, operation, description
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Tokens as written in the source
+fn token_text(token: &Token) -> String {
+    match token.type_() {
+        TokenType::Identifier(name) | TokenType::Keyword(name) => name,
+        TokenType::Plus => "+".to_string(),
+        TokenType::Minus => "-".to_string(),
+        TokenType::Multiply => "*".to_string(),
+        TokenType::Divide => "/".to_string(),
+        TokenType::Pow => "^".to_string(),
+        TokenType::EqualEqual => "==".to_string(),
+        TokenType::NotEqual => "!=".to_string(),
+        TokenType::LessThan => "<".to_string(),
+        TokenType::GreaterThan => ">".to_string(),
+        TokenType::LessThanEqual => "<=".to_string(),
+        TokenType::GreaterThanEqual => ">=".to_string(),
+        other => format!("{:?}", other),
+    }
+}
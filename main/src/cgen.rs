@@ -0,0 +1,889 @@
+use crate::ast::{collect_locals, defines_function, identifier_name, push_unique};
+use crate::errors::{ErrorType, TypeError};
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Lowers syntax trees to C99. Values stay dynamic like in the interpreter: the runtime header
+// has the operators, builtins and errors, and the generated code calls them in the order the
+// interpreter visits nodes, so programs print the same output and fail with the same errors.
+
+/// The runtime header generated programs include
+pub const RUNTIME: &str = include_str!("fin_runtime.h");
+
+/// The name generated programs include the runtime with
+pub const RUNTIME_NAME: &str = "fin_runtime.h";
+
+/// Generates a C program. `file_name` and `source` are shown in the errors of the program.
+///
+/// The `iso`, `murto` and `kompleksi` number types are not supported. Their literals are errors
+/// here, and operations that would make them are errors when the program runs.
+pub fn generate(file_name: &str, source: &str, node: &Node) -> Result<String, ErrorType> {
+    let mut generator = Generator::default();
+    generator.bodies.push(Body::new(Scope::Globals, None));
+    generator.statements(node)?;
+    let program = generator.bodies.pop().expect("The program body was pushed");
+    Ok(generator.finish(file_name, source, program))
+}
+
+/// Compiles a generated program to an executable with the C compiler in `CC`, or `cc`
+pub fn build(c_source: &str, output: &Path) -> io::Result<()> {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let build = BUILDS.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("finc-{}-{}", process::id(), build));
+    fs::create_dir_all(&dir)?;
+    let result = compile_in(&dir, c_source, output);
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+fn compile_in(dir: &Path, c_source: &str, output: &Path) -> io::Result<()> {
+    let source = dir.join("program.c");
+    fs::write(dir.join(RUNTIME_NAME), RUNTIME)?;
+    fs::write(&source, c_source)?;
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let result = Command::new(&compiler)
+        .args(["-std=c99", "-O2", "-o"])
+        .arg(output)
+        .arg(&source)
+        .arg("-lm")
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cant run {}: {}", compiler, e)))?;
+    if !result.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed:\n{}",
+            compiler,
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(())
+}
+
+// Where the variables of the code being generated live
+enum Scope {
+    // In the symbol map of the program
+    Globals,
+    // In the symbol map of the call, because functions defined inside can see it
+    Env,
+    // In C variables numbered by their place here. Other names are looked up around
+    // the function.
+    Locals(Vec<String>),
+}
+
+// The C code of the program or of one function
+struct Body {
+    code: String,
+    indent: usize,
+    scope: Scope,
+    // The function, for its tail calls to itself
+    function: Option<usize>,
+    arity: usize,
+    tail_calls: bool,
+}
+
+impl Body {
+    fn new(scope: Scope, function: Option<usize>) -> Self {
+        Self {
+            code: String::new(),
+            indent: 1,
+            scope,
+            function,
+            arity: 0,
+            tail_calls: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Generator {
+    bodies: Vec<Body>,
+    symbols: Vec<String>,
+    symbol_ids: HashMap<String, usize>,
+    spans: Vec<(i64, i64, i64, i64)>,
+    span_ids: HashMap<(i64, i64, i64, i64), usize>,
+    texts: Vec<String>,
+    params: Vec<Vec<usize>>,
+    functions: Vec<String>,
+    temps: usize,
+}
+
+impl Generator {
+    fn body(&mut self) -> &mut Body {
+        self.bodies
+            .last_mut()
+            .expect("Code is generated inside a body")
+    }
+
+    fn line(&mut self, line: String) {
+        let body = self.body();
+        for _ in 0..body.indent {
+            body.code.push_str("    ");
+        }
+        body.code.push_str(&line);
+        body.code.push('\n');
+    }
+
+    fn open(&mut self, line: String) {
+        self.line(line);
+        self.body().indent += 1;
+    }
+
+    fn close(&mut self, line: &str) {
+        self.body().indent -= 1;
+        self.line(line.to_string());
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("t{}", self.temps)
+    }
+
+    // Declares a temporary holding the value of a C expression
+    fn assign(&mut self, expression: String) -> String {
+        let temp = self.temp();
+        self.line(format!("FinValue {} = {};", temp, expression));
+        temp
+    }
+
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(id) = self.symbol_ids.get(name) {
+            return *id;
+        }
+        self.symbols.push(name.to_string());
+        self.symbol_ids
+            .insert(name.to_string(), self.symbols.len() - 1);
+        self.symbols.len() - 1
+    }
+
+    // The number of a span in the span table, or -1 without a position
+    fn span(&mut self, start: Option<Position>, end: Option<Position>) -> i64 {
+        let start = match start {
+            Some(start) => start,
+            None => return -1,
+        };
+        let end = end.unwrap_or_else(|| start.clone());
+        let key = (start.line(), start.column(), end.line(), end.column());
+        if let Some(id) = self.span_ids.get(&key) {
+            return *id as i64;
+        }
+        self.spans.push(key);
+        self.span_ids.insert(key, self.spans.len() - 1);
+        self.spans.len() as i64 - 1
+    }
+
+    fn node_span(&mut self, node: &Node) -> i64 {
+        self.span(node.pos_start(), node.pos_end())
+    }
+
+    fn token_span(&mut self, token: &Token) -> i64 {
+        self.span(token.position_start(), token.position_end())
+    }
+
+    // Generates a node and returns the temporary that holds its value
+    fn expression(&mut self, node: &Node) -> Result<String, ErrorType> {
+        match node {
+            Node::Binop(..) => self.binops(node),
+            Node::Value(token) => self.value(token),
+            Node::Unary(optok, operand) => self.unary(optok, operand),
+            Node::VarAccessNode(token) => {
+                let name = identifier_name(token)?;
+                let span = self.token_span(token);
+                let value = self.get(&name, span);
+                Ok(self.assign(value))
+            }
+            Node::VarAssignNode(token, _, value) => {
+                let name = identifier_name(token)?;
+                let value = self.expression(value)?;
+                self.set(&name, &value);
+                Ok(value)
+            }
+            Node::CallNode(name_tok, args) => {
+                let (callee, argc, args, span) = self.call_parts(name_tok, args)?;
+                let name = c_string(&identifier_name(name_tok)?);
+                Ok(self.assign(format!(
+                    "fin_call({}, {}, {}, {}, {})",
+                    callee, name, argc, args, span
+                )))
+            }
+            Node::ListNode(_, items) => {
+                let (count, items) = self.values(items)?;
+                Ok(self.assign(format!("fin_list({}, {})", count, items)))
+            }
+            Node::RecordNode(_, fields) => {
+                let record = self.assign("fin_record()".to_string());
+                for (name, value) in fields {
+                    let name = c_string(&identifier_name(name)?);
+                    let value = self.expression(value)?;
+                    self.line(format!("fin_record_set({}, {}, {});", record, name, value));
+                }
+                Ok(record)
+            }
+            Node::IndexNode(collection, _, index) => {
+                let collection = self.expression(collection)?;
+                let value = self.expression(index)?;
+                let span = self.node_span(index);
+                Ok(self.assign(format!("fin_index({}, {}, {})", collection, value, span)))
+            }
+            Node::FieldNode(record, name_tok) => {
+                let record = self.expression(record)?;
+                let name = c_string(&identifier_name(name_tok)?);
+                let span = self.token_span(name_tok);
+                Ok(self.assign(format!("fin_field({}, {}, {})", record, name, span)))
+            }
+            Node::FuncDefNode(name_tok, params, _, body) => self.function(name_tok, params, body),
+            Node::IfNode(_, cases, else_case) => {
+                let result = self.assign("fin_nil()".to_string());
+                self.cases(&result, cases, else_case)?;
+                Ok(result)
+            }
+            Node::WhileNode(_, condition, body) => {
+                self.open("for (;;) {".to_string());
+                let truth = self.condition(condition)?;
+                self.line(format!("if (!{})", truth));
+                self.line("    break;".to_string());
+                self.statements(body)?;
+                self.close("}");
+                Ok(self.assign("fin_nil()".to_string()))
+            }
+            Node::ReturnNode(_, value) => {
+                self.ret(value.as_deref())?;
+                Ok(self.assign("fin_nil()".to_string()))
+            }
+            Node::StatementsNode(nodes) => {
+                let mut value = None;
+                for node in nodes {
+                    value = Some(self.expression(node)?);
+                }
+                match value {
+                    Some(value) => Ok(value),
+                    None => Ok(self.assign("fin_nil()".to_string())),
+                }
+            }
+        }
+    }
+
+    // Generates a node whose value is not needed
+    fn statements(&mut self, node: &Node) -> Result<(), ErrorType> {
+        match node {
+            Node::StatementsNode(nodes) => {
+                for node in nodes {
+                    self.statements(node)?;
+                }
+            }
+            Node::ReturnNode(_, value) => self.ret(value.as_deref())?,
+            node => {
+                self.expression(node)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, token: &Token) -> Result<String, ErrorType> {
+        let span = self.token_span(token);
+        let value = match token.type_() {
+            TokenType::Int(i64::MIN) => "fin_int(INT64_MIN)".to_string(),
+            TokenType::Int(val) => format!("fin_int(INT64_C({}))", val),
+            TokenType::Float(val) => format!("fin_float({})", c_float(val)),
+            TokenType::String(text) => {
+                self.texts.push(text);
+                format!("fin_text(&fin_text_{})", self.texts.len() - 1)
+            }
+            TokenType::Keyword(keyword) if keyword == "tosi" => "fin_bool(1)".to_string(),
+            TokenType::Keyword(keyword) if keyword == "epätosi" => "fin_bool(0)".to_string(),
+            TokenType::Keyword(keyword) if keyword == "tyhjä" => "fin_nil()".to_string(),
+            TokenType::BigInt(_) => return Err(unsupported(token, "iso")),
+            TokenType::Imaginary(_) => return Err(unsupported(token, "kompleksi")),
+            other => {
+                return Err(type_error(
+                    token,
+                    format!(
+                        "Non Value Token {:?} found inside generate value function",
+                        other
+                    ),
+                ))
+            }
+        };
+        Ok(self.assign(format!("fin_at({}, {}, {})", value, span, span)))
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are generated in a loop
+    fn binops(&mut self, node: &Node) -> Result<String, ErrorType> {
+        let operations = node.binop_chain();
+        let start = operations[0].0.pos_start();
+        let mut left = self.expression(operations[0].0)?;
+        for (left_node, optok, right) in operations {
+            left = self.binop(left, start.clone(), left_node, optok, right)?;
+        }
+        Ok(left)
+    }
+
+    // `left` holds the value of `left_node`, which starts at `start`
+    fn binop(
+        &mut self,
+        left: String,
+        start: Option<Position>,
+        left_node: &Node,
+        optok: &Token,
+        right: &Node,
+    ) -> Result<String, ErrorType> {
+        // 'ja' and 'tai' only look at the right side when they need to
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            let span = self.span(start, left_node.pos_end());
+            let truth = format!("fin_truth({}, {})", left, span);
+            let result = self.assign(format!("fin_bool({})", truth));
+            self.open(format!(
+                "if ({}.as.b == {}) {{",
+                result,
+                (keyword == "ja") as i32
+            ));
+            let truth = self.condition(right)?;
+            self.line(format!("{} = fin_bool({});", result, truth));
+            self.close("}");
+            return Ok(result);
+        }
+
+        let end = right.pos_end();
+        let right = self.expression(right)?;
+        let operator = operator(optok)?;
+        let op_span = self.token_span(optok);
+        let span = self.span(start, end);
+        Ok(self.assign(format!(
+            "fin_at(fin_binary({}, {}, {}, {}), {}, {})",
+            operator, left, right, op_span, span, span
+        )))
+    }
+
+    fn unary(&mut self, optok: &Token, operand: &Node) -> Result<String, ErrorType> {
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            let truth = self.condition(operand)?;
+            return Ok(self.assign(format!("fin_bool(!{})", truth)));
+        }
+        let value = self.expression(operand)?;
+        let operator = operator(optok)?;
+        let span = self.token_span(optok);
+        Ok(self.assign(format!("fin_unary({}, {}, {})", operator, value, span)))
+    }
+
+    // A C expression for whether a condition holds
+    fn condition(&mut self, node: &Node) -> Result<String, ErrorType> {
+        let value = self.expression(node)?;
+        let span = self.node_span(node);
+        Ok(format!("fin_truth({}, {})", value, span))
+    }
+
+    fn cases(
+        &mut self,
+        result: &str,
+        cases: &[(Node, Node)],
+        else_case: &Option<Box<Node>>,
+    ) -> Result<(), ErrorType> {
+        let ((condition, body), rest) = match cases.split_first() {
+            Some(first) => first,
+            None => {
+                if let Some(body) = else_case {
+                    let value = self.expression(body)?;
+                    self.line(format!("{} = {};", result, value));
+                }
+                return Ok(());
+            }
+        };
+        let truth = self.condition(condition)?;
+        self.open(format!("if ({}) {{", truth));
+        let value = self.expression(body)?;
+        self.line(format!("{} = {};", result, value));
+        if rest.is_empty() && else_case.is_none() {
+            self.close("}");
+            return Ok(());
+        }
+        self.body().indent -= 1;
+        self.open("} else {".to_string());
+        self.cases(result, rest, else_case)?;
+        self.close("}");
+        Ok(())
+    }
+
+    // Evaluates nodes into a C array, and returns its length and name, or NULL when empty
+    fn values(&mut self, nodes: &[Node]) -> Result<(usize, String), ErrorType> {
+        let mut values = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            values.push(self.expression(node)?);
+        }
+        if values.is_empty() {
+            return Ok((0, "NULL".to_string()));
+        }
+        let array = self.temp();
+        self.line(format!(
+            "FinValue {}[{}] = {{{}}};",
+            array,
+            values.len(),
+            values.join(", ")
+        ));
+        Ok((values.len(), array))
+    }
+
+    // Arguments are evaluated before the function is looked up, like in the interpreter
+    fn call_parts(
+        &mut self,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<(String, usize, String, i64), ErrorType> {
+        let (argc, args) = self.values(args)?;
+        let span = self.token_span(name_tok);
+        let name = identifier_name(name_tok)?;
+        let callee = self.get(&name, span);
+        let callee = self.assign(callee);
+        Ok((callee, argc, args, span))
+    }
+
+    fn ret(&mut self, value: Option<&Node>) -> Result<(), ErrorType> {
+        let function = self.body().function;
+        if function.is_none() {
+            // A 'palata' outside of functions ends the program
+            if let Some(value) = value {
+                self.expression(value)?;
+            }
+            self.line("return;".to_string());
+            return Ok(());
+        }
+
+        let value = match value {
+            Some(Node::CallNode(name_tok, args)) => {
+                let (callee, argc, args, span) = self.call_parts(name_tok, args)?;
+                // A call to the running function with the right number of arguments
+                // runs in this same frame
+                if argc == self.body().arity {
+                    self.body().tail_calls = true;
+                    self.open(format!(
+                        "if ({}.tag == FIN_FUNCTION && {}.as.fn->code == self->code && {}.as.fn->closure == self->closure) {{",
+                        callee, callee, callee
+                    ));
+                    if argc > 0 {
+                        self.line(format!("fin_tail_call({});", args));
+                        self.line(format!("memcpy(fin_args, {}, sizeof fin_args);", args));
+                    } else {
+                        self.line("fin_tail_call(NULL);".to_string());
+                    }
+                    self.line("goto fin_start;".to_string());
+                    self.close("}");
+                }
+                let name = c_string(&identifier_name(name_tok)?);
+                format!(
+                    "fin_call({}, {}, {}, {}, {})",
+                    callee, name, argc, args, span
+                )
+            }
+            Some(value) => self.expression(value)?,
+            None => "fin_nil()".to_string(),
+        };
+        self.line(format!("return {};", value));
+        Ok(())
+    }
+
+    // A C expression for the value of a variable
+    fn get(&mut self, name: &str, span: i64) -> String {
+        let symbol = self.symbol(name);
+        match &self.body().scope {
+            Scope::Globals => format!("fin_lookup(fin_globals, {}, {})", symbol, span),
+            Scope::Env => format!("fin_lookup(env, {}, {})", symbol, span),
+            Scope::Locals(locals) => match locals.iter().position(|local| local == name) {
+                Some(local) => {
+                    format!("fin_local(v{}, self->closure, {}, {})", local, symbol, span)
+                }
+                None => format!("fin_lookup(self->closure, {}, {})", symbol, span),
+            },
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) {
+        let symbol = self.symbol(name);
+        let line = match &self.body().scope {
+            Scope::Globals => format!("fin_env_set(fin_globals, {}, {});", symbol, value),
+            Scope::Env => format!("fin_env_set(env, {}, {});", symbol, value),
+            Scope::Locals(locals) => {
+                let local = locals.iter().position(|local| local == name);
+                format!(
+                    "v{} = {};",
+                    local.expect("Locals are collected first"),
+                    value
+                )
+            }
+        };
+        self.line(line);
+    }
+
+    fn function(
+        &mut self,
+        name_tok: &Token,
+        params: &[(Token, Option<Token>)],
+        body: &Node,
+    ) -> Result<String, ErrorType> {
+        let name = identifier_name(name_tok)?;
+        let mut param_names = Vec::with_capacity(params.len());
+        for (param, _) in params {
+            param_names.push(identifier_name(param)?);
+        }
+        let index = self.functions.len();
+        // Reserved now, so functions defined inside get the next numbers
+        self.functions.push(String::new());
+
+        // Local variables can live in C variables unless a function defined inside
+        // needs the symbol map of the call
+        let scope = match defines_function(body) {
+            true => Scope::Env,
+            false => {
+                let mut locals = Vec::new();
+                for param in &param_names {
+                    push_unique(&mut locals, param.clone());
+                }
+                collect_locals(body, &mut locals)?;
+                Scope::Locals(locals)
+            }
+        };
+        let mut function = Body::new(scope, Some(index));
+        function.arity = params.len();
+        self.bodies.push(function);
+        self.statements(body)?;
+        self.line("return fin_nil();".to_string());
+        let function = self.bodies.pop().expect("The function body was pushed");
+
+        let symbols: Vec<usize> = param_names.iter().map(|p| self.symbol(p)).collect();
+        self.functions[index] = self.function_definition(index, &symbols, function);
+        let arity = symbols.len();
+        let params = match symbols.is_empty() {
+            true => "NULL".to_string(),
+            false => {
+                self.params.push(symbols);
+                format!("fin_params_{}", self.params.len() - 1)
+            }
+        };
+        let env = match self.body().scope {
+            Scope::Globals => "fin_globals",
+            _ => "env",
+        };
+        let value = self.assign(format!(
+            "fin_function({}, {}, {}, fin_fn_{}, {})",
+            c_string(&name),
+            arity,
+            params,
+            index,
+            env
+        ));
+        self.set(&name, &value);
+        Ok(value)
+    }
+
+    fn function_definition(&self, index: usize, params: &[usize], body: Body) -> String {
+        let mut code = format!(
+            "static FinValue fin_fn_{}(const FinFunction *self, const FinValue *args)\n{{\n",
+            index
+        );
+        // Tail calls start over with new arguments
+        let args = match body.tail_calls && !params.is_empty() {
+            true => {
+                code.push_str(&format!("    FinValue fin_args[{}];\n", params.len()));
+                code.push_str("    memcpy(fin_args, args, sizeof fin_args);\n");
+                "fin_args"
+            }
+            false => "args",
+        };
+        if body.tail_calls {
+            code.push_str("fin_start:;\n");
+        }
+        match &body.scope {
+            Scope::Locals(locals) => {
+                for (i, name) in locals.iter().enumerate() {
+                    let symbol = self.symbol_ids[name];
+                    // The last of parameters with the same name wins
+                    let value = match params.iter().rposition(|param| *param == symbol) {
+                        Some(param) => format!("{}[{}]", args, param),
+                        None => "fin_unset()".to_string(),
+                    };
+                    code.push_str(&format!(
+                        "    FinValue v{} = {}; /* {} */\n",
+                        i,
+                        value,
+                        comment(name)
+                    ));
+                }
+            }
+            _ => {
+                code.push_str("    FinEnv *env = fin_env_new(self->closure);\n");
+                for (i, param) in params.iter().enumerate() {
+                    code.push_str(&format!(
+                        "    fin_env_set(env, {}, {}[{}]);\n",
+                        param, args, i
+                    ));
+                }
+            }
+        }
+        code.push_str(&body.code);
+        code.push_str("}\n");
+        code
+    }
+
+    fn finish(&self, file_name: &str, source: &str, program: Body) -> String {
+        let mut c = String::new();
+        writeln!(c, "/* Generated by finc from {} */", comment(file_name)).ok();
+        writeln!(c, "#include \"{}\"\n", RUNTIME_NAME).ok();
+
+        let lines: Vec<String> = source.split('\n').map(c_string).collect();
+        writeln!(
+            c,
+            "static const char *const fin_source_lines[] = {{\n    {}\n}};\n",
+            lines.join(",\n    ")
+        )
+        .ok();
+
+        // C arrays cant be empty
+        let mut spans: Vec<String> = self
+            .spans
+            .iter()
+            .map(|(l0, c0, l1, c1)| format!("{{{}, {}, {}, {}}}", l0, c0, l1, c1))
+            .collect();
+        if spans.is_empty() {
+            spans.push("{0, 0, 0, 0}".to_string());
+        }
+        writeln!(
+            c,
+            "static const FinSpan fin_span_table[] = {{\n    {}\n}};\n",
+            spans.join(",\n    ")
+        )
+        .ok();
+        let mut symbols: Vec<String> = self.symbols.iter().map(|s| c_string(s)).collect();
+        let symbol_count = symbols.len();
+        if symbols.is_empty() {
+            symbols.push("\"\"".to_string());
+        }
+        writeln!(
+            c,
+            "static const char *const fin_symbol_table[] = {{\n    {}\n}};\n",
+            symbols.join(",\n    ")
+        )
+        .ok();
+
+        for (i, params) in self.params.iter().enumerate() {
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            writeln!(
+                c,
+                "static const int fin_params_{}[] = {{{}}};",
+                i,
+                params.join(", ")
+            )
+            .ok();
+        }
+        for (i, text) in self.texts.iter().enumerate() {
+            writeln!(
+                c,
+                "static const FinText fin_text_{} = {{{}, {}}};",
+                i,
+                text.len(),
+                c_string(text)
+            )
+            .ok();
+        }
+        c.push('\n');
+
+        for i in 0..self.functions.len() {
+            writeln!(
+                c,
+                "static FinValue fin_fn_{}(const FinFunction *self, const FinValue *args);",
+                i
+            )
+            .ok();
+        }
+        for function in &self.functions {
+            writeln!(c, "\n{}", function).ok();
+        }
+
+        writeln!(c, "static void fin_program(void)\n{{\n{}}}\n", program.code).ok();
+        writeln!(c, "int main(void)\n{{").ok();
+        writeln!(
+            c,
+            "    fin_init({}, fin_source_lines, {}, fin_span_table, fin_symbol_table, {});",
+            c_string(file_name),
+            lines.len(),
+            symbol_count
+        )
+        .ok();
+        writeln!(
+            c,
+            "    fin_program();\n    fflush(stdout);\n    return 0;\n}}"
+        )
+        .ok();
+        c
+    }
+}
+
+fn operator(optok: &Token) -> Result<&'static str, ErrorType> {
+    let operator = match optok.type_() {
+        TokenType::Plus => "FIN_ADD",
+        TokenType::Minus => "FIN_SUB",
+        TokenType::Multiply => "FIN_MUL",
+        TokenType::Divide => "FIN_DIV",
+        TokenType::Pow => "FIN_POW",
+        TokenType::EqualEqual => "FIN_EQ",
+        TokenType::NotEqual => "FIN_NE",
+        TokenType::LessThan => "FIN_LT",
+        TokenType::GreaterThan => "FIN_GT",
+        TokenType::LessThanEqual => "FIN_LE",
+        TokenType::GreaterThanEqual => "FIN_GE",
+        _ => {
+            return Err(type_error(
+                optok,
+                format!("Invalid operator token '{}'", optok),
+            ))
+        }
+    };
+    Ok(operator)
+}
+
+fn unsupported(token: &Token, type_: &str) -> ErrorType {
+    type_error(
+        token,
+        format!("{} numbers are not supported in compiled programs", type_),
+    )
+}
+
+fn type_error(token: &Token, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(
+        token.position_start(),
+        token.position_end(),
+        message,
+    ))
+}
+
+// A C string literal. Bytes outside of printable ASCII are written as octal escapes,
+// which unlike hex escapes never run into the characters after them.
+fn c_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            // Trigraphs start with '??'
+            b'?' => literal.push_str("\\?"),
+            b' '..=b'~' => literal.push(byte as char),
+            byte => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn c_float(value: f64) -> String {
+    match value {
+        value if value.is_nan() => "NAN".to_string(),
+        value if value.is_infinite() && value > 0.0 => "HUGE_VAL".to_string(),
+        value if value.is_infinite() => "-HUGE_VAL".to_string(),
+        // Debug formatting reads back as the same value, and always has a '.' or 'e'
+        value => format!("{:?}", value),
+    }
+}
+
+// Text that can go inside a C comment
+fn comment(text: &str) -> String {
+    text.replace("*/", "* /")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::test_support::Target;
+
+    const C: Target = Target {
+        name: "c",
+        generate: Engine::to_c,
+        build,
+    };
+
+    #[test]
+    fn test_same_as_interpeter() {
+        for text in [
+            "tulosta(1 + 2 * 3, 7 / 2, -7 / 2, 2 ^ 10, 2.0 ^ -2, 1.5 * 4.0, 0.1 + 0.2, 1.0 / 3.0)",
+            "tulosta(1000000000000000000000.0 * 1.0, 1.0 / 10000000.0, -0.0, 2 ^ 0.5, 10 ^ 2.0, liu(3), sqrt(2))",
+            "muut a = [1, \"kaksi\\n\", [tosi, tyhjä]]; tulosta(a, a[1], pituus(a), a + [3.5])",
+            "muut r = {b: 2, a: \"yksi\"}; tulosta(r, r.a, r[\"b\"], pituus(r), \"äö\"[1])",
+            "tominto fib(n) { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; tulosta(fib(20))",
+            "tominto f(n, s) { kun n > 0 { muut s = s + n; muut n = n - 1 }; palata s }; tulosta(f(100000, 0))",
+            "tominto laskuri() { muut n = 0; tominto lisää() { muut n = n + 1; palata n }; palata lisää }; muut c = laskuri(); c(); tulosta(c())",
+            "muut x = 1; tominto f() { palata x }; muut x = 2; tulosta(f(), f == f, tulosta)",
+            "jos 1 > 2 { tulosta(1) } muuten jos 1 == 1 ja ei epätosi { tulosta(2) } muuten { tulosta(3) }",
+            "tulosta(1 < 2, \"a\" < \"b\", [1] == [1], {a: 1} != {a: 2}, 1 == \"1\", min(3, 1, 2), max([1.5, 2.5]))",
+            "tulosta(abs(-3), abs(-2.5), re(2), im(2), conj(2), arg(-1), str(1.5) + \"!\")",
+            "tulosta(wrapping_add(9223372036854775807, 1), saturating_mul(4611686018427387904, 4), wrapping_pow(3, 41), saturating_pow(-2, 63))",
+            "muut nimi = syöte(\"Nimi? \"); tulosta(\"Moi\", nimi); tulosta(syöte())",
+            "tominto f(n) { jos n > 0 { palata f(n - 1) }; palata \"valmis\" }; tulosta(f(5000))",
+            "palata 1; tulosta(2)",
+        ] {
+            C.assert_same(text);
+        }
+    }
+
+    #[test]
+    fn test_same_errors() {
+        for text in [
+            "tulosta(1); muut a = 9223372036854775807; a + 1",
+            "muut a = 0; tulosta(\"ennen\"); 1 / a",
+            "muut a = 0.0; 1.0 / a",
+            "tominto f(n) { palata 10 / n }; tominto g(n) { palata f(n - 1) }; g(1)",
+            "tominto f(n) { palata 1 + f(n + 1) }; f(0)",
+            "tominto f(n) { jos n > 0 { palata f(n - 1) }; palata 1 / n }; f(3)",
+            "muut l = [1, 2]; l[2]",
+            "muut x = id(\"a\"); -x",
+            "muut t = id(\"a\"); jos t { 1 }",
+            "muut f = id(tominto f(a) { palata a }); f(1, 2)",
+            "muut x = id(1); x()",
+            "puuttuu(1)",
+            "muut a = 0; a ^ -1",
+            "muut a = 2; a ^ 63",
+            "muut a = -1.0; a ^ 0.5",
+            "muut r = {a: 1}; r.b",
+            "muut x = id(1); muut y = 1.5; x < y",
+            "tulosta(1)\nmuut b = id([1,\n  2]) + \"x\"",
+            "min([])",
+            "pituus(1)",
+        ] {
+            // Hides the types of values from the type checker, so the errors happen at runtime
+            C.assert_same(&format!("tominto id(x) {{ palata x }}; {}", text));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_numbers() {
+        let error = Engine::new().to_c("test.fin", "muut a = 2i").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("kompleksi numbers are not supported"));
+
+        let (_, error) = C.compile_and_run("muut a = 1; murto(a, 2)");
+        assert!(
+            error.contains("murto numbers are not supported"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_examples() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examplez/src");
+        let mut count = 0;
+        for entry in fs::read_dir(examples).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "fin") {
+                C.assert_same(&fs::read_to_string(&path).unwrap());
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::ast::{collect_locals, defines_function, identifier_name, push_unique};
+use crate::bytecode::{Chunk, FunctionTemplate, Instruction, Span};
+use crate::context::Context;
+use crate::errors::{ErrorType, RunTimeError};
+use crate::parser::Node;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+
+/// Compiles abstract syntax trees to bytecode for the virtual machine
+pub struct Compiler {
+    chunk: Chunk,
+    // Functions can only tail call themselves, so programs never do
+    in_function: bool,
+}
+
+impl Compiler {
+    /// Compiles a whole program. Its value is the value of the last statement,
+    /// unless a 'palata' ends it first.
+    pub fn compile_program(name: &str, node: &Node) -> Result<Chunk, ErrorType> {
+        let mut compiler = Self {
+            chunk: Chunk::new(name),
+            in_function: false,
+        };
+        compiler.compile(node)?;
+        compiler.chunk.emit(Instruction::Return, (None, None));
+        Ok(compiler.chunk)
+    }
+
+    /// Compiles the body of a function. Functions return `tyhjä` without a 'palata'.
+    pub fn compile_function(function: &FunctionTemplate) -> Result<Chunk, ErrorType> {
+        let mut compiler = Self {
+            chunk: Chunk::new(&function.name),
+            in_function: true,
+        };
+
+        // Local variables can live in slots unless a function defined inside
+        // needs the symbol map of the call
+        if !defines_function(&function.body) {
+            let mut locals = Vec::new();
+            for param in &function.params {
+                push_unique(&mut locals, identifier_name(param)?);
+            }
+            collect_locals(&function.body, &mut locals)?;
+            compiler.chunk.set_locals(locals);
+        }
+
+        compiler.compile_effect(&function.body)?;
+        compiler.chunk.emit(Instruction::Nil, (None, None));
+        compiler.chunk.emit(Instruction::Return, (None, None));
+        Ok(compiler.chunk)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), ErrorType> {
+        match node {
+            Node::Binop(..) => self.compile_binops(node),
+            Node::Value(token) => self.compile_value(token),
+            Node::Unary(optok, node) => self.compile_unary(optok, node),
+            Node::VarAccessNode(token) => self.compile_get(token),
+            Node::VarAssignNode(token, _type, node) => {
+                self.compile(node)?;
+                self.compile_set(token)
+            }
+            Node::CallNode(name_tok, args) => self.compile_call(name_tok, args, false),
+            Node::ListNode(_, items) => {
+                for item in items {
+                    self.compile(item)?;
+                }
+                self.chunk.emit(Instruction::List(items.len()), span(node));
+                Ok(())
+            }
+            Node::RecordNode(_, fields) => {
+                let mut names = Vec::with_capacity(fields.len());
+                for (name, node) in fields {
+                    names.push(identifier_name(name)?);
+                    self.compile(node)?;
+                }
+                let index = self.chunk.add_record(names);
+                self.chunk.emit(Instruction::Record(index), span(node));
+                Ok(())
+            }
+            Node::IndexNode(collection, _, index) => {
+                self.compile(collection)?;
+                self.compile(index)?;
+                self.chunk.emit(Instruction::Index, span(index));
+                Ok(())
+            }
+            Node::FieldNode(record, name_tok) => {
+                self.compile(record)?;
+                identifier_name(name_tok)?;
+                let index = self.chunk.add_token(name_tok.clone());
+                self.chunk
+                    .emit(Instruction::Field(index), token_span(name_tok));
+                Ok(())
+            }
+            Node::FuncDefNode(name_tok, params, _, body) => {
+                let template = FunctionTemplate {
+                    name: identifier_name(name_tok)?,
+                    params: params.iter().map(|(param, _type)| param.clone()).collect(),
+                    body: body.clone(),
+                };
+                let index = self.chunk.add_function(template);
+                self.chunk
+                    .emit(Instruction::Function(index), token_span(name_tok));
+                Ok(())
+            }
+            Node::IfNode(_, cases, else_case) => self.compile_if(cases, else_case),
+            Node::WhileNode(_, condition, body) => {
+                let start = self.chunk.code().len();
+                let exit = self.compile_condition(condition)?;
+                self.compile_effect(body)?;
+                self.chunk.emit(Instruction::Jump(start), (None, None));
+                self.chunk.patch_jump(exit);
+                self.chunk.emit(Instruction::Nil, (None, None));
+                Ok(())
+            }
+            Node::ReturnNode(_, node) => {
+                match node.as_deref() {
+                    Some(Node::CallNode(name_tok, args)) if self.in_function => {
+                        self.compile_call(name_tok, args, true)?
+                    }
+                    Some(node) => self.compile(node)?,
+                    None => {
+                        self.chunk.emit(Instruction::Nil, (None, None));
+                    }
+                }
+                self.chunk.emit(Instruction::Return, (None, None));
+                Ok(())
+            }
+            Node::StatementsNode(nodes) => match nodes.split_last() {
+                Some((last, nodes)) => {
+                    for node in nodes {
+                        self.compile_effect(node)?;
+                    }
+                    self.compile(last)
+                }
+                None => {
+                    self.chunk.emit(Instruction::Nil, (None, None));
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    // Compiles a node whose value is not needed, leaving nothing on the stack
+    fn compile_effect(&mut self, node: &Node) -> Result<(), ErrorType> {
+        match node {
+            Node::StatementsNode(nodes) => {
+                for node in nodes {
+                    self.compile_effect(node)?;
+                }
+            }
+            Node::VarAssignNode(token, _type, node) => {
+                self.compile(node)?;
+                let name = identifier_name(token)?;
+                let instruction = match self.chunk.local(&name) {
+                    Some(slot) => Instruction::StoreLocal(slot),
+                    None => Instruction::StoreName(self.chunk.add_name(&name)),
+                };
+                self.chunk.emit(instruction, token_span(token));
+            }
+            // Nothing runs after a return to pop its value
+            Node::ReturnNode(..) => self.compile(node)?,
+            node => {
+                self.compile(node)?;
+                self.chunk.emit(Instruction::Pop, (None, None));
+            }
+        }
+        Ok(())
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are compiled in a loop
+    fn compile_binops(&mut self, node: &Node) -> Result<(), ErrorType> {
+        let operations = node.binop_chain();
+        let start = operations[0].0.pos_start();
+        self.compile(operations[0].0)?;
+        for (left, optok, right) in operations {
+            self.compile_binop((start.clone(), left.pos_end()), optok, right)?;
+        }
+        Ok(())
+    }
+
+    // The left side, which spans `left`, is already on the stack
+    fn compile_binop(&mut self, left: Span, optok: &Token, right: &Node) -> Result<(), ErrorType> {
+        // 'ja' and 'tai' only look at the right side when they need to
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            let jump = self.chunk.emit(
+                Instruction::ShortCircuit {
+                    target: 0,
+                    when: keyword == "tai",
+                },
+                left,
+            );
+            self.compile(right)?;
+            self.chunk.emit(Instruction::Truth, span(right));
+            self.chunk.patch_jump(jump);
+            return Ok(());
+        }
+
+        let operator = self.chunk.add_token(optok.clone());
+        let instruction = match right {
+            // A literal on the right is used straight from the constants
+            Node::Value(token) => {
+                let constant = self.chunk.add_constant(self.literal(token)?);
+                Instruction::BinaryConstant { operator, constant }
+            }
+            right => {
+                self.compile(right)?;
+                Instruction::Binary(operator)
+            }
+        };
+        self.chunk.emit(instruction, (left.0, right.pos_end()));
+        Ok(())
+    }
+
+    // Compiles a condition followed by a jump that is taken if it is 'epätosi',
+    // and returns the offset of the jump for patching
+    fn compile_condition(&mut self, condition: &Node) -> Result<usize, ErrorType> {
+        if let Node::Binop(left, optok, right) = condition {
+            if is_comparison(&optok.type_()) {
+                self.compile(left)?;
+                self.compile(right)?;
+                let operator = self.chunk.add_token(optok.clone());
+                return Ok(self.chunk.emit(
+                    Instruction::CompareJump {
+                        operator,
+                        target: 0,
+                    },
+                    span(condition),
+                ));
+            }
+        }
+        self.compile(condition)?;
+        Ok(self
+            .chunk
+            .emit(Instruction::JumpIfFalse(0), span(condition)))
+    }
+
+    fn compile_value(&mut self, token: &Token) -> Result<(), ErrorType> {
+        match self.literal(token)? {
+            Value::Nil => {
+                self.chunk.emit(Instruction::Nil, token_span(token));
+            }
+            value => {
+                let index = self.chunk.add_constant(value);
+                self.chunk
+                    .emit(Instruction::Constant(index), token_span(token));
+            }
+        }
+        Ok(())
+    }
+
+    fn literal(&self, token: &Token) -> Result<Value, ErrorType> {
+        Value::literal(token).ok_or_else(|| {
+            self.error(
+                token,
+                format!(
+                    "Non Value Token {:?} found inside compile value function",
+                    token.type_()
+                ),
+            )
+        })
+    }
+
+    fn compile_unary(&mut self, optok: &Token, node: &Node) -> Result<(), ErrorType> {
+        self.compile(node)?;
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            self.chunk.emit(Instruction::Not, span(node));
+        } else {
+            let index = self.chunk.add_token(optok.clone());
+            self.chunk
+                .emit(Instruction::Unary(index), token_span(optok));
+        }
+        Ok(())
+    }
+
+    fn compile_get(&mut self, token: &Token) -> Result<(), ErrorType> {
+        let name = identifier_name(token)?;
+        let instruction = match self.chunk.local(&name) {
+            Some(slot) => Instruction::GetLocal(slot),
+            None => Instruction::GetName(self.chunk.add_name(&name)),
+        };
+        self.chunk.emit(instruction, token_span(token));
+        Ok(())
+    }
+
+    fn compile_set(&mut self, token: &Token) -> Result<(), ErrorType> {
+        let name = identifier_name(token)?;
+        let instruction = match self.chunk.local(&name) {
+            Some(slot) => Instruction::SetLocal(slot),
+            None => Instruction::SetName(self.chunk.add_name(&name)),
+        };
+        self.chunk.emit(instruction, token_span(token));
+        Ok(())
+    }
+
+    // Arguments are evaluated before the function is looked up, like in the interpreter
+    fn compile_call(
+        &mut self,
+        name_tok: &Token,
+        args: &[Node],
+        tail: bool,
+    ) -> Result<(), ErrorType> {
+        for arg in args {
+            self.compile(arg)?;
+        }
+        self.compile_get(name_tok)?;
+        let name = self.chunk.add_token(name_tok.clone());
+        let argc = args.len();
+        let instruction = match tail {
+            true => Instruction::TailCall { argc, name },
+            false => Instruction::Call { argc, name },
+        };
+        self.chunk.emit(instruction, token_span(name_tok));
+        Ok(())
+    }
+
+    fn compile_if(
+        &mut self,
+        cases: &[(Node, Node)],
+        else_case: &Option<Box<Node>>,
+    ) -> Result<(), ErrorType> {
+        let mut exits = Vec::with_capacity(cases.len());
+        for (condition, body) in cases {
+            let next = self.compile_condition(condition)?;
+            self.compile(body)?;
+            exits.push(self.chunk.emit(Instruction::Jump(0), (None, None)));
+            self.chunk.patch_jump(next);
+        }
+        match else_case {
+            Some(body) => self.compile(body)?,
+            None => {
+                self.chunk.emit(Instruction::Nil, (None, None));
+            }
+        }
+        for exit in exits {
+            self.chunk.patch_jump(exit);
+        }
+        Ok(())
+    }
+
+    fn error(&self, token: &Token, message: String) -> ErrorType {
+        ErrorType::RunTimeError(RunTimeError::new(
+            token.position_start(),
+            token.position_end(),
+            message,
+            Context::init(self.chunk.name()),
+        ))
+    }
+}
+
+fn is_comparison(operator: &TokenType) -> bool {
+    matches!(
+        operator,
+        TokenType::EqualEqual
+            | TokenType::NotEqual
+            | TokenType::LessThan
+            | TokenType::GreaterThan
+            | TokenType::LessThanEqual
+            | TokenType::GreaterThanEqual
+    )
+}
+
+fn span(node: &Node) -> Span {
+    (node.pos_start(), node.pos_end())
+}
+
+fn token_span(token: &Token) -> Span {
+    (token.position_start(), token.position_end())
+}
+
+/// Lists the bytecode of a program followed by the functions it defines
+pub fn disassemble(name: &str, node: &Node) -> Result<String, ErrorType> {
+    let mut listing = String::new();
+    let mut chunks = vec![Compiler::compile_program(name, node)?];
+    while let Some(chunk) = chunks.pop() {
+        listing.push_str(&chunk.to_string());
+        for function in chunk.functions().iter().rev() {
+            chunks.push(Compiler::compile_function(function)?);
+        }
+    }
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn listing(text: &str) -> String {
+        let tokens = Lexer::new("test".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        disassemble("Program", &Parser::new(tokens).parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let text = "muut a = 1\ntominto f(x) { palata x + a * 2 }\njos a < 2 { f(a) }";
+        let expected = "\
+== Program ==
+0000    1 CONSTANT 0           ; 1
+0001    | STORE_NAME 0         ; a
+0002    2 FUNCTION 0           ; f
+0003    | POP
+0004    3 GET_NAME 0           ; a
+0005    | CONSTANT 1           ; 2
+0006    | COMPARE_JUMP 11      ; <
+0007    | GET_NAME 0           ; a
+0008    | GET_NAME 1           ; f
+0009    | CALL 1               ; f
+0010    | JUMP 12
+0011    | NIL
+0012    | RETURN
+== f ==
+locals: x
+0000    2 GET_LOCAL 0          ; x
+0001    | GET_NAME 0           ; a
+0002    | BINARY_CONSTANT 0    ; * 2
+0003    | BINARY               ; +
+0004    | RETURN
+0005    | NIL
+0006    | RETURN
+";
+        assert_eq!(listing(text), expected);
+    }
+
+    #[test]
+    fn test_tail_calls_and_closures() {
+        let text = "tominto f(n) { jos n > 0 { palata f(n - 1) }; palata n }";
+        assert!(listing(text).contains("TAIL_CALL 1          ; f"));
+
+        // Functions that define functions keep their variables in a symbol map
+        let text = listing("tominto f(n) { muut m = n; tominto g() { palata m }; palata g }");
+        assert!(text.contains("== f ==\n0000"), "{}", text);
+        assert!(text.contains("STORE_NAME 1         ; m"), "{}", text);
+    }
+}
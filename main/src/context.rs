@@ -1,22 +1,23 @@
-use crate::number::NumberType;
 use crate::position::Position;
-use crate::symbols::SymbolMap;
+use crate::symbols::SharedSymbolMap;
+use crate::value::Value;
+use std::rc::Rc;
 
 // Context Or Scope?
 #[derive(Debug, Clone, PartialEq)]
 pub struct Context {
     display_name: String,
-    parent: Option<Box<Context>>,
+    parent: Option<Rc<Context>>,
     parent_pos: Option<Position>,
-    symbol_map: Option<SymbolMap<NumberType>>,
+    symbol_map: Option<SharedSymbolMap<Value>>,
 }
 
 impl Context {
     pub fn new(
         display_name: &str,
-        parent: Option<Box<Context>>,
+        parent: Option<Rc<Context>>,
         parent_pos: Option<Position>,
-        symbol_map: Option<SymbolMap<NumberType>>,
+        symbol_map: Option<SharedSymbolMap<Value>>,
     ) -> Self {
         Self {
             display_name: display_name.to_string(),
@@ -40,7 +41,7 @@ impl Context {
         self.display_name.clone()
     }
 
-    pub fn parent(&self) -> Option<Box<Context>> {
+    pub fn parent(&self) -> Option<Rc<Context>> {
         self.parent.clone()
     }
 
@@ -48,11 +49,86 @@ impl Context {
         self.parent_pos.clone()
     }
 
-    pub fn symbol_map(&self) -> Option<SymbolMap<NumberType>> {
+    pub fn symbol_map(&self) -> Option<SharedSymbolMap<Value>> {
         self.symbol_map.clone()
     }
 
-    pub fn set_symbol_map(&mut self, symbol_map: SymbolMap<NumberType>) {
+    /// The same context without its symbol map, for values to keep for their errors.
+    /// Values live in symbol maps, so keeping the map would chain every value to the
+    /// ones computed before it. Parents never have symbol maps.
+    pub fn detached(&self) -> Context {
+        Context {
+            display_name: self.display_name.clone(),
+            parent: self.parent.clone(),
+            parent_pos: self.parent_pos.clone(),
+            symbol_map: None,
+        }
+    }
+
+    pub fn set_symbol_map(&mut self, symbol_map: SharedSymbolMap<Value>) {
         self.symbol_map = Some(symbol_map);
     }
 }
+
+/// Parameter names and the values bound to them, as shown in tracebacks
+pub type BoundArgs = Vec<(String, String)>;
+
+/// One entry on the interpreter's call stack, kept for tracebacks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    name: String,
+    // `None` for the program itself
+    args: Option<BoundArgs>,
+    call_pos: Option<Position>,
+    // Tail calls that reused this frame
+    elided: usize,
+}
+
+impl Frame {
+    pub fn program(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            args: None,
+            call_pos: None,
+            elided: 0,
+        }
+    }
+
+    pub fn call(name: &str, args: BoundArgs, call_pos: Option<Position>) -> Self {
+        Self {
+            name: name.to_string(),
+            args: Some(args),
+            call_pos,
+            elided: 0,
+        }
+    }
+
+    /// The frame is reused by a tail call with new arguments
+    pub fn tail_call(&mut self, args: BoundArgs) {
+        self.args = Some(args);
+        self.elided += 1;
+    }
+
+    pub fn elided(&self) -> usize {
+        self.elided
+    }
+
+    /// Where the function of this frame was called from
+    pub fn call_pos(&self) -> Option<Position> {
+        self.call_pos.clone()
+    }
+
+    /// The function name with its arguments, like `f(n = 3)`
+    pub fn signature(&self) -> String {
+        match &self.args {
+            None => self.name.clone(),
+            Some(args) => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", name, value))
+                    .collect();
+                format!("{}({})", self.name, args.join(", "))
+            }
+        }
+    }
+}
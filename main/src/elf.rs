@@ -0,0 +1,571 @@
+use crate::x86::{self, Object, RelocationKind, BSS, DATA, RODATA, SECTION_NAMES, TEXT};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// Writes assembled objects as ELF64 files for x86-64 Linux: relocatable objects that other
+// linkers can use, or static executables linked here. Executables have no section headers,
+// only a segment for the code and read only data and one for the data and bss.
+
+// Where executables are loaded
+const BASE_ADDRESS: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PC32: u64 = 2;
+
+/// Links objects into a static executable that starts at `_start`
+pub fn executable(objects: &[Object]) -> Result<Vec<u8>, String> {
+    // Where the sections of every object are, by object and section
+    let mut addresses = vec![[0u64; 4]; objects.len()];
+    let headers = HEADER_SIZE + 3 * PROGRAM_HEADER_SIZE;
+    let mut offset = headers as u64;
+    for &section in &[TEXT, RODATA] {
+        for (i, object) in objects.iter().enumerate() {
+            let section_ = &object.sections[section];
+            offset = align(offset, section_.align);
+            addresses[i][section] = BASE_ADDRESS + offset;
+            offset += section_.size;
+        }
+    }
+    let code_size = offset;
+    // The data goes on its own pages, at the same offset into a page as in the file
+    let data_offset = align(code_size, 16);
+    let data_address = align(BASE_ADDRESS + code_size, PAGE_SIZE) + data_offset % PAGE_SIZE;
+    let mut address = data_address;
+    for &section in &[DATA, BSS] {
+        for (i, object) in objects.iter().enumerate() {
+            let section_ = &object.sections[section];
+            address = align(address, section_.align);
+            addresses[i][section] = address;
+            address += section_.size;
+        }
+    }
+    let data_end = addresses
+        .iter()
+        .zip(objects)
+        .map(|(addresses, object)| addresses[DATA] + object.sections[DATA].size)
+        .max()
+        .unwrap_or(data_address)
+        .max(data_address);
+    let data_size = data_end - data_address;
+    let memory_size = address - data_address;
+
+    // The addresses of symbols, global ones shared by all objects
+    let mut globals = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            if let (Some(section), true) = (symbol.section, symbol.global) {
+                let address = addresses[i][section] + symbol.offset;
+                if globals.insert(symbol.name.as_str(), address).is_some() {
+                    return Err(format!("{} is defined more than once", symbol.name));
+                }
+            }
+        }
+    }
+    let resolve = |i: usize, name: &str| -> Result<u64, String> {
+        let object = &objects[i];
+        match object.symbol(name) {
+            Some(symbol) if !symbol.global => {
+                let section = symbol
+                    .section
+                    .ok_or_else(|| format!("{} is not defined", name))?;
+                Ok(addresses[i][section] + symbol.offset)
+            }
+            _ => globals
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("{} is not defined", name)),
+        }
+    };
+    let entry = globals
+        .get("_start")
+        .copied()
+        .ok_or("_start is not defined")?;
+
+    let mut file = vec![0; (data_offset + data_size) as usize];
+    for (i, object) in objects.iter().enumerate() {
+        for section in [TEXT, RODATA, DATA] {
+            let data = &object.sections[section].data;
+            let at = file_offset(addresses[i][section], data_address, data_offset);
+            file[at..at + data.len()].copy_from_slice(data);
+        }
+        for relocation in &object.relocations {
+            let place = addresses[i][relocation.section] + relocation.offset;
+            let value = resolve(i, &relocation.symbol)? as i64 + relocation.addend;
+            let at = file_offset(place, data_address, data_offset);
+            match relocation.kind {
+                RelocationKind::Abs64 => {
+                    file[at..at + 8].copy_from_slice(&value.to_le_bytes());
+                }
+                RelocationKind::Pc32 => {
+                    let value = i32::try_from(value - place as i64)
+                        .map_err(|_| format!("{} is too far away", relocation.symbol))?;
+                    file[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    let mut header = Vec::with_capacity(headers);
+    elf_header(&mut header, ET_EXEC, entry, 3, 0, 0);
+    // The code and read only data, with the headers
+    program_header(
+        &mut header,
+        PT_LOAD,
+        PF_R | PF_X,
+        0,
+        BASE_ADDRESS,
+        code_size,
+        code_size,
+    );
+    program_header(
+        &mut header,
+        PT_LOAD,
+        PF_R | PF_W,
+        data_offset,
+        data_address,
+        data_size,
+        memory_size,
+    );
+    // The stack is not executable
+    program_header(&mut header, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0, 0);
+    file[..headers].copy_from_slice(&header);
+    Ok(file)
+}
+
+// Where an address of the executable is in its file
+fn file_offset(address: u64, data_address: u64, data_offset: u64) -> usize {
+    match address >= data_address {
+        true => (address - data_address + data_offset) as usize,
+        false => (address - BASE_ADDRESS) as usize,
+    }
+}
+
+/// Writes an object as a relocatable ELF file, for linking with other linkers
+pub fn relocatable(object: &Object) -> Vec<u8> {
+    // Section headers: null, the four sections, their relocations, then the tables
+    let mut names = StringTable::new();
+    let relocated: Vec<usize> = [TEXT, RODATA, DATA]
+        .iter()
+        .copied()
+        .filter(|section| {
+            object
+                .relocations
+                .iter()
+                .any(|relocation| relocation.section == *section)
+        })
+        .collect();
+    let symtab_index = 5 + relocated.len();
+
+    // Section symbols first, then other local symbols, then global ones. Relocations to
+    // local labels point at their section instead.
+    let mut strings = StringTable::new();
+    let mut symbols = Vec::new();
+    symbol_entry(&mut symbols, 0, STB_LOCAL, STT_NOTYPE, 0, 0);
+    for section in 0..SECTION_NAMES.len() {
+        symbol_entry(
+            &mut symbols,
+            0,
+            STB_LOCAL,
+            STT_SECTION,
+            section as u16 + 1,
+            0,
+        );
+    }
+    let mut symbol_indexes = HashMap::new();
+    let mut count = 1 + SECTION_NAMES.len();
+    let mut first_global = 0;
+    for global in [false, true] {
+        if global {
+            first_global = count as u32;
+        }
+        for symbol in &object.symbols {
+            if symbol.global != global || x86::is_local_label(&symbol.name) {
+                continue;
+            }
+            let section = symbol.section.map_or(0, |section| section as u16 + 1);
+            let bind = if global { STB_GLOBAL } else { STB_LOCAL };
+            let name = strings.add(&symbol.name);
+            symbol_entry(&mut symbols, name, bind, STT_NOTYPE, section, symbol.offset);
+            symbol_indexes.insert(symbol.name.as_str(), count);
+            count += 1;
+        }
+    }
+
+    let mut relas = Vec::new();
+    for section in &relocated {
+        let mut rela = Vec::new();
+        for relocation in &object.relocations {
+            if relocation.section != *section {
+                continue;
+            }
+            let (index, addend) = match symbol_indexes.get(relocation.symbol.as_str()) {
+                Some(index) => (*index, relocation.addend),
+                None => {
+                    let symbol = object
+                        .symbol(&relocation.symbol)
+                        .expect("Relocated symbols are known");
+                    let section = symbol.section.expect("Local labels are defined");
+                    (1 + section, relocation.addend + symbol.offset as i64)
+                }
+            };
+            let kind = match relocation.kind {
+                RelocationKind::Abs64 => R_X86_64_64,
+                RelocationKind::Pc32 => R_X86_64_PC32,
+            };
+            put64(&mut rela, relocation.offset);
+            put64(&mut rela, (index as u64) << 32 | kind);
+            put64(&mut rela, addend as u64);
+        }
+        relas.push(rela);
+    }
+
+    let mut file = vec![0; HEADER_SIZE];
+    let mut headers = vec![0; SECTION_HEADER_SIZE];
+    for (index, section) in object.sections.iter().enumerate() {
+        let name = names.add(SECTION_NAMES[index]);
+        let (kind, flags) = match index {
+            TEXT => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+            RODATA => (SHT_PROGBITS, SHF_ALLOC),
+            DATA => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+            _ => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+        };
+        let offset = place(&mut file, &section.data, section.align);
+        section_header(
+            &mut headers,
+            SectionHeader {
+                name,
+                kind,
+                flags,
+                offset,
+                size: section.size,
+                link: 0,
+                info: 0,
+                align: section.align,
+                entry_size: 0,
+            },
+        );
+    }
+    for (section, rela) in relocated.iter().zip(&relas) {
+        let name = names.add(&format!(".rela{}", SECTION_NAMES[*section]));
+        let offset = place(&mut file, rela, 8);
+        section_header(
+            &mut headers,
+            SectionHeader {
+                name,
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset,
+                size: rela.len() as u64,
+                link: symtab_index as u32,
+                info: *section as u32 + 1,
+                align: 8,
+                entry_size: RELA_SIZE as u64,
+            },
+        );
+    }
+    let name = names.add(".symtab");
+    let offset = place(&mut file, &symbols, 8);
+    section_header(
+        &mut headers,
+        SectionHeader {
+            name,
+            kind: SHT_SYMTAB,
+            flags: 0,
+            offset,
+            size: symbols.len() as u64,
+            link: symtab_index as u32 + 1,
+            info: first_global,
+            align: 8,
+            entry_size: SYMBOL_SIZE as u64,
+        },
+    );
+    let name = names.add(".strtab");
+    let offset = place(&mut file, &strings.bytes, 1);
+    section_header(
+        &mut headers,
+        SectionHeader {
+            name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset,
+            size: strings.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        },
+    );
+    let name = names.add(".shstrtab");
+    let offset = place(&mut file, &names.bytes, 1);
+    section_header(
+        &mut headers,
+        SectionHeader {
+            name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset,
+            size: names.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        },
+    );
+
+    let section_count = (headers.len() / SECTION_HEADER_SIZE) as u16;
+    let headers_offset = place(&mut file, &headers, 8);
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    elf_header(&mut header, ET_REL, 0, 0, headers_offset, section_count);
+    file[..HEADER_SIZE].copy_from_slice(&header);
+    file
+}
+
+// Appends bytes at an aligned offset, and returns the offset
+fn place(file: &mut Vec<u8>, bytes: &[u8], align_to: u64) -> u64 {
+    file.resize(align(file.len() as u64, align_to) as usize, 0);
+    let offset = file.len() as u64;
+    file.extend_from_slice(bytes);
+    offset
+}
+
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, text: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(text.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+fn elf_header(
+    bytes: &mut Vec<u8>,
+    kind: u16,
+    entry: u64,
+    program_headers: u16,
+    section_headers_offset: u64,
+    section_headers: u16,
+) {
+    // 64-bit, little endian, version 1, System V
+    bytes.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    bytes.extend_from_slice(&[0; 8]);
+    put16(bytes, kind);
+    put16(bytes, EM_X86_64);
+    put32(bytes, 1);
+    put64(bytes, entry);
+    put64(
+        bytes,
+        if program_headers > 0 {
+            HEADER_SIZE as u64
+        } else {
+            0
+        },
+    );
+    put64(bytes, section_headers_offset);
+    put32(bytes, 0);
+    put16(bytes, HEADER_SIZE as u16);
+    put16(bytes, PROGRAM_HEADER_SIZE as u16);
+    put16(bytes, program_headers);
+    put16(bytes, SECTION_HEADER_SIZE as u16);
+    put16(bytes, section_headers);
+    // The section names are in the last section
+    put16(bytes, section_headers.saturating_sub(1));
+}
+
+fn program_header(
+    bytes: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+) {
+    put32(bytes, kind);
+    put32(bytes, flags);
+    put64(bytes, offset);
+    put64(bytes, address);
+    put64(bytes, address);
+    put64(bytes, file_size);
+    put64(bytes, memory_size);
+    put64(bytes, if kind == PT_LOAD { PAGE_SIZE } else { 16 });
+}
+
+fn section_header(bytes: &mut Vec<u8>, header: SectionHeader) {
+    put32(bytes, header.name);
+    put32(bytes, header.kind);
+    put64(bytes, header.flags);
+    put64(bytes, 0);
+    put64(bytes, header.offset);
+    put64(bytes, header.size);
+    put32(bytes, header.link);
+    put32(bytes, header.info);
+    put64(bytes, header.align);
+    put64(bytes, header.entry_size);
+}
+
+fn symbol_entry(bytes: &mut Vec<u8>, name: u32, bind: u8, kind: u8, section: u16, value: u64) {
+    put32(bytes, name);
+    bytes.push(bind << 4 | kind);
+    bytes.push(0);
+    put16(bytes, section);
+    put64(bytes, value);
+    put64(bytes, 0);
+}
+
+fn align(value: u64, align: u64) -> u64 {
+    value.div_ceil(align.max(1)) * align.max(1)
+}
+
+fn put16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asmgen;
+    use crate::engine::Engine;
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{self, Command};
+
+    const PROGRAM: &str = "tominto f(n: kok): kok { palata n * 2 }; tulosta(f(21), 0.5, tosi)";
+
+    fn objects() -> Vec<Object> {
+        let source = Engine::new().to_asm("test.fin", PROGRAM).unwrap();
+        vec![
+            x86::assemble(&source).unwrap(),
+            x86::assemble(asmgen::RUNTIME).unwrap(),
+        ]
+    }
+
+    fn run(path: &std::path::Path) -> String {
+        let output = Command::new(path).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_executable() {
+        let file = executable(&objects()).unwrap();
+        assert_eq!(&file[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([file[16], file[17]]), ET_EXEC);
+        let entry = u64::from_le_bytes(file[24..32].try_into().unwrap());
+        assert!(entry > BASE_ADDRESS && entry < BASE_ADDRESS + file.len() as u64);
+
+        let dir = env::temp_dir().join(format!("fin-elf-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("program");
+        fs::write(&path, file).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(run(&path), "42 0.5 tosi\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let mut objects = objects();
+        let runtime = objects.pop().unwrap();
+        assert_eq!(executable(&objects).unwrap_err(), "_start is not defined");
+        objects.push(runtime);
+        objects.push(x86::assemble(asmgen::RUNTIME).unwrap());
+        assert!(executable(&objects)
+            .unwrap_err()
+            .contains("is defined more than once"));
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let objects = objects();
+        for object in &objects {
+            let file = relocatable(object);
+            assert_eq!(&file[..4], b"\x7fELF");
+            assert_eq!(u16::from_le_bytes([file[16], file[17]]), ET_REL);
+        }
+
+        // Other linkers can link the objects, when there is one
+        if Command::new("ld").arg("--version").output().is_err() {
+            return;
+        }
+        let dir = env::temp_dir().join(format!("fin-elf-ld-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut paths = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            let path = dir.join(format!("object{}.o", i));
+            fs::write(&path, relocatable(object)).unwrap();
+            paths.push(path);
+        }
+        let program = dir.join("program");
+        let status = Command::new("ld")
+            .arg("-o")
+            .arg(&program)
+            .args(&paths)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(run(&program), "42 0.5 tosi\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,443 @@
+use crate::asmgen;
+use crate::ast;
+use crate::builtins;
+use crate::cgen;
+use crate::compiler::{self, Compiler};
+use crate::context::Context;
+use crate::errors::{ErrorType, TypeError};
+use crate::interpeter::{Input, Interpeter, Output};
+use crate::ir::{self, Module};
+use crate::jit;
+use crate::lexer::Lexer;
+use crate::limits::Limits;
+use crate::native::{Native, NativeFunction};
+use crate::optimizer;
+use crate::parser::{Node, Parser};
+use crate::rustgen;
+use crate::symbols::{SharedSymbolMap, SymbolMap};
+use crate::types::{Checker, Type};
+use crate::value::Value;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+/// Runs programs for a host application.
+/// Globals, including functions defined by programs, are kept between calls to `eval`.
+///
+/// Every nested call takes some Rust stack. Deeply recursive programs should be evaluated
+/// inside `interpeter::with_stack`.
+pub struct Engine {
+    globals: SharedSymbolMap<Value>,
+    // Knows the types of the globals of programs that passed it
+    checker: Checker,
+    limits: Limits,
+    backend: Backend,
+    optimize: bool,
+    // Calls before the interpreter compiles a function, `None` when it doesnt
+    jit_threshold: Option<u64>,
+    output: Output,
+    // Stdin when `None`
+    input: Option<Input>,
+}
+
+/// How an engine runs programs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// Walks the syntax tree
+    Interpreter,
+    /// Compiles to bytecode and runs it on the virtual machine
+    Vm,
+}
+
+impl Engine {
+    /// An engine with the builtin prelude that uses stdin and stdout
+    pub fn new() -> Self {
+        let mut globals = SymbolMap::new();
+        builtins::prelude().install(&mut globals);
+        Self {
+            globals: globals.shared(),
+            checker: Checker::new(),
+            limits: Limits::new(),
+            backend: Backend::Interpreter,
+            optimize: true,
+            jit_threshold: Some(jit::THRESHOLD),
+            output: Rc::new(RefCell::new(io::stdout())),
+            input: None,
+        }
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<Value, Diagnostics> {
+        self.eval_file("<eval>", source)
+    }
+
+    /// Like `eval`, with the file name errors should show
+    pub fn eval_file(&mut self, file_name: &str, source: &str) -> Result<Value, Diagnostics> {
+        let mut checker = self.checker.clone();
+        let root = self.parse(&mut checker, file_name, source)?;
+        self.checker = checker;
+
+        let mut context = Context::init("Program");
+        context.set_symbol_map(self.globals.clone());
+        let result = match self.backend {
+            Backend::Interpreter => {
+                let mut interpeter = Interpeter::with_limits(self.limits.clone());
+                interpeter.set_output(self.output.clone());
+                if let Some(input) = &self.input {
+                    interpeter.set_input(input.clone());
+                }
+                interpeter.set_jit_threshold(self.jit_threshold);
+                interpeter.run(&root, context)
+            }
+            Backend::Vm => {
+                let chunk = Compiler::compile_program("Program", &root)?;
+                let mut vm = Vm::with_limits(self.limits.clone());
+                vm.set_output(self.output.clone());
+                if let Some(input) = &self.input {
+                    vm.set_input(input.clone());
+                }
+                vm.run(Rc::new(chunk), context)
+            }
+        };
+        self.output.borrow_mut().flush().ok();
+        Ok(result?)
+    }
+
+    /// The bytecode listing of a program, as the virtual machine would run it
+    pub fn disassemble(&self, file_name: &str, source: &str) -> Result<String, Diagnostics> {
+        let root = self.parse(&mut self.checker.clone(), file_name, source)?;
+        Ok(compiler::disassemble("Program", &root)?)
+    }
+
+    /// The program as C source for `cgen::build`, with `file_name` in its errors
+    pub fn to_c(&self, file_name: &str, source: &str) -> Result<String, Diagnostics> {
+        let root = self.parse(&mut self.checker.clone(), file_name, source)?;
+        Ok(cgen::generate(file_name, source, &root)?)
+    }
+
+    /// The program as x86-64 assembly for `asmgen::build`, with `file_name` in its errors
+    pub fn to_asm(&self, file_name: &str, source: &str) -> Result<String, Diagnostics> {
+        let root = self.parse(&mut self.checker.clone(), file_name, source)?;
+        Ok(asmgen::generate(file_name, &root)?)
+    }
+
+    /// The program as Rust source for `rustgen::build`, with `file_name` in its errors
+    pub fn to_rust(&self, file_name: &str, source: &str) -> Result<String, Diagnostics> {
+        let root = self.parse(&mut self.checker.clone(), file_name, source)?;
+        Ok(rustgen::generate(file_name, &root)?)
+    }
+
+    /// The program lowered to IR, with `file_name` in its errors
+    pub fn to_ir(&self, file_name: &str, source: &str) -> Result<Module, Diagnostics> {
+        let root = self.parse(&mut self.checker.clone(), file_name, source)?;
+        Ok(ir::lower(&root)?)
+    }
+
+    /// The type the checker finds for the value of a program, without running it.
+    /// A name that is not defined is an error, instead of a value of any type.
+    pub fn type_of(&self, source: &str) -> Result<Type, Diagnostics> {
+        let tokens = Lexer::new("<type>".to_string(), source.to_string()).tokenize()?;
+        let root = Parser::new(tokens).parse()?;
+        if let Node::StatementsNode(statements) = &root {
+            if let [Node::VarAccessNode(token)] = &statements[..] {
+                let name = ast::identifier_name(token)?;
+                if self.checker.global(&name).is_none() && self.get_global(&name).is_none() {
+                    return Err(ErrorType::TypeError(TypeError::new(
+                        token.position_start(),
+                        token.position_end(),
+                        format!("{} is not defined", name),
+                    ))
+                    .into());
+                }
+            }
+        }
+        Ok(self.checker.clone().check(&root)?)
+    }
+
+    // The syntax tree of a program that passed the type checker,
+    // optimized unless that is turned off
+    fn parse(
+        &self,
+        checker: &mut Checker,
+        file_name: &str,
+        source: &str,
+    ) -> Result<Node, Diagnostics> {
+        let tokens = Lexer::new(file_name.to_string(), source.to_string()).tokenize()?;
+        let root = Parser::new(tokens).parse()?;
+        checker.check(&root)?;
+        match self.optimize {
+            true => Ok(optimizer::optimize("Program", &root)?),
+            false => Ok(root),
+        }
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.checker.set_global(name, Type::of(&value));
+        self.globals.borrow_mut().set(name.to_string(), value);
+    }
+
+    /// Makes a host function callable by its name
+    pub fn register<F: NativeFunction + 'static>(&mut self, function: F) {
+        let name = function.name().to_string();
+        self.set_global(&name, Value::Native(Native::new(function)));
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Whether programs are optimized before they run, on by default.
+    /// Errors in constant expressions are reported before anything runs when it is on.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// How many times the interpreter calls a function before compiling it to machine code,
+    /// `jit::THRESHOLD` by default. `None` keeps every call in the interpreter. Programs
+    /// do the same either way, only faster.
+    pub fn set_jit_threshold(&mut self, threshold: Option<u64>) {
+        self.jit_threshold = threshold;
+    }
+
+    /// Where `tulosta` and `syöte` write to
+    pub fn set_output<W: Write + 'static>(&mut self, sink: W) {
+        self.output = Rc::new(RefCell::new(sink));
+    }
+
+    /// Where `syöte` reads from
+    pub fn set_input<R: BufRead + 'static>(&mut self, source: R) {
+        self.input = Some(Rc::new(RefCell::new(source)));
+    }
+
+    /// The output programs write to, shared with the engine
+    pub fn output(&self) -> Output {
+        self.output.clone()
+    }
+
+    /// Reads a line from the input programs read from, with the line ending
+    pub fn read_line(&self, line: &mut String) -> io::Result<usize> {
+        match &self.input {
+            Some(input) => input.borrow_mut().read_line(line),
+            None => io::stdin().read_line(line),
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything that went wrong in a program
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    errors: Vec<ErrorType>,
+}
+
+impl Diagnostics {
+    pub fn errors(&self) -> &[ErrorType] {
+        &self.errors
+    }
+}
+
+impl From<ErrorType> for Diagnostics {
+    fn from(error: ErrorType) -> Self {
+        Self {
+            errors: vec![error],
+        }
+    }
+}
+
+impl From<Vec<ErrorType>> for Diagnostics {
+    fn from(errors: Vec<ErrorType>) -> Self {
+        Self { errors }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<String> = self.errors.iter().map(|e| e.as_string()).collect();
+        write!(f, "{}", errors.join("\n\n"))
+    }
+}
+
+/// An output sink that keeps what is written, for hosts that want to read it back
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).to_string()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::{Arity, NativeFn};
+    use crate::number::{Number, NumberType};
+
+    #[test]
+    fn test_eval_and_globals() {
+        let mut engine = Engine::new();
+        assert_eq!(engine.eval("1 + 2").unwrap().to_string(), "3");
+
+        engine
+            .eval("muut a = 5; tominto kaksi(x) { palata x * 2 }")
+            .unwrap();
+        assert_eq!(engine.get_global("a").unwrap().to_string(), "5");
+        assert_eq!(engine.eval("kaksi(a)").unwrap().to_string(), "10");
+
+        engine.set_global("nimi", Value::Text("Olle".to_string()));
+        assert_eq!(engine.eval("nimi + \"!\"").unwrap().to_string(), "Olle!");
+        assert!(engine.get_global("puuttuu").is_none());
+    }
+
+    #[test]
+    fn test_host_functions_and_output() {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        engine.register(NativeFn::new("vastaus", Arity::exactly(0), |_, _| {
+            Ok(Value::Number(NumberType::Integer(Number::new_no_pos(42))))
+        }));
+
+        engine
+            .eval("tulosta(\"vastaus:\", vastaus()); tulosta([1])")
+            .unwrap();
+        assert_eq!(output.contents(), "vastaus: 42\n[1]\n");
+    }
+
+    #[test]
+    fn test_input() {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        engine.set_input(io::Cursor::new("Olle\n3\n"));
+
+        let program = "muut nimi = syöte(\"Nimi? \"); tulosta(\"Moi\", nimi); syöte(); syöte()";
+        assert_eq!(engine.eval(program).unwrap(), Value::Nil);
+        assert_eq!(output.contents(), "Nimi? Moi Olle\n");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut engine = Engine::new();
+        let diagnostics = engine.eval_file("virhe.fin", "1 +").unwrap_err();
+        assert!(matches!(diagnostics.errors(), [ErrorType::SyntaxError(_)]));
+        assert!(diagnostics.to_string().contains("virhe.fin"));
+
+        // As many carets as the code they point at has characters
+        let diagnostics = engine.eval("muut a: kok = 1.5").unwrap_err();
+        assert!(diagnostics
+            .to_string()
+            .ends_with("\nmuut a: kok = 1.5\n              ^^^"));
+        let diagnostics = engine.eval("1 / \"a\"").unwrap_err();
+        assert!(diagnostics.to_string().ends_with("\n1 / \"a\"\n  ^"));
+
+        engine.set_limits(Limits::new().with_max_steps(100));
+        let diagnostics = engine.eval("kun tosi { 1 }").unwrap_err();
+        assert!(matches!(diagnostics.errors(), [ErrorType::LimitError(_)]));
+    }
+
+    #[test]
+    fn test_constant_errors_before_running() {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        let program = "tulosta(\"alku\"); muut a = 4 * (3 - 2) / (2 - 2)";
+        let diagnostics = engine.eval(program).unwrap_err();
+        assert!(matches!(
+            diagnostics.errors(),
+            [ErrorType::DivisionByZeroError(_)]
+        ));
+        assert_eq!(output.contents(), "");
+
+        engine.set_optimize(false);
+        assert!(engine.eval(program).is_err());
+        assert_eq!(output.contents(), "alku\n");
+    }
+
+    #[test]
+    fn test_type_errors_before_running() {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        engine
+            .eval("tominto tupla(n: kok): kok { palata n * 2 }")
+            .unwrap();
+
+        let diagnostics = engine
+            .eval("tulosta(\"alku\"); muut a: teksti = tupla(\"a\")")
+            .unwrap_err();
+        assert!(matches!(
+            diagnostics.errors(),
+            [ErrorType::TypeError(_), ErrorType::TypeError(_)]
+        ));
+        assert_eq!(output.contents(), "");
+        assert_eq!(engine.eval("tupla(21)").unwrap().to_string(), "42");
+
+        engine.set_global("tupla", Value::Nil);
+        assert!(engine
+            .eval("tupla(\"a\")")
+            .unwrap_err()
+            .to_string()
+            .contains("not a function"));
+    }
+
+    #[test]
+    fn test_inferred_types() {
+        let mut engine = Engine::new();
+        engine
+            .eval("muut x = 5; tominto nimi() { palata \"Olle\" }")
+            .unwrap();
+        assert_eq!(engine.type_of("x").unwrap(), Type::Integer);
+        assert_eq!(engine.type_of("nimi()").unwrap(), Type::Text);
+        assert_eq!(engine.type_of("x / 2 + 1").unwrap(), Type::Integer);
+        assert_eq!(
+            engine.type_of("nimi").unwrap().to_string(),
+            "tominto(): teksti"
+        );
+
+        let diagnostics = engine.eval("x + nimi()").unwrap_err();
+        assert!(diagnostics
+            .to_string()
+            .contains("x is kok from line 1, col 9. nimi returns teksti from line 1, col 29"));
+
+        // The type stays for the programs after
+        let diagnostics = engine.eval("muut x = \"viisi\"").unwrap_err();
+        assert!(diagnostics.to_string().contains("x is kok"));
+        assert_eq!(engine.type_of("x").unwrap(), Type::Integer);
+
+        let diagnostics = engine.type_of("zz").unwrap_err();
+        assert!(matches!(diagnostics.errors(), [ErrorType::TypeError(_)]));
+        assert!(diagnostics.to_string().contains("zz is not defined"));
+        assert!(engine.type_of("tulosta").is_ok());
+    }
+}
@@ -1,14 +1,71 @@
-use crate::context::Context;
+use crate::context::{Context, Frame};
+use crate::limits::Limit;
 use crate::position::Position;
-use std::cmp;
-use std::fmt::{self, format};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum ErrorType {
     DisallowedCharError(DisallowedCharError),
     SyntaxError(SyntaxError),
     RunTimeError(RunTimeError),
     DivisionByZeroError(DivisionByZeroError),
+    LimitError(LimitError),
+    TypeError(TypeError),
+}
+
+impl ErrorType {
+    pub fn as_string(&self) -> String {
+        match self {
+            ErrorType::DisallowedCharError(e) => e.as_string(),
+            ErrorType::SyntaxError(e) => e.as_string(),
+            ErrorType::RunTimeError(e) => e.as_string(),
+            ErrorType::DivisionByZeroError(e) => e.as_string(),
+            ErrorType::LimitError(e) => e.as_string(),
+            ErrorType::TypeError(e) => e.as_string(),
+        }
+    }
+
+    /// Attaches the call stack to runtime errors that do not have one yet.
+    /// The innermost call sees the error first, so it records the whole stack.
+    pub fn with_frames(self, frames: &[Frame]) -> Self {
+        match self {
+            ErrorType::RunTimeError(mut e) => {
+                if e.frames.is_empty() {
+                    e.frames = frames.to_vec();
+                }
+                ErrorType::RunTimeError(e)
+            }
+            ErrorType::DivisionByZeroError(mut e) => {
+                if e.error.frames.is_empty() {
+                    e.error.frames = frames.to_vec();
+                }
+                ErrorType::DivisionByZeroError(e)
+            }
+            ErrorType::LimitError(mut e) => {
+                if e.error.frames.is_empty() {
+                    e.error.frames = frames.to_vec();
+                }
+                ErrorType::LimitError(e)
+            }
+            e => e,
+        }
+    }
+
+    /// Marks runtime errors that were found before the program ran, like a constant `1 / 0`.
+    /// They are shown at their position without a traceback, like type errors.
+    pub fn before_running(self) -> Self {
+        match self {
+            ErrorType::RunTimeError(mut e) => {
+                e.before_running = true;
+                ErrorType::RunTimeError(e)
+            }
+            ErrorType::DivisionByZeroError(mut e) => {
+                e.error.before_running = true;
+                ErrorType::DivisionByZeroError(e)
+            }
+            e => e,
+        }
+    }
 }
 
 /*
@@ -72,11 +129,8 @@ impl Error {
         let error_origin = format!(", File {}, line {}, col {}", file_name, line, col);
         let error_origin = &error_origin[..];
         error.push_str(error_origin);
-        // Dont know why arrow wont appear automatically here but placing one manually fixes it./
-        // String with arrows adds spaces though.
-        // Would fix but not worthy of my time right now.
         format!(
-            "{}\n\n{}^",
+            "{}\n\n{}",
             error,
             string_with_arrows(self.pos_begin.clone(), self.pos_end.clone(),)
         )
@@ -133,10 +187,35 @@ impl SyntaxError {
     }
 }
 
+/// A program uses a value where its type is not allowed, found before it runs
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    error: Error,
+}
+
+impl TypeError {
+    pub fn new(
+        pos_begin: Option<Position>,
+        pos_end: Option<Position>,
+        error_message: String,
+    ) -> Self {
+        Self {
+            error: Error::new(pos_begin, pos_end, "Type Error".to_string(), error_message),
+        }
+    }
+    pub fn as_string(&self) -> String {
+        self.error.as_string()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RunTimeError {
     error: Error,
-    context: Context,
+    // Boxed to keep results with errors small
+    context: Box<Context>,
+    frames: Vec<Frame>,
+    // Found before the program ran, so there is no call stack to show
+    before_running: bool,
 }
 
 impl RunTimeError {
@@ -145,59 +224,153 @@ impl RunTimeError {
         pos_end: Option<Position>,
         error_message: String,
         context: Context,
+    ) -> Self {
+        Self::named("Runtime Error", pos_begin, pos_end, error_message, context)
+    }
+
+    fn named(
+        error_name: &str,
+        pos_begin: Option<Position>,
+        pos_end: Option<Position>,
+        error_message: String,
+        context: Context,
     ) -> Self {
         Self {
-            error: Error::new(
-                pos_begin,
-                pos_end,
-                "Runtime Error".to_string(),
-                error_message,
-            ),
-            context,
+            error: Error::new(pos_begin, pos_end, error_name.to_string(), error_message),
+            context: Box::new(context),
+            frames: Vec::new(),
+            before_running: false,
         }
     }
     pub fn as_string(&self) -> String {
-        let result = self.traceback_error();
-        let result = format!(
-            "{} {}: {}",
-            result, self.error.error_name, self.error.error_message
-        );
+        if self.before_running {
+            return self.error.as_string();
+        }
         format!(
-            "{}\n\n{}",
-            result,
-            string_with_arrows(self.error.pos_begin.clone(), self.error.pos_end.clone(),)
+            "{}{}",
+            self.traceback_error(),
+            error_report(
+                &self.error.error_name,
+                &self.error.error_message,
+                self.error.pos_begin.clone(),
+                self.error.pos_end.clone(),
+            )
         )
     }
 
     pub fn traceback_error(&self) -> String {
+        if !self.frames.is_empty() {
+            return traceback(&self.frames, self.error.pos_begin.clone());
+        }
+
+        // Errors raised outside of the interpreter only know their contexts
         let mut result = String::new();
         let mut position = self.error.pos_begin.clone();
-        let mut context = Some(self.context.clone());
+        let mut context = Some((*self.context).clone());
 
         while let Some(ctx) = context {
-            let pos = position.unwrap(); // If context is not None so should pos
             result = format!(
-                "File: {} Line {} Col {}, in {} \n{}",
-                pos.file_name(),
-                pos.line(),
-                pos.column(),
-                ctx.display_name(),
+                "{}\n{}",
+                frame_line(position.as_ref(), &ctx.display_name()),
                 result
             );
             position = ctx.parent_pos();
-            context = match ctx.parent() {
-                Some(c) => Some(*c),
-                None => None,
-            };
+            context = ctx.parent().map(|c| (*c).clone());
         }
 
-        format!("Traceback from most recent callback: \n{}", result)
+        format!("Traceback (most recent call last):\n{}", result)
     }
 }
 
+/// A program ran out of steps, call depth or time
+#[derive(Debug, Clone)]
+pub struct LimitError {
+    limit: Limit,
+    error: RunTimeError,
+}
+
+impl LimitError {
+    pub fn new(
+        limit: Limit,
+        pos_begin: Option<Position>,
+        pos_end: Option<Position>,
+        error_message: String,
+        context: Context,
+    ) -> Self {
+        Self {
+            limit,
+            error: RunTimeError::named(
+                &format!("{} Error", limit),
+                pos_begin,
+                pos_end,
+                error_message,
+                context,
+            ),
+        }
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    pub fn as_string(&self) -> String {
+        self.error.as_string()
+    }
+}
+
+// The frames are ordered from the program to the most recent call.
+// Each frame is shown at the place where it called the next one.
+fn traceback(frames: &[Frame], error_pos: Option<Position>) -> String {
+    let mut result = "Traceback (most recent call last):\n".to_string();
+    for (i, frame) in frames.iter().enumerate() {
+        let position = match frames.get(i + 1) {
+            Some(next) => next.call_pos(),
+            None => error_pos.clone(),
+        };
+        if frame.elided() > 0 {
+            result.push_str(&format!("  [{} tail calls elided]\n", frame.elided()));
+        }
+        result.push_str(&frame_line(position.as_ref(), &frame.signature()));
+        result.push('\n');
+    }
+    result
+}
+
+fn frame_line(position: Option<&Position>, name: &str) -> String {
+    format!("{}{}", frame_location(position), name)
+}
+
+/// How a traceback line starts, up to the name of the function
+pub fn frame_location(position: Option<&Position>) -> String {
+    match position {
+        Some(pos) => format!(
+            "  File {}, line {}, col {}, in ",
+            pos.file_name(),
+            pos.line() + 1,
+            pos.column()
+        ),
+        None => "  File <unknown>, in ".to_string(),
+    }
+}
+
+/// The name, message and arrows of an error, as they follow its traceback
+pub fn error_report(
+    error_name: &str,
+    error_message: &str,
+    pos_start: Option<Position>,
+    pos_end: Option<Position>,
+) -> String {
+    format!(
+        "{}: {}\n\n{}",
+        error_name,
+        error_message,
+        string_with_arrows(pos_start, pos_end)
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct DivisionByZeroError {
-    error: Error,
+    error: RunTimeError,
 }
 
 impl DivisionByZeroError {
@@ -205,16 +378,19 @@ impl DivisionByZeroError {
         pos_begin: Option<Position>,
         pos_end: Option<Position>,
         error_message: String,
+        context: Context,
     ) -> Self {
         Self {
-            error: Error::new(
+            error: RunTimeError::named(
+                "DivisionByZero Error",
                 pos_begin,
                 pos_end,
-                "DivisionByZero Error".to_string(),
                 error_message,
+                context,
             ),
         }
     }
+
     pub fn as_string(&self) -> String {
         self.error.as_string()
     }
@@ -225,7 +401,7 @@ fn string_with_arrows(pos_start: Option<Position>, pos_end: Option<Position>) ->
 
     let pos_start = match pos_start {
         Some(pos) => pos,
-        None => panic!("Cant construct ERROR arrows"),
+        None => return result,
     };
 
     let pos_end = match pos_end {
@@ -235,54 +411,32 @@ fn string_with_arrows(pos_start: Option<Position>, pos_end: Option<Position>) ->
 
     let text = pos_start.file_text();
 
-    // Calc slice to show arrows under
-    let text_start_slice = &text[0..pos_start.index() as usize];
-    let text_end_slice = &text[pos_end.index() as usize..];
-
-    let mut index_start = match text_start_slice.to_string().rfind('\n') {
-        Some(idx) => idx,
-        None => 0,
-    };
-    let mut index_end = match text_end_slice.to_string().find('\n') {
-        Some(idx) => idx,
-        None => text.len(),
-    };
+    // Positions count characters, so work line by line instead of slicing bytes
+    let lines: Vec<&str> = text.split('\n').collect();
+    let first_line = pos_start.line() as usize;
+    let line_count = (pos_end.line() - pos_start.line() + 1) as usize;
 
     // Gen lines
-    let line_count = pos_end.line() - pos_start.line() + 1;
-    for i in 0..line_count {
-        let line_ = &text[index_start..index_end];
-
-        let column_start = {
-            if i == 0 {
-                pos_start.column()
-            } else {
-                0
-            }
-        };
-        let column_end = {
-            if i == line_count - 1 {
-                pos_end.column()
-            } else {
-                line_.len() as i64 - 1
-            }
+    for (i, line_) in lines.iter().skip(first_line).take(line_count).enumerate() {
+        let column_start = if i == 0 { pos_start.column() } else { 0 };
+        let column_end = if i == line_count - 1 {
+            pos_end.column()
+        } else {
+            line_.chars().count() as i64
         };
 
         // Add To resulting Output String
-        result = format!("{}{}\n", result, line_);
+        if i > 0 {
+            result.push('\n');
+        }
+        result.push_str(line_);
+        result.push('\n');
         for _ in 0..column_start {
-            result = format!("{} ", result) // Add beginning Spaces
+            result.push(' ') // Add beginning Spaces
         }
-        for _ in 0..(column_end - column_start) {
-            result = format!("{}^", result)
+        for _ in column_start..column_end.max(column_start + 1) {
+            result.push('^')
         }
-
-        // Re-Calc slices
-        index_start = index_end;
-        let mut index_end = match text_end_slice.to_string().find('\n') {
-            Some(idx) => idx,
-            None => text.len(),
-        };
     }
 
     result.replace('\t', "")
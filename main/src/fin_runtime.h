@@ -0,0 +1,1404 @@
+/*
+ * Runtime for programs compiled to C by finc.
+ *
+ * Values, operators, builtins and errors behave like in the interpreter, including the
+ * tracebacks of errors. Programs are short lived, so memory is never freed.
+ * The iso, murto and kompleksi number types are not supported.
+ */
+#ifndef FIN_RUNTIME_H
+#define FIN_RUNTIME_H
+
+#include <float.h>
+#include <math.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* Lines start from 0 and columns count characters, like in Position */
+typedef struct {
+    int line0, col0, line1, col1;
+} FinSpan;
+
+enum {
+    FIN_UNSET,
+    FIN_NIL,
+    FIN_INT,
+    FIN_FLOAT,
+    FIN_BOOL,
+    FIN_TEXT,
+    FIN_LIST,
+    FIN_RECORD,
+    FIN_FUNCTION,
+    FIN_NATIVE
+};
+
+enum {
+    FIN_ADD,
+    FIN_SUB,
+    FIN_MUL,
+    FIN_DIV,
+    FIN_POW,
+    FIN_EQ,
+    FIN_NE,
+    FIN_LT,
+    FIN_GT,
+    FIN_LE,
+    FIN_GE
+};
+
+typedef struct FinText FinText;
+typedef struct FinList FinList;
+typedef struct FinRecord FinRecord;
+typedef struct FinFunction FinFunction;
+typedef struct FinNative FinNative;
+typedef struct FinEnv FinEnv;
+
+/* Numbers remember where they were computed, between the starts and ends of two spans */
+typedef struct {
+    int tag;
+    int start, end;
+    union {
+        int64_t i;
+        double f;
+        int b;
+        const FinText *t;
+        const FinList *l;
+        const FinRecord *r;
+        const FinFunction *fn;
+        const FinNative *nat;
+    } as;
+} FinValue;
+
+struct FinText {
+    size_t len;
+    const char *data;
+};
+
+struct FinList {
+    size_t len;
+    FinValue *items;
+};
+
+/* Fields are sorted by name */
+struct FinRecord {
+    size_t len;
+    const char **names;
+    FinValue *values;
+};
+
+typedef FinValue (*FinCode)(const FinFunction *self, const FinValue *args);
+
+struct FinFunction {
+    const char *name;
+    int arity;
+    const int *params;
+    FinCode code;
+    FinEnv *closure;
+};
+
+typedef FinValue (*FinNativeCode)(const char *name, int argc, const FinValue *args);
+
+/* max is -1 for any number of arguments */
+struct FinNative {
+    const char *name;
+    int min, max;
+    FinNativeCode code;
+};
+
+/* A symbol map: variables by symbol number, and the map of the surrounding function */
+struct FinEnv {
+    FinEnv *parent;
+    int len, cap;
+    int *names;
+    FinValue *values;
+};
+
+typedef struct {
+    const char *name;
+    /* -1 for the program itself */
+    int argc;
+    const int *params;
+    FinValue *args;
+    int call_span;
+    long elided;
+} FinFrame;
+
+#define FIN_MAX_DEPTH 1000
+
+static const char *fin_file_name;
+static const char *const *fin_lines;
+static int fin_line_count;
+static const FinSpan *fin_spans;
+static const char *const *fin_symbols;
+static int fin_symbol_count;
+
+static FinEnv *fin_globals;
+static FinFrame *fin_frames;
+static int fin_frame_count, fin_frame_cap;
+/* Where the running builtin was called, for its errors */
+static int fin_native_span = -1;
+
+static void *fin_alloc(size_t size)
+{
+    void *memory = malloc(size ? size : 1);
+    if (!memory) {
+        fputs("Out of memory\n", stderr);
+        exit(1);
+    }
+    return memory;
+}
+
+/* Growing text buffer */
+typedef struct {
+    char *data;
+    size_t len, cap;
+} FinBuf;
+
+static void fin_buf_put(FinBuf *buf, const char *data, size_t len)
+{
+    if (buf->len + len + 1 > buf->cap) {
+        size_t cap = buf->cap ? buf->cap * 2 : 64;
+        while (cap < buf->len + len + 1)
+            cap *= 2;
+        char *grown = fin_alloc(cap);
+        if (buf->data)
+            memcpy(grown, buf->data, buf->len);
+        free(buf->data);
+        buf->data = grown;
+        buf->cap = cap;
+    }
+    memcpy(buf->data + buf->len, data, len);
+    buf->len += len;
+    buf->data[buf->len] = '\0';
+}
+
+static void fin_buf_str(FinBuf *buf, const char *text)
+{
+    fin_buf_put(buf, text, strlen(text));
+}
+
+static void fin_buf_printf(FinBuf *buf, const char *format, ...)
+{
+    char small[256];
+    va_list args;
+    va_start(args, format);
+    int len = vsnprintf(small, sizeof small, format, args);
+    va_end(args);
+    if (len < (int)sizeof small) {
+        fin_buf_put(buf, small, (size_t)len);
+        return;
+    }
+    char *large = fin_alloc((size_t)len + 1);
+    va_start(args, format);
+    vsnprintf(large, (size_t)len + 1, format, args);
+    va_end(args);
+    fin_buf_put(buf, large, (size_t)len);
+    free(large);
+}
+
+/* Values */
+
+static FinValue fin_value(int tag)
+{
+    FinValue value;
+    memset(&value, 0, sizeof value);
+    value.tag = tag;
+    value.start = value.end = -1;
+    return value;
+}
+
+static FinValue fin_nil(void)
+{
+    return fin_value(FIN_NIL);
+}
+
+static FinValue fin_unset(void)
+{
+    return fin_value(FIN_UNSET);
+}
+
+static FinValue fin_int(int64_t i)
+{
+    FinValue value = fin_value(FIN_INT);
+    value.as.i = i;
+    return value;
+}
+
+static FinValue fin_float(double f)
+{
+    FinValue value = fin_value(FIN_FLOAT);
+    value.as.f = f;
+    return value;
+}
+
+static FinValue fin_bool(int b)
+{
+    FinValue value = fin_value(FIN_BOOL);
+    value.as.b = b != 0;
+    return value;
+}
+
+static FinValue fin_text(const FinText *text)
+{
+    FinValue value = fin_value(FIN_TEXT);
+    value.as.t = text;
+    return value;
+}
+
+static FinValue fin_text_copy(const char *data, size_t len)
+{
+    FinText *text = fin_alloc(sizeof *text);
+    char *copy = fin_alloc(len + 1);
+    memcpy(copy, data, len);
+    copy[len] = '\0';
+    text->len = len;
+    text->data = copy;
+    return fin_text(text);
+}
+
+/* Takes the contents of the buffer */
+static FinValue fin_text_buf(FinBuf *buf)
+{
+    FinText *text = fin_alloc(sizeof *text);
+    text->len = buf->len;
+    text->data = buf->data ? buf->data : "";
+    return fin_text(text);
+}
+
+/* The value at a position, from the start of one span to the end of another */
+static FinValue fin_at(FinValue value, int start, int end)
+{
+    value.start = start;
+    value.end = end;
+    return value;
+}
+
+static int fin_is_number(FinValue value)
+{
+    return value.tag == FIN_INT || value.tag == FIN_FLOAT;
+}
+
+static const char *fin_type_name(FinValue value)
+{
+    switch (value.tag) {
+    case FIN_INT:
+        return "kok";
+    case FIN_FLOAT:
+        return "liu";
+    case FIN_BOOL:
+        return "totuus";
+    case FIN_TEXT:
+        return "teksti";
+    case FIN_LIST:
+        return "lista";
+    case FIN_RECORD:
+        return "tietue";
+    case FIN_FUNCTION:
+    case FIN_NATIVE:
+        return "tominto";
+    default:
+        return "tyhj\303\244";
+    }
+}
+
+static const char *const fin_operator_names[] = {
+    "+", "-", "*", "/", "^", "==", "!=", "<", ">", "<=", ">="
+};
+
+/* Display */
+
+/* The shortest digits that read back as the same float, written without an exponent */
+static void fin_put_float(FinBuf *buf, double value)
+{
+    if (isnan(value)) {
+        fin_buf_str(buf, "NaN");
+        return;
+    }
+    if (signbit(value))
+        fin_buf_str(buf, "-");
+    value = fabs(value);
+    if (isinf(value)) {
+        fin_buf_str(buf, "inf");
+        return;
+    }
+    if (value == 0) {
+        fin_buf_str(buf, "0");
+        return;
+    }
+
+    char scientific[40];
+    for (int precision = 0; precision < 17; precision++) {
+        snprintf(scientific, sizeof scientific, "%.*e", precision, value);
+        if (strtod(scientific, NULL) == value)
+            break;
+    }
+    char digits[40];
+    size_t count = 0;
+    const char *c = scientific;
+    for (; *c && *c != 'e'; c++) {
+        if (*c != '.')
+            digits[count++] = *c;
+    }
+    while (count > 1 && digits[count - 1] == '0')
+        count--;
+    int point = atoi(c + 1) + 1;
+
+    if (point <= 0) {
+        fin_buf_str(buf, "0.");
+        for (int i = 0; i < -point; i++)
+            fin_buf_str(buf, "0");
+        fin_buf_put(buf, digits, count);
+    } else if ((size_t)point >= count) {
+        fin_buf_put(buf, digits, count);
+        for (size_t i = count; i < (size_t)point; i++)
+            fin_buf_str(buf, "0");
+    } else {
+        fin_buf_put(buf, digits, (size_t)point);
+        fin_buf_str(buf, ".");
+        fin_buf_put(buf, digits + point, count - (size_t)point);
+    }
+}
+
+static void fin_put_repr(FinBuf *buf, FinValue value);
+
+static void fin_put(FinBuf *buf, FinValue value)
+{
+    switch (value.tag) {
+    case FIN_INT:
+        fin_buf_printf(buf, "%lld", (long long)value.as.i);
+        break;
+    case FIN_FLOAT:
+        fin_put_float(buf, value.as.f);
+        break;
+    case FIN_BOOL:
+        fin_buf_str(buf, value.as.b ? "tosi" : "ep\303\244tosi");
+        break;
+    case FIN_TEXT:
+        fin_buf_put(buf, value.as.t->data, value.as.t->len);
+        break;
+    case FIN_FUNCTION:
+        fin_buf_printf(buf, "<tominto %s>", value.as.fn->name);
+        break;
+    case FIN_NATIVE:
+        fin_buf_printf(buf, "<tominto %s>", value.as.nat->name);
+        break;
+    case FIN_LIST:
+        fin_buf_str(buf, "[");
+        for (size_t i = 0; i < value.as.l->len; i++) {
+            if (i > 0)
+                fin_buf_str(buf, ", ");
+            fin_put_repr(buf, value.as.l->items[i]);
+        }
+        fin_buf_str(buf, "]");
+        break;
+    case FIN_RECORD:
+        fin_buf_str(buf, "{");
+        for (size_t i = 0; i < value.as.r->len; i++) {
+            if (i > 0)
+                fin_buf_str(buf, ", ");
+            fin_buf_printf(buf, "%s: ", value.as.r->names[i]);
+            fin_put_repr(buf, value.as.r->values[i]);
+        }
+        fin_buf_str(buf, "}");
+        break;
+    default:
+        fin_buf_str(buf, "tyhj\303\244");
+    }
+}
+
+/* Texts are quoted and escaped like Rust's Debug formatting */
+static void fin_put_repr(FinBuf *buf, FinValue value)
+{
+    if (value.tag != FIN_TEXT) {
+        fin_put(buf, value);
+        return;
+    }
+    const unsigned char *c = (const unsigned char *)value.as.t->data;
+    const unsigned char *end = c + value.as.t->len;
+    fin_buf_str(buf, "\"");
+    while (c < end) {
+        switch (*c) {
+        case '"':
+            fin_buf_str(buf, "\\\"");
+            break;
+        case '\\':
+            fin_buf_str(buf, "\\\\");
+            break;
+        case '\n':
+            fin_buf_str(buf, "\\n");
+            break;
+        case '\r':
+            fin_buf_str(buf, "\\r");
+            break;
+        case '\t':
+            fin_buf_str(buf, "\\t");
+            break;
+        case '\0':
+            fin_buf_str(buf, "\\0");
+            break;
+        default:
+            if (*c < 0x20 || *c == 0x7f) {
+                fin_buf_printf(buf, "\\u{%x}", *c);
+            } else if (*c == 0xc2 && c + 1 < end && c[1] >= 0x80 && c[1] < 0xa0) {
+                /* Control characters after ASCII */
+                fin_buf_printf(buf, "\\u{%x}", c[1]);
+                c++;
+            } else {
+                fin_buf_put(buf, (const char *)c, 1);
+            }
+        }
+        c++;
+    }
+    fin_buf_str(buf, "\"");
+}
+
+static char *fin_display(FinValue value)
+{
+    FinBuf buf = {0};
+    fin_put(&buf, value);
+    return buf.data ? buf.data : "";
+}
+
+/* Errors */
+
+static size_t fin_char_count(const char *text)
+{
+    size_t count = 0;
+    for (; *text; text++) {
+        if (((unsigned char)*text & 0xc0) != 0x80)
+            count++;
+    }
+    return count;
+}
+
+static void fin_put_frame_line(FinBuf *buf, int span, const FinFrame *frame)
+{
+    if (span >= 0)
+        fin_buf_printf(buf, "  File %s, line %d, col %d, in %s", fin_file_name,
+                       fin_spans[span].line0 + 1, fin_spans[span].col0, frame->name);
+    else
+        fin_buf_printf(buf, "  File <unknown>, in %s", frame->name);
+    if (frame->argc >= 0) {
+        fin_buf_str(buf, "(");
+        for (int i = 0; i < frame->argc; i++) {
+            if (i > 0)
+                fin_buf_str(buf, ", ");
+            fin_buf_printf(buf, "%s = ", fin_symbols[frame->params[i]]);
+            fin_put_repr(buf, frame->args[i]);
+        }
+        fin_buf_str(buf, ")");
+    }
+    fin_buf_str(buf, "\n");
+}
+
+static void fin_put_arrows(FinBuf *buf, int start, int end)
+{
+    if (start < 0)
+        return;
+    if (end < 0)
+        end = start;
+    int first = fin_spans[start].line0;
+    int count = fin_spans[end].line1 - first + 1;
+    for (int i = 0; i < count && first + i < fin_line_count; i++) {
+        const char *line = fin_lines[first + i];
+        long col_start = i == 0 ? fin_spans[start].col0 : 0;
+        long col_end = i == count - 1 ? fin_spans[end].col1 : (long)fin_char_count(line);
+        if (i > 0)
+            fin_buf_str(buf, "\n");
+        for (const char *c = line; *c; c++) {
+            if (*c != '\t')
+                fin_buf_put(buf, c, 1);
+        }
+        fin_buf_str(buf, "\n");
+        for (long col = 0; col < col_start; col++)
+            fin_buf_str(buf, " ");
+        long carets = col_end > col_start + 1 ? col_end : col_start + 1;
+        for (long col = col_start; col < carets; col++)
+            fin_buf_str(buf, "^");
+    }
+}
+
+/* Ends the program with an error between the start of one span and the end of another */
+static void fin_fail(const char *kind, int start, int end, const char *format, ...)
+{
+    FinBuf buf = {0};
+    fin_buf_str(&buf, "Traceback (most recent call last):\n");
+    for (int i = 0; i < fin_frame_count; i++) {
+        int span = i + 1 < fin_frame_count ? fin_frames[i + 1].call_span : start;
+        if (fin_frames[i].elided > 0)
+            fin_buf_printf(&buf, "  [%ld tail calls elided]\n", fin_frames[i].elided);
+        fin_put_frame_line(&buf, span, &fin_frames[i]);
+    }
+    fin_buf_printf(&buf, "%s: ", kind);
+    char message[1024];
+    va_list args;
+    va_start(args, format);
+    vsnprintf(message, sizeof message, format, args);
+    va_end(args);
+    fin_buf_str(&buf, message);
+    fin_buf_str(&buf, "\n\n");
+    fin_put_arrows(&buf, start, end);
+
+    fflush(stdout);
+    fprintf(stderr, "%s\n", buf.data);
+    exit(1);
+}
+
+#define fin_runtime_error(start, end, ...) fin_fail("Runtime Error", start, end, __VA_ARGS__)
+
+static void fin_overflow(int start, int end)
+{
+    fin_runtime_error(start, end, "kokonaisluvun ylivuoto");
+}
+
+static void fin_division_by_zero(int start, int end)
+{
+    fin_fail("DivisionByZero Error", start, end, "Division by Zero");
+}
+
+static void fin_unsupported(int start, int end, const char *type)
+{
+    fin_runtime_error(start, end, "%s numbers are not supported in compiled programs", type);
+}
+
+/* Symbol maps */
+
+static FinEnv *fin_env_new(FinEnv *parent)
+{
+    FinEnv *env = fin_alloc(sizeof *env);
+    memset(env, 0, sizeof *env);
+    env->parent = parent;
+    return env;
+}
+
+static void fin_env_set(FinEnv *env, int name, FinValue value)
+{
+    for (int i = 0; i < env->len; i++) {
+        if (env->names[i] == name) {
+            env->values[i] = value;
+            return;
+        }
+    }
+    if (env->len == env->cap) {
+        int cap = env->cap ? env->cap * 2 : 8;
+        int *names = fin_alloc(sizeof *names * (size_t)cap);
+        FinValue *values = fin_alloc(sizeof *values * (size_t)cap);
+        if (env->len) {
+            memcpy(names, env->names, sizeof *names * (size_t)env->len);
+            memcpy(values, env->values, sizeof *values * (size_t)env->len);
+        }
+        env->names = names;
+        env->values = values;
+        env->cap = cap;
+    }
+    env->names[env->len] = name;
+    env->values[env->len] = value;
+    env->len++;
+}
+
+/* The value of a variable, at the position it is used */
+static FinValue fin_lookup(const FinEnv *env, int name, int span)
+{
+    for (; env; env = env->parent) {
+        for (int i = 0; i < env->len; i++) {
+            if (env->names[i] == name)
+                return fin_at(env->values[i], span, span);
+        }
+    }
+    fin_runtime_error(span, span, "%s is not defined", fin_symbols[name]);
+    return fin_nil();
+}
+
+/* A local variable, or the variable around the function while it is not set */
+static FinValue fin_local(FinValue local, const FinEnv *closure, int name, int span)
+{
+    if (local.tag == FIN_UNSET)
+        return fin_lookup(closure, name, span);
+    return fin_at(local, span, span);
+}
+
+/* Collections */
+
+static FinValue fin_list(size_t len, const FinValue *items)
+{
+    FinList *list = fin_alloc(sizeof *list);
+    list->len = len;
+    list->items = fin_alloc(sizeof *items * len);
+    if (len)
+        memcpy(list->items, items, sizeof *items * len);
+    FinValue value = fin_value(FIN_LIST);
+    value.as.l = list;
+    return value;
+}
+
+static FinValue fin_record(void)
+{
+    FinRecord *record = fin_alloc(sizeof *record);
+    memset(record, 0, sizeof *record);
+    FinValue value = fin_value(FIN_RECORD);
+    value.as.r = record;
+    return value;
+}
+
+/* Only for records that are being built */
+static void fin_record_set(FinValue record, const char *name, FinValue field)
+{
+    FinRecord *fields = (FinRecord *)record.as.r;
+    size_t at = 0;
+    while (at < fields->len && strcmp(fields->names[at], name) < 0)
+        at++;
+    if (at < fields->len && strcmp(fields->names[at], name) == 0) {
+        fields->values[at] = field;
+        return;
+    }
+    const char **names = fin_alloc(sizeof *names * (fields->len + 1));
+    FinValue *values = fin_alloc(sizeof *values * (fields->len + 1));
+    for (size_t i = 0, j = 0; i <= fields->len; i++) {
+        if (i == at) {
+            names[i] = name;
+            values[i] = field;
+        } else {
+            names[i] = fields->names[j];
+            values[i] = fields->values[j++];
+        }
+    }
+    fields->names = names;
+    fields->values = values;
+    fields->len++;
+}
+
+static size_t fin_utf8_length(unsigned char lead)
+{
+    if (lead >= 0xf0)
+        return 4;
+    if (lead >= 0xe0)
+        return 3;
+    if (lead >= 0xc0)
+        return 2;
+    return 1;
+}
+
+static FinValue fin_index(FinValue collection, FinValue index, int span)
+{
+    int whole = index.tag == FIN_INT;
+    int in_range = whole && index.as.i >= 0;
+    if (collection.tag == FIN_LIST && whole) {
+        if (in_range && (uint64_t)index.as.i < collection.as.l->len)
+            return collection.as.l->items[index.as.i];
+    } else if (collection.tag == FIN_TEXT && whole) {
+        const char *c = collection.as.t->data;
+        const char *end = c + collection.as.t->len;
+        for (int64_t i = 0; in_range && c < end; i++) {
+            size_t len = fin_utf8_length((unsigned char)*c);
+            if (i == index.as.i)
+                return fin_text_copy(c, len);
+            c += len;
+        }
+    } else if (collection.tag == FIN_RECORD && index.tag == FIN_TEXT) {
+        for (size_t i = 0; i < collection.as.r->len; i++) {
+            if (strcmp(collection.as.r->names[i], index.as.t->data) == 0)
+                return collection.as.r->values[i];
+        }
+    } else {
+        fin_runtime_error(span, span, "Cant index %s with %s", fin_type_name(collection),
+                          fin_type_name(index));
+    }
+    fin_runtime_error(span, span, "Index %s is out of range", fin_display(index));
+    return fin_nil();
+}
+
+static FinValue fin_field(FinValue record, const char *name, int span)
+{
+    if (record.tag == FIN_RECORD) {
+        for (size_t i = 0; i < record.as.r->len; i++) {
+            if (strcmp(record.as.r->names[i], name) == 0)
+                return record.as.r->values[i];
+        }
+    }
+    fin_runtime_error(span, span, "%s has no field %s", fin_type_name(record), name);
+    return fin_nil();
+}
+
+/* Operators */
+
+static int fin_truth(FinValue value, int span)
+{
+    if (value.tag != FIN_BOOL)
+        fin_runtime_error(span, span, "Condition must be totuus, found %s", fin_type_name(value));
+    return value.as.b;
+}
+
+static int fin_checked_add(int64_t a, int64_t b, int64_t *result)
+{
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b))
+        return 0;
+    *result = a + b;
+    return 1;
+}
+
+static int fin_checked_sub(int64_t a, int64_t b, int64_t *result)
+{
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b))
+        return 0;
+    *result = a - b;
+    return 1;
+}
+
+static int fin_checked_mul(int64_t a, int64_t b, int64_t *result)
+{
+    if (a != 0 && b != 0) {
+        int overflow;
+        if (a > 0)
+            overflow = b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a;
+        else
+            overflow = b > 0 ? a < INT64_MIN / b : a < INT64_MAX / b;
+        if (overflow)
+            return 0;
+    }
+    *result = a * b;
+    return 1;
+}
+
+/* Powers by squaring, in the same order as the interpreter so results agree */
+static int fin_checked_pow(int64_t base, uint64_t exponent, int64_t *result)
+{
+    if (exponent == 0) {
+        *result = 1;
+        return 1;
+    }
+    while ((exponent & 1) == 0) {
+        if (!fin_checked_mul(base, base, &base))
+            return 0;
+        exponent >>= 1;
+    }
+    if (exponent == 1) {
+        *result = base;
+        return 1;
+    }
+    int64_t acc = base;
+    while (exponent > 1) {
+        exponent >>= 1;
+        if (!fin_checked_mul(base, base, &base))
+            return 0;
+        if ((exponent & 1) && !fin_checked_mul(acc, base, &acc))
+            return 0;
+    }
+    *result = acc;
+    return 1;
+}
+
+static double fin_float_pow(double base, uint64_t exponent)
+{
+    if (exponent == 0)
+        return 1.0;
+    while ((exponent & 1) == 0) {
+        base = base * base;
+        exponent >>= 1;
+    }
+    if (exponent == 1)
+        return base;
+    double acc = base;
+    while (exponent > 1) {
+        exponent >>= 1;
+        base = base * base;
+        if (exponent & 1)
+            acc = acc * base;
+    }
+    return acc;
+}
+
+static uint64_t fin_unsigned_abs(int64_t value)
+{
+    return value < 0 ? (uint64_t)0 - (uint64_t)value : (uint64_t)value;
+}
+
+/* Any real number to a real power */
+static FinValue fin_powf(double base, double exponent, FinValue left, FinValue right)
+{
+    if (base == 0 && exponent < 0)
+        fin_division_by_zero(left.start, right.end);
+    if (base < 0 && fmod(exponent, 1.0) != 0)
+        fin_runtime_error(left.start, right.end,
+                          "Cant raise a negative number to a fractional power, use a complex base");
+    return fin_float(pow(base, exponent));
+}
+
+static FinValue fin_power(FinValue left, FinValue right)
+{
+    if (right.tag == FIN_FLOAT) {
+        double base = left.tag == FIN_INT ? (double)left.as.i : left.as.f;
+        return fin_powf(base, right.as.f, left, right);
+    }
+    int64_t exponent = right.as.i;
+    uint64_t magnitude = fin_unsigned_abs(exponent);
+    if (left.tag == FIN_FLOAT) {
+        double power = fin_float_pow(left.as.f, magnitude);
+        if (exponent >= 0)
+            return fin_float(power);
+        if (power == 0)
+            fin_division_by_zero(left.start, right.end);
+        return fin_float(1.0 / power);
+    }
+    int64_t power;
+    if (exponent >= 0) {
+        if (!fin_checked_pow(left.as.i, magnitude, &power))
+            fin_overflow(left.start, right.end);
+        return fin_int(power);
+    }
+    /* The result is a murto, which is exact and never overflows */
+    if (left.as.i == 0)
+        fin_division_by_zero(left.start, right.end);
+    fin_unsupported(left.start, right.end, "murto");
+    return fin_nil();
+}
+
+static FinValue fin_arithmetic(int op, FinValue left, FinValue right)
+{
+    if (op == FIN_POW)
+        return fin_power(left, right);
+    if (left.tag == FIN_FLOAT) {
+        double a = left.as.f, b = right.as.f;
+        switch (op) {
+        case FIN_ADD:
+            return fin_float(a + b);
+        case FIN_SUB:
+            return fin_float(a - b);
+        case FIN_MUL:
+            return fin_float(a * b);
+        default:
+            if (b == 0)
+                fin_division_by_zero(right.start, right.end);
+            return fin_float(a / b);
+        }
+    }
+    int64_t a = left.as.i, b = right.as.i, result = 0;
+    int fits;
+    switch (op) {
+    case FIN_ADD:
+        fits = fin_checked_add(a, b, &result);
+        break;
+    case FIN_SUB:
+        fits = fin_checked_sub(a, b, &result);
+        break;
+    case FIN_MUL:
+        fits = fin_checked_mul(a, b, &result);
+        break;
+    default:
+        if (b == 0)
+            fin_division_by_zero(right.start, right.end);
+        fits = !(a == INT64_MIN && b == -1);
+        if (fits)
+            result = a / b;
+    }
+    if (!fits)
+        fin_overflow(left.start, right.end);
+    return fin_int(result);
+}
+
+/* -1, 0 or 1, or 2 when the values are unordered */
+#define FIN_UNORDERED 2
+
+static int fin_compare_numbers(FinValue left, FinValue right)
+{
+    if (left.tag == FIN_INT)
+        return (left.as.i > right.as.i) - (left.as.i < right.as.i);
+    if (isnan(left.as.f) || isnan(right.as.f))
+        return FIN_UNORDERED;
+    return (left.as.f > right.as.f) - (left.as.f < right.as.f);
+}
+
+static int fin_compare_texts(const FinText *left, const FinText *right)
+{
+    size_t len = left->len < right->len ? left->len : right->len;
+    int order = memcmp(left->data, right->data, len);
+    if (order == 0)
+        return (left->len > right->len) - (left->len < right->len);
+    return order < 0 ? -1 : 1;
+}
+
+static int fin_equals(FinValue left, FinValue right)
+{
+    if (left.tag != right.tag)
+        return 0;
+    switch (left.tag) {
+    case FIN_INT:
+    case FIN_FLOAT:
+        return fin_compare_numbers(left, right) == 0;
+    case FIN_BOOL:
+        return left.as.b == right.as.b;
+    case FIN_TEXT:
+        return fin_compare_texts(left.as.t, right.as.t) == 0;
+    case FIN_FUNCTION:
+        return left.as.fn->code == right.as.fn->code
+               && left.as.fn->closure == right.as.fn->closure;
+    case FIN_NATIVE:
+        return left.as.nat == right.as.nat;
+    case FIN_LIST:
+        if (left.as.l->len != right.as.l->len)
+            return 0;
+        for (size_t i = 0; i < left.as.l->len; i++) {
+            if (!fin_equals(left.as.l->items[i], right.as.l->items[i]))
+                return 0;
+        }
+        return 1;
+    case FIN_RECORD:
+        if (left.as.r->len != right.as.r->len)
+            return 0;
+        for (size_t i = 0; i < left.as.r->len; i++) {
+            if (strcmp(left.as.r->names[i], right.as.r->names[i]) != 0
+                || !fin_equals(left.as.r->values[i], right.as.r->values[i]))
+                return 0;
+        }
+        return 1;
+    default:
+        return 1;
+    }
+}
+
+static FinValue fin_comparison(int op, FinValue left, FinValue right, int span)
+{
+    int equality = op == FIN_EQ || op == FIN_NE;
+    int order;
+    if (fin_is_number(left) && fin_is_number(right)) {
+        if (left.tag != right.tag)
+            fin_runtime_error(span, span, "Cant compare %s with %s due to different types",
+                              fin_display(left), fin_display(right));
+        order = fin_compare_numbers(left, right);
+    } else if (left.tag == FIN_TEXT && right.tag == FIN_TEXT) {
+        order = fin_compare_texts(left.as.t, right.as.t);
+    } else if (equality) {
+        order = fin_equals(left, right) ? 0 : FIN_UNORDERED;
+    } else {
+        fin_runtime_error(span, span, "Cant compare %s with %s", fin_type_name(left),
+                          fin_type_name(right));
+        order = FIN_UNORDERED;
+    }
+    switch (op) {
+    case FIN_NE:
+        return fin_bool(order != 0);
+    case FIN_EQ:
+        return fin_bool(order == 0);
+    case FIN_LT:
+        return fin_bool(order == -1);
+    case FIN_GT:
+        return fin_bool(order == 1);
+    case FIN_LE:
+        return fin_bool(order == -1 || order == 0);
+    default:
+        return fin_bool(order == 1 || order == 0);
+    }
+}
+
+static FinValue fin_concat(FinValue left, FinValue right)
+{
+    if (left.tag == FIN_TEXT) {
+        FinBuf buf = {0};
+        fin_buf_put(&buf, left.as.t->data, left.as.t->len);
+        fin_buf_put(&buf, right.as.t->data, right.as.t->len);
+        return fin_text_buf(&buf);
+    }
+    size_t len = left.as.l->len + right.as.l->len;
+    FinValue *items = fin_alloc(sizeof *items * len);
+    if (left.as.l->len)
+        memcpy(items, left.as.l->items, sizeof *items * left.as.l->len);
+    if (right.as.l->len)
+        memcpy(items + left.as.l->len, right.as.l->items, sizeof *items * right.as.l->len);
+    return fin_list(len, items);
+}
+
+/* Any operator but 'ja' and 'tai'. Errors about the operator point at its span. */
+static FinValue fin_binary(int op, FinValue left, FinValue right, int span)
+{
+    if (op >= FIN_EQ)
+        return fin_comparison(op, left, right, span);
+    if (fin_is_number(left) && fin_is_number(right)) {
+        /* kok and liu do not mix, except in powers */
+        if (left.tag != right.tag && op != FIN_POW) {
+            static const char *const formats[] = {
+                "Cant add %s with %s due to different types",
+                "Cant subtract %s from %s due to different types",
+                "Cant Multiply %s with %s due to different types",
+                "Cant Divide %s with %s due to different types",
+            };
+            fin_runtime_error(span, span, formats[op], fin_display(left), fin_display(right));
+        }
+        return fin_arithmetic(op, left, right);
+    }
+    if (op == FIN_ADD && ((left.tag == FIN_TEXT && right.tag == FIN_TEXT)
+                          || (left.tag == FIN_LIST && right.tag == FIN_LIST)))
+        return fin_concat(left, right);
+    fin_runtime_error(span, span, "Cant use %s with %s and %s", fin_operator_names[op],
+                      fin_type_name(left), fin_type_name(right));
+    return fin_nil();
+}
+
+/* Unary '+' and '-'. The result starts at the operator and ends where the number did. */
+static FinValue fin_unary(int op, FinValue value, int span)
+{
+    if (!fin_is_number(value))
+        fin_runtime_error(span, span, "Cant use %s with %s", fin_operator_names[op],
+                          fin_type_name(value));
+    FinValue result = value;
+    if (op == FIN_SUB && value.tag == FIN_FLOAT) {
+        result = fin_float(-value.as.f);
+    } else if (op == FIN_SUB) {
+        if (value.as.i == INT64_MIN)
+            fin_overflow(value.start, value.end);
+        result = fin_int(-value.as.i);
+    }
+    return fin_at(result, span, value.end);
+}
+
+/* Calls */
+
+static FinValue fin_function(const char *name, int arity, const int *params, FinCode code,
+                             FinEnv *closure)
+{
+    FinFunction *function = fin_alloc(sizeof *function);
+    function->name = name;
+    function->arity = arity;
+    function->params = params;
+    function->code = code;
+    function->closure = closure;
+    FinValue value = fin_value(FIN_FUNCTION);
+    value.as.fn = function;
+    return value;
+}
+
+static void fin_push_frame(const char *name, int argc, const int *params, const FinValue *args,
+                           int call_span)
+{
+    if (fin_frame_count == fin_frame_cap) {
+        int cap = fin_frame_cap ? fin_frame_cap * 2 : 64;
+        FinFrame *frames = fin_alloc(sizeof *frames * (size_t)cap);
+        if (fin_frame_count)
+            memcpy(frames, fin_frames, sizeof *frames * (size_t)fin_frame_count);
+        free(fin_frames);
+        fin_frames = frames;
+        fin_frame_cap = cap;
+    }
+    FinFrame *frame = &fin_frames[fin_frame_count++];
+    frame->name = name;
+    frame->argc = argc;
+    frame->params = params;
+    frame->args = NULL;
+    if (argc > 0) {
+        frame->args = fin_alloc(sizeof *args * (size_t)argc);
+        memcpy(frame->args, args, sizeof *args * (size_t)argc);
+    }
+    frame->call_span = call_span;
+    frame->elided = 0;
+}
+
+/* The running function calls itself again in the same frame */
+static void fin_tail_call(const FinValue *args)
+{
+    FinFrame *frame = &fin_frames[fin_frame_count - 1];
+    if (frame->argc > 0)
+        memcpy(frame->args, args, sizeof *args * (size_t)frame->argc);
+    frame->elided++;
+}
+
+static void fin_put_arity(FinBuf *buf, const FinNative *native)
+{
+    if (native->max == native->min)
+        fin_buf_printf(buf, "%d", native->min);
+    else if (native->max >= 0)
+        fin_buf_printf(buf, "%d to %d", native->min, native->max);
+    else
+        fin_buf_printf(buf, "at least %d", native->min);
+}
+
+/* Calls the value of the variable `name` */
+static FinValue fin_call(FinValue callee, const char *name, int argc, const FinValue *args,
+                         int span)
+{
+    FinValue result;
+    if (callee.tag == FIN_FUNCTION) {
+        const FinFunction *function = callee.as.fn;
+        if (argc != function->arity)
+            fin_runtime_error(span, span, "%s expects %d arguments, got %d", function->name,
+                              function->arity, argc);
+        if (fin_frame_count > FIN_MAX_DEPTH)
+            fin_fail("Call Depth Limit Error", span, span, "Calls nested more than %d deep",
+                     FIN_MAX_DEPTH);
+        fin_push_frame(function->name, argc, function->params, args, span);
+        result = function->code(function, args);
+        fin_frame_count--;
+    } else if (callee.tag == FIN_NATIVE) {
+        const FinNative *native = callee.as.nat;
+        if (argc < native->min || (native->max >= 0 && argc > native->max)) {
+            FinBuf arity = {0};
+            fin_put_arity(&arity, native);
+            fin_runtime_error(span, span, "%s expects %s arguments, got %d", name, arity.data,
+                              argc);
+        }
+        int caller = fin_native_span;
+        fin_native_span = span;
+        result = native->code(name, argc, args);
+        fin_native_span = caller;
+    } else {
+        fin_runtime_error(span, span, "%s is a %s, not a function", name, fin_type_name(callee));
+        result = fin_nil();
+    }
+    return fin_at(result, span, span);
+}
+
+/* Builtins */
+
+#define fin_native_error(...) fin_runtime_error(fin_native_span, fin_native_span, __VA_ARGS__)
+
+static FinValue fin_native_tulosta(const char *name, int argc, const FinValue *args)
+{
+    (void)name;
+    FinBuf buf = {0};
+    for (int i = 0; i < argc; i++) {
+        if (i > 0)
+            fin_buf_str(&buf, " ");
+        fin_put(&buf, args[i]);
+    }
+    fin_buf_str(&buf, "\n");
+    fwrite(buf.data, 1, buf.len, stdout);
+    free(buf.data);
+    return fin_nil();
+}
+
+static FinValue fin_native_syote(const char *name, int argc, const FinValue *args)
+{
+    (void)name;
+    if (argc > 0) {
+        fputs(fin_display(args[0]), stdout);
+        fflush(stdout);
+    }
+    FinBuf line = {0};
+    char chunk[256];
+    while (fgets(chunk, sizeof chunk, stdin)) {
+        fin_buf_str(&line, chunk);
+        if (line.len > 0 && line.data[line.len - 1] == '\n')
+            break;
+    }
+    if (line.len == 0)
+        return fin_nil();
+    while (line.len > 0 && (line.data[line.len - 1] == '\n' || line.data[line.len - 1] == '\r'))
+        line.data[--line.len] = '\0';
+    return fin_text_buf(&line);
+}
+
+static FinValue fin_native_str(const char *name, int argc, const FinValue *args)
+{
+    (void)name;
+    (void)argc;
+    FinBuf buf = {0};
+    fin_put(&buf, args[0]);
+    return fin_text_buf(&buf);
+}
+
+static FinValue fin_native_pituus(const char *name, int argc, const FinValue *args)
+{
+    (void)name;
+    (void)argc;
+    switch (args[0].tag) {
+    case FIN_TEXT:
+        return fin_int((int64_t)fin_char_count(args[0].as.t->data));
+    case FIN_LIST:
+        return fin_int((int64_t)args[0].as.l->len);
+    case FIN_RECORD:
+        return fin_int((int64_t)args[0].as.r->len);
+    default:
+        fin_native_error("pituus expects teksti, lista or tietue, got %s",
+                         fin_type_name(args[0]));
+        return fin_nil();
+    }
+}
+
+static FinValue fin_native_extreme(const char *name, int argc, const FinValue *args)
+{
+    int wanted = strcmp(name, "min") == 0 ? -1 : 1;
+    const FinValue *values = args;
+    size_t count = (size_t)argc;
+    if (argc == 1 && args[0].tag == FIN_LIST) {
+        if (args[0].as.l->len == 0)
+            fin_native_error("%s of an empty lista", name);
+        values = args[0].as.l->items;
+        count = args[0].as.l->len;
+    }
+    FinValue best = values[0];
+    for (size_t i = 1; i < count; i++) {
+        FinValue value = values[i];
+        int order = FIN_UNORDERED;
+        if (fin_is_number(value) && value.tag == best.tag)
+            order = fin_compare_numbers(value, best);
+        else if (value.tag == FIN_TEXT && best.tag == FIN_TEXT)
+            order = fin_compare_texts(value.as.t, best.as.t);
+        if (order == FIN_UNORDERED)
+            fin_native_error("%s cant compare %s with %s", name, fin_type_name(value),
+                             fin_type_name(best));
+        if (order == wanted)
+            best = value;
+    }
+    return best;
+}
+
+static double fin_number_arg(const char *name, FinValue value)
+{
+    if (!fin_is_number(value))
+        fin_native_error("%s expects numbers, got %s", name, fin_type_name(value));
+    return value.tag == FIN_INT ? (double)value.as.i : value.as.f;
+}
+
+static FinValue fin_native_sqrt(const char *name, int argc, const FinValue *args)
+{
+    (void)argc;
+    double value = fin_number_arg(name, args[0]);
+    if (value < 0)
+        fin_native_error("kompleksi numbers are not supported in compiled programs");
+    return fin_float(sqrt(value));
+}
+
+/* The complex plane accessors, for real numbers */
+static FinValue fin_native_complex(const char *name, int argc, const FinValue *args)
+{
+    (void)argc;
+    double value = fin_number_arg(name, args[0]);
+    if (strcmp(name, "abs") == 0) {
+        if (args[0].tag == FIN_FLOAT)
+            return fin_float(fabs(value));
+        if (args[0].as.i == INT64_MIN)
+            fin_native_error("kokonaisluvun ylivuoto");
+        return fin_int(args[0].as.i < 0 ? -args[0].as.i : args[0].as.i);
+    }
+    if (strcmp(name, "arg") == 0)
+        return fin_float(atan2(0.0, value));
+    if (strcmp(name, "conj") == 0)
+        return args[0];
+    if (strcmp(name, "re") == 0)
+        return fin_float(value);
+    return fin_float(0.0);
+}
+
+static FinValue fin_native_unsupported(const char *name, int argc, const FinValue *args)
+{
+    (void)argc;
+    (void)args;
+    fin_native_error("%s numbers are not supported in compiled programs", name);
+    return fin_nil();
+}
+
+static FinValue fin_native_liu(const char *name, int argc, const FinValue *args)
+{
+    (void)argc;
+    return fin_float(fin_number_arg(name, args[0]));
+}
+
+static int64_t fin_wrapping_mul(int64_t a, int64_t b)
+{
+    return (int64_t)((uint64_t)a * (uint64_t)b);
+}
+
+/* Integer operations that wrap around or saturate instead of reporting overflow */
+static FinValue fin_native_integer(const char *name, int argc, const FinValue *args)
+{
+    (void)argc;
+    if (args[0].tag != FIN_INT || args[1].tag != FIN_INT)
+        fin_native_error("%s expects two integers", name);
+    int64_t a = args[0].as.i, b = args[1].as.i, result;
+    int pow = strstr(name, "_pow") != NULL;
+    if (pow && b < 0)
+        fin_native_error("Cant raise to Negative power");
+    int saturating = strncmp(name, "saturating", 10) == 0;
+    int fits = 1;
+    if (strstr(name, "_add")) {
+        fits = fin_checked_add(a, b, &result);
+        if (!fits)
+            result = (int64_t)((uint64_t)a + (uint64_t)b);
+        if (!fits && saturating)
+            result = b > 0 ? INT64_MAX : INT64_MIN;
+    } else if (strstr(name, "_sub")) {
+        fits = fin_checked_sub(a, b, &result);
+        if (!fits)
+            result = (int64_t)((uint64_t)a - (uint64_t)b);
+        if (!fits && saturating)
+            result = b < 0 ? INT64_MAX : INT64_MIN;
+    } else if (strstr(name, "_mul")) {
+        fits = fin_checked_mul(a, b, &result);
+        if (!fits)
+            result = fin_wrapping_mul(a, b);
+        if (!fits && saturating)
+            result = (a < 0) != (b < 0) ? INT64_MIN : INT64_MAX;
+    } else if (saturating) {
+        if (!fin_checked_pow(a, (uint64_t)b, &result))
+            result = a < 0 && b % 2 == 1 ? INT64_MIN : INT64_MAX;
+    } else {
+        uint64_t exponent = (uint64_t)b;
+        int64_t base = a;
+        result = 1;
+        if (exponent > 0) {
+            while ((exponent & 1) == 0) {
+                base = fin_wrapping_mul(base, base);
+                exponent >>= 1;
+            }
+            result = base;
+            while (exponent > 1) {
+                exponent >>= 1;
+                base = fin_wrapping_mul(base, base);
+                if (exponent & 1)
+                    result = fin_wrapping_mul(result, base);
+            }
+        }
+    }
+    return fin_int(result);
+}
+
+static const FinNative fin_prelude[] = {
+    {"tulosta", 0, -1, fin_native_tulosta},
+    {"sy\303\266te", 0, 1, fin_native_syote},
+    {"str", 1, 1, fin_native_str},
+    {"pituus", 1, 1, fin_native_pituus},
+    {"min", 1, -1, fin_native_extreme},
+    {"max", 1, -1, fin_native_extreme},
+    {"sqrt", 1, 1, fin_native_sqrt},
+    {"abs", 1, 1, fin_native_complex},
+    {"arg", 1, 1, fin_native_complex},
+    {"conj", 1, 1, fin_native_complex},
+    {"re", 1, 1, fin_native_complex},
+    {"im", 1, 1, fin_native_complex},
+    {"iso", 1, 1, fin_native_unsupported},
+    {"murto", 1, 2, fin_native_unsupported},
+    {"liu", 1, 1, fin_native_liu},
+    {"wrapping_add", 2, 2, fin_native_integer},
+    {"wrapping_sub", 2, 2, fin_native_integer},
+    {"wrapping_mul", 2, 2, fin_native_integer},
+    {"wrapping_pow", 2, 2, fin_native_integer},
+    {"saturating_add", 2, 2, fin_native_integer},
+    {"saturating_sub", 2, 2, fin_native_integer},
+    {"saturating_mul", 2, 2, fin_native_integer},
+    {"saturating_pow", 2, 2, fin_native_integer},
+};
+
+/* Sets up the globals and the program frame. Builtins the program never names are left out. */
+static void fin_init(const char *file_name, const char *const *lines, int line_count,
+                     const FinSpan *spans, const char *const *symbols, int symbol_count)
+{
+    fin_file_name = file_name;
+    fin_lines = lines;
+    fin_line_count = line_count;
+    fin_spans = spans;
+    fin_symbols = symbols;
+    fin_symbol_count = symbol_count;
+
+    fin_globals = fin_env_new(NULL);
+    for (size_t i = 0; i < sizeof fin_prelude / sizeof fin_prelude[0]; i++) {
+        for (int name = 0; name < symbol_count; name++) {
+            if (strcmp(symbols[name], fin_prelude[i].name) == 0) {
+                FinValue native = fin_value(FIN_NATIVE);
+                native.as.nat = &fin_prelude[i];
+                fin_env_set(fin_globals, name, native);
+            }
+        }
+    }
+    fin_push_frame("Program", -1, NULL, NULL, -1);
+}
+
+#endif
@@ -0,0 +1,877 @@
+# Runtime for programs compiled by finc's assembly backend, for x86-64 Linux.
+#
+# It needs no C library: output is buffered here and written with system calls, and
+# floats are printed with the shortest digits that read back as the same number, like
+# the interpreter prints them. Functions follow the System V calling convention.
+#
+# Generated programs define fin_main, and keep a frame for the program and every call:
+#
+#   [rbp - 8]        the descriptor of the function
+#   [rbp - 16]       where the function was called from, as shown in tracebacks
+#   [rbp - 24]       tail calls that reused the frame
+#   [rbp - 32 - 8i]  argument i, as the function was called with it
+#
+# A descriptor has the name of the function with its opening parenthesis, then the number
+# of parameters, or -1 for the program, then a label and a type for every parameter.
+# Types are 0 for kok, 1 for liu and 2 for totuus.
+
+    .intel_syntax noprefix
+
+    .globl _start
+    .globl fin_depth
+    .globl fin_program_frame
+    .globl fin_fail
+    .globl fin_put_cstr
+    .globl fin_put_int
+    .globl fin_put_float
+    .globl fin_put_bool
+    .globl fin_pow_int
+    .globl fin_pow_float
+    .globl fin_space_text
+    .globl fin_newline_text
+
+    .data
+    .balign 8
+# Calls running, counting the program
+fin_depth:
+    .quad 0
+fin_program_frame:
+    .quad 0
+fin_out_fd:
+    .quad 1
+fin_out_len:
+    .quad 0
+
+    .bss
+    .balign 8
+fin_out_buf:
+    .zero 4096
+# Frames of a traceback, the most recent first
+fin_frames:
+    .zero 8200
+# Numbers for printing floats, 20 limbs each with the lowest first
+fin_big_mant:
+    .zero 160
+fin_big_minus:
+    .zero 160
+fin_big_plus:
+    .zero 160
+fin_big_scale:
+    .zero 160
+fin_big_scale2:
+    .zero 160
+fin_big_scale4:
+    .zero 160
+fin_big_scale8:
+    .zero 160
+fin_big_sum:
+    .zero 160
+
+    .section .rodata
+fin_space_text:
+    .asciz " "
+fin_newline_text:
+    .asciz "\n"
+fin_minus_text:
+    .asciz "-"
+fin_zero_text:
+    .asciz "0"
+fin_point_text:
+    .asciz "."
+fin_zero_point_text:
+    .asciz "0."
+fin_nan_text:
+    .asciz "NaN"
+fin_inf_text:
+    .asciz "inf"
+fin_tosi_text:
+    .asciz "tosi"
+fin_epatosi_text:
+    .asciz "ep\303\244tosi"
+fin_close_text:
+    .asciz ")"
+fin_traceback_text:
+    .asciz "Traceback (most recent call last):\n"
+fin_elided_open_text:
+    .asciz "  ["
+fin_elided_close_text:
+    .asciz " tail calls elided]\n"
+
+    .text
+
+_start:
+    call fin_main
+    call fin_flush
+    xor edi, edi
+    mov eax, 231
+    syscall
+
+# Output
+
+# Writes the buffered output to its file
+fin_flush:
+    push rbx
+    xor ebx, ebx
+.Lflush_loop:
+    mov rdx, qword ptr [rip + fin_out_len]
+    sub rdx, rbx
+    jle .Lflush_done
+    mov eax, 1
+    mov rdi, qword ptr [rip + fin_out_fd]
+    lea rsi, [rip + fin_out_buf]
+    add rsi, rbx
+    syscall
+    # Output that cant be written is dropped
+    test rax, rax
+    jle .Lflush_done
+    add rbx, rax
+    jmp .Lflush_loop
+.Lflush_done:
+    mov qword ptr [rip + fin_out_len], 0
+    pop rbx
+    ret
+
+# Buffers rsi bytes from rdi
+fin_put_bytes:
+    push rbx
+    push r12
+    push r13
+    mov r12, rdi
+    mov r13, rsi
+.Lput_loop:
+    test r13, r13
+    je .Lput_done
+    mov rbx, qword ptr [rip + fin_out_len]
+    cmp rbx, 4096
+    jne .Lput_byte
+    call fin_flush
+    xor ebx, ebx
+.Lput_byte:
+    movzx eax, byte ptr [r12]
+    lea rcx, [rip + fin_out_buf]
+    mov byte ptr [rcx + rbx], al
+    add rbx, 1
+    mov qword ptr [rip + fin_out_len], rbx
+    add r12, 1
+    sub r13, 1
+    jmp .Lput_loop
+.Lput_done:
+    pop r13
+    pop r12
+    pop rbx
+    ret
+
+# Buffers the text ending in a zero byte at rdi
+fin_put_cstr:
+    mov rsi, rdi
+.Lcstr_loop:
+    cmp byte ptr [rsi], 0
+    je .Lcstr_done
+    add rsi, 1
+    jmp .Lcstr_loop
+.Lcstr_done:
+    sub rsi, rdi
+    jmp fin_put_bytes
+
+fin_put_int:
+    sub rsp, 40
+    mov rax, rdi
+    mov rcx, rdi
+    lea rsi, [rsp + 32]
+    test rax, rax
+    jns .Lint_digit
+    # The magnitude of the smallest kok only fits unsigned
+    neg rax
+.Lint_digit:
+    xor edx, edx
+    mov r8d, 10
+    div r8
+    add edx, 48
+    sub rsi, 1
+    mov byte ptr [rsi], dl
+    test rax, rax
+    jne .Lint_digit
+    test rcx, rcx
+    jns .Lint_write
+    sub rsi, 1
+    mov byte ptr [rsi], 45
+.Lint_write:
+    mov rdi, rsi
+    lea rsi, [rsp + 32]
+    sub rsi, rdi
+    call fin_put_bytes
+    add rsp, 40
+    ret
+
+fin_put_bool:
+    lea rax, [rip + fin_tosi_text]
+    test rdi, rdi
+    jne .Lbool_write
+    lea rax, [rip + fin_epatosi_text]
+.Lbool_write:
+    mov rdi, rax
+    jmp fin_put_cstr
+
+# Big numbers
+
+# Sets the number at rdi to rsi
+fin_big_set:
+    mov qword ptr [rdi], rsi
+    mov ecx, 1
+.Lset_loop:
+    mov qword ptr [rdi + rcx*8], 0
+    add rcx, 1
+    cmp rcx, 20
+    jne .Lset_loop
+    ret
+
+# Copies the number at rsi to rdi
+fin_big_copy:
+    xor ecx, ecx
+.Lcopy_loop:
+    mov rax, qword ptr [rsi + rcx*8]
+    mov qword ptr [rdi + rcx*8], rax
+    add rcx, 1
+    cmp rcx, 20
+    jne .Lcopy_loop
+    ret
+
+# Adds the number at rsi to the one at rdi
+fin_big_add:
+    xor ecx, ecx
+    mov r8d, 20
+    clc
+.Ladd_loop:
+    mov rax, qword ptr [rsi + rcx*8]
+    adc qword ptr [rdi + rcx*8], rax
+    lea rcx, [rcx + 1]
+    dec r8
+    jne .Ladd_loop
+    ret
+
+# Subtracts the number at rsi from the one at rdi, which is not smaller
+fin_big_sub:
+    xor ecx, ecx
+    mov r8d, 20
+    clc
+.Lsub_loop:
+    mov rax, qword ptr [rsi + rcx*8]
+    sbb qword ptr [rdi + rcx*8], rax
+    lea rcx, [rcx + 1]
+    dec r8
+    jne .Lsub_loop
+    ret
+
+# Compares the numbers at rdi and rsi, giving -1, 0 or 1 in eax
+fin_big_cmp:
+    mov ecx, 19
+.Lcmp_loop:
+    mov rax, qword ptr [rdi + rcx*8]
+    cmp rax, qword ptr [rsi + rcx*8]
+    ja .Lcmp_greater
+    jb .Lcmp_less
+    sub rcx, 1
+    jns .Lcmp_loop
+    xor eax, eax
+    ret
+.Lcmp_greater:
+    mov eax, 1
+    ret
+.Lcmp_less:
+    mov eax, -1
+    ret
+
+# Multiplies the number at rdi by rsi
+fin_big_mul_small:
+    xor r8d, r8d
+    xor ecx, ecx
+.Lmul_loop:
+    mov rax, qword ptr [rdi + rcx*8]
+    mul rsi
+    add rax, r8
+    adc rdx, 0
+    mov qword ptr [rdi + rcx*8], rax
+    mov r8, rdx
+    add rcx, 1
+    cmp rcx, 20
+    jne .Lmul_loop
+    ret
+
+# Multiplies the number at rdi by 2 to the power of rsi
+fin_big_shl:
+    test rsi, rsi
+    je .Lshl_done
+    xor ecx, ecx
+    mov r8d, 20
+    clc
+.Lshl_loop:
+    mov rax, qword ptr [rdi + rcx*8]
+    adc rax, rax
+    mov qword ptr [rdi + rcx*8], rax
+    lea rcx, [rcx + 1]
+    dec r8
+    jne .Lshl_loop
+    sub rsi, 1
+    jmp fin_big_shl
+.Lshl_done:
+    ret
+
+# Multiplies the number at rdi by 10 to the power of rsi
+fin_big_mul_pow10:
+    mov r9, rdi
+    mov r10, rsi
+.Lpow10_loop:
+    test r10, r10
+    je .Lpow10_done
+    mov rdi, r9
+    mov esi, 10
+    call fin_big_mul_small
+    sub r10, 1
+    jmp .Lpow10_loop
+.Lpow10_done:
+    ret
+
+# Multiplies the numbers for mant, minus and plus by 10
+fin_big_next_digit:
+    lea rdi, [rip + fin_big_mant]
+    mov esi, 10
+    call fin_big_mul_small
+    lea rdi, [rip + fin_big_minus]
+    mov esi, 10
+    call fin_big_mul_small
+    lea rdi, [rip + fin_big_plus]
+    mov esi, 10
+    jmp fin_big_mul_small
+
+# Whether scale is below mant + plus, or not above it when r14 is 1, in eax
+fin_big_up:
+    lea rdi, [rip + fin_big_sum]
+    lea rsi, [rip + fin_big_mant]
+    call fin_big_copy
+    lea rdi, [rip + fin_big_sum]
+    lea rsi, [rip + fin_big_plus]
+    call fin_big_add
+    lea rdi, [rip + fin_big_scale]
+    lea rsi, [rip + fin_big_sum]
+    call fin_big_cmp
+    cmp eax, r14d
+    setl al
+    movzx eax, al
+    ret
+
+# Floats
+
+# Prints xmm0 without an exponent, in the shortest digits that read back as the same
+# float. The digits are found like Rust finds them, with the Dragon4 algorithm as fixed
+# by Steele and White: the float is a fraction of big numbers, and digits are made until
+# they are closer to it than to any other float.
+fin_put_float:
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 32
+    movq rax, xmm0
+    mov rbx, rax
+    shr rbx, 52
+    and ebx, 2047
+    movabs r12, 4503599627370495
+    and r12, rax
+    cmp ebx, 2047
+    jne .Lfloat_sign
+    test r12, r12
+    je .Lfloat_sign
+    lea rdi, [rip + fin_nan_text]
+    call fin_put_cstr
+    jmp .Lfloat_done
+.Lfloat_sign:
+    test rax, rax
+    jns .Lfloat_magnitude
+    lea rdi, [rip + fin_minus_text]
+    call fin_put_cstr
+.Lfloat_magnitude:
+    cmp ebx, 2047
+    jne .Lfloat_finite
+    lea rdi, [rip + fin_inf_text]
+    call fin_put_cstr
+    jmp .Lfloat_done
+.Lfloat_finite:
+    test rbx, rbx
+    jne .Lfloat_normal
+    test r12, r12
+    jne .Lfloat_subnormal
+    lea rdi, [rip + fin_zero_text]
+    call fin_put_cstr
+    jmp .Lfloat_done
+
+    # The float is mant * 2^exp, and the floats next to it are minus and plus away
+    # in the same units. Ties round to an even mantissa, so an even one owns the
+    # ends of its interval: r14 is 1 then.
+.Lfloat_subnormal:
+    add r12, r12
+    mov r13, -1075
+    mov r14d, 1
+    mov r15d, 1
+    jmp .Lfloat_decoded
+.Lfloat_normal:
+    mov r14d, r12d
+    not r14d
+    and r14d, 1
+    mov r13, rbx
+    sub r13, 1075
+    test r12, r12
+    jne .Lfloat_gap
+    # Powers of two have a closer float below them
+    movabs r12, 18014398509481984
+    sub r13, 2
+    mov r15d, 2
+    jmp .Lfloat_decoded
+.Lfloat_gap:
+    movabs rax, 4503599627370496
+    or r12, rax
+    add r12, r12
+    sub r13, 1
+    mov r15d, 1
+.Lfloat_decoded:
+    # Estimate the decimal exponent k from the highest bit of mant + plus,
+    # low by at most one
+    lea rax, [r12 + r15]
+    sub rax, 1
+    bsr rcx, rax
+    add rcx, 1
+    add rcx, r13
+    imul rcx, rcx, 1292913986
+    sar rcx, 32
+    mov rbx, rcx
+
+    lea rdi, [rip + fin_big_mant]
+    mov rsi, r12
+    call fin_big_set
+    lea rdi, [rip + fin_big_minus]
+    mov esi, 1
+    call fin_big_set
+    lea rdi, [rip + fin_big_plus]
+    mov rsi, r15
+    call fin_big_set
+    lea rdi, [rip + fin_big_scale]
+    mov esi, 1
+    call fin_big_set
+
+    # The float is mant / scale from here on
+    test r13, r13
+    jns .Lfloat_large
+    lea rdi, [rip + fin_big_scale]
+    mov rsi, r13
+    neg rsi
+    call fin_big_shl
+    jmp .Lfloat_scaled
+.Lfloat_large:
+    lea rdi, [rip + fin_big_mant]
+    mov rsi, r13
+    call fin_big_shl
+    lea rdi, [rip + fin_big_minus]
+    mov rsi, r13
+    call fin_big_shl
+    lea rdi, [rip + fin_big_plus]
+    mov rsi, r13
+    call fin_big_shl
+.Lfloat_scaled:
+    test rbx, rbx
+    js .Lfloat_small_k
+    lea rdi, [rip + fin_big_scale]
+    mov rsi, rbx
+    call fin_big_mul_pow10
+    jmp .Lfloat_fixup
+.Lfloat_small_k:
+    mov r12, rbx
+    neg r12
+    lea rdi, [rip + fin_big_mant]
+    mov rsi, r12
+    call fin_big_mul_pow10
+    lea rdi, [rip + fin_big_minus]
+    mov rsi, r12
+    call fin_big_mul_pow10
+    lea rdi, [rip + fin_big_plus]
+    mov rsi, r12
+    call fin_big_mul_pow10
+.Lfloat_fixup:
+    # Correct the estimate, so that scale < mant + plus <= scale * 10
+    call fin_big_up
+    test eax, eax
+    je .Lfloat_times_ten
+    add rbx, 1
+    jmp .Lfloat_scales
+.Lfloat_times_ten:
+    call fin_big_next_digit
+.Lfloat_scales:
+    mov r15, rbx
+    lea rdi, [rip + fin_big_scale2]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_copy
+    lea rdi, [rip + fin_big_scale2]
+    mov esi, 1
+    call fin_big_shl
+    lea rdi, [rip + fin_big_scale4]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_copy
+    lea rdi, [rip + fin_big_scale4]
+    mov esi, 2
+    call fin_big_shl
+    lea rdi, [rip + fin_big_scale8]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_copy
+    lea rdi, [rip + fin_big_scale8]
+    mov esi, 3
+    call fin_big_shl
+
+    # r15 is k and rbx counts the digits at rsp from here on
+    xor ebx, ebx
+.Lfloat_digit:
+    xor r12d, r12d
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale8]
+    call fin_big_cmp
+    test eax, eax
+    js .Lfloat_digit4
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale8]
+    call fin_big_sub
+    add r12d, 8
+.Lfloat_digit4:
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale4]
+    call fin_big_cmp
+    test eax, eax
+    js .Lfloat_digit2
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale4]
+    call fin_big_sub
+    add r12d, 4
+.Lfloat_digit2:
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale2]
+    call fin_big_cmp
+    test eax, eax
+    js .Lfloat_digit1
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale2]
+    call fin_big_sub
+    add r12d, 2
+.Lfloat_digit1:
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_cmp
+    test eax, eax
+    js .Lfloat_store
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_sub
+    add r12d, 1
+.Lfloat_store:
+    add r12d, 48
+    mov byte ptr [rsp + rbx], r12b
+    add rbx, 1
+
+    # Stop rounding down when mant < minus, or up when scale < mant + plus
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_minus]
+    call fin_big_cmp
+    cmp eax, r14d
+    setl r12b
+    movzx r12d, r12b
+    call fin_big_up
+    mov r13d, eax
+    mov eax, r12d
+    or eax, r13d
+    jne .Lfloat_round
+    call fin_big_next_digit
+    jmp .Lfloat_digit
+
+.Lfloat_round:
+    # Round up when only that is close enough, or when both are and the rest is
+    # at least half a digit
+    test r13d, r13d
+    je .Lfloat_write
+    test r12d, r12d
+    je .Lfloat_round_up
+    lea rdi, [rip + fin_big_mant]
+    mov esi, 1
+    call fin_big_shl
+    lea rdi, [rip + fin_big_mant]
+    lea rsi, [rip + fin_big_scale]
+    call fin_big_cmp
+    test eax, eax
+    js .Lfloat_write
+.Lfloat_round_up:
+    mov rcx, rbx
+.Lfloat_carry:
+    sub rcx, 1
+    js .Lfloat_all_nines
+    cmp byte ptr [rsp + rcx], 57
+    jne .Lfloat_increment
+    mov byte ptr [rsp + rcx], 48
+    jmp .Lfloat_carry
+.Lfloat_increment:
+    add byte ptr [rsp + rcx], 1
+    jmp .Lfloat_write
+.Lfloat_all_nines:
+    # 99 became 00, so it is 100 with a higher exponent
+    mov byte ptr [rsp], 49
+    mov byte ptr [rsp + rbx], 48
+    add rbx, 1
+    add r15, 1
+
+.Lfloat_write:
+    # The digits are followed by k - digits zeros, or have a point after k of them
+    test r15, r15
+    jg .Lfloat_whole
+    lea rdi, [rip + fin_zero_point_text]
+    call fin_put_cstr
+.Lfloat_leading_zero:
+    test r15, r15
+    je .Lfloat_all_digits
+    lea rdi, [rip + fin_zero_text]
+    call fin_put_cstr
+    add r15, 1
+    jmp .Lfloat_leading_zero
+.Lfloat_all_digits:
+    mov rdi, rsp
+    mov rsi, rbx
+    call fin_put_bytes
+    jmp .Lfloat_done
+.Lfloat_whole:
+    cmp r15, rbx
+    jge .Lfloat_trailing
+    mov rdi, rsp
+    mov rsi, r15
+    call fin_put_bytes
+    lea rdi, [rip + fin_point_text]
+    call fin_put_cstr
+    lea rdi, [rsp + r15]
+    mov rsi, rbx
+    sub rsi, r15
+    call fin_put_bytes
+    jmp .Lfloat_done
+.Lfloat_trailing:
+    mov rdi, rsp
+    mov rsi, rbx
+    call fin_put_bytes
+.Lfloat_trailing_zero:
+    cmp r15, rbx
+    je .Lfloat_done
+    lea rdi, [rip + fin_zero_text]
+    call fin_put_cstr
+    sub r15, 1
+    jmp .Lfloat_trailing_zero
+.Lfloat_done:
+    add rsp, 32
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    ret
+
+# Powers
+
+# rdi to the power of rsi by squaring, in the same order as the interpreter so that
+# they overflow alike. The power is in rax, and edx is 0 when it is a kok, 1 when it
+# overflows, 2 for a division by zero and 3 when it would be a murto.
+fin_pow_int:
+    test rsi, rsi
+    js .Lpow_negative
+    mov eax, 1
+    xor edx, edx
+    test rsi, rsi
+    je .Lpow_done
+.Lpow_even:
+    test rsi, 1
+    jne .Lpow_odd
+    imul rdi, rdi
+    jo .Lpow_overflow
+    shr rsi, 1
+    jmp .Lpow_even
+.Lpow_odd:
+    mov rax, rdi
+.Lpow_loop:
+    cmp rsi, 1
+    jbe .Lpow_done
+    shr rsi, 1
+    imul rdi, rdi
+    jo .Lpow_overflow
+    test rsi, 1
+    je .Lpow_loop
+    imul rax, rdi
+    jo .Lpow_overflow
+    jmp .Lpow_loop
+.Lpow_done:
+    xor edx, edx
+    ret
+.Lpow_overflow:
+    mov edx, 1
+    ret
+.Lpow_negative:
+    mov edx, 3
+    test rdi, rdi
+    jne .Lpow_murto
+    mov edx, 2
+.Lpow_murto:
+    ret
+
+# xmm0 to the power of the kok in rdi, with edx 2 for a division by zero
+fin_pow_float:
+    mov rax, rdi
+    test rax, rax
+    jns .Lpowf_magnitude
+    neg rax
+.Lpowf_magnitude:
+    movsd xmm1, xmm0
+    movabs rcx, 4607182418800017408
+    movq xmm0, rcx
+    test rax, rax
+    je .Lpowf_sign
+.Lpowf_even:
+    test rax, 1
+    jne .Lpowf_odd
+    mulsd xmm1, xmm1
+    shr rax, 1
+    jmp .Lpowf_even
+.Lpowf_odd:
+    movsd xmm0, xmm1
+.Lpowf_loop:
+    cmp rax, 1
+    jbe .Lpowf_sign
+    shr rax, 1
+    mulsd xmm1, xmm1
+    test rax, 1
+    je .Lpowf_loop
+    mulsd xmm0, xmm1
+    jmp .Lpowf_loop
+.Lpowf_sign:
+    xor edx, edx
+    test rdi, rdi
+    jns .Lpowf_done
+    xorpd xmm1, xmm1
+    ucomisd xmm0, xmm1
+    jp .Lpowf_invert
+    jne .Lpowf_invert
+    mov edx, 2
+    ret
+.Lpowf_invert:
+    movsd xmm1, xmm0
+    movq xmm0, rcx
+    divsd xmm0, xmm1
+.Lpowf_done:
+    ret
+
+# Errors
+
+# Prints the frame at rdi as a traceback shows it, after its location
+fin_put_frame:
+    push rbx
+    push r12
+    push r13
+    mov rbx, rdi
+    mov r12, qword ptr [rbx - 8]
+    mov rdi, qword ptr [r12]
+    call fin_put_cstr
+    cmp qword ptr [r12 + 8], 0
+    jl .Lframe_end
+    xor r13d, r13d
+.Lframe_arg:
+    cmp r13, qword ptr [r12 + 8]
+    jge .Lframe_close
+    mov rax, r13
+    shl rax, 4
+    mov rdi, qword ptr [r12 + rax + 16]
+    call fin_put_cstr
+    mov rax, r13
+    shl rax, 4
+    mov rcx, qword ptr [r12 + rax + 24]
+    mov rax, r13
+    shl rax, 3
+    neg rax
+    mov rdi, qword ptr [rbx + rax - 32]
+    cmp rcx, 1
+    je .Lframe_float
+    cmp rcx, 2
+    je .Lframe_bool
+    call fin_put_int
+    jmp .Lframe_next
+.Lframe_float:
+    movq xmm0, rdi
+    call fin_put_float
+    jmp .Lframe_next
+.Lframe_bool:
+    call fin_put_bool
+.Lframe_next:
+    add r13, 1
+    jmp .Lframe_arg
+.Lframe_close:
+    lea rdi, [rip + fin_close_text]
+    call fin_put_cstr
+.Lframe_end:
+    lea rdi, [rip + fin_newline_text]
+    call fin_put_cstr
+    pop r13
+    pop r12
+    pop rbx
+    ret
+
+# Ends the program with an error. rdi is where the error is, as the traceback shows it,
+# rsi has the name, message and arrows of the error, and rdx is the frame it happened in.
+fin_fail:
+    mov r12, rdi
+    mov r13, rsi
+    mov r14, rdx
+    and rsp, -16
+    call fin_flush
+    mov qword ptr [rip + fin_out_fd], 2
+    lea rdi, [rip + fin_traceback_text]
+    call fin_put_cstr
+
+    xor ebx, ebx
+    mov rax, r14
+    lea rcx, [rip + fin_frames]
+.Lfail_walk:
+    mov qword ptr [rcx + rbx*8], rax
+    add rbx, 1
+    cmp rax, qword ptr [rip + fin_program_frame]
+    je .Lfail_frame
+    mov rax, qword ptr [rax]
+    jmp .Lfail_walk
+
+    # Each frame is shown where it called the next one, and the last where it failed
+.Lfail_frame:
+    sub rbx, 1
+    lea rcx, [rip + fin_frames]
+    mov r15, qword ptr [rcx + rbx*8]
+    cmp qword ptr [r15 - 24], 0
+    je .Lfail_location
+    lea rdi, [rip + fin_elided_open_text]
+    call fin_put_cstr
+    mov rdi, qword ptr [r15 - 24]
+    call fin_put_int
+    lea rdi, [rip + fin_elided_close_text]
+    call fin_put_cstr
+.Lfail_location:
+    mov rdi, r12
+    test rbx, rbx
+    je .Lfail_line
+    lea rcx, [rip + fin_frames]
+    mov rax, qword ptr [rcx + rbx*8 - 8]
+    mov rdi, qword ptr [rax - 16]
+.Lfail_line:
+    call fin_put_cstr
+    mov rdi, r15
+    call fin_put_frame
+    test rbx, rbx
+    jne .Lfail_frame
+
+    mov rdi, r13
+    call fin_put_cstr
+    call fin_flush
+    mov edi, 1
+    mov eax, 231
+    syscall
@@ -0,0 +1,197 @@
+use fin::passes::{self, Optimizations, Pass, PASSES};
+use fin::{asmgen, cgen, rustgen, Engine};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "usage: finc build <file> [-o <output>] [--backend=c|asm|rust] \
+[--emit=c|asm|obj|rust|ir]\n       finc build <file> --emit=ir [-o <output>] [-O0|-O1|-O2] \
+[--inline-threshold=<n>] [--print-after=<pass>|all]";
+
+// The code programs are compiled to
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+    C,
+    Asm,
+    Rust,
+}
+
+// What to write instead of an executable
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    // The source of the backend
+    Source,
+    // A relocatable object of the assembly backend
+    Object,
+    // The text form of the IR, whatever the backend
+    Ir,
+}
+
+// The options of `finc build`
+struct Options {
+    file: String,
+    output: Option<PathBuf>,
+    backend: Backend,
+    emit: Option<Emit>,
+    // How much the IR is optimised
+    optimizations: Optimizations,
+    // The passes the IR is printed after, to stderr
+    print_after: Vec<Pass>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    if args.next()? != "build" {
+        return None;
+    }
+    let mut file = None;
+    let mut output = None;
+    let mut backend = Backend::C;
+    let mut emit = None;
+    let mut level = 0;
+    let mut inline_threshold = passes::INLINE_THRESHOLD;
+    let mut print_after = Vec::new();
+    // The backends compile the syntax tree, so only the IR output is optimised
+    let mut optimizing = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(args.next()?)),
+            "--backend=c" => backend = Backend::C,
+            "--backend=asm" => backend = Backend::Asm,
+            "--backend=rust" => backend = Backend::Rust,
+            "--emit=c" => (backend, emit) = (Backend::C, Some(Emit::Source)),
+            "--emit=asm" => (backend, emit) = (Backend::Asm, Some(Emit::Source)),
+            "--emit=obj" => (backend, emit) = (Backend::Asm, Some(Emit::Object)),
+            "--emit=rust" => (backend, emit) = (Backend::Rust, Some(Emit::Source)),
+            "--emit=ir" => emit = Some(Emit::Ir),
+            "-O0" => (level, optimizing) = (0, true),
+            "-O1" => (level, optimizing) = (1, true),
+            "-O2" => (level, optimizing) = (2, true),
+            _ if arg.starts_with("--inline-threshold=") => {
+                inline_threshold = arg["--inline-threshold=".len()..].parse().ok()?;
+                optimizing = true;
+            }
+            "--print-after=all" => (print_after, optimizing) = (PASSES.to_vec(), true),
+            _ if arg.starts_with("--print-after=") => {
+                print_after.push(Pass::from_name(&arg["--print-after=".len()..])?);
+                optimizing = true;
+            }
+            _ if arg.starts_with('-') || file.is_some() => return None,
+            _ => file = Some(arg),
+        }
+    }
+    if optimizing && emit != Some(Emit::Ir) {
+        return None;
+    }
+    Some(Options {
+        file: file?,
+        output,
+        backend,
+        emit,
+        optimizations: Optimizations::new(level).with_inline_threshold(inline_threshold),
+        print_after,
+    })
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    match fin::interpeter::with_stack(move || build(&options)) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("finc: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Compiles the file of the options. Errors in the program go to stderr, and `false` is
+/// returned if there were any.
+fn build(options: &Options) -> io::Result<bool> {
+    let text = fs::read_to_string(&options.file)?;
+    let engine = Engine::new();
+    let stem = Path::new(&options.file).with_extension("");
+    if options.emit == Some(Emit::Ir) {
+        let mut module = match engine.to_ir(&options.file, &text) {
+            Ok(module) => module,
+            Err(diagnostics) => {
+                eprintln!("{}", diagnostics);
+                return Ok(false);
+            }
+        };
+        passes::optimize(&mut module, &options.optimizations, |pass, module| {
+            if options.print_after.contains(&pass) {
+                eprintln!("; after {}\n{}", pass.name(), module);
+            }
+        });
+        let output = options
+            .output
+            .clone()
+            .unwrap_or_else(|| stem.with_extension("ir"));
+        fs::write(&output, module.to_string())?;
+        return Ok(true);
+    }
+
+    let source = match options.backend {
+        Backend::C => engine.to_c(&options.file, &text),
+        Backend::Asm => engine.to_asm(&options.file, &text),
+        Backend::Rust => engine.to_rust(&options.file, &text),
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(diagnostics) => {
+            eprintln!("{}", diagnostics);
+            return Ok(false);
+        }
+    };
+
+    let (extension, runtime) = match options.backend {
+        Backend::C => ("c", Some((cgen::RUNTIME_NAME, cgen::RUNTIME))),
+        Backend::Asm => ("s", Some((asmgen::RUNTIME_NAME, asmgen::RUNTIME))),
+        Backend::Rust => ("rs", None),
+    };
+    match options.emit {
+        // The runtime goes beside the source so it can be built as is. Rust programs
+        // dont need one.
+        Some(Emit::Source) => {
+            let output = options
+                .output
+                .clone()
+                .unwrap_or_else(|| stem.with_extension(extension));
+            fs::write(&output, source)?;
+            if let Some((runtime_name, runtime)) = runtime {
+                fs::write(output.with_file_name(runtime_name), runtime)?;
+            }
+        }
+        Some(Emit::Ir) => unreachable!("The IR is written before any source"),
+        Some(Emit::Object) => {
+            let output = options
+                .output
+                .clone()
+                .unwrap_or_else(|| stem.with_extension("o"));
+            fs::write(&output, asmgen::object(&source)?)?;
+            let runtime_object = asmgen::object(asmgen::RUNTIME)?;
+            fs::write(
+                output.with_file_name(asmgen::RUNTIME_OBJECT_NAME),
+                runtime_object,
+            )?;
+        }
+        None => {
+            let output = options.output.clone().unwrap_or(stem);
+            match options.backend {
+                Backend::C => cgen::build(&source, &output)?,
+                Backend::Asm => asmgen::build(&source, &output)?,
+                Backend::Rust => rustgen::build(&source, &output)?,
+            }
+        }
+    }
+    Ok(true)
+}
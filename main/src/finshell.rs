@@ -1,68 +1,99 @@
-use crate::context::Context;
-use crate::errors::ErrorType;
-use crate::interpeter::Interpeter;
-use crate::lexer::Lexer;
-use crate::number::{Number, NumberType};
-use crate::parser::{Node, Parser};
-use crate::symbols::SymbolMap;
-use crate::token::Token;
-use std::io::{self, BufRead, Write};
+use fin::{Engine, Value};
+use std::fs;
+use std::io::{self, Read};
 
-pub fn run(file_name: String, text: String) -> Result<Node, ErrorType> {
-    let mut main_symbol_map = SymbolMap::<NumberType>::new();
-    main_symbol_map.set(
-        "zero".to_string(),
-        NumberType::Integer(Number::new_no_pos(0)),
-    );
+/// Reads lines from the engine's input and runs them until the input ends.
+/// Results, errors and prompts go to the engine's output.
+pub fn shell_loop(engine: &mut Engine) -> io::Result<()> {
+    let output = engine.output();
+    writeln!(output.borrow_mut(), "Starting Shell")?;
 
-    // Get tokens
-    let mut lexer: Lexer = Lexer::new(file_name, text);
-    let tokens = match lexer.tokenize() {
-        Ok(t) => (t),
-        Err(e) => return Err(e),
-    };
+    loop {
+        write!(output.borrow_mut(), "<finshell>> ")?;
+        output.borrow_mut().flush()?;
+
+        // Programs read from the same input
+        let mut line = String::new();
+        if engine.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let text = line.trim_end_matches(&['\r', '\n'][..]);
 
-    // Get Abstract Syntax Tree
-    let mut parser = Parser::new(tokens);
-    let result = parser.parse();
+        if let Some(expression) = text.strip_prefix(":type ") {
+            match engine.type_of(expression) {
+                Ok(type_) => writeln!(output.borrow_mut(), "{}", type_)?,
+                Err(diagnostics) => writeln!(output.borrow_mut(), "{}", diagnostics)?,
+            };
+            continue;
+        }
 
-    let mut node: Option<Node> = None;
-    if let Ok(root) = result.clone() {
-        node = Some(root);
+        match engine.eval_file("<stdin>", text) {
+            Ok(Value::Nil) => (),
+            Ok(value) => writeln!(output.borrow_mut(), "{}", value)?,
+            Err(diagnostics) => writeln!(output.borrow_mut(), "{}", diagnostics)?,
+        };
     }
+}
 
-    // Interpet and Run
-    if let Some(root) = node {
-        let interpeter = Interpeter::new();
-        let mut context = Context::init("Program");
-        context.set_symbol_map(main_symbol_map);
-        let result = interpeter.visit(root, context);
-        match result {
-            Ok(num) => println!("{}", num),
-            Err(e) => return Err(e),
+/// Runs a program file. Errors go to stderr, and `false` is returned if there were any.
+pub fn run_file(engine: &mut Engine, path: &str) -> io::Result<bool> {
+    let text = fs::read_to_string(path)?;
+    match engine.eval_file(path, &text) {
+        Ok(_) => Ok(true),
+        Err(diagnostics) => {
+            eprintln!("{}", diagnostics);
+            Ok(false)
         }
     }
+}
 
-    result
+/// Prints the bytecode of a program file, or of stdin without a file
+pub fn disassemble(engine: &Engine, path: Option<&str>) -> io::Result<bool> {
+    let (name, text) = match path {
+        Some(path) => (path, fs::read_to_string(path)?),
+        None => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            ("<stdin>", text)
+        }
+    };
+    match engine.disassemble(name, &text) {
+        Ok(listing) => {
+            print!("{}", listing);
+            Ok(true)
+        }
+        Err(diagnostics) => {
+            eprintln!("{}", diagnostics);
+            Ok(false)
+        }
+    }
 }
 
-pub fn shell_loop() {
-    print!("<finshell>> ");
-    io::stdout().flush();
-    let stdin = io::stdin();
-    let mut lines = stdin.lock().lines().map(|line| line.unwrap());
-    while let Some(line) = lines.next() {
-        // Unclear Clippy message for while
-        match run("<stdin>".to_string(), line) {
-            Ok(out) => (/*println!("Ast: {}", out)*/),
-            Err(e) => match e {
-                ErrorType::DisallowedCharError(e) => println!("{}", e.as_string()),
-                ErrorType::SyntaxError(e) => println!("{}", e.as_string()),
-                ErrorType::RunTimeError(e) => println!("{}", e.as_string()),
-                ErrorType::DivisionByZeroError(e) => println!("{}", e.as_string()),
-            },
-        };
-        print!("<finshell>> ");
-        io::stdout().flush();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fin::OutputBuffer;
+
+    #[test]
+    fn test_shell_output() {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        engine.set_input(io::Cursor::new(
+            "muut x = 2\ntulosta(x, syöte(\"? \"))\nkolme\n:type x / 2\nx +\n",
+        ));
+
+        shell_loop(&mut engine).unwrap();
+        let expected = "Starting Shell\n\
+            <finshell>> 2\n\
+            <finshell>> ? 2 kolme\n\
+            <finshell>> kok\n\
+            <finshell>> Syntax Error: ";
+        assert!(
+            output.contents().starts_with(expected),
+            "{}",
+            output.contents()
+        );
+        assert!(output.contents().ends_with("\n<finshell>> "));
     }
 }
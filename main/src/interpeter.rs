@@ -1,289 +1,807 @@
-use crate::context::Context;
-use crate::errors::{ErrorType, RunTimeError};
-use crate::lexer::Lexer;
+use crate::context::{BoundArgs, Context, Frame};
+use crate::errors::{ErrorType, LimitError, RunTimeError};
+use crate::jit::Jit;
+use crate::limits::{Limit, Limits};
+use crate::native::Native;
 use crate::number::{
     Number,
-    NumberType::{self, Float, Integer},
+    NumberType::{BigInteger, Complex, Float, Integer},
 };
-use crate::parser::Node::{self, Binop, Unary, Value, VarAccessNode, VarAssignNode};
-use crate::parser::Parser;
-use crate::position::Position;
+use crate::operators;
+use crate::parser::{Node, MAX_NESTING};
+use crate::symbols::SymbolMap;
 use crate::token::{Token, TokenType};
+use crate::value::{Function, Value};
+use num::complex::Complex64;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::Instant;
 
-use std::ops;
-use std::{clone, collections, fmt};
+/// Where programs print to
+pub type Output = Rc<RefCell<dyn Write>>;
 
-pub struct Interpeter;
+/// Where programs read lines from
+pub type Input = Rc<RefCell<dyn BufRead>>;
+
+pub struct Interpeter {
+    // Set by 'palata' until the function call it returns from takes it
+    returning: Option<Value>,
+    // The call stack, from the program to the most recent call
+    frames: Vec<Frame>,
+    // The functions being called, most recent last
+    active: Vec<Function>,
+    // Arguments of a tail call the running function should continue with
+    tail_call: Option<Vec<Value>>,
+    // Where the running native function was called, for its errors
+    call_site: Option<(Token, Context)>,
+    output: Output,
+    // Stdin when `None`. Stdin is only locked while a line is read.
+    input: Option<Input>,
+    limits: Limits,
+    steps: u64,
+    started: Instant,
+    // How many nodes are being visited inside each other, across all calls
+    depth: usize,
+    // Compiles the functions called often, when it is on
+    jit: Option<Jit>,
+}
+
+/// How deep nodes may be visited inside each other, counting the nodes of every active call.
+/// Parsed programs nest at most `MAX_NESTING` deep, but calls stack them on top of each other.
+pub const MAX_VISIT_DEPTH: usize = 10 * MAX_NESTING;
+
+// Looking at the clock on every step would be slow
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+/// Stack size for threads that run programs.
+/// Every nested call takes a few kilobytes of Rust stack, more than the main thread has
+/// for the default call depth limit.
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Runs `f` on a new thread with `STACK_SIZE` bytes of stack and waits for it
+pub fn with_stack<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let handle = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(f)
+        .expect("Failed to start the interpreter thread");
+    match handle.join() {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
 
 impl Interpeter {
     pub fn new() -> Self {
-        Self {}
+        Self::with_limits(Limits::new())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            returning: None,
+            frames: Vec::new(),
+            active: Vec::new(),
+            tail_call: None,
+            call_site: None,
+            output: Rc::new(RefCell::new(io::stdout())),
+            input: None,
+            limits,
+            steps: 0,
+            started: Instant::now(),
+            depth: 0,
+            jit: None,
+        }
+    }
+
+    pub fn visit(&mut self, node: &Node, context: Context) -> Result<Value, ErrorType> {
+        self.step(node, &context)?;
+        if self.depth >= MAX_VISIT_DEPTH {
+            return Err(self.error(
+                node,
+                format!("Evaluation nested more than {} deep", MAX_VISIT_DEPTH),
+                context,
+            ));
+        }
+        self.depth += 1;
+        let result = self.visit_node(node, context);
+        self.depth -= 1;
+        result
+    }
+
+    fn visit_node(&mut self, node: &Node, context: Context) -> Result<Value, ErrorType> {
+        match node {
+            Node::Binop(..) => self.visit_binop_node(node, context),
+            Node::Value(val) => self.visit_value_node(val, context),
+            Node::Unary(op, child) => self.visit_unary_node(op, child, context),
+            Node::VarAssignNode(name, _type, node) => self.visit_varass_node(name, node, context),
+            Node::VarAccessNode(tok) => self.visit_varacc_node(tok, context),
+            Node::CallNode(name, args) => self.visit_call_node(name, args, context),
+            Node::ListNode(_, items) => self.visit_list_node(items, context),
+            Node::RecordNode(_, fields) => self.visit_record_node(fields, context),
+            Node::IndexNode(node, _, index) => self.visit_index_node(node, index, context),
+            Node::FieldNode(node, name) => self.visit_field_node(node, name, context),
+            Node::FuncDefNode(name, params, _, body) => {
+                self.visit_funcdef_node(name, params, body, context)
+            }
+            Node::IfNode(_, cases, else_case) => self.visit_if_node(cases, else_case, context),
+            Node::WhileNode(_, condition, body) => self.visit_while_node(condition, body, context),
+            Node::ReturnNode(_, node) => self.visit_return_node(node, context),
+            Node::StatementsNode(nodes) => self.visit_statements_node(nodes, context),
+        }
+    }
+
+    /// Runs a whole program. A 'palata' outside of functions ends the program with its value.
+    pub fn run(&mut self, node: &Node, context: Context) -> Result<Value, ErrorType> {
+        self.frames = vec![Frame::program(&context.display_name())];
+        self.steps = 0;
+        self.started = Instant::now();
+        self.depth = 0;
+        let result = self
+            .visit(node, context)
+            .map_err(|e| e.with_frames(&self.frames));
+        self.frames.clear();
+        match self.returning.take() {
+            Some(value) => result.map(|_| value),
+            None => result,
+        }
+    }
+
+    // Counts one step and checks the step and time budgets
+    fn step(&mut self, node: &Node, context: &Context) -> Result<(), ErrorType> {
+        self.steps += 1;
+        let limit_error = |limit, message| {
+            Err(ErrorType::LimitError(LimitError::new(
+                limit,
+                node.pos_start(),
+                node.pos_end(),
+                message,
+                context.clone(),
+            )))
+        };
+
+        if let Some(max_steps) = self.limits.max_steps() {
+            if self.steps > max_steps {
+                return limit_error(
+                    Limit::Steps,
+                    format!("Program took more than {} steps", max_steps),
+                );
+            }
+        }
+        if let Some(timeout) = self.limits.timeout() {
+            if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && self.started.elapsed() > timeout
+            {
+                return limit_error(
+                    Limit::Time,
+                    format!("Program ran for more than {:?}", timeout),
+                );
+            }
+        }
+        Ok(())
     }
 
-    pub fn visit(&self, node: Node, context: Context) -> Result<NumberType, ErrorType> {
-        match node.clone() {
-            Binop(left, op, right) => self.visit_binop_node(node, *left, op, *right, context),
-            Value(val) => self.visit_value_node(val, context),
-            Unary(op, child) => self.visit_unary_node(op, *child, context),
-            VarAssignNode(name, node) => self.visit_varass_node(name, *node, context),
-            VarAccessNode(tok) => self.visit_varacc_node(tok, context),
+    pub fn output(&self) -> Output {
+        self.output.clone()
+    }
+
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    pub fn set_input(&mut self, input: Input) {
+        self.input = Some(input);
+    }
+
+    /// Compiles functions to machine code once they are called `threshold` times, or never
+    /// with `None`. Limits on steps or time turn it off, as machine code doesnt count them.
+    pub fn set_jit_threshold(&mut self, threshold: Option<u64>) {
+        self.jit = threshold.map(Jit::new);
+    }
+
+    /// Whether a function runs as machine code when it is called
+    pub fn is_compiled(&self, function: &Function) -> bool {
+        match &self.jit {
+            Some(jit) => jit.is_compiled(function),
+            None => false,
+        }
+    }
+
+    /// Reads a line of program input, with the line ending
+    pub fn read_line(&self, line: &mut String) -> io::Result<usize> {
+        match &self.input {
+            Some(input) => input.borrow_mut().read_line(line),
+            None => io::stdin().read_line(line),
+        }
+    }
+
+    /// An error at the call of the running native function
+    pub fn native_error(&self, message: String) -> ErrorType {
+        let (pos_start, pos_end, context) = match &self.call_site {
+            Some((token, context)) => (
+                token.position_start(),
+                token.position_end(),
+                context.clone(),
+            ),
+            None => (None, None, Context::init("<native>")),
+        };
+        ErrorType::RunTimeError(RunTimeError::new(pos_start, pos_end, message, context))
+    }
+
+    fn error(&self, node: &Node, message: String, context: Context) -> ErrorType {
+        ErrorType::RunTimeError(RunTimeError::new(
+            node.pos_start(),
+            node.pos_end(),
+            message,
+            context,
+        ))
+    }
+
+    fn identifier_name(&self, token: &Token, context: &Context) -> Result<String, ErrorType> {
+        match token.type_() {
+            TokenType::Identifier(name) => Ok(name),
+            _ => Err(ErrorType::RunTimeError(RunTimeError::new(
+                token.position_start(),
+                token.position_end(),
+                "Invalid Variable name".to_string(),
+                context.clone(),
+            ))),
         }
     }
 
     fn visit_varacc_node(
-        &self,
-        name_tok: Token,
+        &mut self,
+        name_tok: &Token,
         context: Context,
-    ) -> Result<NumberType, ErrorType> {
-        let variable_name = match name_tok.type_() {
-            TokenType::Identifier(name) => name,
-            _ => {
+    ) -> Result<Value, ErrorType> {
+        let variable_name = self.identifier_name(name_tok, &context)?;
+
+        let symbol_map = match context.symbol_map() {
+            Some(symbols) => symbols,
+            None => {
                 return Err(ErrorType::RunTimeError(RunTimeError::new(
                     name_tok.position_start(),
                     name_tok.position_end(),
-                    "Invalid Variable name".to_string(),
+                    "No Symbol Table!".to_string(),
                     context,
                 )))
             }
         };
 
-        let symbol_map = match context.symbol_map() {
-            Some(symbols) => symbols,
-            None => {
+        let value = symbol_map.borrow().get(&variable_name);
+        match value {
+            None => Err(ErrorType::RunTimeError(RunTimeError::new(
+                name_tok.position_start(),
+                name_tok.position_end(),
+                format!("{} is not defined", variable_name,),
+                context,
+            ))),
+            Some(mut value) => {
+                value.set_pos(name_tok.position_start(), name_tok.position_end());
+                value.set_context(context);
+                Ok(value)
+            }
+        }
+    }
+
+    fn visit_call_node(
+        &mut self,
+        name_tok: &Token,
+        arg_nodes: &[Node],
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let name = self.identifier_name(name_tok, &context)?;
+
+        let mut args = Vec::with_capacity(arg_nodes.len());
+        for node in arg_nodes {
+            args.push(self.visit(node, context.clone())?);
+        }
+
+        let function = context
+            .symbol_map()
+            .and_then(|symbols| symbols.borrow().get(&name));
+        let mut result = match function {
+            Some(Value::Function(function)) => {
+                self.call_function(function, args, name_tok, context.clone())?
+            }
+            Some(Value::Native(native)) => self.call_native(&native, args, name_tok, &context)?,
+            Some(value) => {
                 return Err(ErrorType::RunTimeError(RunTimeError::new(
                     name_tok.position_start(),
                     name_tok.position_end(),
-                    "No Symbol Table!".to_string(),
+                    format!("{} is a {}, not a function", name, value.type_name()),
                     context,
                 )))
             }
-        };
-
-        match symbol_map.get(variable_name.clone()) {
             None => {
                 return Err(ErrorType::RunTimeError(RunTimeError::new(
                     name_tok.position_start(),
                     name_tok.position_end(),
-                    format!("{} is not defined", variable_name,),
+                    format!("{} is not defined", name),
                     context,
                 )))
             }
-            Some(value) => Ok(value.clone()),
+        };
+        result.set_pos(name_tok.position_start(), name_tok.position_end());
+        result.set_context(context);
+        Ok(result)
+    }
+
+    /// Calls a native function, with errors pointing at the call
+    pub fn call_native(
+        &mut self,
+        native: &Native,
+        args: Vec<Value>,
+        name_tok: &Token,
+        context: &Context,
+    ) -> Result<Value, ErrorType> {
+        if !native.arity().accepts(args.len()) {
+            let name = self.identifier_name(name_tok, context)?;
+            return Err(ErrorType::RunTimeError(RunTimeError::new(
+                name_tok.position_start(),
+                name_tok.position_end(),
+                format!(
+                    "{} expects {} arguments, got {}",
+                    name,
+                    native.arity(),
+                    args.len()
+                ),
+                context.clone(),
+            )));
         }
+        let caller = self.call_site.replace((name_tok.clone(), context.clone()));
+        let result = native.call(self, args);
+        self.call_site = caller;
+        result
     }
 
-    fn visit_varass_node(
+    fn call_function(
+        &mut self,
+        function: Function,
+        args: Vec<Value>,
+        name_tok: &Token,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let params = function.params();
+        if params.len() != args.len() {
+            return Err(ErrorType::RunTimeError(RunTimeError::new(
+                name_tok.position_start(),
+                name_tok.position_end(),
+                format!(
+                    "{} expects {} arguments, got {}",
+                    function.name(),
+                    params.len(),
+                    args.len()
+                ),
+                context,
+            )));
+        }
+
+        // The program itself is the first frame
+        if let Some(max_depth) = self.limits.max_depth() {
+            if self.frames.len() > max_depth {
+                return Err(ErrorType::LimitError(LimitError::new(
+                    Limit::Depth,
+                    name_tok.position_start(),
+                    name_tok.position_end(),
+                    format!("Calls nested more than {} deep", max_depth),
+                    context,
+                )));
+            }
+        }
+
+        let counted = self.limits.max_steps().is_some() || self.limits.timeout().is_some();
+        let calls_left = self
+            .limits
+            .max_depth()
+            .map(|max_depth| max_depth - self.frames.len());
+        let visits_left = MAX_VISIT_DEPTH - self.depth;
+        if let (Some(jit), false) = (&mut self.jit, counted) {
+            if let Some(result) = jit.call(&function, &args, calls_left, visits_left) {
+                return Ok(result);
+            }
+        }
+
+        let (symbol_map, bound_args) = self.bind_args(&function, args, &context)?;
+        self.frames.push(Frame::call(
+            &function.name(),
+            bound_args,
+            name_tok.position_start(),
+        ));
+        self.active.push(function.clone());
+
+        let mut symbol_map = symbol_map;
+        let parent = Rc::new(context.detached());
+        let result = loop {
+            let call_context = Context::new(
+                &function.name(),
+                Some(parent.clone()),
+                name_tok.position_start(),
+                Some(symbol_map.shared()),
+            );
+            let result = self.visit(&function.body(), call_context);
+            let args = match (result, self.tail_call.take()) {
+                (Ok(_), Some(args)) => args,
+                (result, _) => break result,
+            };
+
+            // A tail call to the function itself runs in this same frame
+            self.returning = None;
+            let (next_map, bound_args) = match self.bind_args(&function, args, &context) {
+                Ok(bound) => bound,
+                Err(e) => break Err(e),
+            };
+            symbol_map = next_map;
+            if let Some(frame) = self.frames.last_mut() {
+                frame.tail_call(bound_args);
+            }
+        }
+        .map_err(|e| e.with_frames(&self.frames));
+        self.active.pop();
+        self.frames.pop();
+        let returned = self.returning.take();
+        result?;
+        Ok(returned.unwrap_or(Value::Nil))
+    }
+
+    // A symbol map for one call with the arguments bound to the parameters,
+    // and the arguments as shown in tracebacks
+    fn bind_args(
         &self,
-        token: Token,
-        node: Node,
+        function: &Function,
+        args: Vec<Value>,
+        context: &Context,
+    ) -> Result<(SymbolMap<Value>, BoundArgs), ErrorType> {
+        let mut symbol_map = SymbolMap::with_parent(function.closure());
+        let mut bound_args = Vec::with_capacity(args.len());
+        for (param, arg) in function.params().iter().zip(args) {
+            let param = self.identifier_name(param, context)?;
+            bound_args.push((param.clone(), arg.representation()));
+            symbol_map.set(param, arg);
+        }
+        Ok((symbol_map, bound_args))
+    }
+
+    fn visit_varass_node(
+        &mut self,
+        token: &Token,
+        node: &Node,
         context: Context,
-    ) -> Result<NumberType, ErrorType> {
-        let variable_name = match token.type_() {
-            TokenType::Identifier(name) => name,
-            _ => {
+    ) -> Result<Value, ErrorType> {
+        let variable_name = self.identifier_name(token, &context)?;
+        let value = self.visit(node, context.clone())?;
+
+        let symbol_map = match context.symbol_map() {
+            Some(symbols) => symbols,
+            None => {
                 return Err(ErrorType::RunTimeError(RunTimeError::new(
                     token.position_start(),
                     token.position_end(),
-                    "Invalid Variable namee".to_string(),
+                    "No Symbol Table!".to_string(),
                     context,
                 )))
             }
         };
-        let value = match self.visit(node, context.clone()) {
-            Ok(val) => val,
-            Err(e) => return Err(e),
-        };
+        let _u = symbol_map.borrow_mut().set(variable_name, value.clone());
+        Ok(value)
+    }
 
-        let mut symbol_map = match context.symbol_map() {
+    fn visit_funcdef_node(
+        &mut self,
+        name_tok: &Token,
+        params: &[(Token, Option<Token>)],
+        body: &std::rc::Rc<Node>,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let name = self.identifier_name(name_tok, &context)?;
+        let symbol_map = match context.symbol_map() {
             Some(symbols) => symbols,
             None => {
                 return Err(ErrorType::RunTimeError(RunTimeError::new(
-                    token.position_start(),
-                    token.position_end(),
+                    name_tok.position_start(),
+                    name_tok.position_end(),
                     "No Symbol Table!".to_string(),
                     context,
                 )))
             }
         };
-        let _u = symbol_map.set(variable_name, value.clone());
-        return Ok(value);
+
+        let params = params.iter().map(|(param, _type)| param.clone()).collect();
+        let function = Function::new(name.clone(), params, body.clone(), symbol_map.clone());
+        symbol_map
+            .borrow_mut()
+            .set(name, Value::Function(function.stored()));
+        Ok(Value::Function(function))
     }
 
-    fn visit_binop_node(
-        &self,
-        node: Node,
-        left: Node,
-        optok: Token,
-        right: Node,
+    fn visit_list_node(&mut self, items: &[Node], context: Context) -> Result<Value, ErrorType> {
+        let mut values = Vec::with_capacity(items.len());
+        for item in items {
+            values.push(self.visit(item, context.clone())?);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn visit_record_node(
+        &mut self,
+        fields: &[(Token, Node)],
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let mut values = BTreeMap::new();
+        for (name, node) in fields {
+            let name = self.identifier_name(name, &context)?;
+            values.insert(name, self.visit(node, context.clone())?);
+        }
+        Ok(Value::Record(values))
+    }
+
+    fn visit_index_node(
+        &mut self,
+        node: &Node,
+        index_node: &Node,
         context: Context,
-    ) -> Result<NumberType, ErrorType> {
-        let result_left = self.visit(left, context.clone());
-        let result_right = self.visit(right, context.clone());
+    ) -> Result<Value, ErrorType> {
+        let collection = self.visit(node, context.clone())?;
+        let index = self.visit(index_node, context.clone())?;
+
+        operators::index(
+            &collection,
+            &index,
+            index_node.pos_start(),
+            index_node.pos_end(),
+            &context,
+        )
+    }
 
-        let left = match result_left {
-            Ok(num) => num,
-            Err(e) => return Err(e),
+    fn visit_field_node(
+        &mut self,
+        node: &Node,
+        name_tok: &Token,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let record = self.visit(node, context.clone())?;
+        let name = self.identifier_name(name_tok, &context)?;
+        operators::field(&record, name_tok, &name, &context)
+    }
+
+    fn condition(&mut self, node: &Node, context: Context) -> Result<bool, ErrorType> {
+        match self.visit(node, context.clone())? {
+            Value::Boolean(value) => Ok(value),
+            value => Err(self.error(node, condition_message(&value), context)),
+        }
+    }
+
+    fn visit_if_node(
+        &mut self,
+        cases: &[(Node, Node)],
+        else_case: &Option<Box<Node>>,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        for (condition, body) in cases {
+            if self.condition(condition, context.clone())? {
+                return self.visit(body, context);
+            }
+        }
+        match else_case {
+            Some(body) => self.visit(body, context),
+            None => Ok(Value::Nil),
+        }
+    }
+
+    fn visit_while_node(
+        &mut self,
+        condition: &Node,
+        body: &Node,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        while self.returning.is_none() && self.condition(condition, context.clone())? {
+            self.visit(body, context.clone())?;
+        }
+        Ok(Value::Nil)
+    }
+
+    fn visit_return_node(
+        &mut self,
+        node: &Option<Box<Node>>,
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        if let Some(Node::CallNode(name_tok, arg_nodes)) = node.as_deref() {
+            if self.is_self_call(name_tok, arg_nodes.len(), &context) {
+                let mut args = Vec::with_capacity(arg_nodes.len());
+                for node in arg_nodes {
+                    args.push(self.visit(node, context.clone())?);
+                }
+                // The running call takes the arguments and starts over
+                self.tail_call = Some(args);
+                self.returning = Some(Value::Nil);
+                return Ok(Value::Nil);
+            }
+        }
+
+        let value = match node {
+            Some(node) => self.visit(node, context)?,
+            None => Value::Nil,
         };
+        self.returning = Some(value.clone());
+        Ok(value)
+    }
 
-        let right = match result_right {
-            Ok(num) => num,
-            Err(e) => return Err(e),
+    // Whether a call is to the function that is running, with the right number of arguments
+    fn is_self_call(&self, name_tok: &Token, arg_count: usize, context: &Context) -> bool {
+        let current = match self.active.last() {
+            Some(function) => function,
+            None => return false,
+        };
+        let called = match (name_tok.type_(), context.symbol_map()) {
+            (TokenType::Identifier(name), Some(symbols)) => symbols.borrow().get(&name),
+            _ => None,
         };
+        match called {
+            Some(Value::Function(function)) => {
+                function == *current && function.params().len() == arg_count
+            }
+            _ => false,
+        }
+    }
 
-        match optok.type_() {
-            TokenType::Plus => match (left.clone(), right.clone()) {
-                (Integer(num1), Integer(num2)) => Ok(Integer(num1.add(num2))),
-                (Float(num1), Float(num2)) => Ok(Float(num1.add(num2))),
-                _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                    optok.position_start(),
-                    optok.position_end(),
-                    format!("Cant add {} with {} due to different types", left, right),
-                    context,
-                ))),
-            },
-            TokenType::Minus => match (left.clone(), right.clone()) {
-                (Integer(num1), Integer(num2)) => Ok(Integer(num1.sub(num2))),
-                (Float(num1), Float(num2)) => Ok(Float(num1.sub(num2))),
-                _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                    optok.position_start(),
-                    optok.position_end(),
-                    format!(
-                        "Cant subtract {} from {} due to different types",
-                        left, right
-                    ),
-                    context,
-                ))),
-            },
-            TokenType::Multiply => match (left.clone(), right.clone()) {
-                (Integer(num1), Integer(num2)) => Ok(Integer(num1.mult(num2))),
-                (Float(num1), Float(num2)) => Ok(Float(num1.mult(num2))),
-                _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                    optok.position_start(),
-                    optok.position_end(),
-                    format!(
-                        "Cant Multiply {} with {} due to different types",
-                        left, right
-                    ),
-                    context,
-                ))),
-            },
-            TokenType::Divide => match (left.clone(), right.clone()) {
-                (Integer(num1), Integer(num2)) => match num1.div(num2) {
-                    Ok(num) => Ok(Integer(num)),
-                    Err(e) => return Err(ErrorType::RunTimeError(e)),
-                },
-                (Float(num1), Float(num2)) => match num1.div(num2) {
-                    Ok(num) => Ok(Float(num)),
-                    Err(e) => return Err(ErrorType::RunTimeError(e)),
-                },
-                _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                    optok.position_start(),
-                    optok.position_end(),
-                    format!("Cant Divide {} with {} due to different types", left, right),
-                    context,
-                ))),
-            },
-
-            // TODO: Add fraction powers and whole powers to floats
-            TokenType::Pow => match (left.clone(), right.clone()) {
-                (Integer(num1), Integer(num2)) => match num1.pow(num2) {
-                    Ok(num) => Ok(Integer(num)),
-                    Err(e) => return Err(ErrorType::RunTimeError(e)),
-                },
-                (Float(num1), Integer(num2)) => match num1.pow(num2) {
-                    Ok(num) => Ok(Float(num)),
-                    Err(e) => return Err(ErrorType::RunTimeError(e)),
-                },
-                _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                    optok.position_start(),
-                    optok.position_end(),
-                    format!("Cant raise {} to {}", left, right),
-                    context,
-                ))),
-            },
-            _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                optok.position_start(),
-                optok.position_end(),
-                format!("Invalid operator token '{}'", optok),
-                context,
-            ))),
+    fn visit_statements_node(
+        &mut self,
+        nodes: &[Node],
+        context: Context,
+    ) -> Result<Value, ErrorType> {
+        let mut value = Value::Nil;
+        for node in nodes {
+            value = self.visit(node, context.clone())?;
+            if self.returning.is_some() {
+                break;
+            }
         }
+        Ok(value)
     }
 
-    fn check_if_same_numbertype(&self, first: NumberType, other: NumberType) -> bool {
-        matches!(
-            (first, other),
-            (Integer(_), Integer(_)) | (Float(_), Float(_))
-        )
+    // Chains like 1 + 2 - 3 are as long as the input, so they are evaluated in a loop
+    fn visit_binop_node(&mut self, node: &Node, context: Context) -> Result<Value, ErrorType> {
+        let operations = node.binop_chain();
+        let start = operations[0].0.pos_start();
+        let mut left = self.visit(operations[0].0, context.clone())?;
+        for (left_node, optok, right_node) in operations {
+            // 'ja' and 'tai' only look at the right side when they need to
+            if let TokenType::Keyword(keyword) = optok.type_() {
+                let value = match left {
+                    Value::Boolean(value) => value,
+                    value => {
+                        let message = condition_message(&value);
+                        return Err(self.error(left_node, message, context));
+                    }
+                };
+                left = match (keyword == "ja") != value {
+                    true => Value::Boolean(value),
+                    false => Value::Boolean(self.condition(right_node, context.clone())?),
+                };
+            } else {
+                let right = self.visit(right_node, context.clone())?;
+                let mut result = operators::binary(left, optok, right, &context)?;
+                result.set_pos(start.clone(), right_node.pos_end());
+                result.set_context(context.clone());
+                left = result;
+            }
+        }
+        Ok(left)
     }
 
-    fn visit_value_node(&self, token: Token, context: Context) -> Result<NumberType, ErrorType> {
-        match token.type_() {
-            TokenType::Int(val) => Ok(Integer(Number::<i64>::new(
+    fn visit_value_node(&self, token: &Token, context: Context) -> Result<Value, ErrorType> {
+        let number = match token.type_() {
+            TokenType::Int(val) => Integer(Number::<i64>::new(
                 val,
                 token.position_start(),
                 token.position_end(),
                 Some(context),
-            ))),
-            TokenType::Float(val) => Ok(Float(Number::<f64>::new(
+            )),
+            TokenType::Float(val) => Float(Number::<f64>::new(
                 val,
                 token.position_start(),
                 token.position_end(),
                 Some(context),
-            ))),
-            _ => Err(ErrorType::RunTimeError(RunTimeError::new(
+            )),
+            TokenType::Imaginary(val) => Complex(Number::new(
+                Complex64::new(0.0, val),
                 token.position_start(),
                 token.position_end(),
-                format!(
-                    "Non Value Token {:?} found inside visit value function",
-                    token.type_()
-                ),
-                context,
-            ))),
-        }
+                Some(context),
+            )),
+            TokenType::BigInt(val) => BigInteger(Number::new(
+                val,
+                token.position_start(),
+                token.position_end(),
+                Some(context),
+            )),
+            TokenType::String(text) => return Ok(Value::Text(text)),
+            TokenType::Keyword(keyword) if keyword == "tosi" => return Ok(Value::Boolean(true)),
+            TokenType::Keyword(keyword) if keyword == "epätosi" => {
+                return Ok(Value::Boolean(false))
+            }
+            TokenType::Keyword(keyword) if keyword == "tyhjä" => return Ok(Value::Nil),
+            _ => {
+                return Err(ErrorType::RunTimeError(RunTimeError::new(
+                    token.position_start(),
+                    token.position_end(),
+                    format!(
+                        "Non Value Token {:?} found inside visit value function",
+                        token.type_()
+                    ),
+                    context,
+                )))
+            }
+        };
+        Ok(Value::Number(number))
     }
 
     fn visit_unary_node(
-        &self,
-        optok: Token,
-        node: Node,
+        &mut self,
+        optok: &Token,
+        node: &Node,
         context: Context,
-    ) -> Result<NumberType, ErrorType> {
-        let result = self.visit(node, context.clone());
+    ) -> Result<Value, ErrorType> {
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            return Ok(Value::Boolean(!self.condition(node, context)?));
+        }
 
-        let number = match result {
-            Ok(num) => num,
-            Err(e) => return Err(e),
-        };
+        let value = self.visit(node, context.clone())?;
+        let mut result = operators::unary(optok, value, &context)?;
+        result.set_context(context);
+        Ok(result)
+    }
+}
 
-        match optok.type_() {
-            TokenType::Minus => match number {
-                Integer(num) => Ok(Integer(num.mult(Number::new_no_pos(-1)))),
-                Float(num) => Ok(Float(num.mult(Number::new_no_pos(-1.0)))),
-            },
-            TokenType::Plus => Ok(number),
-            _ => Err(ErrorType::RunTimeError(RunTimeError::new(
-                optok.position_start(),
-                optok.position_end(),
-                format!("Invalid operator token '{}'", optok),
-                context,
-            ))),
-        }
+fn condition_message(value: &Value) -> String {
+    format!("Condition must be totuus, found {}", value.type_name())
+}
+
+impl Default for Interpeter {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builtins;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::time::Duration;
+
+    fn run_str(text: &str) -> Result<Value, ErrorType> {
+        let mut lexer: Lexer = Lexer::new("finshell £".to_string(), text.to_string());
+        let tokens = lexer.tokenize()?;
+        let root = Parser::new(tokens).parse()?;
+        let mut symbol_map = SymbolMap::new();
+        builtins::prelude().install(&mut symbol_map);
+        let mut context = Context::init("Test Program");
+        context.set_symbol_map(symbol_map.shared());
+        Interpeter::new().run(&root, context)
+    }
+
+    fn assert_overflow(text: &str) {
+        match run_str(text) {
+            Err(ErrorType::RunTimeError(e)) => {
+                assert!(e.as_string().contains("kokonaisluvun ylivuoto"))
+            }
+            other => panic!("Expected overflow error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_interpeter() {
         let text = "4*(3-2)/(4-2)".to_string();
         let text_value = 2;
         let mut lexer: Lexer = Lexer::new("finshell £".to_string(), text);
-        let mut tokens: Vec<Token> = vec![];
-
-        match lexer.tokenize() {
-            Ok(t) => tokens = t,
+        let tokens: Vec<Token> = match lexer.tokenize() {
+            Ok(t) => t,
             Err(e) => panic!("{:?}", e),
         };
 
@@ -297,12 +815,492 @@ mod tests {
         }
 
         if let Some(root) = node {
-            let interpeter = Interpeter::new();
-            let result = interpeter.visit(root, Context::init("Test Program"));
-            let number = match result {
-                Ok(num) => println!("{}", num),
+            let mut interpeter = Interpeter::new();
+            let result = interpeter.visit(&root, Context::init("Test Program"));
+            match result {
+                Ok(num) => assert_eq!(num.to_string(), text_value.to_string()),
                 Err(e) => panic!("{:?}", e),
             };
         }
     }
+
+    #[test]
+    fn test_checked_overflow() {
+        assert_overflow("9223372036854775807 + 1");
+        assert_overflow("-9223372036854775807 - 2");
+        assert_overflow("4611686018427387904 * 2");
+        assert_overflow("2 ^ 63");
+        assert_overflow("-(-9223372036854775807 - 1)");
+        assert_eq!(
+            run_str("2 ^ 62").unwrap().to_string(),
+            "4611686018427387904"
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert!(matches!(
+            run_str("(1+1)/(2-2)"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrapping_and_saturating() {
+        let cases = [
+            (
+                "wrapping_add(9223372036854775807, 1)",
+                "-9223372036854775808",
+            ),
+            (
+                "wrapping_mul(4611686018427387904, 2)",
+                "-9223372036854775808",
+            ),
+            ("wrapping_pow(2, 64)", "0"),
+            (
+                "saturating_add(9223372036854775807, 1)",
+                "9223372036854775807",
+            ),
+            (
+                "saturating_sub(-9223372036854775807, 5)",
+                "-9223372036854775808",
+            ),
+            ("saturating_pow(-2, 65)", "-9223372036854775808"),
+            ("saturating_add(1, 2) * 2", "6"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(run_str("wrapping_add(1)").is_err());
+        assert!(run_str("wrapping_add(1.0, 2)").is_err());
+    }
+
+    #[test]
+    fn test_big_integer() {
+        let cases = [
+            ("9223372036854775807n + 1", "9223372036854775808"),
+            ("2n ^ 100", "1267650600228229401496703205376"),
+            ("(2 ^ 62) * 4n", "18446744073709551616"),
+            ("-100000000000000000000n / 3", "-33333333333333333333"),
+            (
+                "iso(9223372036854775807) * iso(9223372036854775807)",
+                "85070591730234615847396907784232501249",
+            ),
+            ("100000000000000000000n > 9223372036854775807", "tosi"),
+            ("5n == 5", "tosi"),
+            ("5n != 5", "epätosi"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(matches!(
+            run_str("1n / 0"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+        assert!(run_str("1n + 1.0").is_err());
+        match run_str("2n ^ 100000000") {
+            Err(ErrorType::RunTimeError(e)) => {
+                assert!(e.as_string().contains("Power is too large"))
+            }
+            other => panic!("Expected power error, got {:?}", other),
+        };
+        assert_eq!(run_str("1n ^ 100000000").unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn test_comparison() {
+        let cases = [
+            ("1 < 2", "tosi"),
+            ("2 <= 2", "tosi"),
+            ("3 > 4", "epätosi"),
+            ("1.5 >= 1.5", "tosi"),
+            ("1 + 1 == 2", "tosi"),
+            ("2 * 3 != 6", "epätosi"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(run_str("1 < 1.0").is_err());
+    }
+
+    #[test]
+    fn test_fraction() {
+        let cases = [
+            ("murto(1, 3)", "1/3"),
+            ("murto(2, 4)", "1/2"),
+            ("murto(1, 3) + murto(1, 6)", "1/2"),
+            ("murto(1, 3) * 3", "1"),
+            ("1 - murto(1, 3)", "2/3"),
+            ("murto(2, 3) / murto(4, 3)", "1/2"),
+            ("murto(1, 2) ^ 3", "1/8"),
+            ("-murto(3, -6)", "1/2"),
+            (
+                "murto(100000000000000000000n, 3) < 100000000000000000000n",
+                "tosi",
+            ),
+            ("murto(1, 3) == murto(2, 6)", "tosi"),
+            ("liu(murto(1, 4))", "0.25"),
+            ("liu(7)", "7"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(matches!(
+            run_str("murto(1, 0)"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+        assert!(matches!(
+            run_str("murto(1, 3) / murto(0, 1)"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+        assert!(run_str("murto(1, 3) + 0.5").is_err());
+        assert!(run_str("murto(0.5, 1)").is_err());
+    }
+
+    #[test]
+    fn test_complex() {
+        let cases = [
+            ("3i", "0+3i"),
+            ("1 + 2i", "1+2i"),
+            ("1 - 2i", "1-2i"),
+            ("(1 + 2i) * (3 - 1i)", "5+5i"),
+            ("(5 + 5i) / (3 - 1i)", "1+2i"),
+            ("1i ^ 2", "-1+0i"),
+            ("abs(3 + 4i)", "5"),
+            ("re(3 + 4i) + im(3 + 4i)", "7"),
+            ("conj(3 + 4i)", "3-4i"),
+            ("arg(1i) == liu(murto(1, 2)) * 3.141592653589793", "tosi"),
+            ("(1 + 2i) == (1 + 2i)", "tosi"),
+            ("(1 + 2i) != 1", "tosi"),
+            ("abs(-5)", "5"),
+            ("abs(murto(-1, 2))", "1/2"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(run_str("1i < 2i").is_err());
+        assert!(matches!(
+            run_str("1i / 0"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+        assert!(run_str("abs(-9223372036854775807 - 1)").is_err());
+    }
+
+    #[test]
+    fn test_power() {
+        let cases = [
+            ("2 ^ 10", "1024"),
+            ("2 ^ -1", "1/2"),
+            ("2 ^ -2 * 4", "1"),
+            ("2n ^ -3", "1/8"),
+            ("murto(2, 3) ^ -2", "9/4"),
+            ("2.0 ^ 0.5 == 1.4142135623730951", "tosi"),
+            ("4 ^ 0.5", "2"),
+            ("8 ^ murto(1, 3)", "2"),
+            ("4 ^ murto(4, 2)", "16"),
+            ("2.0 ^ -1", "0.5"),
+            ("-8.0 ^ 2", "-64"),
+            ("(-8.0) ^ 3.0", "-512"),
+            ("2 ^ 3 ^ 2", "512"),
+            ("2 ^ 62n", "4611686018427387904"),
+            ("1i ^ -1", "0-1i"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(matches!(
+            run_str("(-4) ^ 0.5"),
+            Err(ErrorType::RunTimeError(_))
+        ));
+        assert!(matches!(
+            run_str("0 ^ -1"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+        assert!(matches!(
+            run_str("0.0 ^ -0.5"),
+            Err(ErrorType::DivisionByZeroError(_))
+        ));
+    }
+
+    #[test]
+    fn test_variables_and_text() {
+        let cases = [
+            ("muut a = 2; muut b = a * 3; a + b", "8"),
+            ("muut a = 1\nmuut a = a + 1\na", "2"),
+            ("\"moi \" + \"maailma\"", "moi maailma"),
+            ("\"abc\" < \"abd\"", "tosi"),
+            ("\"abc\"[1]", "b"),
+            ("tosi ja ei epätosi", "tosi"),
+            ("epätosi tai 1 < 2", "tosi"),
+            ("tyhjä == tyhjä", "tosi"),
+            ("1 == \"1\"", "epätosi"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        // The right side is never looked at
+        assert!(run_str("epätosi ja 1").is_ok());
+        assert!(run_str("1 ja tosi").is_err());
+        assert!(run_str("\"a\" + 1").is_err());
+        assert!(run_str("x").is_err());
+        // Operators are named the way the type checker names them
+        let error = run_str("\"a\" * [1]").unwrap_err().as_string();
+        assert!(
+            error.contains("Cant use * with teksti and lista"),
+            "{}",
+            error
+        );
+        let error = run_str("-\"a\"").unwrap_err().as_string();
+        assert!(error.contains("Cant use - with teksti"), "{}", error);
+    }
+
+    #[test]
+    fn test_collections() {
+        let cases = [
+            ("[1, 2] + [3]", "[1, 2, 3]"),
+            ("[1, \"a\", [tosi]]", "[1, \"a\", [tosi]]"),
+            ("muut l = [4, 5, 6]; l[2]", "6"),
+            ("muut r = {x: 1, y: \"b\"}; r.x + 1", "2"),
+            ("{y: 2, x: 1}", "{x: 1, y: 2}"),
+            ("{x: 1}[\"x\"]", "1"),
+            ("[1, [2]] == [1, [2]]", "tosi"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(run_str("[1, 2][2]").is_err());
+        assert!(run_str("[1, 2][-1]").is_err());
+        assert!(run_str("{x: 1}.y").is_err());
+        assert!(run_str("1[0]").is_err());
+    }
+
+    #[test]
+    fn test_functions_and_control_flow() {
+        let cases = [
+            ("tominto f(x) { palata x * 2 }; f(21)", "42"),
+            (
+                "tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; fib(15)",
+                "610",
+            ),
+            ("muut a = 10; tominto lisää(b) { palata a + b }; lisää(5)", "15"),
+            ("tominto f() { muut sisä = 1 }; f()", "tyhjä"),
+            (
+                "muut i = 0; muut s = 0; kun i < 5 { muut s = s + i; muut i = i + 1 }; s",
+                "10",
+            ),
+            ("jos 1 > 2 { 1 } muuten jos 2 > 1 { 2 } muuten { 3 }", "2"),
+            ("jos epätosi { 1 }", "tyhjä"),
+            ("tominto f(n) { kun tosi { palata n } }; f(3)", "3"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        assert!(run_str("tominto f(x) { palata x }; f(1, 2)").is_err());
+        assert!(run_str("tominto f() { muut sisä = 1 }; f(); sisä").is_err());
+        assert!(run_str("jos 1 { 2 }").is_err());
+    }
+
+    #[test]
+    fn test_functions_dont_leak() {
+        let text =
+            "muut a = 2; tominto ulko() { tominto sisä() { palata a }; palata sisä() }; ulko()";
+        let tokens = Lexer::new("finshell £".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        let root = Parser::new(tokens).parse().unwrap();
+        let symbols = SymbolMap::new().shared();
+        let mut context = Context::init("Test Program");
+        context.set_symbol_map(symbols.clone());
+        let result = Interpeter::new().run(&root, context).unwrap();
+        assert_eq!(result.to_string(), "2");
+
+        let weak = std::rc::Rc::downgrade(&symbols);
+        drop(symbols);
+        drop(result);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_traceback() {
+        let text =
+            "tominto jaa(a, b) { palata a / b }\ntominto f(n) { palata jaa(n, n - 1) }\nf(1)";
+        let error = match run_str(text) {
+            Err(ErrorType::DivisionByZeroError(e)) => e.as_string(),
+            other => panic!("Expected division by zero, got {:?}", other),
+        };
+        let expected = "Traceback (most recent call last):\n  \
+            File finshell £, line 3, col 0, in Test Program\n  \
+            File finshell £, line 2, col 22, in f(n = 1)\n  \
+            File finshell £, line 1, col 31, in jaa(a = 1, b = 0)\n";
+        assert!(error.starts_with(expected), "{}", error);
+
+        let error = match run_str("tominto f(s) { palata s + 1 }; f(\"a\")") {
+            Err(ErrorType::RunTimeError(e)) => e.as_string(),
+            other => panic!("Expected runtime error, got {:?}", other),
+        };
+        assert!(error.contains("in f(s = \"a\")"), "{}", error);
+    }
+
+    #[test]
+    fn test_error_without_position() {
+        let num = Number::new_no_pos(1);
+        let error = match num.div(Number::new_no_pos(0)) {
+            Err(e) => e,
+            Ok(num) => panic!("Expected an error, got {:?}", num),
+        };
+        if let ErrorType::DivisionByZeroError(e) = error.with_frames(&[Frame::program("Test")]) {
+            assert!(e.as_string().contains("File <unknown>, in Test"));
+        }
+        let error = RunTimeError::new(None, None, "Oops".to_string(), Context::init("Test"));
+        assert!(error.as_string().contains("File <unknown>, in Test"));
+    }
+
+    fn run_limited(text: &str, limits: Limits) -> Result<Value, ErrorType> {
+        let mut lexer: Lexer = Lexer::new("finshell £".to_string(), text.to_string());
+        let tokens = lexer.tokenize()?;
+        let root = Parser::new(tokens).parse()?;
+        let mut symbol_map = SymbolMap::new();
+        builtins::prelude().install(&mut symbol_map);
+        let mut context = Context::init("Test Program");
+        context.set_symbol_map(symbol_map.shared());
+        Interpeter::with_limits(limits).run(&root, context)
+    }
+
+    fn limit_of(result: Result<Value, ErrorType>) -> Limit {
+        match result {
+            Err(ErrorType::LimitError(e)) => {
+                assert!(e.as_string().starts_with("Traceback"));
+                e.limit()
+            }
+            other => panic!("Expected a limit error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        // Deep recursion needs more stack than test threads have
+        with_stack(|| {
+            let forever = "kun tosi { 1 }";
+            let steps = Limits::new().with_max_steps(1000);
+            assert_eq!(limit_of(run_limited(forever, steps.clone())), Limit::Steps);
+            assert!(run_limited("1 + 2 * 3", steps).is_ok());
+
+            let time = Limits::new().with_timeout(Duration::from_millis(20));
+            assert_eq!(limit_of(run_limited(forever, time)), Limit::Time);
+
+            let recursion = "tominto f(n) { palata 1 + f(n + 1) }; f(0)";
+            let depth = Limits::new().with_max_depth(50);
+            let result = run_limited(recursion, depth.clone());
+            if let Err(ErrorType::LimitError(e)) = &result {
+                assert!(e.as_string().contains("in f(n = 49)"), "{}", e.as_string());
+            }
+            assert_eq!(limit_of(result), Limit::Depth);
+            assert!(run_limited(
+                "tominto f(n) { jos n > 0 { palata f(n - 1) } }; f(49)",
+                depth
+            )
+            .is_ok());
+            assert_eq!(limit_of(run_str(recursion)), Limit::Depth);
+        })
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        with_stack(|| {
+            let deep = |open: &str, close: &str, count| {
+                format!("{}1{}", open.repeat(count), close.repeat(count))
+            };
+            for text in [
+                deep("(", ")", 100_000),
+                deep("[", "]", 100_000),
+                deep("-", "", 100_000),
+                deep("ei ", "", 100_000),
+                deep("jos tosi { ", " }", 100_000),
+                deep("2 ^ ", "", 100_000),
+                format!("[1]{}", "[0]".repeat(100_000)),
+            ] {
+                assert!(matches!(run_str(&text), Err(ErrorType::SyntaxError(_))));
+            }
+            assert_eq!(run_str(&deep("(", ")", 900)).unwrap().to_string(), "1");
+            assert_eq!(run_str(&deep("-", "", 900)).unwrap().to_string(), "1");
+
+            // Chains of operations are not nested, however long they are
+            let chain = deep("1 + ", "", 100_000);
+            assert_eq!(run_str(&chain).unwrap().to_string(), "100001");
+            let chain = format!("muut a = tosi; a{}", " ja a".repeat(100_000));
+            assert_eq!(run_str(&chain).unwrap().to_string(), "tosi");
+
+            // Nested calls stack the nesting of their bodies
+            let body = deep("-", "", 900).replace('1', "f(n - 1)");
+            let text = format!("tominto f(n) {{ jos n > 0 {{ palata {} }} }}; f(20)", body);
+            match run_str(&text) {
+                Err(ErrorType::RunTimeError(e)) => assert!(e.as_string().contains("nested")),
+                other => panic!("Expected a runtime error, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let sum =
+            "tominto summa(n, acc) { jos n == 0 { palata acc }; palata summa(n - 1, acc + n) }";
+        let text = format!("{}; summa(100000, 0)", sum);
+        assert_eq!(run_str(&text).unwrap().to_string(), "5000050000");
+
+        // Tail calls from inside a loop, and calls that are not in tail position
+        let text = "tominto f(n) { kun tosi { jos n > 0 { palata f(n - 1) }; palata n } }; f(5000)";
+        assert_eq!(run_str(text).unwrap().to_string(), "0");
+        let text = "tominto f(n) { jos n > 0 { palata 1 + f(n - 1) }; palata 0 }; f(10)";
+        assert_eq!(run_str(text).unwrap().to_string(), "10");
+
+        let text = "tominto f(n) { jos n == 0 { palata 1 / n }; palata f(n - 1) }; f(3)";
+        let error = match run_str(text) {
+            Err(ErrorType::DivisionByZeroError(e)) => e.as_string(),
+            other => panic!("Expected division by zero, got {:?}", other),
+        };
+        assert!(
+            error.contains(
+                "  [3 tail calls elided]\n  File finshell £, line 1, col 39, in f(n = 0)"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_prelude() {
+        let cases = [
+            ("str(12) + \"3\"", "123"),
+            ("str([1, \"a\"])", "[1, \"a\"]"),
+            ("pituus(\"äiti\")", "4"),
+            ("pituus([1, 2, 3])", "3"),
+            ("pituus({a: 1})", "1"),
+            ("abs(-3)", "3"),
+            ("min(3, 1, 2)", "1"),
+            ("max([murto(1, 2), murto(2, 3)])", "2/3"),
+            ("max(\"a\", \"b\")", "b"),
+            ("sqrt(16)", "4"),
+            ("sqrt(-4)", "0+2i"),
+            ("tulosta(1, \"a\")", "tyhjä"),
+            ("muut f = pituus; f(\"ab\")", "2"),
+            ("pituus", "<tominto pituus>"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run_str(text).unwrap().to_string(), expected, "{}", text);
+        }
+        for text in [
+            "min()",
+            "str(1, 2)",
+            "pituus(1)",
+            "min(1, \"a\")",
+            "min(1i, 2i)",
+            "nope(1)",
+        ] {
+            assert!(
+                matches!(run_str(text), Err(ErrorType::RunTimeError(_))),
+                "{}",
+                text
+            );
+        }
+    }
 }
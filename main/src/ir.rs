@@ -0,0 +1,2778 @@
+use crate::ast::{collect_locals, identifier_name};
+use crate::builtins;
+use crate::bytecode::Span;
+use crate::context::{BoundArgs, Context, Frame};
+use crate::errors::{DivisionByZeroError, ErrorType, LimitError, RunTimeError, TypeError};
+use crate::limits::Limit;
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::types::Type;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// A typed three-address code between syntax trees and machine code. Functions are basic
+// blocks of instructions that end in a jump, a branch or a return, so the control flow
+// graph is explicit. Every register has one of the types kok, liu or totuus; texts only
+// appear as constants printed by `tulosta`.
+//
+// Lowering takes the programs the assembly backend takes, with the same rules: functions
+// are defined at the top of the program, annotate their parameters, and return nothing
+// unless they annotate a result. Variables of the program that functions use are globals,
+// and the others are registers like the variables of functions.
+//
+// Registers can be set more than once until the IR is put in SSA form, where phis choose
+// between the values that reach a block.
+
+/// A register of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(pub usize);
+
+/// A basic block of a function. The first block is where the function starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// Only printed
+    Text(String),
+}
+
+impl Const {
+    pub fn type_(&self) -> Type {
+        match self {
+            Const::Int(_) => Type::Integer,
+            Const::Float(_) => Type::Float,
+            Const::Bool(_) => Type::Boolean,
+            Const::Text(_) => Type::Text,
+        }
+    }
+
+    /// How the interpreter shows the value in tracebacks
+    pub fn representation(&self) -> String {
+        match self {
+            Const::Float(value) => value.to_string(),
+            value => value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Const(Const),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow
+        )
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Pow => "pow",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Le => "le",
+            BinaryOp::Ge => "ge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    /// `ei`
+    Not,
+}
+
+/// One instruction. Instructions that can fail keep where they came from for the error,
+/// and the calls they were inlined from for its traceback.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Copy {
+        dest: Reg,
+        value: Operand,
+    },
+    /// Arithmetic on kok fails when it overflows, and divisions and powers when they divide
+    /// by zero. Overflows are reported at `span` and divisions by zero at `right_span`,
+    /// except for powers, where both are at `span`. Negative powers of kok are murto
+    /// numbers and fail too.
+    Binary {
+        dest: Reg,
+        op: BinaryOp,
+        left: Operand,
+        right: Operand,
+        span: Span,
+        right_span: Span,
+        inlined: Vec<Inlined>,
+    },
+    /// Negating the smallest kok fails
+    Unary {
+        dest: Reg,
+        op: UnaryOp,
+        value: Operand,
+        span: Span,
+        inlined: Vec<Inlined>,
+    },
+    /// A kok as a liu
+    Convert {
+        dest: Reg,
+        value: Operand,
+    },
+    /// Calls a function of the module. The span is the call site in tracebacks.
+    /// A tail call is a function calling itself just to return the result, which like in
+    /// the interpreter reuses the frame of the caller.
+    Call {
+        dest: Option<Reg>,
+        function: usize,
+        args: Vec<Operand>,
+        span: Span,
+        inlined: Vec<Inlined>,
+        tail: bool,
+    },
+    Load {
+        dest: Reg,
+        global: usize,
+    },
+    Store {
+        global: usize,
+        value: Operand,
+    },
+    /// `tulosta`: the values separated by spaces, and a newline
+    Print {
+        values: Vec<Operand>,
+    },
+    /// Where the body of an inlined function starts. The last inlined call is the one the
+    /// body replaced, and like it, this fails when calls nest too deep.
+    Enter {
+        inlined: Vec<Inlined>,
+    },
+    /// The value from the block control came from. Phis are at the start of their block.
+    Phi {
+        dest: Reg,
+        incoming: Vec<(BlockId, Operand)>,
+    },
+}
+
+/// A call that was replaced by the body of its function. Instructions from the body keep
+/// the calls they came from, outermost first, so their errors have the same traceback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inlined {
+    pub function: usize,
+    pub args: Vec<Operand>,
+    /// The call site
+    pub span: Span,
+}
+
+impl Inst {
+    /// The register the instruction sets
+    pub fn dest(&self) -> Option<Reg> {
+        match self {
+            Inst::Copy { dest, .. }
+            | Inst::Binary { dest, .. }
+            | Inst::Unary { dest, .. }
+            | Inst::Convert { dest, .. }
+            | Inst::Load { dest, .. }
+            | Inst::Phi { dest, .. } => Some(*dest),
+            Inst::Call { dest, .. } => *dest,
+            Inst::Store { .. } | Inst::Print { .. } | Inst::Enter { .. } => None,
+        }
+    }
+
+    pub fn dest_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Inst::Copy { dest, .. }
+            | Inst::Binary { dest, .. }
+            | Inst::Unary { dest, .. }
+            | Inst::Convert { dest, .. }
+            | Inst::Load { dest, .. }
+            | Inst::Phi { dest, .. } => Some(dest),
+            Inst::Call { dest, .. } => dest.as_mut(),
+            Inst::Store { .. } | Inst::Print { .. } | Inst::Enter { .. } => None,
+        }
+    }
+
+    /// The values the instruction uses, then the arguments of the calls it was inlined
+    /// from
+    pub fn operands(&self) -> Vec<&Operand> {
+        let mut operands = match self {
+            Inst::Copy { value, .. }
+            | Inst::Unary { value, .. }
+            | Inst::Convert { value, .. }
+            | Inst::Store { value, .. } => vec![value],
+            Inst::Binary { left, right, .. } => vec![left, right],
+            Inst::Call { args, .. } => args.iter().collect(),
+            Inst::Print { values } => values.iter().collect(),
+            Inst::Phi { incoming, .. } => incoming.iter().map(|(_, value)| value).collect(),
+            Inst::Load { .. } | Inst::Enter { .. } => Vec::new(),
+        };
+        operands.extend(self.inlined().iter().flat_map(|call| &call.args));
+        operands
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy { value, .. } | Inst::Convert { value, .. } | Inst::Store { value, .. } => {
+                vec![value]
+            }
+            Inst::Unary { value, inlined, .. } => Some(value)
+                .into_iter()
+                .chain(inlined.iter_mut().flat_map(|call| &mut call.args))
+                .collect(),
+            Inst::Binary {
+                left,
+                right,
+                inlined,
+                ..
+            } => vec![left, right]
+                .into_iter()
+                .chain(inlined.iter_mut().flat_map(|call| &mut call.args))
+                .collect(),
+            Inst::Call { args, inlined, .. } => args
+                .iter_mut()
+                .chain(inlined.iter_mut().flat_map(|call| &mut call.args))
+                .collect(),
+            Inst::Print { values } => values.iter_mut().collect(),
+            Inst::Phi { incoming, .. } => incoming.iter_mut().map(|(_, value)| value).collect(),
+            Inst::Enter { inlined } => inlined.iter_mut().flat_map(|call| &mut call.args).collect(),
+            Inst::Load { .. } => Vec::new(),
+        }
+    }
+
+    /// The calls the instruction was inlined from, outermost first
+    pub fn inlined(&self) -> &[Inlined] {
+        match self {
+            Inst::Binary { inlined, .. }
+            | Inst::Unary { inlined, .. }
+            | Inst::Call { inlined, .. }
+            | Inst::Enter { inlined } => inlined,
+            _ => &[],
+        }
+    }
+
+    pub fn inlined_mut(&mut self) -> Option<&mut Vec<Inlined>> {
+        match self {
+            Inst::Binary { inlined, .. }
+            | Inst::Unary { inlined, .. }
+            | Inst::Call { inlined, .. }
+            | Inst::Enter { inlined } => Some(inlined),
+            _ => None,
+        }
+    }
+
+    /// Whether running the instruction can end the program with an error
+    pub fn can_fail(&self, function: &Function) -> bool {
+        match self {
+            Inst::Binary {
+                op, left, right, ..
+            } => match op {
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => {
+                    function.type_of(left) == Type::Integer
+                }
+                BinaryOp::Div => function.type_of(left) == Type::Integer || !is_nonzero(right),
+                BinaryOp::Pow => true,
+                _ => false,
+            },
+            Inst::Unary { op, value, .. } => {
+                *op == UnaryOp::Neg && function.type_of(value) == Type::Integer
+            }
+            Inst::Call { .. } | Inst::Enter { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the instruction does more than set its register, or can fail
+    pub fn has_effects(&self, function: &Function) -> bool {
+        match self {
+            Inst::Call { .. } | Inst::Store { .. } | Inst::Print { .. } => true,
+            inst => inst.can_fail(function),
+        }
+    }
+}
+
+// Whether an operand is a liu constant that is not zero
+fn is_nonzero(operand: &Operand) -> bool {
+    matches!(operand, Operand::Const(Const::Float(value)) if *value != 0.0)
+}
+
+/// How a block ends
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        condition: Operand,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Return(Option<Operand>),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![condition],
+            Terminator::Return(Some(value)) => vec![value],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![condition],
+            Terminator::Return(Some(value)) => vec![value],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+}
+
+/// The type of a register, and the variable it holds if it is one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegInfo {
+    pub type_: Type,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Reg>,
+    /// `None` when the function returns nothing
+    pub returns: Option<Type>,
+    pub regs: Vec<RegInfo>,
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub fn new(name: &str, returns: Option<Type>) -> Self {
+        Function {
+            name: name.to_string(),
+            params: Vec::new(),
+            returns,
+            regs: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn new_reg(&mut self, type_: Type, name: Option<String>) -> Reg {
+        self.regs.push(RegInfo { type_, name });
+        Reg(self.regs.len() - 1)
+    }
+
+    /// Adds a block that returns until it is given another terminator
+    pub fn new_block(&mut self) -> BlockId {
+        self.blocks.push(Block {
+            insts: Vec::new(),
+            terminator: Terminator::Return(None),
+        });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0]
+    }
+
+    pub fn type_of(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Reg(reg) => self.regs[reg.0].type_.clone(),
+            Operand::Const(value) => value.type_(),
+        }
+    }
+
+    /// The blocks each block can be reached from, in order
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                if !predecessors[successor.0].contains(&BlockId(i)) {
+                    predecessors[successor.0].push(BlockId(i));
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// The blocks that can be reached from the start, in reverse postorder
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::with_capacity(self.blocks.len());
+        // Blocks with the index of the next successor to visit
+        let mut stack = vec![(BlockId(0), 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            // The last successor is visited first so the first comes first in the order
+            let mut successors = self.block(block).terminator.successors();
+            successors.reverse();
+            match successors.get(next) {
+                Some(&successor) => {
+                    stack.push((block, next + 1));
+                    if !visited[successor.0] {
+                        visited[successor.0] = true;
+                        stack.push((successor, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Puts the blocks in reverse postorder, so blocks come before the blocks they jump
+    /// to outside of loops. Blocks that cant be reached are removed, and their values
+    /// from phis.
+    pub fn sort_blocks(&mut self) {
+        let order = self.reverse_postorder();
+        let mut renumbered = vec![None; self.blocks.len()];
+        for (new, old) in order.iter().enumerate() {
+            renumbered[old.0] = Some(BlockId(new));
+        }
+        let mut blocks: Vec<Option<Block>> = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(Some)
+            .collect();
+        for old in order {
+            let mut block = blocks[old.0].take().expect("Blocks are visited once");
+            for target in block.terminator.successors_mut() {
+                *target =
+                    renumbered[target.0].expect("Successors of reachable blocks are reachable");
+            }
+            for inst in &mut block.insts {
+                if let Inst::Phi { incoming, .. } = inst {
+                    incoming.retain(|(from, _)| renumbered[from.0].is_some());
+                    for (from, _) in incoming.iter_mut() {
+                        *from = renumbered[from.0].expect("Kept values come from kept blocks");
+                    }
+                }
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// Uses values instead of registers. Values can be registers that are replaced too.
+    pub fn replace_uses(&mut self, replacements: &HashMap<Reg, Operand>) {
+        if replacements.is_empty() {
+            return;
+        }
+        let replace = |operand: &mut Operand| {
+            while let Operand::Reg(reg) = operand {
+                match replacements.get(reg) {
+                    Some(replacement) => *operand = replacement.clone(),
+                    None => break,
+                }
+            }
+        };
+        for block in &mut self.blocks {
+            for inst in &mut block.insts {
+                inst.operands_mut().into_iter().for_each(replace);
+            }
+            block
+                .terminator
+                .operands_mut()
+                .into_iter()
+                .for_each(replace);
+        }
+    }
+
+    /// Removes the registers nothing sets or uses, numbering the rest in the order they
+    /// are set
+    pub fn remove_unused_regs(&mut self) {
+        let mut order = self.params.clone();
+        for block in &self.blocks {
+            order.extend(block.insts.iter().filter_map(Inst::dest));
+        }
+        for block in &self.blocks {
+            for inst in &block.insts {
+                for operand in inst.operands() {
+                    if let Operand::Reg(reg) = operand {
+                        order.push(*reg);
+                    }
+                }
+            }
+            for operand in block.terminator.operands() {
+                if let Operand::Reg(reg) = operand {
+                    order.push(*reg);
+                }
+            }
+        }
+        let mut renumbered = vec![None; self.regs.len()];
+        let mut regs = Vec::new();
+        for reg in order {
+            if renumbered[reg.0].is_none() {
+                renumbered[reg.0] = Some(Reg(regs.len()));
+                regs.push(self.regs[reg.0].clone());
+            }
+        }
+        self.regs = regs;
+        let renumber = |reg: &mut Reg| *reg = renumbered[reg.0].expect("Used registers are kept");
+        self.params.iter_mut().for_each(renumber);
+        for block in &mut self.blocks {
+            for inst in &mut block.insts {
+                if let Some(dest) = inst.dest_mut() {
+                    renumber(dest);
+                }
+                for operand in inst.operands_mut() {
+                    if let Operand::Reg(reg) = operand {
+                        renumber(reg);
+                    }
+                }
+            }
+            for operand in block.terminator.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    renumber(reg);
+                }
+            }
+        }
+    }
+
+    /// How many instructions the function has, terminators included
+    pub fn size(&self) -> usize {
+        self.blocks.iter().map(|block| block.insts.len() + 1).sum()
+    }
+
+    /// How registers are written: by the name of their variable, numbered if more than one
+    /// register holds it, and by their number among the other registers otherwise
+    pub fn reg_names(&self) -> Vec<String> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for info in &self.regs {
+            if let Some(name) = &info.name {
+                *counts.entry(name).or_default() += 1;
+            }
+        }
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        let mut temps = 0;
+        self.regs
+            .iter()
+            .map(|info| match &info.name {
+                Some(name) if counts[name.as_str()] == 1 => format!("%{}", name),
+                Some(name) => {
+                    let count = seen.entry(name).or_default();
+                    *count += 1;
+                    format!("%{}.{}", name, count)
+                }
+                None => {
+                    temps += 1;
+                    format!("%{}", temps - 1)
+                }
+            })
+            .collect()
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, module: &Module, header: &str) -> fmt::Result {
+        let names = self.reg_names();
+        let operand = |operand: &Operand| match operand {
+            Operand::Reg(reg) => names[reg.0].clone(),
+            Operand::Const(value) => value.to_string(),
+        };
+        let operands =
+            |operands: &[Operand]| operands.iter().map(&operand).collect::<Vec<_>>().join(", ");
+        writeln!(f, "{} {{", header)?;
+        let predecessors = self.predecessors();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let from = &predecessors[i];
+            match from.is_empty() {
+                true => writeln!(f, "b{}:", i)?,
+                false => {
+                    let from: Vec<String> = from.iter().map(|block| block.to_string()).collect();
+                    writeln!(f, "b{}:{:<10}; from {}", i, "", from.join(", "))?
+                }
+            }
+            for inst in &block.insts {
+                let calls: Vec<String> = inst
+                    .inlined()
+                    .iter()
+                    .map(|call| {
+                        format!(
+                            "{}({})",
+                            module.functions[call.function].name,
+                            operands(&call.args)
+                        )
+                    })
+                    .collect();
+                let dest = |reg: &Reg| format!("{}: {}", names[reg.0], self.regs[reg.0].type_);
+                let line = match inst {
+                    Inst::Copy { dest: to, value } => {
+                        format!("{} = copy {}", dest(to), operand(value))
+                    }
+                    Inst::Binary {
+                        dest: to,
+                        op,
+                        left,
+                        right,
+                        ..
+                    } => format!(
+                        "{} = {} {}, {}",
+                        dest(to),
+                        op.name(),
+                        operand(left),
+                        operand(right)
+                    ),
+                    Inst::Unary {
+                        dest: to,
+                        op,
+                        value,
+                        ..
+                    } => {
+                        let name = match op {
+                            UnaryOp::Neg => "neg",
+                            UnaryOp::Not => "not",
+                        };
+                        format!("{} = {} {}", dest(to), name, operand(value))
+                    }
+                    Inst::Convert { dest: to, value } => {
+                        format!("{} = liu {}", dest(to), operand(value))
+                    }
+                    Inst::Call {
+                        dest: to,
+                        function,
+                        args,
+                        tail,
+                        ..
+                    } => {
+                        let call = format!(
+                            "{}call {}({})",
+                            if *tail { "tail " } else { "" },
+                            module.functions[*function].name,
+                            operands(args)
+                        );
+                        match to {
+                            Some(to) => format!("{} = {}", dest(to), call),
+                            None => call,
+                        }
+                    }
+                    Inst::Load { dest: to, global } => {
+                        format!("{} = load @{}", dest(to), module.globals[*global].name)
+                    }
+                    Inst::Store { global, value } => {
+                        format!(
+                            "store @{}, {}",
+                            module.globals[*global].name,
+                            operand(value)
+                        )
+                    }
+                    Inst::Print { values } => {
+                        format!("print {}", operands(values)).trim_end().to_string()
+                    }
+                    Inst::Phi { dest: to, incoming } => {
+                        let incoming: Vec<String> = incoming
+                            .iter()
+                            .map(|(from, value)| format!("[{}: {}]", from, operand(value)))
+                            .collect();
+                        format!("{} = phi {}", dest(to), incoming.join(", "))
+                    }
+                    Inst::Enter { .. } => format!("enter {}", calls.join(" > ")),
+                };
+                match inst {
+                    Inst::Enter { .. } => writeln!(f, "    {}", line)?,
+                    _ if calls.is_empty() => writeln!(f, "    {}", line)?,
+                    _ => writeln!(f, "    {}{:<4}; in {}", line, "", calls.join(" > "))?,
+                }
+            }
+            let line = match &block.terminator {
+                Terminator::Jump(target) => format!("jmp {}", target),
+                Terminator::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => format!("br {}, {}, {}", operand(condition), then, otherwise),
+                Terminator::Return(None) => "ret".to_string(),
+                Terminator::Return(Some(value)) => format!("ret {}", operand(value)),
+            };
+            writeln!(f, "    {}", line)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub type_: Type,
+}
+
+/// The functions of a program, and the program itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub globals: Vec<Global>,
+    pub functions: Vec<Function>,
+    pub program: Function,
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl fmt::Display for Const {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Const::Int(value) => write!(f, "{}", value),
+            Const::Float(value) => write!(f, "{:?}", value),
+            Const::Bool(true) => write!(f, "tosi"),
+            Const::Bool(false) => write!(f, "epätosi"),
+            Const::Text(text) => write!(f, "{:?}", text),
+        }
+    }
+}
+
+/// The text form of the IR, the form `finc build --emit=ir` writes
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for global in &self.globals {
+            writeln!(f, "global @{}: {}", global.name, global.type_)?;
+        }
+        if !self.globals.is_empty() {
+            writeln!(f)?;
+        }
+        for function in &self.functions {
+            let names = function.reg_names();
+            let params: Vec<String> = function
+                .params
+                .iter()
+                .map(|param| format!("{}: {}", names[param.0], function.regs[param.0].type_))
+                .collect();
+            let returns = match &function.returns {
+                Some(type_) => format!(" -> {}", type_),
+                None => String::new(),
+            };
+            let header = format!("fn {}({}){}", function.name, params.join(", "), returns);
+            function.write(f, self, &header)?;
+            writeln!(f)?;
+        }
+        self.program.write(f, self, "program")
+    }
+}
+
+// Evaluating
+
+/// Why an instruction failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Overflow,
+    DivisionByZero,
+    /// A negative power of a kok
+    Murto,
+    /// A fractional power of a negative liu
+    NegativeBase,
+}
+
+impl Failure {
+    /// The name of the error, as the interpreter reports it
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::DivisionByZero => "DivisionByZero Error",
+            _ => "Runtime Error",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Failure::Overflow => "kokonaisluvun ylivuoto",
+            Failure::DivisionByZero => "Division by Zero",
+            Failure::Murto => "murto numbers are not supported in compiled programs",
+            Failure::NegativeBase => {
+                "Cant raise a negative number to a fractional power, use a complex base"
+            }
+        }
+    }
+
+    /// Where the error of a failed instruction is reported
+    pub fn span(self, inst: &Inst) -> Span {
+        match (self, inst) {
+            (
+                Failure::DivisionByZero,
+                Inst::Binary {
+                    op: BinaryOp::Div,
+                    right_span,
+                    ..
+                },
+            ) => right_span.clone(),
+            (_, Inst::Binary { span, .. }) | (_, Inst::Unary { span, .. }) => span.clone(),
+            _ => (None, None),
+        }
+    }
+
+    /// The error the interpreter raises for the failure at `span`, in the calls of `frames`
+    pub fn error(self, span: Span, frames: &[Frame]) -> ErrorType {
+        let (start, end) = span;
+        let message = self.message().to_string();
+        let error =
+            match self {
+                Failure::DivisionByZero => ErrorType::DivisionByZeroError(
+                    DivisionByZeroError::new(start, end, message, Context::init("Program")),
+                ),
+                _ => ErrorType::RunTimeError(RunTimeError::new(
+                    start,
+                    end,
+                    message,
+                    Context::init("Program"),
+                )),
+            };
+        error.with_frames(frames)
+    }
+}
+
+/// The error the interpreter raises for a call at `span` that would nest calls deeper
+/// than `limit`
+pub fn depth_error(limit: usize, span: Span, frames: &[Frame]) -> ErrorType {
+    let (start, end) = span;
+    ErrorType::LimitError(LimitError::new(
+        Limit::Depth,
+        start,
+        end,
+        format!("Calls nested more than {} deep", limit),
+        Context::init("Program"),
+    ))
+    .with_frames(frames)
+}
+
+/// The traceback frame of a call to a function of the module
+pub fn call_frame(module: &Module, function: usize, args: &[Const], span: &Span) -> Frame {
+    let callee = &module.functions[function];
+    Frame::call(&callee.name, bound_args(callee, args), span.0.clone())
+}
+
+/// The arguments of a call as tracebacks show them
+pub fn bound_args(function: &Function, args: &[Const]) -> BoundArgs {
+    function
+        .params
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| {
+            let name = function.regs[param.0].name.clone().unwrap_or_default();
+            (name, arg.representation())
+        })
+        .collect()
+}
+
+/// Computes an operation like the interpreter does. Values must have the types the
+/// operation takes.
+pub fn evaluate_binary(op: BinaryOp, left: &Const, right: &Const) -> Result<Const, Failure> {
+    use Const::{Bool, Float, Int};
+    let value = match (op, left, right) {
+        (BinaryOp::Add, Int(a), Int(b)) => Int(a.checked_add(*b).ok_or(Failure::Overflow)?),
+        (BinaryOp::Sub, Int(a), Int(b)) => Int(a.checked_sub(*b).ok_or(Failure::Overflow)?),
+        (BinaryOp::Mul, Int(a), Int(b)) => Int(a.checked_mul(*b).ok_or(Failure::Overflow)?),
+        (BinaryOp::Div, Int(_), Int(0)) => return Err(Failure::DivisionByZero),
+        (BinaryOp::Div, Int(a), Int(b)) => Int(a.checked_div(*b).ok_or(Failure::Overflow)?),
+        (BinaryOp::Pow, Int(a), Int(b)) => Int(power(*a, *b)?),
+        (BinaryOp::Add, Float(a), Float(b)) => Float(a + b),
+        (BinaryOp::Sub, Float(a), Float(b)) => Float(a - b),
+        (BinaryOp::Mul, Float(a), Float(b)) => Float(a * b),
+        (BinaryOp::Div, Float(_), Float(b)) if *b == 0.0 => return Err(Failure::DivisionByZero),
+        (BinaryOp::Div, Float(a), Float(b)) => Float(a / b),
+        (BinaryOp::Pow, Float(a), Int(b)) => Float(power_float(*a, *b)?),
+        (BinaryOp::Pow, Float(a), Float(b)) => {
+            if *a == 0.0 && *b < 0.0 {
+                return Err(Failure::DivisionByZero);
+            }
+            if *a < 0.0 && b.fract() != 0.0 {
+                return Err(Failure::NegativeBase);
+            }
+            Float(a.powf(*b))
+        }
+        (op, Int(a), Int(b)) => Bool(compare(op, a, b)),
+        (op, Float(a), Float(b)) => Bool(compare(op, a, b)),
+        (BinaryOp::Eq, Bool(a), Bool(b)) => Bool(a == b),
+        (BinaryOp::Ne, Bool(a), Bool(b)) => Bool(a != b),
+        (op, left, right) => panic!("Cant evaluate {:?} with {} and {}", op, left, right),
+    };
+    Ok(value)
+}
+
+pub fn evaluate_unary(op: UnaryOp, value: &Const) -> Result<Const, Failure> {
+    match (op, value) {
+        (UnaryOp::Neg, Const::Int(value)) => {
+            Ok(Const::Int(value.checked_neg().ok_or(Failure::Overflow)?))
+        }
+        (UnaryOp::Neg, Const::Float(value)) => Ok(Const::Float(-value)),
+        (UnaryOp::Not, Const::Bool(value)) => Ok(Const::Bool(!value)),
+        (op, value) => panic!("Cant evaluate {:?} with {}", op, value),
+    }
+}
+
+fn compare<T: PartialOrd>(op: BinaryOp, a: &T, b: &T) -> bool {
+    match op {
+        BinaryOp::Eq => a == b,
+        BinaryOp::Ne => a != b,
+        BinaryOp::Lt => a < b,
+        BinaryOp::Gt => a > b,
+        BinaryOp::Le => a <= b,
+        _ => a >= b,
+    }
+}
+
+// Powers by squaring, failing where the interpreter would need a murto number
+fn power(mut base: i64, exponent: i64) -> Result<i64, Failure> {
+    if exponent < 0 {
+        return match base {
+            0 => Err(Failure::DivisionByZero),
+            _ => Err(Failure::Murto),
+        };
+    }
+    let mut exponent = exponent as u64;
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(base).ok_or(Failure::Overflow)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base).ok_or(Failure::Overflow)?;
+        }
+    }
+    Ok(result)
+}
+
+// Multiplies in the order the interpreter does, so results round the same way
+fn power_float(mut base: f64, exponent: i64) -> Result<f64, Failure> {
+    let mut magnitude = exponent.unsigned_abs();
+    let mut result = 1.0;
+    if magnitude > 0 {
+        while magnitude & 1 == 0 {
+            base *= base;
+            magnitude >>= 1;
+        }
+        result = base;
+        while magnitude > 1 {
+            magnitude >>= 1;
+            base *= base;
+            if magnitude & 1 == 1 {
+                result *= base;
+            }
+        }
+    }
+    if exponent >= 0 {
+        return Ok(result);
+    }
+    if result == 0.0 {
+        return Err(Failure::DivisionByZero);
+    }
+    Ok(1.0 / result)
+}
+
+// Verifying
+
+/// Checks that the IR is well formed: jumps go to blocks of the function, registers and
+/// globals exist, the types of instructions fit together, phis are at the start of their
+/// block with a value from each block before it, and registers are set on every path to
+/// where they are used. The error names the function and block of the first problem.
+pub fn verify(module: &Module) -> Result<(), String> {
+    for function in module.functions.iter().chain(Some(&module.program)) {
+        Verifier { module, function }
+            .function()
+            .map_err(|e| format!("{}: {}", function.name, e))?;
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    module: &'a Module,
+    function: &'a Function,
+}
+
+impl Verifier<'_> {
+    fn function(&self) -> Result<(), String> {
+        let function = self.function;
+        if function.blocks.is_empty() {
+            return Err("the function has no blocks".to_string());
+        }
+        for param in &function.params {
+            self.reg(*param)?;
+        }
+        if function
+            .params
+            .iter()
+            .any(|param| !is_value_type(&function.regs[param.0].type_))
+        {
+            return Err("parameters must be kok, liu or totuus".to_string());
+        }
+        for (i, block) in function.blocks.iter().enumerate() {
+            for target in block.terminator.successors() {
+                self.target(target).map_err(|e| format!("b{}: {}", i, e))?;
+            }
+        }
+        let predecessors = function.predecessors();
+        for (i, block) in function.blocks.iter().enumerate() {
+            self.block(block, &predecessors[i])
+                .map_err(|e| format!("b{}: {}", i, e))?;
+        }
+        self.definitions()
+    }
+
+    fn reg(&self, reg: Reg) -> Result<&Type, String> {
+        match self.function.regs.get(reg.0) {
+            Some(info) => Ok(&info.type_),
+            None => Err(format!("%{} is not a register", reg.0)),
+        }
+    }
+
+    fn operand(&self, operand: &Operand) -> Result<Type, String> {
+        match operand {
+            Operand::Reg(reg) => self.reg(*reg).cloned(),
+            Operand::Const(value) => Ok(value.type_()),
+        }
+    }
+
+    fn target(&self, block: BlockId) -> Result<(), String> {
+        match block.0 < self.function.blocks.len() {
+            true => Ok(()),
+            false => Err(format!("{} is not a block", block)),
+        }
+    }
+
+    fn global(&self, global: usize) -> Result<&Type, String> {
+        match self.module.globals.get(global) {
+            Some(global) => Ok(&global.type_),
+            None => Err(format!("@{} is not a global", global)),
+        }
+    }
+
+    fn block(&self, block: &Block, predecessors: &[BlockId]) -> Result<(), String> {
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Inst::Call {
+                dest, tail: true, ..
+            } = inst
+            {
+                let returned = match &block.terminator {
+                    Terminator::Return(value) => Some(value.clone()),
+                    _ => None,
+                };
+                if i + 1 != block.insts.len() || returned != Some(dest.map(Operand::Reg)) {
+                    return Err("a tail call must be followed by returning its value".to_string());
+                }
+            }
+        }
+        let mut phis = true;
+        for inst in &block.insts {
+            match inst {
+                Inst::Phi { .. } if !phis => {
+                    return Err("phis must be at the start of the block".to_string())
+                }
+                Inst::Phi { .. } => (),
+                _ => phis = false,
+            }
+            self.inst(inst, predecessors)?;
+        }
+        match &block.terminator {
+            Terminator::Branch { condition, .. } => {
+                expect("the condition", &self.operand(condition)?, &Type::Boolean)
+            }
+            Terminator::Return(value) => {
+                let type_ = match value {
+                    Some(value) => Some(self.operand(value)?),
+                    None => None,
+                };
+                match (type_, &self.function.returns) {
+                    (None, None) => Ok(()),
+                    (Some(type_), Some(returns)) => expect("the returned value", &type_, returns),
+                    (None, Some(returns)) => Err(format!("the function must return {}", returns)),
+                    (Some(_), None) => Err("the function returns nothing".to_string()),
+                }
+            }
+            Terminator::Jump(_) => Ok(()),
+        }
+    }
+
+    fn inst(&self, inst: &Inst, predecessors: &[BlockId]) -> Result<(), String> {
+        let mut types = Vec::new();
+        for operand in inst.operands() {
+            let type_ = self.operand(operand)?;
+            if type_ == Type::Text && !matches!(inst, Inst::Print { .. }) {
+                return Err("teksti values can only be printed".to_string());
+            }
+            types.push(type_);
+        }
+        let dest = match inst.dest() {
+            Some(reg) => Some(self.reg(reg)?.clone()),
+            None => None,
+        };
+        if let Some(type_) = &dest {
+            if !is_value_type(type_) {
+                return Err("registers must be kok, liu or totuus".to_string());
+            }
+        }
+        let dest_type = dest.clone().unwrap_or(Type::Nil);
+        for call in inst.inlined() {
+            self.arguments(call.function, &call.args)?;
+        }
+
+        match inst {
+            Inst::Copy { .. } => expect("the copied value", &types[0], &dest_type),
+            Inst::Binary { op, .. } => {
+                let result = binary_type(*op, &types[0], &types[1]).ok_or_else(|| {
+                    format!("cant use {} with {} and {}", op.name(), types[0], types[1])
+                })?;
+                expect("the result", &result, &dest_type)
+            }
+            Inst::Unary { op, .. } => {
+                let fits = match op {
+                    UnaryOp::Neg => matches!(types[0], Type::Integer | Type::Float),
+                    UnaryOp::Not => types[0] == Type::Boolean,
+                };
+                match fits {
+                    true => expect("the result", &types[0], &dest_type),
+                    false => Err(format!("cant use {:?} with {}", op, types[0])),
+                }
+            }
+            Inst::Convert { .. } => {
+                expect("the converted value", &types[0], &Type::Integer)?;
+                expect("the result", &Type::Float, &dest_type)
+            }
+            Inst::Call {
+                function,
+                args,
+                tail,
+                ..
+            } => {
+                let callee = self.arguments(*function, args)?;
+                if *tail && !std::ptr::eq(callee, self.function) {
+                    return Err(format!(
+                        "{} cant tail call {}",
+                        self.function.name, callee.name
+                    ));
+                }
+                match (&dest, &callee.returns) {
+                    (Some(type_), Some(returns)) => expect("the result", returns, type_),
+                    (Some(_), None) => Err(format!("{} returns nothing", callee.name)),
+                    (None, _) => Ok(()),
+                }
+            }
+            Inst::Load { global, .. } => expect("the global", self.global(*global)?, &dest_type),
+            Inst::Store { global, .. } => {
+                expect("the stored value", &types[0], self.global(*global)?)
+            }
+            Inst::Print { .. } => Ok(()),
+            Inst::Enter { inlined } => match inlined.is_empty() {
+                true => Err("enter needs the call it replaced".to_string()),
+                false => Ok(()),
+            },
+            Inst::Phi { incoming, .. } => {
+                let mut from: Vec<BlockId> = incoming.iter().map(|(block, _)| *block).collect();
+                from.sort_unstable();
+                let mut expected = predecessors.to_vec();
+                expected.sort_unstable();
+                if from != expected {
+                    return Err("phis need one value from each block before theirs".to_string());
+                }
+                for type_ in &types {
+                    expect("the value", type_, &dest_type)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // The function a call calls, if the arguments fit its parameters
+    fn arguments(&self, function: usize, args: &[Operand]) -> Result<&Function, String> {
+        let callee = match self.module.functions.get(function) {
+            Some(callee) => callee,
+            None => return Err(format!("function {} does not exist", function)),
+        };
+        if args.len() != callee.params.len() {
+            return Err(format!(
+                "{} takes {} arguments, not {}",
+                callee.name,
+                callee.params.len(),
+                args.len()
+            ));
+        }
+        for (arg, param) in args.iter().zip(&callee.params) {
+            expect(
+                "the argument",
+                &self.operand(arg)?,
+                &callee.regs[param.0].type_,
+            )?;
+        }
+        Ok(callee)
+    }
+
+    // Registers must be set on every path to their uses. Values of phis must be set at
+    // the end of the block they come from.
+    fn definitions(&self) -> Result<(), String> {
+        let function = self.function;
+        let set = set_registers(function);
+        for block in function.reverse_postorder() {
+            let mut defined = set[block.0].clone().expect("Reachable blocks have sets");
+            let check = |defined: &[bool], operand: &Operand| match operand {
+                Operand::Reg(reg) if !defined[reg.0] => Err(format!(
+                    "{}: {} can be used before it is set",
+                    block,
+                    function.reg_names()[reg.0]
+                )),
+                _ => Ok(()),
+            };
+            for inst in &function.block(block).insts {
+                if let Inst::Phi { incoming, .. } = inst {
+                    for (from, value) in incoming {
+                        let mut at_end = match &set[from.0] {
+                            Some(defined) => defined.clone(),
+                            None => continue,
+                        };
+                        for inst in &function.block(*from).insts {
+                            if let Some(dest) = inst.dest() {
+                                at_end[dest.0] = true;
+                            }
+                        }
+                        check(&at_end, value)?;
+                    }
+                } else {
+                    for operand in inst.operands() {
+                        check(&defined, operand)?;
+                    }
+                }
+                if let Some(dest) = inst.dest() {
+                    defined[dest.0] = true;
+                }
+            }
+            for operand in function.block(block).terminator.operands() {
+                check(&defined, operand)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The registers set on every path to the start of each block, or `None` for blocks that
+// cant be reached
+fn set_registers(function: &Function) -> Vec<Option<Vec<bool>>> {
+    let order = function.reverse_postorder();
+    let predecessors = function.predecessors();
+    let mut at_start: Vec<Option<Vec<bool>>> = vec![None; function.blocks.len()];
+    let mut at_end: Vec<Option<Vec<bool>>> = vec![None; function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order {
+            let mut defined = match block.0 {
+                0 => {
+                    let mut defined = vec![false; function.regs.len()];
+                    for param in &function.params {
+                        defined[param.0] = true;
+                    }
+                    defined
+                }
+                _ => {
+                    let mut defined: Option<Vec<bool>> = None;
+                    for from in &predecessors[block.0] {
+                        if let Some(end) = &at_end[from.0] {
+                            defined = Some(match defined {
+                                Some(defined) => {
+                                    defined.iter().zip(end).map(|(a, b)| *a && *b).collect()
+                                }
+                                None => end.clone(),
+                            });
+                        }
+                    }
+                    defined.unwrap_or_else(|| vec![false; function.regs.len()])
+                }
+            };
+            if at_start[block.0].as_ref() != Some(&defined) {
+                at_start[block.0] = Some(defined.clone());
+                changed = true;
+            }
+            for inst in &function.block(block).insts {
+                if let Some(dest) = inst.dest() {
+                    defined[dest.0] = true;
+                }
+            }
+            at_end[block.0] = Some(defined);
+        }
+    }
+    at_start
+}
+
+fn is_value_type(type_: &Type) -> bool {
+    matches!(type_, Type::Integer | Type::Float | Type::Boolean)
+}
+
+/// The type of the result of an operation, or `None` if it cant take the values
+pub fn binary_type(op: BinaryOp, left: &Type, right: &Type) -> Option<Type> {
+    match (op, left, right) {
+        (BinaryOp::Pow, Type::Integer, Type::Integer) => Some(Type::Integer),
+        (BinaryOp::Pow, Type::Float, Type::Integer | Type::Float) => Some(Type::Float),
+        (BinaryOp::Pow, ..) => None,
+        (BinaryOp::Eq | BinaryOp::Ne, _, _) if left == right && is_value_type(left) => {
+            Some(Type::Boolean)
+        }
+        (_, Type::Integer, Type::Integer) | (_, Type::Float, Type::Float) => {
+            match op.is_comparison() {
+                true => Some(Type::Boolean),
+                false => Some(left.clone()),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn expect(what: &str, found: &Type, expected: &Type) -> Result<(), String> {
+    match found == expected {
+        true => Ok(()),
+        false => Err(format!("{} is {}, not {}", what, found, expected)),
+    }
+}
+
+// Lowering
+
+/// Lowers a program to IR. Programs can only use kok, liu and totuus values, and texts as
+/// arguments of `tulosta`.
+pub fn lower(node: &Node) -> Result<Module, ErrorType> {
+    let statements = match node {
+        Node::StatementsNode(nodes) => nodes.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    let mut lowering = Lowering::default();
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, params, returns, body) = statement {
+            lowering.declare(name_tok, params, returns)?;
+            lowering.shared_names(params, body)?;
+        }
+    }
+    let program = lowering.program(statements)?;
+    let mut functions = Vec::new();
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, _, _, body) = statement {
+            functions.push(lowering.function(name_tok, body)?);
+        }
+    }
+
+    let module = Module {
+        globals: lowering.globals,
+        functions,
+        program,
+    };
+    if let Err(e) = verify(&module) {
+        panic!("Lowering made invalid IR: {}\n{}", e, module);
+    }
+    Ok(module)
+}
+
+// A function of the program, known before any is lowered
+struct Signature {
+    name: String,
+    params: Vec<(String, Type)>,
+    returns: Option<Type>,
+    // Whether the program has defined it by the code being lowered
+    defined: bool,
+}
+
+// The function being lowered
+struct Body {
+    function: Function,
+    index: Option<usize>,
+    // The block code is added to
+    current: BlockId,
+    // Local variables: parameters and the variables the function sets, with their
+    // registers once they are set
+    locals: Vec<(String, Option<Reg>)>,
+}
+
+#[derive(Default)]
+struct Lowering {
+    signatures: Vec<Signature>,
+    function_ids: HashMap<String, usize>,
+    globals: Vec<Global>,
+    global_ids: HashMap<String, usize>,
+    // Names functions use without setting them, which are globals in the program
+    shared: HashSet<String>,
+    body: Option<Body>,
+}
+
+impl Lowering {
+    fn body(&mut self) -> &mut Body {
+        self.body.as_mut().expect("Code is lowered inside a body")
+    }
+
+    fn current(&self) -> &Body {
+        self.body.as_ref().expect("Code is lowered inside a body")
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        let body = self.body();
+        let current = body.current;
+        body.function.block_mut(current).insts.push(inst);
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        let body = self.body();
+        let current = body.current;
+        body.function.block_mut(current).terminator = terminator;
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.body().function.new_block()
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.body().current = block;
+    }
+
+    fn temp(&mut self, type_: Type) -> Reg {
+        self.body().function.new_reg(type_, None)
+    }
+
+    fn type_of(&self, operand: &Operand) -> Type {
+        self.current().function.type_of(operand)
+    }
+
+    // Branches on a condition, or jumps if it is known
+    fn branch(&mut self, condition: Operand, then: BlockId, otherwise: BlockId) {
+        let terminator = match condition {
+            Operand::Const(Const::Bool(true)) => Terminator::Jump(then),
+            Operand::Const(Const::Bool(false)) => Terminator::Jump(otherwise),
+            condition => Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            },
+        };
+        self.terminate(terminator);
+    }
+
+    // Variables
+
+    fn local(&self, name: &str) -> Option<&(String, Option<Reg>)> {
+        self.current()
+            .locals
+            .iter()
+            .find(|(local, _)| local == name)
+    }
+
+    fn read(&mut self, token: &Token) -> Result<Operand, ErrorType> {
+        let name = identifier_name(token)?;
+        if let Some((_, reg)) = self.local(&name) {
+            return match reg {
+                Some(reg) => Ok(Operand::Reg(*reg)),
+                None => Err(not_defined(token, &name)),
+            };
+        }
+        if let Some(global) = self.global_ids.get(&name).copied() {
+            let dest = self.temp(self.globals[global].type_.clone());
+            self.emit(Inst::Load { dest, global });
+            return Ok(Operand::Reg(dest));
+        }
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(token, "functions as values"));
+        }
+        Err(not_defined(token, &name))
+    }
+
+    fn assign(
+        &mut self,
+        token: &Token,
+        annotation: &Option<Token>,
+        value: &Node,
+    ) -> Result<Operand, ErrorType> {
+        let name = identifier_name(token)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(token, "variables named like functions"));
+        }
+        let value = self.expression(value)?;
+        let type_ = self.type_of(&value);
+        if let Some(annotation) = annotation {
+            let expected = annotated_type(annotation)?;
+            if expected != type_ {
+                return Err(type_error(
+                    token,
+                    format!("{} is {}, not {}", name, expected, type_),
+                ));
+            }
+        }
+
+        // A register has one type, so the first value decides
+        let known = match self.local(&name) {
+            Some((_, Some(reg))) => Some(Operand::Reg(*reg)),
+            Some((_, None)) => None,
+            None => {
+                // Variables of the program that functions use are globals
+                if self.current().index.is_none()
+                    && self.shared.contains(&name)
+                    && !self.global_ids.contains_key(&name)
+                {
+                    self.globals.push(Global {
+                        name: name.clone(),
+                        type_: type_.clone(),
+                    });
+                    self.global_ids.insert(name.clone(), self.globals.len() - 1);
+                }
+                if let Some(global) = self.global_ids.get(&name).copied() {
+                    check_variable(token, &name, &self.globals[global].type_, &type_)?;
+                    self.emit(Inst::Store {
+                        global,
+                        value: value.clone(),
+                    });
+                    return Ok(value);
+                }
+                None
+            }
+        };
+        let reg = match known {
+            Some(Operand::Reg(reg)) => {
+                let known = self.current().function.regs[reg.0].type_.clone();
+                check_variable(token, &name, &known, &type_)?;
+                reg
+            }
+            _ => {
+                let reg = self.body().function.new_reg(type_, Some(name.clone()));
+                let body = self.body();
+                match body.locals.iter_mut().find(|(local, _)| *local == name) {
+                    Some((_, slot)) => *slot = Some(reg),
+                    None => body.locals.push((name, Some(reg))),
+                }
+                reg
+            }
+        };
+        self.emit(Inst::Copy {
+            dest: reg,
+            value: value.clone(),
+        });
+        Ok(Operand::Reg(reg))
+    }
+
+    // Expressions
+
+    // Lowers an expression to the operand that holds its value
+    fn expression(&mut self, node: &Node) -> Result<Operand, ErrorType> {
+        match node {
+            Node::Value(token) => match value(token)? {
+                Const::Text(_) => Err(unsupported(token, "teksti values")),
+                value => Ok(Operand::Const(value)),
+            },
+            Node::VarAccessNode(token) => self.read(token),
+            Node::VarAssignNode(token, annotation, value) => self.assign(token, annotation, value),
+            Node::Binop(..) => self.binops(node),
+            Node::Unary(optok, operand) => self.unary(optok, operand),
+            Node::CallNode(name_tok, args) => match self.call(name_tok, args)? {
+                Some(operand) => Ok(operand),
+                None => Err(type_error(
+                    name_tok,
+                    format!("{} returns nothing", identifier_name(name_tok)?),
+                )),
+            },
+            Node::ListNode(..) => Err(unsupported_node(node, "lista values")),
+            Node::RecordNode(..) => Err(unsupported_node(node, "tietue values")),
+            Node::IndexNode(..) => Err(unsupported_node(node, "indexes")),
+            Node::FieldNode(..) => Err(unsupported_node(node, "fields")),
+            Node::FuncDefNode(..) => Err(unsupported_node(node, "functions inside other code")),
+            Node::IfNode(..) | Node::WhileNode(..) | Node::ReturnNode(..) => {
+                Err(unsupported_node(node, "statements as values"))
+            }
+            Node::StatementsNode(_) => Err(unsupported_node(node, "statements as values")),
+        }
+    }
+
+    // Lowers expressions that are evaluated in order. Variables read early are copied if a
+    // later expression sets them.
+    fn expressions(&mut self, nodes: &[&Node]) -> Result<Vec<Operand>, ErrorType> {
+        let mut operands = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let operand = self.expression(node)?;
+            operands.push(self.kept(operand, &nodes[i + 1..]));
+        }
+        Ok(operands)
+    }
+
+    // A variable read before nodes that set it is copied, so it keeps the value it was read with
+    fn kept(&mut self, operand: Operand, later: &[&Node]) -> Operand {
+        let is_variable = matches!(&operand, Operand::Reg(reg)
+            if self.current().function.regs[reg.0].name.is_some());
+        if !is_variable || !later.iter().any(|node| sets_variable(node)) {
+            return operand;
+        }
+        let type_ = self.type_of(&operand);
+        let dest = self.temp(type_);
+        self.emit(Inst::Copy {
+            dest,
+            value: operand,
+        });
+        Operand::Reg(dest)
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are lowered in a loop
+    fn binops(&mut self, node: &Node) -> Result<Operand, ErrorType> {
+        let operations = node.binop_chain();
+        let first = operations[0].0;
+        let mut left = self.expression(first)?;
+        for (i, (left_node, optok, right)) in operations.into_iter().enumerate() {
+            let start = match i {
+                0 => value_span(first).0,
+                _ => first.pos_start(),
+            };
+            left = self.binop(left, left_node, start, optok, right)?;
+        }
+        Ok(left)
+    }
+
+    // `left` is the value of `left_node`, which starts at `start`
+    fn binop(
+        &mut self,
+        left: Operand,
+        left_node: &Node,
+        start: Option<Position>,
+        optok: &Token,
+        right: &Node,
+    ) -> Result<Operand, ErrorType> {
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            let left = self.truth(left, left_node)?;
+            return self.logic(keyword == "ja", left, right);
+        }
+
+        let mut left_value = self.kept(left, &[right]);
+        let right_value = self.expression(right)?;
+        let mut left_type = self.type_of(&left_value);
+        let right_type = self.type_of(&right_value);
+        let op = match optok.type_() {
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Sub,
+            TokenType::Multiply => BinaryOp::Mul,
+            TokenType::Divide => BinaryOp::Div,
+            TokenType::Pow => BinaryOp::Pow,
+            TokenType::EqualEqual => BinaryOp::Eq,
+            TokenType::NotEqual => BinaryOp::Ne,
+            TokenType::LessThan => BinaryOp::Lt,
+            TokenType::GreaterThan => BinaryOp::Gt,
+            TokenType::LessThanEqual => BinaryOp::Le,
+            TokenType::GreaterThanEqual => BinaryOp::Ge,
+            other => return Err(type_error(optok, format!("Unknown operator {:?}", other))),
+        };
+        // Powers of kok with liu exponents are liu
+        if op == BinaryOp::Pow && left_type == Type::Integer && right_type == Type::Float {
+            left_value = self.convert(left_value);
+            left_type = Type::Float;
+        }
+        let type_ = match binary_type(op, &left_type, &right_type) {
+            Some(type_) => type_,
+            None => {
+                return Err(type_error(
+                    optok,
+                    format!(
+                        "Cant use {} with {} and {}",
+                        optok.type_().symbol(),
+                        left_type,
+                        right_type
+                    ),
+                ))
+            }
+        };
+        let dest = self.temp(type_);
+        let (right_start, end) = value_span(right);
+        self.emit(Inst::Binary {
+            dest,
+            op,
+            left: left_value,
+            right: right_value,
+            span: (start, end.clone()),
+            right_span: (right_start, end),
+            inlined: Vec::new(),
+        });
+        Ok(Operand::Reg(dest))
+    }
+
+    fn convert(&mut self, value: Operand) -> Operand {
+        if let Operand::Const(Const::Int(value)) = value {
+            return Operand::Const(Const::Float(value as f64));
+        }
+        let dest = self.temp(Type::Float);
+        self.emit(Inst::Convert { dest, value });
+        Operand::Reg(dest)
+    }
+
+    // `ja` and `tai` only evaluate their right side when the left does not decide
+    fn logic(&mut self, and: bool, left: Operand, right: &Node) -> Result<Operand, ErrorType> {
+        let result = self.temp(Type::Boolean);
+        self.emit(Inst::Copy {
+            dest: result,
+            value: left.clone(),
+        });
+        let rest = self.new_block();
+        let end = self.new_block();
+        match and {
+            true => self.branch(left, rest, end),
+            false => self.branch(left, end, rest),
+        }
+        self.switch_to(rest);
+        let right = self.condition(right)?;
+        self.emit(Inst::Copy {
+            dest: result,
+            value: right,
+        });
+        self.terminate(Terminator::Jump(end));
+        self.switch_to(end);
+        Ok(Operand::Reg(result))
+    }
+
+    fn unary(&mut self, optok: &Token, operand: &Node) -> Result<Operand, ErrorType> {
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            let value = self.condition(operand)?;
+            let dest = self.temp(Type::Boolean);
+            self.emit(Inst::Unary {
+                dest,
+                op: UnaryOp::Not,
+                value,
+                span: value_span(operand),
+                inlined: Vec::new(),
+            });
+            return Ok(Operand::Reg(dest));
+        }
+
+        let value = self.expression(operand)?;
+        let type_ = self.type_of(&value);
+        match (optok.type_(), &type_) {
+            (TokenType::Plus, Type::Integer | Type::Float) => Ok(value),
+            (TokenType::Minus, Type::Integer | Type::Float) => {
+                let dest = self.temp(type_);
+                self.emit(Inst::Unary {
+                    dest,
+                    op: UnaryOp::Neg,
+                    value,
+                    span: value_span(operand),
+                    inlined: Vec::new(),
+                });
+                Ok(Operand::Reg(dest))
+            }
+            (operator, _) => Err(type_error(
+                optok,
+                format!("Cant use {} with {}", operator.symbol(), type_),
+            )),
+        }
+    }
+
+    // Lowers a totuus
+    fn condition(&mut self, node: &Node) -> Result<Operand, ErrorType> {
+        let value = self.expression(node)?;
+        self.truth(value, node)
+    }
+
+    // Checks that the value of `node` is a condition
+    fn truth(&mut self, value: Operand, node: &Node) -> Result<Operand, ErrorType> {
+        let type_ = self.type_of(&value);
+        if type_ != Type::Boolean {
+            return Err(node_error(
+                node,
+                format!("Condition must be totuus, found {}", type_),
+            ));
+        }
+        Ok(value)
+    }
+
+    // Calls
+
+    // Lowers a call, with `None` for calls that return nothing
+    fn call(&mut self, name_tok: &Token, args: &[Node]) -> Result<Option<Operand>, ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if let Some(index) = self.function_ids.get(&name) {
+            return self.call_function(*index, name_tok, args);
+        }
+        match name.as_str() {
+            "tulosta" => {
+                // Texts can only be printed, so they are only lowered here
+                let mut values = Vec::with_capacity(args.len());
+                let mut nodes = Vec::new();
+                for arg in args {
+                    match arg {
+                        Node::Value(token) if matches!(token.type_(), TokenType::String(_)) => {
+                            values.push(Some(value(token)?))
+                        }
+                        arg => {
+                            values.push(None);
+                            nodes.push(arg);
+                        }
+                    }
+                }
+                let mut operands = self.expressions(&nodes)?.into_iter();
+                let values = values
+                    .into_iter()
+                    .map(|value| match value {
+                        Some(text) => Operand::Const(text),
+                        None => operands.next().expect("Each other argument has an operand"),
+                    })
+                    .collect();
+                self.emit(Inst::Print { values });
+                Ok(None)
+            }
+            "liu" => {
+                if args.len() != 1 {
+                    return Err(type_error(
+                        name_tok,
+                        format!("liu expects 1 arguments, got {}", args.len()),
+                    ));
+                }
+                let value = self.expression(&args[0])?;
+                match self.type_of(&value) {
+                    Type::Float => Ok(Some(value)),
+                    Type::Integer => Ok(Some(self.convert(value))),
+                    type_ => Err(node_error(
+                        &args[0],
+                        format!("liu expects a number, got {}", type_),
+                    )),
+                }
+            }
+            _ if builtins::prelude().get(&name).is_some() => {
+                Err(unsupported(name_tok, &format!("calls to {}", name)))
+            }
+            _ => {
+                let value = self.read(name_tok)?;
+                Err(type_error(
+                    name_tok,
+                    format!("{} is a {}, not a function", name, self.type_of(&value)),
+                ))
+            }
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        index: usize,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<Option<Operand>, ErrorType> {
+        // Only functions defined so far can be called from the program
+        if self.current().index.is_none() && !self.signatures[index].defined {
+            return Err(not_defined(name_tok, &self.signatures[index].name));
+        }
+        let signature = &self.signatures[index];
+        if args.len() != signature.params.len() {
+            return Err(type_error(
+                name_tok,
+                format!(
+                    "{} expects {} arguments, got {}",
+                    signature.name,
+                    signature.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let nodes: Vec<&Node> = args.iter().collect();
+        let values = self.expressions(&nodes)?;
+        for (i, value) in values.iter().enumerate() {
+            let (param, expected) = &self.signatures[index].params[i];
+            let type_ = self.type_of(value);
+            if type_ != *expected {
+                return Err(node_error(
+                    &args[i],
+                    format!(
+                        "{} expects {} for {}, got {}",
+                        self.signatures[index].name, expected, param, type_
+                    ),
+                ));
+            }
+        }
+
+        let dest = self.signatures[index]
+            .returns
+            .clone()
+            .map(|type_| self.temp(type_));
+        self.emit(Inst::Call {
+            dest,
+            function: index,
+            args: values,
+            span: (name_tok.position_start(), name_tok.position_end()),
+            inlined: Vec::new(),
+            tail: false,
+        });
+        Ok(dest.map(Operand::Reg))
+    }
+
+    // Statements
+
+    fn statement(&mut self, node: &Node) -> Result<(), ErrorType> {
+        match node {
+            Node::StatementsNode(nodes) => {
+                for node in nodes {
+                    self.statement(node)?;
+                }
+            }
+            Node::IfNode(_, cases, else_case) => {
+                let end = self.new_block();
+                for (condition, body) in cases {
+                    let condition = self.condition(condition)?;
+                    let then = self.new_block();
+                    let next = self.new_block();
+                    self.branch(condition, then, next);
+                    self.switch_to(then);
+                    self.statement(body)?;
+                    self.terminate(Terminator::Jump(end));
+                    self.switch_to(next);
+                }
+                if let Some(body) = else_case {
+                    self.statement(body)?;
+                }
+                self.terminate(Terminator::Jump(end));
+                self.switch_to(end);
+            }
+            Node::WhileNode(_, condition, body) => {
+                let start = self.new_block();
+                let then = self.new_block();
+                let end = self.new_block();
+                self.terminate(Terminator::Jump(start));
+                self.switch_to(start);
+                let condition = self.condition(condition)?;
+                self.branch(condition, then, end);
+                self.switch_to(then);
+                self.statement(body)?;
+                self.terminate(Terminator::Jump(start));
+                self.switch_to(end);
+            }
+            Node::ReturnNode(token, value) => self.ret(token, value.as_deref())?,
+            Node::CallNode(name_tok, args) => {
+                self.call(name_tok, args)?;
+            }
+            node => {
+                self.expression(node)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ret(&mut self, token: &Token, value: Option<&Node>) -> Result<(), ErrorType> {
+        let index = match self.current().index {
+            Some(index) => index,
+            None => {
+                // The program returns nothing, whatever value the 'palata' has
+                if let Some(value) = value {
+                    self.statement(value)?;
+                }
+                self.terminate(Terminator::Return(None));
+                let unreachable = self.new_block();
+                self.switch_to(unreachable);
+                return Ok(());
+            }
+        };
+
+        let name = self.signatures[index].name.clone();
+        let returned = match (value, self.signatures[index].returns.clone()) {
+            (None, None) => None,
+            (Some(value), Some(returns)) => {
+                let operand = self.expression(value)?;
+                let type_ = self.type_of(&operand);
+                if type_ != returns {
+                    return Err(node_error(
+                        value,
+                        format!("{} returns {}, not {}", name, returns, type_),
+                    ));
+                }
+                Some(operand)
+            }
+            (Some(Node::CallNode(name_tok, args)), None) => {
+                if self.call(name_tok, args)?.is_some() {
+                    return Err(unsupported(
+                        token,
+                        "values returned from functions without a return type",
+                    ));
+                }
+                None
+            }
+            (Some(_), None) => {
+                return Err(unsupported(
+                    token,
+                    "values returned from functions without a return type",
+                ))
+            }
+            (None, Some(returns)) => {
+                return Err(type_error(
+                    token,
+                    format!("{} returns {}, not tyhjä", name, returns),
+                ))
+            }
+        };
+        // Like in the interpreter, returning what the function itself returns is a tail call
+        if let Some(Node::CallNode(name_tok, args)) = value {
+            let own = identifier_name(name_tok)? == name
+                && args.len() == self.signatures[index].params.len();
+            let body = self.body();
+            let current = body.current;
+            if let Some(Inst::Call { function, tail, .. }) =
+                body.function.block_mut(current).insts.last_mut()
+            {
+                *tail = own && *function == index;
+            }
+        }
+        self.terminate(Terminator::Return(returned));
+        let unreachable = self.new_block();
+        self.switch_to(unreachable);
+        Ok(())
+    }
+
+    // Functions and the program
+
+    fn declare(
+        &mut self,
+        name_tok: &Token,
+        params: &[(Token, Option<Token>)],
+        returns: &Option<Token>,
+    ) -> Result<(), ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(name_tok, "functions defined more than once"));
+        }
+        let mut typed_params: Vec<(String, Type)> = Vec::with_capacity(params.len());
+        for (param, annotation) in params {
+            let type_ = match annotation {
+                Some(annotation) => annotated_type(annotation)?,
+                None => {
+                    return Err(unsupported(
+                        param,
+                        "parameters without kok, liu or totuus types",
+                    ))
+                }
+            };
+            let param_name = identifier_name(param)?;
+            if typed_params.iter().any(|(other, _)| *other == param_name) {
+                return Err(unsupported(param, "parameters with the same name"));
+            }
+            typed_params.push((param_name, type_));
+        }
+        let returns = match returns {
+            Some(token) if token.type_() == TokenType::Keyword("tyhjä".to_string()) => None,
+            Some(token) => Some(annotated_type(token)?),
+            None => None,
+        };
+        self.signatures.push(Signature {
+            name: name.clone(),
+            params: typed_params,
+            returns,
+            defined: false,
+        });
+        self.function_ids.insert(name, self.signatures.len() - 1);
+        Ok(())
+    }
+
+    // Notes the names a function uses without setting them
+    fn shared_names(
+        &mut self,
+        params: &[(Token, Option<Token>)],
+        body: &Node,
+    ) -> Result<(), ErrorType> {
+        let mut locals = Vec::new();
+        for (param, _) in params {
+            locals.push(identifier_name(param)?);
+        }
+        collect_locals(body, &mut locals)?;
+        let mut used = Vec::new();
+        collect_reads(body, &mut used);
+        for name in used {
+            if !locals.contains(&name) {
+                self.shared.insert(name);
+            }
+        }
+        Ok(())
+    }
+
+    fn program(&mut self, statements: &[Node]) -> Result<Function, ErrorType> {
+        let mut function = Function::new("program", None);
+        let current = function.new_block();
+        self.body = Some(Body {
+            function,
+            index: None,
+            current,
+            locals: Vec::new(),
+        });
+
+        for statement in statements {
+            match statement {
+                Node::FuncDefNode(name_tok, ..) => {
+                    let index = self.function_ids[&identifier_name(name_tok)?];
+                    self.signatures[index].defined = true;
+                }
+                statement => self.statement(statement)?,
+            }
+        }
+        self.terminate(Terminator::Return(None));
+        let body = self.body.take().expect("The program body was set");
+        Ok(finish(body.function))
+    }
+
+    fn function(&mut self, name_tok: &Token, body: &Node) -> Result<Function, ErrorType> {
+        let index = self.function_ids[&identifier_name(name_tok)?];
+        let signature = &self.signatures[index];
+        let mut function = Function::new(&signature.name, signature.returns.clone());
+        let mut locals = Vec::new();
+        for (param, type_) in &signature.params {
+            if self.function_ids.contains_key(param) {
+                return Err(unsupported(name_tok, "parameters named like functions"));
+            }
+            let reg = function.new_reg(type_.clone(), Some(param.clone()));
+            function.params.push(reg);
+            locals.push((param.clone(), Some(reg)));
+        }
+        let mut names = Vec::new();
+        collect_locals(body, &mut names)?;
+        for name in names {
+            if locals.iter().all(|(local, _)| *local != name) {
+                locals.push((name, None));
+            }
+        }
+        let current = function.new_block();
+        self.body = Some(Body {
+            function,
+            index: Some(index),
+            current,
+            locals,
+        });
+
+        self.statement(body)?;
+        let body = self.body.take().expect("The function body was set");
+        let function = body.function;
+        if let Some(returns) = &function.returns {
+            // Code that reaches the end returns nothing, which the function cant
+            if function.reverse_postorder().contains(&body.current) {
+                return Err(type_error(
+                    name_tok,
+                    format!("{} can end without returning a {}", function.name, returns),
+                ));
+            }
+        }
+        Ok(finish(function))
+    }
+}
+
+// Removes the blocks no code reaches, and sets variables that are not set on every path
+// to where they are used to zero at the start, like the frames of compiled programs
+fn finish(mut function: Function) -> Function {
+    function.sort_blocks();
+    let set = set_registers(&function);
+    let mut unset = Vec::new();
+    for block in function.reverse_postorder() {
+        let mut defined = set[block.0].clone().expect("Reachable blocks have sets");
+        let block = function.block(block);
+        for inst in &block.insts {
+            for operand in inst.operands() {
+                if let Operand::Reg(reg) = operand {
+                    if !defined[reg.0] && !unset.contains(reg) {
+                        unset.push(*reg);
+                    }
+                }
+            }
+            if let Some(dest) = inst.dest() {
+                defined[dest.0] = true;
+            }
+        }
+        for operand in block.terminator.operands() {
+            if let Operand::Reg(reg) = operand {
+                if !defined[reg.0] && !unset.contains(reg) {
+                    unset.push(*reg);
+                }
+            }
+        }
+    }
+    unset.sort_unstable();
+    let zeros: Vec<Inst> = unset
+        .into_iter()
+        .map(|reg| Inst::Copy {
+            dest: reg,
+            value: Operand::Const(zero(&function.regs[reg.0].type_)),
+        })
+        .collect();
+    let start = &mut function.blocks[0].insts;
+    let rest = std::mem::take(start);
+    start.extend(zeros);
+    start.extend(rest);
+    function
+}
+
+/// The zero value of a type
+pub fn zero(type_: &Type) -> Const {
+    match type_ {
+        Type::Float => Const::Float(0.0),
+        Type::Boolean => Const::Bool(false),
+        _ => Const::Int(0),
+    }
+}
+
+fn value(token: &Token) -> Result<Const, ErrorType> {
+    match token.type_() {
+        TokenType::Int(value) => Ok(Const::Int(value)),
+        TokenType::Float(value) => Ok(Const::Float(value)),
+        TokenType::String(text) => Ok(Const::Text(text)),
+        TokenType::Keyword(keyword) if keyword == "tosi" || keyword == "epätosi" => {
+            Ok(Const::Bool(keyword == "tosi"))
+        }
+        TokenType::Keyword(keyword) if keyword == "tyhjä" => {
+            Err(unsupported(token, "tyhjä values"))
+        }
+        TokenType::BigInt(_) => Err(unsupported(token, "iso numbers")),
+        TokenType::Imaginary(_) => Err(unsupported(token, "kompleksi numbers")),
+        other => Err(type_error(
+            token,
+            format!(
+                "Non Value Token {:?} found inside lower value function",
+                other
+            ),
+        )),
+    }
+}
+
+// Whether evaluating a node can set a variable
+fn sets_variable(node: &Node) -> bool {
+    matches!(node, Node::VarAssignNode(..)) || node.children().into_iter().any(sets_variable)
+}
+
+fn value_span(node: &Node) -> Span {
+    match node {
+        Node::Value(token) | Node::VarAccessNode(token) | Node::CallNode(token, _) => {
+            (token.position_start(), token.position_end())
+        }
+        Node::Unary(optok, operand) => (optok.position_start(), value_span(operand).1),
+        Node::VarAssignNode(_, _, value) => value_span(value),
+        node => (node.pos_start(), node.pos_end()),
+    }
+}
+
+fn annotated_type(token: &Token) -> Result<Type, ErrorType> {
+    let name = match token.type_() {
+        TokenType::Identifier(name) | TokenType::Keyword(name) => name,
+        _ => String::new(),
+    };
+    match Type::from_name(&name) {
+        Some(type_ @ (Type::Integer | Type::Float | Type::Boolean)) => Ok(type_),
+        Some(type_) => Err(unsupported(token, &format!("{} values", type_))),
+        None => Err(type_error(token, format!("Unknown type {}", name))),
+    }
+}
+
+fn check_variable(token: &Token, name: &str, known: &Type, type_: &Type) -> Result<(), ErrorType> {
+    match known == type_ {
+        true => Ok(()),
+        false => Err(type_error(
+            token,
+            format!("{} is {}, not {}", name, known, type_),
+        )),
+    }
+}
+
+// Every variable a function reads
+fn collect_reads(node: &Node, names: &mut Vec<String>) {
+    if let Node::VarAccessNode(token) = node {
+        if let TokenType::Identifier(name) = token.type_() {
+            names.push(name);
+        }
+    }
+    for child in node.children() {
+        collect_reads(child, names);
+    }
+}
+
+fn not_defined(token: &Token, name: &str) -> ErrorType {
+    type_error(token, format!("{} is not defined", name))
+}
+
+fn unsupported(token: &Token, what: &str) -> ErrorType {
+    type_error(token, format!("{} are not supported in the IR", what))
+}
+
+fn unsupported_node(node: &Node, what: &str) -> ErrorType {
+    node_error(node, format!("{} are not supported in the IR", what))
+}
+
+fn type_error(token: &Token, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(
+        token.position_start(),
+        token.position_end(),
+        message,
+    ))
+}
+
+fn node_error(node: &Node, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(node.pos_start(), node.pos_end(), message))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::interpeter;
+    use crate::test_support;
+
+    // Runs IR the simple way, for comparing it with the interpreter and checking passes
+    struct Evaluator<'a> {
+        module: &'a Module,
+        globals: Vec<Option<Const>>,
+        output: String,
+        // The calls that are running, as the interpreter keeps them for tracebacks
+        frames: Vec<Frame>,
+    }
+
+    impl Evaluator<'_> {
+        // The running calls with the calls an instruction was inlined from
+        fn frames(&self, inlined: &[Inlined], regs: &[Option<Const>]) -> Vec<Frame> {
+            let mut frames = self.frames.clone();
+            for call in inlined {
+                let args: Vec<Const> = call.args.iter().map(|arg| value(regs, arg)).collect();
+                frames.push(call_frame(self.module, call.function, &args, &call.span));
+            }
+            frames
+        }
+
+        fn call(
+            &mut self,
+            function: &Function,
+            args: Vec<Const>,
+        ) -> Result<Option<Const>, ErrorType> {
+            let mut regs: Vec<Option<Const>> = vec![None; function.regs.len()];
+            for (param, arg) in function.params.iter().zip(args) {
+                regs[param.0] = Some(arg);
+            }
+            let (mut block, mut from) = (BlockId(0), None);
+            'blocks: loop {
+                let insts = &function.block(block).insts;
+                // Phis all take their values before any is set
+                let phis: Vec<(Reg, Const)> = insts
+                    .iter()
+                    .filter_map(|inst| match inst {
+                        Inst::Phi { dest, incoming } => {
+                            let (_, operand) = incoming
+                                .iter()
+                                .find(|(block, _)| Some(*block) == from)
+                                .expect("Phis have a value for each block before theirs");
+                            Some((*dest, value(&regs, operand)))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                for (dest, phi) in phis {
+                    regs[dest.0] = Some(phi);
+                }
+                for inst in insts {
+                    let failed = |failure: Failure| {
+                        failure.error(failure.span(inst), &self.frames(inst.inlined(), &regs))
+                    };
+                    let result = match inst {
+                        Inst::Phi { .. } => continue,
+                        Inst::Copy { value: operand, .. } => value(&regs, operand),
+                        Inst::Binary {
+                            op, left, right, ..
+                        } => evaluate_binary(*op, &value(&regs, left), &value(&regs, right))
+                            .map_err(failed)?,
+                        Inst::Unary {
+                            op, value: operand, ..
+                        } => evaluate_unary(*op, &value(&regs, operand)).map_err(failed)?,
+                        Inst::Convert { value: operand, .. } => match value(&regs, operand) {
+                            Const::Int(int) => Const::Float(int as f64),
+                            other => panic!("Cant convert {}", other),
+                        },
+                        Inst::Call {
+                            args, tail: true, ..
+                        } => {
+                            // The call starts this function over in the same frame
+                            let args: Vec<Const> =
+                                args.iter().map(|arg| value(&regs, arg)).collect();
+                            if let Some(frame) = self.frames.last_mut() {
+                                frame.tail_call(bound_args(function, &args));
+                            }
+                            regs = vec![None; function.regs.len()];
+                            for (param, arg) in function.params.iter().zip(args) {
+                                regs[param.0] = Some(arg);
+                            }
+                            block = BlockId(0);
+                            from = None;
+                            continue 'blocks;
+                        }
+                        Inst::Call {
+                            dest,
+                            function: index,
+                            args,
+                            span,
+                            inlined,
+                            ..
+                        } => {
+                            let args: Vec<Const> =
+                                args.iter().map(|arg| value(&regs, arg)).collect();
+                            let frames = self.frames(inlined, &regs);
+                            // The program itself is the first frame
+                            if frames.len() > 1000 {
+                                return Err(depth_error(1000, span.clone(), &frames));
+                            }
+                            let module = self.module;
+                            let caller = std::mem::replace(&mut self.frames, frames);
+                            self.frames.push(call_frame(module, *index, &args, span));
+                            let result = self.call(&module.functions[*index], args)?;
+                            self.frames = caller;
+                            match (dest, result) {
+                                (Some(_), Some(result)) => result,
+                                _ => continue,
+                            }
+                        }
+                        Inst::Enter { inlined } => {
+                            let (call, outer) = inlined.split_last().expect("Enter has a call");
+                            let frames = self.frames(outer, &regs);
+                            if frames.len() > 1000 {
+                                return Err(depth_error(1000, call.span.clone(), &frames));
+                            }
+                            continue;
+                        }
+                        Inst::Load { global, .. } => self.globals[*global]
+                            .clone()
+                            .unwrap_or_else(|| zero(&self.module.globals[*global].type_)),
+                        Inst::Store {
+                            global,
+                            value: operand,
+                        } => {
+                            self.globals[*global] = Some(value(&regs, operand));
+                            continue;
+                        }
+                        Inst::Print { values } => {
+                            let values: Vec<String> = values
+                                .iter()
+                                .map(|operand| match value(&regs, operand) {
+                                    Const::Text(text) => text,
+                                    other => other.representation(),
+                                })
+                                .collect();
+                            self.output.push_str(&values.join(" "));
+                            self.output.push('\n');
+                            continue;
+                        }
+                    };
+                    let dest = inst.dest().expect("Instructions with values set registers");
+                    regs[dest.0] = Some(result);
+                }
+                from = Some(block);
+                block = match &function.block(block).terminator {
+                    Terminator::Jump(target) => *target,
+                    Terminator::Branch {
+                        condition,
+                        then,
+                        otherwise,
+                    } => match value(&regs, condition) {
+                        Const::Bool(true) => *then,
+                        _ => *otherwise,
+                    },
+                    Terminator::Return(operand) => {
+                        return Ok(operand.as_ref().map(|operand| value(&regs, operand)))
+                    }
+                };
+            }
+        }
+    }
+
+    fn value(regs: &[Option<Const>], operand: &Operand) -> Const {
+        match operand {
+            Operand::Reg(reg) => regs[reg.0].clone().expect("Registers are set before use"),
+            Operand::Const(value) => value.clone(),
+        }
+    }
+
+    /// What a module prints, and the error it ends with as the interpreter shows it
+    pub fn run(module: &Module) -> (String, String) {
+        let mut evaluator = Evaluator {
+            module,
+            globals: vec![None; module.globals.len()],
+            output: String::new(),
+            frames: vec![Frame::program("Program")],
+        };
+        let error = match evaluator.call(&module.program, Vec::new()) {
+            Ok(_) => String::new(),
+            Err(e) => format!("{}\n", e.as_string()),
+        };
+        (evaluator.output, error)
+    }
+
+    pub fn lower_text(text: &str) -> Module {
+        Engine::new().to_ir("test.fin", text).unwrap()
+    }
+
+    /// Checks that a program lowered to IR and changed by `transform` does what the
+    /// interpreter does
+    pub fn assert_same<F>(text: &str, transform: F)
+    where
+        F: FnOnce(Module) -> Module + Send + 'static,
+    {
+        let source = text.to_string();
+        let (output, error, module) = interpeter::with_stack(move || {
+            let module = transform(lower_text(&source));
+            let (output, error) = run(&module);
+            (output, error, module.to_string())
+        });
+        let (expected_output, expected_error) = test_support::interpret(text);
+        assert_eq!(output, expected_output, "{}\n{}", text, module);
+        assert_eq!(error, expected_error, "{}\n{}", text, module);
+    }
+
+    /// Programs that use everything the IR has
+    pub const PROGRAMS: [&str; 29] = [
+        "tulosta(1 + 2 * 3, 7 / 2, -7 / 2, 2 ^ 10, 2.0 ^ -2, 1.5 * 4.0, 0.1 + 0.2, 1.0 / 3.0)",
+        "tulosta(\"äö\", tosi, 1 < 2, 1.5 >= 2.5, 2 == 2, tosi != epätosi, ei tosi, 0.0 / 1.0 == -0.0)",
+        "muut n = 0.0 / 1.0 * 0.0; muut x = n / 1.0; tulosta(x < x, x == x, x != x, x >= 1.0)",
+        "tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; tulosta(fib(15))",
+        "tominto f(n: kok, s: kok): kok { kun n > 0 { muut s = s + n; muut n = n - 1 }; palata s }; tulosta(f(1000, 0))",
+        "tominto f(a: liu, b: kok, c: totuus, d: liu): liu { jos c { palata a * liu(b) + d }; palata d }; tulosta(f(1.5, 2, tosi, 0.25), f(1.5, 2, epätosi, 0.25))",
+        "tominto näytä(x: kok) { tulosta(\"x on\", x) }; näytä(3); näytä(-3)",
+        "muut x = 1; tominto f(): kok { palata x }; muut x = 2; tulosta(f())",
+        "jos 1 > 2 { tulosta(1) } muuten jos 1 == 1 ja ei epätosi { tulosta(2) } muuten { tulosta(3) }",
+        "tominto t(x: kok): totuus { tulosta(x); palata x > 1 }; tulosta(t(1) ja t(2), t(2) tai t(3), t(1) tai t(2) ja t(3))",
+        "muut a = 3; tulosta(a ^ 3, (-a) ^ 3, a ^ 0, 2.0 ^ 10, 0.5 ^ -2, -a, +a, -(1.5 * liu(a)))",
+        "muut a = 2; muut b = 0.5; tulosta(a ^ b, b ^ b, a ^ 1.5, 4.0 ^ -0.5)",
+        "palata 1; tulosta(2)",
+        "muut x = 1; tulosta(x, muut x = 2, x)",
+        "muut i = 0; muut s = 0; kun i < 10 { jos i == 5 { muut t = i }; muut s = s + i; muut i = i + 1 }; tulosta(s)",
+        "muut n = 0; tominto lisää(k: kok) { muut n = k; tulosta(n) }; lisää(4); tulosta(n)",
+        "muut m = 0; tominto f(): kok { palata m * 2 }; kun m < 3 { muut m = m + 1; tulosta(f()) }",
+        "tulosta(1); muut a = 9223372036854775807; a + 1",
+        "muut a = 0; tulosta(\"ennen\"); 1 / a",
+        "muut a = -9223372036854775807 - 1; tulosta(a / -1)",
+        "muut a = -9223372036854775807 - 1; -a",
+        "muut a = 0.0; 1.0 / a",
+        "tominto f(n: kok): kok { palata 10 / n }; tominto g(n: kok): kok { palata f(n - 1) }; g(1)",
+        "tominto f(n: kok): kok { palata 1 + f(n + 1) }; f(0)",
+        "muut a = 0; a ^ -2",
+        "muut a = 2; a ^ 63",
+        "muut a = -2.0; a ^ 0.5",
+        "tominto f(n: kok, s: kok): kok { jos n == 0 { palata s }; palata f(n - 1, s + n) }; tulosta(f(5000, 0))",
+        "tominto f(n: kok) { jos n == 0 { 1 / n }; palata f(n - 1) }; f(3)",
+    ];
+
+    #[test]
+    fn test_same_as_interpeter() {
+        for text in PROGRAMS {
+            assert_same(text, |module| module);
+        }
+    }
+
+    #[test]
+    fn test_text_form() {
+        let text = "muut raja = 2
+tominto fib(n: kok): kok {
+    jos n < raja { palata n }
+    palata fib(n - 1) + fib(n - 2)
+}
+muut i = 0
+kun i < 3 ja i >= 0 {
+    tulosta(\"fib\", fib(i))
+    muut i = i + 1
+}";
+        let expected = "global @raja: kok
+
+fn fib(%n: kok) -> kok {
+b0:
+    %0: kok = load @raja
+    %1: totuus = lt %n, %0
+    br %1, b1, b2
+b1:          ; from b0
+    ret %n
+b2:          ; from b0
+    jmp b3
+b3:          ; from b2
+    %2: kok = sub %n, 1
+    %3: kok = call fib(%2)
+    %4: kok = sub %n, 2
+    %5: kok = call fib(%4)
+    %6: kok = add %3, %5
+    ret %6
+}
+
+program {
+b0:
+    store @raja, 2
+    %i: kok = copy 0
+    jmp b1
+b1:          ; from b0, b4
+    %0: totuus = lt %i, 3
+    %1: totuus = copy %0
+    br %0, b2, b3
+b2:          ; from b1
+    %2: totuus = ge %i, 0
+    %1: totuus = copy %2
+    jmp b3
+b3:          ; from b1, b2
+    br %1, b4, b5
+b4:          ; from b3
+    %3: kok = call fib(%i)
+    print \"fib\", %3
+    %4: kok = add %i, 1
+    %i: kok = copy %4
+    jmp b1
+b5:          ; from b3
+    ret
+}
+";
+        assert_eq!(lower_text(text).to_string(), expected);
+    }
+
+    #[test]
+    fn test_verify() {
+        let text = "tominto f(n: kok): kok { jos n > 0 { palata n }; palata 0 }; muut x = f(2); tulosta(x)";
+        let module = lower_text(text);
+        assert_eq!(verify(&module), Ok(()));
+
+        let mut broken = module.clone();
+        broken.functions[0].blocks[0].terminator = Terminator::Jump(BlockId(9));
+        assert_eq!(verify(&broken), Err("f: b0: b9 is not a block".to_string()));
+
+        let mut broken = module.clone();
+        broken.functions[0].blocks[1].terminator =
+            Terminator::Return(Some(Operand::Const(Const::Bool(true))));
+        assert_eq!(
+            verify(&broken),
+            Err("f: b1: the returned value is totuus, not kok".to_string())
+        );
+
+        let mut broken = module.clone();
+        broken.functions[0].blocks[0].terminator = Terminator::Branch {
+            condition: Operand::Const(Const::Int(1)),
+            then: BlockId(1),
+            otherwise: BlockId(2),
+        };
+        assert_eq!(
+            verify(&broken),
+            Err("f: b0: the condition is kok, not totuus".to_string())
+        );
+
+        let mut broken = module.clone();
+        let x = Operand::Reg(Reg(0));
+        broken.program.blocks[0].insts.insert(
+            0,
+            Inst::Print {
+                values: vec![x.clone()],
+            },
+        );
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: %0 can be used before it is set".to_string())
+        );
+
+        let mut broken = module.clone();
+        broken.program.blocks[0].insts.push(Inst::Phi {
+            dest: Reg(0),
+            incoming: Vec::new(),
+        });
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: phis must be at the start of the block".to_string())
+        );
+
+        let mut broken = module.clone();
+        broken.program.blocks[0].insts.insert(
+            0,
+            Inst::Binary {
+                dest: Reg(0),
+                op: BinaryOp::Add,
+                left: Operand::Const(Const::Int(1)),
+                right: Operand::Const(Const::Float(1.0)),
+                span: (None, None),
+                right_span: (None, None),
+                inlined: Vec::new(),
+            },
+        );
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: cant use add with kok and liu".to_string())
+        );
+
+        let mut broken = module.clone();
+        broken.program.blocks[0].insts.push(Inst::Enter {
+            inlined: Vec::new(),
+        });
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: enter needs the call it replaced".to_string())
+        );
+
+        let mut broken = module.clone();
+        if let Inst::Call { tail, .. } = &mut broken.program.blocks[0].insts[0] {
+            *tail = true;
+        }
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: a tail call must be followed by returning its value".to_string())
+        );
+
+        let mut broken = module;
+        if let Inst::Call { args, .. } = &mut broken.program.blocks[0].insts[0] {
+            args.clear();
+        }
+        assert_eq!(
+            verify(&broken),
+            Err("program: b0: f takes 1 arguments, not 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for (text, message) in [
+            ("muut a = [1]", "lista values are not supported in the IR"),
+            (
+                "muut a = \"a\"",
+                "teksti values are not supported in the IR",
+            ),
+            (
+                "tominto f(a) { tulosta(a) }",
+                "parameters without kok, liu or totuus types are not supported",
+            ),
+            (
+                "muut a = 2i",
+                "kompleksi numbers are not supported in the IR",
+            ),
+            (
+                "tominto f() { palata 1 }",
+                "values returned from functions without a return type are not supported",
+            ),
+            ("tulosta(pituus([1]))", "calls to pituus are not supported"),
+            ("f(); tominto f() { tulosta(1) }", "f is not defined"),
+            ("tulosta(tyhjä)", "tyhjä values are not supported in the IR"),
+        ] {
+            let error = Engine::new()
+                .to_ir("test.fin", text)
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains(message), "{}: {}", text, error);
+        }
+    }
+}
@@ -0,0 +1,1099 @@
+use crate::ast;
+use crate::ir::{self, BinaryOp, BlockId, Const, Inst, Operand, Reg, Terminator, UnaryOp};
+use crate::number::{Number, NumberType};
+use crate::parser::Node;
+use crate::passes::{self, Optimizations};
+use crate::token::{Token, TokenType};
+use crate::types::Type;
+use crate::value::{Function, Value};
+use crate::x86;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+// Compiles the functions the interpreter calls often to x86-64 machine code. Calls are
+// counted by the body of the function, and once a function is called `threshold` times it
+// is compiled for the types of the arguments it was called with: the body is lowered to IR
+// like a function annotated with those types, optimized, and assembled by x86.rs into
+// memory mapped executable. Functions the IR cant express, and ones that print or call
+// other functions, are left to the interpreter.
+//
+// Except for small helpers: a function that calls other functions of its scope is lowered
+// with them, each annotated with the argument types every call to it so far had, and the
+// optimizer puts their bodies in place of the calls. When a call is left, because a helper
+// is too big or calls itself, the function is left to the interpreter too.
+//
+// The machine code never reports errors. When an operation overflows, divides by zero or
+// needs a value the IR doesnt have, and when calls would nest deeper than the interpreter
+// allows, the code gives up and the interpreter runs the whole call again. The functions
+// it compiles only compute their result, so running them twice is the same as running
+// them once, and errors and tracebacks come from the interpreter either way.
+//
+// The compiled functions take their arguments as an array of 8 byte values, where to write
+// the result, and how many more calls may nest in the call. They return 0 when they wrote
+// the result and 1 when the interpreter has to run the call. Every register of the IR has
+// a slot in the frame, and values only stay in machine registers within one instruction.
+
+/// How many times a function is called before it is compiled
+pub const THRESHOLD: u64 = 1000;
+
+// What compiled code returns when the interpreter has to run the call
+const BAIL: u64 = 1;
+
+// The frame starts with the result pointer, the call budget and the result of a call
+const FRAME_HEADER: usize = 24;
+
+/// Counts the calls of functions, and runs the ones called often as machine code
+pub struct Jit {
+    threshold: u64,
+    // By the address of the body
+    functions: HashMap<*const Node, Counter>,
+}
+
+struct Counter {
+    // Keeps the body alive, so no other body gets its address
+    _body: Rc<Node>,
+    calls: u64,
+    // The argument types of the calls so far when they all had the same ones, for
+    // lowering the function where it is inlined
+    types: Option<Vec<Type>>,
+    // The code for each list of argument types, `None` when it cant be compiled
+    compiled: Vec<(Vec<Type>, Option<Compiled>)>,
+}
+
+impl Jit {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            functions: HashMap::new(),
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    /// Whether a function has been compiled for some arguments
+    pub fn is_compiled(&self, function: &Function) -> bool {
+        match self.functions.get(&Rc::as_ptr(&function.body())) {
+            Some(counter) => counter.compiled.iter().any(|(_, code)| code.is_some()),
+            None => false,
+        }
+    }
+
+    /// Counts a call, and runs it as machine code when the function is called often and
+    /// can be compiled for the arguments. `None` means the interpreter has to run the call.
+    ///
+    /// `calls_left` is how many more frames the call may push after its own, and
+    /// `visits_left` how much deeper than the call nodes may be visited.
+    pub fn call(
+        &mut self,
+        function: &Function,
+        args: &[Value],
+        calls_left: Option<usize>,
+        visits_left: usize,
+    ) -> Option<Value> {
+        let body = function.body();
+        let counter = self
+            .functions
+            .entry(Rc::as_ptr(&body))
+            .or_insert_with(|| Counter {
+                _body: body.clone(),
+                calls: 0,
+                types: None,
+                compiled: Vec::new(),
+            });
+        counter.calls = counter.calls.saturating_add(1);
+        let arguments: Option<Vec<(Type, u64)>> = args.iter().map(argument).collect();
+        let types = arguments
+            .as_ref()
+            .map(|arguments| arguments.iter().map(|(type_, _)| type_.clone()).collect());
+        counter.types = match counter.calls {
+            1 => types,
+            _ if counter.types == types => types,
+            _ => None,
+        };
+        if counter.calls < self.threshold {
+            return None;
+        }
+
+        let (types, values): (Vec<Type>, Vec<u64>) = arguments?.into_iter().unzip();
+        let known = counter
+            .compiled
+            .iter()
+            .position(|(known, _)| *known == types);
+        let index = match known {
+            Some(index) => index,
+            None => {
+                let compiled = Compiled::new(function, &types, &self.functions);
+                let counter = self
+                    .functions
+                    .get_mut(&Rc::as_ptr(&body))
+                    .expect("The function was counted");
+                counter.compiled.push((types, compiled));
+                counter.compiled.len() - 1
+            }
+        };
+        let compiled = self.functions[&Rc::as_ptr(&body)].compiled[index]
+            .1
+            .as_ref()?;
+        compiled.call(function, &values, calls_left, visits_left)
+    }
+}
+
+// The type of an argument and its bits, for the values compiled code takes
+fn argument(value: &Value) -> Option<(Type, u64)> {
+    match value {
+        Value::Number(NumberType::Integer(number)) => Some((Type::Integer, number.value() as u64)),
+        Value::Number(NumberType::Float(number)) => Some((Type::Float, number.value().to_bits())),
+        Value::Boolean(boolean) => Some((Type::Boolean, *boolean as u64)),
+        _ => None,
+    }
+}
+
+struct Compiled {
+    code: Code,
+    returns: Option<Type>,
+    // How deep the nodes of the body nest, for what each call adds to the visit depth
+    nesting: usize,
+    // Functions and builtins the code does itself, as they were when it was compiled
+    names: Vec<(String, Value)>,
+}
+
+impl Compiled {
+    fn new(
+        function: &Function,
+        types: &[Type],
+        counters: &HashMap<*const Node, Counter>,
+    ) -> Option<Self> {
+        let name = function.name();
+        let body = function.body();
+        let params = typed_params(function, types)?;
+        let closure = function.closure();
+
+        // The helpers it calls go before it in the module, where they are inlined. They
+        // are lowered alone, so they can only call themselves and builtins.
+        let mut definitions = Vec::new();
+        let mut names = Vec::new();
+        let mut deepest = nesting(&body);
+        let mut callees = Vec::new();
+        called_names(&body, &mut callees);
+        let mut next = 0;
+        while next < callees.len() {
+            let callee = callees[next].clone();
+            next += 1;
+            if callee == name {
+                continue;
+            }
+            let value = closure.borrow().get(&callee)?;
+            if let Value::Function(helper) = &value {
+                if !Rc::ptr_eq(&helper.closure(), &closure) {
+                    return None;
+                }
+                let types = counters.get(&Rc::as_ptr(&helper.body()))?.types.as_ref()?;
+                let params = typed_params(helper, types)?;
+                definitions.push(definition(helper, params, &[])?.0);
+                called_names(&helper.body(), &mut callees);
+                deepest = deepest.max(nesting(&body) + nesting(&helper.body()));
+            }
+            names.push((callee, value));
+        }
+        for (callee, value) in &names {
+            match value {
+                Value::Function(_) => (),
+                Value::Native(native) if native.name() == "liu" && callee == "liu" => (),
+                _ => return None,
+            }
+        }
+
+        let (_, mut module) = definition(function, params, &definitions)?;
+        passes::optimize(&mut module, &Optimizations::new(2), |_, _| ());
+        let index = module.functions.len() - 1;
+        let lowered = &module.functions[index];
+        let only_itself = lowered
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .all(|inst| !matches!(inst, Inst::Call { function, .. } if *function != index));
+        if !only_itself {
+            return None;
+        }
+        let source = generate(lowered).ok()?;
+        let object = x86::assemble(&source).ok()?;
+        if !object.relocations.is_empty() {
+            return None;
+        }
+        Some(Self {
+            code: Code::new(&object.sections[x86::TEXT].data)?,
+            returns: lowered.returns.clone(),
+            nesting: deepest,
+            names,
+        })
+    }
+
+    fn call(
+        &self,
+        function: &Function,
+        args: &[u64],
+        calls_left: Option<usize>,
+        visits_left: usize,
+    ) -> Option<Value> {
+        // The code calls itself, the helpers and the builtins directly, so the names must
+        // still mean them
+        let closure = function.closure();
+        let closure = closure.borrow();
+        match closure.get(&function.name()) {
+            Some(Value::Function(named)) if named == *function => (),
+            _ => return None,
+        }
+        for (name, value) in &self.names {
+            match closure.get(name) {
+                Some(current) if current.equals(value) => (),
+                _ => return None,
+            }
+        }
+
+        // Each call nests the nodes of the body once more
+        let levels = visits_left / (self.nesting + 1);
+        if levels == 0 {
+            return None;
+        }
+        let mut budget = levels as i64 - 2;
+        if let Some(calls_left) = calls_left {
+            budget = budget.min(calls_left as i64 - 1);
+        }
+
+        let mut result = 0;
+        if self.code.run(args, &mut result, budget) != 0 {
+            return None;
+        }
+        let value = match self.returns {
+            Some(Type::Integer) => {
+                Value::Number(NumberType::Integer(Number::new_no_pos(result as i64)))
+            }
+            Some(Type::Float) => Value::Number(NumberType::Float(Number::new_no_pos(
+                f64::from_bits(result),
+            ))),
+            Some(Type::Boolean) => Value::Boolean(result != 0),
+            _ => Value::Nil,
+        };
+        Some(value)
+    }
+}
+
+// The parameters of a function annotated with the types of its arguments, when the IR can
+// lower its body like the interpreter runs it
+fn typed_params(function: &Function, types: &[Type]) -> Option<Vec<(Token, Option<Token>)>> {
+    let mut params = Vec::with_capacity(types.len());
+    for (param, type_) in function.params().into_iter().zip(types) {
+        params.push((param, Some(type_token(type_))));
+    }
+    match is_pure(&function.name(), &params, &function.body()) {
+        true => Some(params),
+        false => None,
+    }
+}
+
+// The definition of a function with typed parameters, lowered after the definitions of the
+// functions it calls. The interpreter doesnt know the return type, so each is tried.
+fn definition(
+    function: &Function,
+    params: Vec<(Token, Option<Token>)>,
+    before: &[Node],
+) -> Option<(Node, ir::Module)> {
+    let returns = [
+        None,
+        Some(Type::Integer),
+        Some(Type::Float),
+        Some(Type::Boolean),
+    ];
+    returns.iter().find_map(|returns| {
+        let definition = Node::FuncDefNode(
+            Token::new_no_pos(TokenType::Identifier(function.name())),
+            params.clone(),
+            returns.as_ref().map(type_token),
+            function.body(),
+        );
+        let mut definitions = before.to_vec();
+        definitions.push(definition.clone());
+        let module = ir::lower(&Node::StatementsNode(definitions)).ok()?;
+        Some((definition, module))
+    })
+}
+
+fn type_token(type_: &Type) -> Token {
+    Token::new_no_pos(TokenType::Identifier(type_.to_string()))
+}
+
+// The names of the functions a node calls, in order of appearance
+fn called_names(node: &Node, names: &mut Vec<String>) {
+    if let Node::CallNode(token, _) = node {
+        if let TokenType::Identifier(name) = token.type_() {
+            ast::push_unique(names, name);
+        }
+    }
+    for child in node.children() {
+        called_names(child, names);
+    }
+}
+
+// How deep the nodes of a tree nest, counting the root
+fn nesting(node: &Node) -> usize {
+    1 + node.children().into_iter().map(nesting).max().unwrap_or(0)
+}
+
+// Whether the IR of a function computes the same as the interpreter running its body: it
+// doesnt define functions, the names of the function and what it calls are not variables, and
+// variables are set before they are read. The IR sets variables that are not to zero, where
+// the interpreter would read the one outside the function.
+fn is_pure(name: &str, params: &[(Token, Option<Token>)], body: &Node) -> bool {
+    let mut set = HashSet::new();
+    for (param, _) in params {
+        match param.type_() {
+            TokenType::Identifier(param) => set.insert(param),
+            _ => return false,
+        };
+    }
+    let mut locals = HashSet::new();
+    if !collect_locals(body, &mut locals) {
+        return false;
+    }
+    let mut reserved = vec![name.to_string()];
+    called_names(body, &mut reserved);
+    if reserved
+        .iter()
+        .any(|name| set.contains(name) || locals.contains(name))
+    {
+        return false;
+    }
+    sets_before_reads(body, &locals, &mut set)
+}
+
+fn collect_locals(node: &Node, locals: &mut HashSet<String>) -> bool {
+    match node {
+        Node::FuncDefNode(..) => return false,
+        Node::VarAssignNode(token, _, _) => match token.type_() {
+            TokenType::Identifier(name) => {
+                locals.insert(name);
+            }
+            _ => return false,
+        },
+        _ => (),
+    }
+    node.children()
+        .into_iter()
+        .all(|child| collect_locals(child, locals))
+}
+
+// Follows the order the interpreter visits the nodes in, with `set` holding the variables
+// set on every path so far
+fn sets_before_reads(node: &Node, locals: &HashSet<String>, set: &mut HashSet<String>) -> bool {
+    match node {
+        Node::VarAccessNode(token) => match token.type_() {
+            TokenType::Identifier(name) => !locals.contains(&name) || set.contains(&name),
+            _ => false,
+        },
+        Node::VarAssignNode(token, _, value) => {
+            if !sets_before_reads(value, locals, set) {
+                return false;
+            }
+            if let TokenType::Identifier(name) = token.type_() {
+                set.insert(name);
+            }
+            true
+        }
+        Node::Binop(..) => {
+            let operations = node.binop_chain();
+            sets_before_reads(operations[0].0, locals, set)
+                && operations
+                    .into_iter()
+                    .all(|(_, optok, right)| match optok.type_() {
+                        // 'ja' and 'tai' may skip the right side
+                        TokenType::Keyword(_) => sets_before_reads(right, locals, &mut set.clone()),
+                        _ => sets_before_reads(right, locals, set),
+                    })
+        }
+        Node::IfNode(_, cases, otherwise) => {
+            let mut after: Option<HashSet<String>> = None;
+            let mut meet = |branch: HashSet<String>| {
+                after = Some(match after.take() {
+                    Some(after) => after.intersection(&branch).cloned().collect(),
+                    None => branch,
+                });
+            };
+            for (condition, body) in cases {
+                if !sets_before_reads(condition, locals, set) {
+                    return false;
+                }
+                let mut branch = set.clone();
+                if !sets_before_reads(body, locals, &mut branch) {
+                    return false;
+                }
+                meet(branch);
+            }
+            let mut branch = set.clone();
+            if let Some(otherwise) = otherwise {
+                if !sets_before_reads(otherwise, locals, &mut branch) {
+                    return false;
+                }
+            }
+            meet(branch);
+            *set = after.unwrap_or_default();
+            true
+        }
+        Node::WhileNode(_, condition, body) => {
+            sets_before_reads(condition, locals, set)
+                && sets_before_reads(body, locals, &mut set.clone())
+        }
+        node => node
+            .children()
+            .into_iter()
+            .all(|child| sets_before_reads(child, locals, set)),
+    }
+}
+
+// Code generation
+
+// Generates the assembly of a function whose calls are all to itself, the others having
+// been inlined
+fn generate(function: &ir::Function) -> Result<String, String> {
+    let mut generator = Generator {
+        function,
+        code: String::new(),
+        labels: 0,
+    };
+    generator.function()?;
+    Ok(generator.code)
+}
+
+struct Generator<'a> {
+    function: &'a ir::Function,
+    code: String,
+    labels: usize,
+}
+
+impl Generator<'_> {
+    fn line(&mut self, line: &str) {
+        self.code.push_str("    ");
+        self.code.push_str(line);
+        self.code.push('\n');
+    }
+
+    fn place_label(&mut self, label: &str) {
+        let _ = writeln!(self.code, "{}:", label);
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    fn slot(reg: Reg) -> usize {
+        FRAME_HEADER + 8 * (reg.0 + 1)
+    }
+
+    fn load(&mut self, operand: &Operand, register: &str) -> Result<(), String> {
+        let line = match operand {
+            Operand::Reg(reg) => {
+                format!("mov {}, qword ptr [rbp - {}]", register, Self::slot(*reg))
+            }
+            Operand::Const(value) => {
+                let bits = match value {
+                    Const::Int(int) => *int,
+                    Const::Float(float) => float.to_bits() as i64,
+                    Const::Bool(boolean) => *boolean as i64,
+                    Const::Text(_) => return Err("teksti values cant be compiled".to_string()),
+                };
+                format!("movabs {}, {}", register, bits)
+            }
+        };
+        self.line(&line);
+        Ok(())
+    }
+
+    fn store(&mut self, reg: Reg, register: &str) {
+        self.line(&format!(
+            "mov qword ptr [rbp - {}], {}",
+            Self::slot(reg),
+            register
+        ));
+    }
+
+    fn type_of(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Reg(reg) => self.function.regs[reg.0].type_.clone(),
+            Operand::Const(value) => value.type_(),
+        }
+    }
+
+    fn function(&mut self) -> Result<(), String> {
+        let frame = (FRAME_HEADER + 8 * self.function.regs.len()).div_ceil(16) * 16;
+        self.place_label(".Lentry");
+        self.line("push rbp");
+        self.line("mov rbp, rsp");
+        self.line(&format!("sub rsp, {}", frame));
+        self.line("mov qword ptr [rbp - 8], rsi");
+        self.line("mov qword ptr [rbp - 16], rdx");
+        for (i, param) in self.function.params.clone().into_iter().enumerate() {
+            self.line(&format!("mov rax, qword ptr [rdi + {}]", 8 * i));
+            self.store(param, "rax");
+        }
+        self.place_label(".Lbody");
+
+        let function = self.function;
+        for (i, block) in function.blocks.iter().enumerate() {
+            self.place_label(&format!(".Lb{}", i));
+            for inst in &block.insts {
+                self.inst(inst)?;
+            }
+            self.terminator(BlockId(i), &block.terminator)?;
+        }
+
+        self.place_label(".Lbail");
+        self.line(&format!("mov rax, {}", BAIL));
+        self.line("leave");
+        self.line("ret");
+        Ok(())
+    }
+
+    fn terminator(&mut self, block: BlockId, terminator: &Terminator) -> Result<(), String> {
+        match terminator {
+            Terminator::Jump(target) => self.edge(block, *target),
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let label = self.new_label();
+                self.load(condition, "rax")?;
+                self.line("test rax, rax");
+                self.line(&format!("jz {}", label));
+                self.edge(block, *then)?;
+                self.place_label(&label);
+                self.edge(block, *otherwise)
+            }
+            Terminator::Return(value) => {
+                if let Some(value) = value {
+                    self.load(value, "rax")?;
+                    self.line("mov rcx, qword ptr [rbp - 8]");
+                    self.line("mov qword ptr [rcx], rax");
+                }
+                self.line("mov rax, 0");
+                self.line("leave");
+                self.line("ret");
+                Ok(())
+            }
+        }
+    }
+
+    // Sets the phis of `to` for coming from `from`, all reading their values before any
+    // is set, and jumps there
+    fn edge(&mut self, from: BlockId, to: BlockId) -> Result<(), String> {
+        let mut phis = Vec::new();
+        for inst in &self.function.block(to).insts {
+            if let Inst::Phi { dest, incoming } = inst {
+                if let Some((_, value)) = incoming.iter().find(|(block, _)| *block == from) {
+                    phis.push((*dest, value.clone()));
+                }
+            }
+        }
+        for (_, value) in &phis {
+            self.load(value, "rax")?;
+            self.line("push rax");
+        }
+        for (dest, _) in phis.iter().rev() {
+            self.line("pop rax");
+            self.store(*dest, "rax");
+        }
+        self.line(&format!("jmp .Lb{}", to.0));
+        Ok(())
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Phi { .. } => Ok(()),
+            Inst::Copy { dest, value } => {
+                self.load(value, "rax")?;
+                self.store(*dest, "rax");
+                Ok(())
+            }
+            Inst::Binary {
+                dest,
+                op,
+                left,
+                right,
+                ..
+            } => self.binary(*dest, *op, left, right),
+            Inst::Unary {
+                dest, op, value, ..
+            } => {
+                self.load(value, "rax")?;
+                match (op, self.type_of(value)) {
+                    (UnaryOp::Neg, Type::Integer) => {
+                        self.line("neg rax");
+                        self.line("jo .Lbail");
+                    }
+                    (UnaryOp::Neg, _) => {
+                        self.line(&format!("movabs rcx, {}", i64::MIN));
+                        self.line("xor rax, rcx");
+                    }
+                    (UnaryOp::Not, _) => self.line("xor rax, 1"),
+                }
+                self.store(*dest, "rax");
+                Ok(())
+            }
+            Inst::Convert { dest, value } => {
+                self.load(value, "rax")?;
+                self.line("cvtsi2sd xmm0, rax");
+                self.line("movq rax, xmm0");
+                self.store(*dest, "rax");
+                Ok(())
+            }
+            Inst::Call {
+                dest, args, tail, ..
+            } => match tail {
+                true => self.tail_call(args),
+                false => self.call(*dest, args),
+            },
+            // An inlined call nests like a call
+            Inst::Enter { .. } => {
+                self.line("mov rdx, qword ptr [rbp - 16]");
+                self.line("test rdx, rdx");
+                self.line("js .Lbail");
+                Ok(())
+            }
+            Inst::Load { .. } | Inst::Store { .. } | Inst::Print { .. } => {
+                Err(format!("cant compile {:?}", inst))
+            }
+        }
+    }
+
+    fn binary(
+        &mut self,
+        dest: Reg,
+        op: BinaryOp,
+        left: &Operand,
+        right: &Operand,
+    ) -> Result<(), String> {
+        let type_ = self.type_of(left);
+        if op == BinaryOp::Pow {
+            return self.power(dest, &type_, left, right);
+        }
+        self.load(left, "rax")?;
+        self.load(right, "rcx")?;
+        match (op, type_) {
+            (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul, Type::Integer) => {
+                let mnemonic = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    _ => "imul",
+                };
+                self.line(&format!("{} rax, rcx", mnemonic));
+                self.line("jo .Lbail");
+            }
+            (BinaryOp::Div, Type::Integer) => {
+                // Dividing the smallest kok by -1 overflows
+                let label = self.new_label();
+                self.line("test rcx, rcx");
+                self.line("jz .Lbail");
+                self.line("cmp rcx, -1");
+                self.line(&format!("jne {}", label));
+                self.line(&format!("movabs rdx, {}", i64::MIN));
+                self.line("cmp rax, rdx");
+                self.line("je .Lbail");
+                self.place_label(&label);
+                self.line("cqo");
+                self.line("idiv rcx");
+            }
+            (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div, _) => {
+                self.line("movq xmm0, rax");
+                self.line("movq xmm1, rcx");
+                if op == BinaryOp::Div {
+                    // Only zero divides by zero, not nan
+                    let label = self.new_label();
+                    self.line("xorpd xmm2, xmm2");
+                    self.line("ucomisd xmm1, xmm2");
+                    self.line(&format!("jp {}", label));
+                    self.line("je .Lbail");
+                    self.place_label(&label);
+                }
+                let mnemonic = match op {
+                    BinaryOp::Add => "addsd",
+                    BinaryOp::Sub => "subsd",
+                    BinaryOp::Mul => "mulsd",
+                    _ => "divsd",
+                };
+                self.line(&format!("{} xmm0, xmm1", mnemonic));
+                self.line("movq rax, xmm0");
+            }
+            (_, Type::Float) => {
+                self.line("movq xmm0, rax");
+                self.line("movq xmm1, rcx");
+                // Comparisons with nan are false, and nan is not equal to anything
+                match op {
+                    BinaryOp::Eq => {
+                        self.line("ucomisd xmm0, xmm1");
+                        self.line("sete al");
+                        self.line("setnp cl");
+                        self.line("movzx eax, al");
+                        self.line("movzx ecx, cl");
+                        self.line("and rax, rcx");
+                    }
+                    BinaryOp::Ne => {
+                        self.line("ucomisd xmm0, xmm1");
+                        self.line("setne al");
+                        self.line("setp cl");
+                        self.line("movzx eax, al");
+                        self.line("movzx ecx, cl");
+                        self.line("or rax, rcx");
+                    }
+                    _ => {
+                        let (first, second, suffix) = match op {
+                            BinaryOp::Gt => ("xmm0", "xmm1", "a"),
+                            BinaryOp::Ge => ("xmm0", "xmm1", "ae"),
+                            BinaryOp::Lt => ("xmm1", "xmm0", "a"),
+                            _ => ("xmm1", "xmm0", "ae"),
+                        };
+                        self.line(&format!("ucomisd {}, {}", first, second));
+                        self.line(&format!("set{} al", suffix));
+                        self.line("movzx eax, al");
+                    }
+                }
+            }
+            (_, _) => {
+                let suffix = match op {
+                    BinaryOp::Eq => "e",
+                    BinaryOp::Ne => "ne",
+                    BinaryOp::Lt => "l",
+                    BinaryOp::Gt => "g",
+                    BinaryOp::Le => "le",
+                    _ => "ge",
+                };
+                self.line("cmp rax, rcx");
+                self.line(&format!("set{} al", suffix));
+                self.line("movzx eax, al");
+            }
+        }
+        self.store(dest, "rax");
+        Ok(())
+    }
+
+    // Powers are computed by `power` like the IR computes them
+    fn power(
+        &mut self,
+        dest: Reg,
+        base: &Type,
+        left: &Operand,
+        right: &Operand,
+    ) -> Result<(), String> {
+        let types = match (base, self.type_of(right)) {
+            (Type::Integer, _) => POWER_KOK,
+            (_, Type::Integer) => POWER_LIU_KOK,
+            _ => POWER_LIU,
+        };
+        self.load(left, "rsi")?;
+        self.load(right, "rdx")?;
+        self.line(&format!("mov rdi, {}", types));
+        self.line(&format!("lea rcx, qword ptr [rbp - {}]", Self::slot(dest)));
+        self.line(&format!(
+            "movabs rax, {}",
+            power as *const () as usize as i64
+        ));
+        self.line("call rax");
+        self.line("test rax, rax");
+        self.line("jnz .Lbail");
+        Ok(())
+    }
+
+    // Calls the function itself with the arguments in an array on the stack. The budget
+    // runs out where the interpreter would stop the call.
+    fn call(&mut self, dest: Option<Reg>, args: &[Operand]) -> Result<(), String> {
+        let size = (8 * args.len()).div_ceil(16) * 16;
+        self.line("mov rdx, qword ptr [rbp - 16]");
+        self.line("test rdx, rdx");
+        self.line("js .Lbail");
+        self.line(&format!("sub rsp, {}", size));
+        for (i, arg) in args.iter().enumerate() {
+            self.load(arg, "rax")?;
+            self.line(&format!("mov qword ptr [rsp + {}], rax", 8 * i));
+        }
+        self.line("sub rdx, 1");
+        self.line("mov rdi, rsp");
+        self.line("lea rsi, qword ptr [rbp - 24]");
+        self.line("call .Lentry");
+        self.line(&format!("add rsp, {}", size));
+        self.line("test rax, rax");
+        self.line("jnz .Lbail");
+        if let Some(dest) = dest {
+            self.line("mov rax, qword ptr [rbp - 24]");
+            self.store(dest, "rax");
+        }
+        Ok(())
+    }
+
+    // Starts the body over with new arguments, like the interpreter reuses the frame
+    fn tail_call(&mut self, args: &[Operand]) -> Result<(), String> {
+        for arg in args {
+            self.load(arg, "rax")?;
+            self.line("push rax");
+        }
+        for param in self.function.params.clone().into_iter().rev() {
+            self.line("pop rax");
+            self.store(param, "rax");
+        }
+        self.line("jmp .Lbody");
+        Ok(())
+    }
+}
+
+// The operand types of `power`
+const POWER_KOK: u64 = 0;
+const POWER_LIU_KOK: u64 = 1;
+const POWER_LIU: u64 = 2;
+
+extern "C" fn power(types: u64, base: u64, exponent: u64, result: *mut u64) -> u64 {
+    let (base, exponent) = match types {
+        POWER_KOK => (Const::Int(base as i64), Const::Int(exponent as i64)),
+        POWER_LIU_KOK => (
+            Const::Float(f64::from_bits(base)),
+            Const::Int(exponent as i64),
+        ),
+        _ => (
+            Const::Float(f64::from_bits(base)),
+            Const::Float(f64::from_bits(exponent)),
+        ),
+    };
+    let bits = match ir::evaluate_binary(BinaryOp::Pow, &base, &exponent) {
+        Ok(Const::Int(int)) => int as u64,
+        Ok(Const::Float(float)) => float.to_bits(),
+        _ => return BAIL,
+    };
+    // The compiled code passes the slot of the result
+    unsafe { *result = bits };
+    0
+}
+
+// Machine code
+
+// Memory mapped executable with code in it
+struct Code {
+    address: *mut u8,
+    size: usize,
+}
+
+type Entry = extern "C" fn(*const u64, *mut u64, i64) -> u64;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod mmap {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const PROT_EXEC: c_int = 4;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+
+    extern "C" {
+        pub fn mmap(
+            address: *mut c_void,
+            length: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        pub fn mprotect(address: *mut c_void, length: usize, prot: c_int) -> c_int;
+        pub fn munmap(address: *mut c_void, length: usize) -> c_int;
+    }
+}
+
+impl Code {
+    // Maps the code writable to copy it there, then executable
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn new(bytes: &[u8]) -> Option<Self> {
+        use mmap::*;
+        let size = bytes.len().max(1);
+        unsafe {
+            let address = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            // MAP_FAILED
+            if address as isize == -1 {
+                return None;
+            }
+            let code = Self {
+                address: address as *mut u8,
+                size,
+            };
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), code.address, bytes.len());
+            match mprotect(address, size, PROT_READ | PROT_EXEC) {
+                0 => Some(code),
+                _ => None,
+            }
+        }
+    }
+
+    // Only x86-64 code for Linux is generated
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn new(_bytes: &[u8]) -> Option<Self> {
+        None
+    }
+
+    fn run(&self, args: &[u64], result: &mut u64, budget: i64) -> u64 {
+        // The code starts with the entry of the function, which follows the C convention
+        let entry = unsafe { std::mem::transmute::<*mut u8, Entry>(self.address) };
+        entry(args.as_ptr(), result, budget)
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        unsafe {
+            mmap::munmap(self.address as *mut _, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins;
+    use crate::context::Context;
+    use crate::engine::{Engine, OutputBuffer};
+    use crate::interpeter::{self, Interpeter};
+    use crate::ir::tests::PROGRAMS;
+    use crate::lexer::Lexer;
+    use crate::limits::Limits;
+    use crate::parser::Parser;
+    use crate::symbols::SymbolMap;
+    use crate::value::Value;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // What a program prints to stdout and stderr
+    fn run(text: &str, threshold: Option<u64>, limits: Limits) -> (String, String) {
+        let text = text.to_string();
+        interpeter::with_stack(move || {
+            let mut engine = Engine::new();
+            let output = OutputBuffer::new();
+            engine.set_output(output.clone());
+            engine.set_limits(limits);
+            engine.set_jit_threshold(threshold);
+            let errors = match engine.eval_file("test.fin", &text) {
+                Ok(_) => String::new(),
+                Err(diagnostics) => format!("{}\n", diagnostics),
+            };
+            (output.contents(), errors)
+        })
+    }
+
+    fn assert_same(text: &str, limits: Limits) {
+        let expected = run(text, None, limits.clone());
+        for threshold in [0, 3] {
+            assert_eq!(
+                run(text, Some(threshold), limits.clone()),
+                expected,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_same_as_interpeter() {
+        let programs = [
+            "tominto f(a, b) { palata a + b }; tulosta(f(1, 2), f(1.5, 2.5), f(9223372036854775807, 0)); f(9223372036854775807, 1)",
+            "tominto f(a, b) { palata a * b - 1 }; tulosta(f(3, 4)); f(4611686018427387904, 2)",
+            "tominto f(a, b) { palata a / b }; tulosta(f(7, 2), f(-7, 2), f(1.0, 3.0), f(1.0, 0.0 / 1.0 * 0.0)); f(1, 0)",
+            "tominto f(a, b) { palata a / b }; tulosta(f(7, -1)); f(-9223372036854775807 - 1, -1)",
+            "tominto f(a, b) { palata a / b }; tulosta(f(1.0, 2.0)); f(1.0, -0.0)",
+            "tominto f(a) { palata -a }; tulosta(f(1), f(0.0), f(-2.5)); f(-9223372036854775807 - 1)",
+            "tominto f(a, b) { palata a ^ b }; tulosta(f(2, 10), f(2.0, -2), f(2.0, 0.5), f(3, -1), f(0.5, 3)); f(2, 63)",
+            "tominto f(a, b) { palata a ^ b }; f(0, -1)",
+            "tominto f(a, b) { palata a ^ b }; f(-2.0, 0.5)",
+            "tominto f(a, b) { palata [a < b, a <= b, a > b, a >= b, a == b, a != b] }; tulosta(f(1, 2), f(2, 2))",
+            "tominto f(a, b) { palata a < b tai a == b ja ei (a != b) }; tulosta(f(1, 2), f(2, 2), f(2.5, 1.0), f(tosi, tosi))",
+            "muut n = 0.0 / 1.0 * 0.0; tominto f(a, b) { palata a < b tai a >= b tai a == b }; tominto g(a, b) { palata a != b }; tulosta(f(n, n), g(n, n), f(1.0, n))",
+            "tominto f(n) { jos n < 2 { palata n }; palata f(n - 1) + f(n - 2) }; tulosta(f(20), f(2.0))",
+            "tominto f(n) { jos n == 0 { palata 0 }; palata 1 + f(n - 1) }; tulosta(f(990)); f(1000)",
+            "tominto f(n) { jos n == 0 { palata 0 }; palata 1 + f(n - 1) }; tominto g(n) { palata f(n) }; tulosta(g(998)); g(999)",
+            "tominto f(n, s) { jos n == 0 { palata s }; palata f(n - 1, s + n) }; tulosta(f(100000, 0)); f(10, 9223372036854775807)",
+            "tominto f(n) { jos n == 0 { palata 1 / n }; palata f(n - 1) }; f(5)",
+            "tominto f(n) { muut s = 0; muut i = 1; kun i <= n { muut s = s + liu(i) / 2.0; muut i = i + 1 }; palata s }; tulosta(f(100), f(0))",
+            "tominto f(n) { tulosta(n); palata n }; tulosta(f(1) + f(2))",
+            "muut x = 10; tominto f(n) { palata n + x }; tulosta(f(1)); muut x = 20; tulosta(f(1))",
+            "muut x = 10; tominto f(n) { jos n > 0 { muut x = 1 }; palata x }; tulosta(f(1), f(0))",
+            "tominto f(n) { palata n * 2 }; tulosta(f(2)); muut g = f; tominto f(n) { palata n * 3 }; tulosta(g(2), f(2))",
+            "tominto f(n) { jos n == 0 { palata 0 }; palata f(n - 1) }; muut g = f; tominto f(n) { palata 7 }; tulosta(g(3))",
+            "tominto f(n) { palata liu(n) }; tulosta(f(1)); muut liu = tominto(n) { palata 2 }; tulosta(f(1))",
+            "tominto f(n) { palata n }; tulosta(f(1), f(1.5), f(tosi), f(\"a\"), f([1]))",
+            "tominto f(n) { jos n > 0 { palata 1 } }; tulosta(f(1), f(0))",
+            "tominto f(a, b) { jos a { palata b }; palata -b }; tulosta(f(tosi, 1.5), f(epätosi, 2))",
+            "tominto apu(a, b) { palata a / b }; tominto f(n) { palata apu(10, n) + 1 }; tulosta(f(1), f(2), f(3), f(4)); f(0)",
+            "tominto apu(n) { palata n + 1 }; tominto f(n) { jos n == 0 { palata apu(n) }; palata 1 + f(n - 1) }; tulosta(f(0), f(0), f(0), f(998)); f(999)",
+            "tominto apu(n) { palata liu(n) / 2.0 }; tominto f(n) { palata apu(n) + apu(n + 1) }; tulosta(f(1), f(2), f(3), f(4))",
+            "tominto apu(n) { palata n * 2 }; tominto f(n) { palata apu(n) }; tulosta(f(1), f(2), f(3)); tominto apu(n) { palata n * 3 }; tulosta(f(1), f(2))",
+        ];
+        for text in programs.iter().chain(&PROGRAMS) {
+            assert_same(text, Limits::new());
+        }
+
+        // Calls nested deeper than the interpreter visits nodes, and ones that stop at a limit
+        let deep = "tominto f(n) { jos n == 0 { palata 0 }; palata 1 + f(n - 1) }; tulosta(f(1000)); f(4000)";
+        assert_same(deep, Limits::new().with_max_depth(100000));
+        let steps =
+            "tominto f(n) { jos n == 0 { palata 0 }; palata 1 + f(n - 1) }; tulosta(f(10), f(100))";
+        assert_same(steps, Limits::new().with_max_steps(2000));
+    }
+
+    #[test]
+    fn test_compiled() {
+        let text = "
+tominto fib(n) { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }
+tominto kerro(a, b) { palata a * liu(b) }
+tominto näytä(n) { tulosta(n) }
+tominto lue(n) { palata n + x }
+tominto muu(n) { palata fib(n) }
+tominto lista(n) { palata [n] }
+tominto apu(n) { palata n * 2 }
+tominto käytä(n) { palata apu(n) + apu(n + 1) }
+muut x = 1
+muut i = 0
+kun i < 3 { fib(10); kerro(1.5, 2); näytä(1); lue(1); muu(1); lista(1); käytä(1); muut i = i + 1 }";
+        let tokens = Lexer::new("test.fin".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        let root = Parser::new(tokens).parse().unwrap();
+        let mut symbol_map = SymbolMap::new();
+        builtins::prelude().install(&mut symbol_map);
+        let symbol_map = symbol_map.shared();
+        let mut context = Context::init("Program");
+        context.set_symbol_map(symbol_map.clone());
+
+        let mut interpeter = Interpeter::new();
+        interpeter.set_output(Rc::new(RefCell::new(io::sink())));
+        interpeter.set_jit_threshold(Some(2));
+        interpeter.run(&root, context).unwrap();
+        for (name, compiled) in [
+            ("fib", true),
+            ("kerro", true),
+            ("näytä", false),
+            ("lue", false),
+            ("muu", false),
+            ("lista", false),
+            ("käytä", true),
+        ] {
+            let function = match symbol_map.borrow().get(name) {
+                Some(Value::Function(function)) => function,
+                other => panic!("{} is {:?}", name, other),
+            };
+            assert_eq!(interpeter.is_compiled(&function), compiled, "{}", name);
+        }
+    }
+}
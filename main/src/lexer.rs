@@ -1,16 +1,23 @@
-use crate::errors::DisallowedCharError;
-use crate::errors::ErrorType;
+use crate::errors::{DisallowedCharError, ErrorType, SyntaxError};
 use crate::position::Position;
 use crate::token::{
     Token,
     TokenType::{
-        self, Divide, EndOfFile, Equal, Float, Int, LParen, Minus, Multiply, Plus, Pow, RParen,
+        self, Colon, Comma, Divide, Dot, EndOfFile, Equal, EqualEqual, Float, GreaterThan,
+        GreaterThanEqual, Int, LBrace, LBracket, LParen, LessThan, LessThanEqual, Minus, Multiply,
+        Newline, NotEqual, Plus, Pow, RBrace, RBracket, RParen,
     },
 };
+use num::BigInt;
+
+pub const KEYWORDS: [&str; 12] = [
+    "muut", "tominto", "palata", "jos", "muuten", "kun", "tosi", "epätosi", "tyhjä", "ja", "tai",
+    "ei",
+];
 
 #[derive(Debug)]
 pub struct Lexer {
-    text: String,
+    chars: Vec<char>,
     pos: Position,
     current_char: Option<char>,
 }
@@ -18,7 +25,7 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(file_name: String, text: String) -> Self {
         let mut lexer = Self {
-            text: text.clone(),
+            chars: text.chars().collect(),
             pos: Position::new(-1, 0, -1, file_name, text),
             current_char: None,
         };
@@ -28,33 +35,56 @@ impl Lexer {
 
     fn advance(&mut self) {
         self.pos.advance(self.current_char);
-        if self.pos.index() < self.text.len() as i64 {
-            self.current_char = Some(self.text.chars().nth(self.pos.index() as usize).unwrap());
-        } else {
-            self.current_char = None;
-        }
+        self.current_char = self.chars.get(self.pos.index() as usize).copied();
     }
 
     // TODO CHECK IF ERROR IN RESULT RETURN SHOULD BE ERROR TRAIT / ILLIGALCHAR STRUCT
     pub fn tokenize(&mut self) -> Result<Vec<Token>, ErrorType> {
-        let keywords = vec!["muut".to_string()];
+        let keywords: Vec<String> = KEYWORDS.iter().map(|k| k.to_string()).collect();
         let mut tokens: Vec<Token> = Vec::new();
         while let Some(current) = self.current_char {
             match current {
-                ' ' | '\t' => (),
+                ' ' | '\t' | '\r' => (),
+                '\n' | ';' => tokens.push(Token::new(Newline, Some(self.pos.clone()), None)),
                 '+' => tokens.push(Token::new(Plus, Some(self.pos.clone()), None)),
                 '-' => tokens.push(Token::new(Minus, Some(self.pos.clone()), None)),
                 '*' => tokens.push(Token::new(Multiply, Some(self.pos.clone()), None)),
                 '/' => tokens.push(Token::new(Divide, Some(self.pos.clone()), None)),
                 '(' => tokens.push(Token::new(LParen, Some(self.pos.clone()), None)),
                 '^' => tokens.push(Token::new(Pow, Some(self.pos.clone()), None)),
-                '=' => tokens.push(Token::new(Equal, Some(self.pos.clone()), None)),
+                '=' => {
+                    tokens.push(self.construct_comparison(Equal, EqualEqual));
+                    continue;
+                }
+                '<' => {
+                    tokens.push(self.construct_comparison(LessThan, LessThanEqual));
+                    continue;
+                }
+                '>' => {
+                    tokens.push(self.construct_comparison(GreaterThan, GreaterThanEqual));
+                    continue;
+                }
+                '!' => {
+                    tokens.push(self.construct_not_equal()?);
+                    continue;
+                }
                 ')' => tokens.push(Token::new(RParen, Some(self.pos.clone()), None)),
+                ',' => tokens.push(Token::new(Comma, Some(self.pos.clone()), None)),
+                '[' => tokens.push(Token::new(LBracket, Some(self.pos.clone()), None)),
+                ']' => tokens.push(Token::new(RBracket, Some(self.pos.clone()), None)),
+                '{' => tokens.push(Token::new(LBrace, Some(self.pos.clone()), None)),
+                '}' => tokens.push(Token::new(RBrace, Some(self.pos.clone()), None)),
+                ':' => tokens.push(Token::new(Colon, Some(self.pos.clone()), None)),
+                '.' => tokens.push(Token::new(Dot, Some(self.pos.clone()), None)),
+                '"' => {
+                    tokens.push(self.construct_string()?);
+                    continue;
+                }
                 '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
-                    tokens.push(self.construct_number());
+                    tokens.push(self.construct_number()?);
                     continue;
                 }
-                a if a.is_ascii_alphabetic() || a == '_' => {
+                a if a.is_alphabetic() || a == '_' => {
                     tokens.push(self.construct_identifier(keywords.clone()));
                     continue;
                 }
@@ -80,62 +110,151 @@ impl Lexer {
         ));
         Ok(tokens)
     }
+    // Single character operator, or the two character version if followed by '='
+    fn construct_comparison(&mut self, single: TokenType, with_equal: TokenType) -> Token {
+        let pos_start = self.pos.clone();
+        self.advance();
+        if self.current_char == Some('=') {
+            self.advance();
+            return Token::new(with_equal, Some(pos_start), Some(self.pos.clone()));
+        }
+        Token::new(single, Some(pos_start), Some(self.pos.clone()))
+    }
+
+    fn construct_not_equal(&mut self) -> Result<Token, ErrorType> {
+        let pos_start = self.pos.clone();
+        self.advance();
+        if self.current_char == Some('=') {
+            self.advance();
+            return Ok(Token::new(
+                NotEqual,
+                Some(pos_start),
+                Some(self.pos.clone()),
+            ));
+        }
+        Err(ErrorType::SyntaxError(SyntaxError::new(
+            Some(pos_start),
+            Some(self.pos.clone()),
+            "Expected '=' after '!'".to_string(),
+        )))
+    }
+
+    fn construct_string(&mut self) -> Result<Token, ErrorType> {
+        let mut string = String::new();
+        let pos_start = self.pos.clone();
+        self.advance();
+
+        while let Some(current) = self.current_char {
+            match current {
+                '"' => {
+                    self.advance();
+                    return Ok(Token::new(
+                        TokenType::String(string),
+                        Some(pos_start),
+                        Some(self.pos.clone()),
+                    ));
+                }
+                '\\' => {
+                    self.advance();
+                    match self.current_char {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some(escaped) => string.push(escaped),
+                        None => break,
+                    }
+                }
+                c => string.push(c),
+            }
+            self.advance()
+        }
+
+        Err(ErrorType::SyntaxError(SyntaxError::new(
+            Some(pos_start),
+            Some(self.pos.clone()),
+            "Expected '\"' to end the text".to_string(),
+        )))
+    }
+
     fn construct_identifier(&mut self, keywords: Vec<String>) -> Token {
         let mut identifier_string = String::new();
         let pos_start = self.pos.clone();
 
         while let Some(current) = self.current_char {
-            if !(current.is_alphabetic() || current.is_digit(10) || current == '_') {
+            if !(current.is_alphabetic() || current.is_ascii_digit() || current == '_') {
                 break;
             }
             identifier_string.push(current);
             self.advance()
         }
 
-        let token_type: TokenType;
-        if keywords.contains(&identifier_string) {
-            token_type = TokenType::Keyword(identifier_string)
+        let token_type = if keywords.contains(&identifier_string) {
+            TokenType::Keyword(identifier_string)
         } else {
-            token_type = TokenType::Identifier(identifier_string);
-        }
+            TokenType::Identifier(identifier_string)
+        };
 
-        return Token::new(token_type, Some(pos_start), Some(self.pos.clone()));
+        Token::new(token_type, Some(pos_start), Some(self.pos.clone()))
     }
 
-    // TODO MAKE THIS RETURN A RESULT INSTEAD OF PANIC
-    fn construct_number(&mut self) -> Token {
+    fn construct_number(&mut self) -> Result<Token, ErrorType> {
         let mut number_string = String::new();
         let mut dot_count = 0;
         let pos_start = self.pos.clone();
 
         while let Some(current) = self.current_char {
-            if !current.is_digit(10) && current != '.' {
+            if !current.is_ascii_digit() && current != '.' {
                 break;
             }
             if current == '.' {
                 if dot_count >= 1 {
-                    panic!("To many dots in number!"); // FIXXX
+                    return Err(ErrorType::SyntaxError(SyntaxError::new(
+                        Some(pos_start),
+                        Some(self.pos.clone()),
+                        "To many dots in number".to_string(),
+                    )));
                 }
                 dot_count += 1;
             }
             number_string.push(current);
             self.advance()
         }
-        if dot_count == 0 {
-            return Token::new(
-                Int(number_string.parse::<i64>().unwrap()),
+        // Suffix 'n' makes an arbitrary precision integer
+        if dot_count == 0 && self.current_char == Some('n') {
+            self.advance();
+            return Ok(Token::new(
+                TokenType::BigInt(number_string.parse::<BigInt>().unwrap()),
                 Some(pos_start),
                 Some(self.pos.clone()),
-            );
+            ));
         }
-        if dot_count == 1 {
-            return Token::new(
-                Float(number_string.parse::<f64>().unwrap()),
+        // Suffix 'i' makes an imaginary number
+        if self.current_char == Some('i') {
+            self.advance();
+            return Ok(Token::new(
+                TokenType::Imaginary(number_string.parse::<f64>().unwrap()),
                 Some(pos_start),
                 Some(self.pos.clone()),
-            );
+            ));
         }
-        Token::new(Divide, None, None)
+        if dot_count == 0 {
+            return match number_string.parse::<i64>() {
+                Ok(val) => Ok(Token::new(
+                    Int(val),
+                    Some(pos_start),
+                    Some(self.pos.clone()),
+                )),
+                Err(_) => Err(ErrorType::SyntaxError(SyntaxError::new(
+                    Some(pos_start),
+                    Some(self.pos.clone()),
+                    "kokonaisluvun ylivuoto".to_string(),
+                ))),
+            };
+        }
+        Ok(Token::new(
+            Float(number_string.parse::<f64>().unwrap()),
+            Some(pos_start),
+            Some(self.pos.clone()),
+        ))
     }
 }
 
@@ -147,7 +266,7 @@ mod tests {
         //let text = "2 + 2".to_string();
         let mut lexer: Lexer = Lexer::new(file_name, text.to_string());
         let tokens = match lexer.tokenize() {
-            Ok(t) => (t),
+            Ok(t) => t,
             Err(e) => panic!("{:?}", e),
         };
         tokens.iter().map(|t| t.type_()).collect()
@@ -234,4 +353,80 @@ mod tests {
         println!("{:?}", valid_tokens);
         println!("{:?}", given); //LEXER
     }
+
+    #[test]
+    fn test_string_and_punctuation() {
+        assert_eq!(
+            get_token_types_from_str("muut t = \"a\\\"b\\n\"; [x.y]{z: tyhjä}\n"),
+            vec![
+                TokenType::Keyword("muut".to_string()),
+                TokenType::Identifier("t".to_string()),
+                Equal,
+                TokenType::String("a\"b\n".to_string()),
+                Newline,
+                LBracket,
+                TokenType::Identifier("x".to_string()),
+                Dot,
+                TokenType::Identifier("y".to_string()),
+                RBracket,
+                LBrace,
+                TokenType::Identifier("z".to_string()),
+                Colon,
+                TokenType::Keyword("tyhjä".to_string()),
+                RBrace,
+                Newline,
+                EndOfFile,
+            ]
+        );
+        assert!(Lexer::new("test".to_string(), "\"abc".to_string())
+            .tokenize()
+            .is_err());
+    }
+
+    #[test]
+    fn test_big_integer() {
+        assert_eq!(
+            get_token_types_from_str("123456789012345678901234567890n"),
+            vec![
+                TokenType::BigInt("123456789012345678901234567890".parse().unwrap()),
+                TokenType::EndOfFile
+            ]
+        );
+        assert!(
+            Lexer::new("test".to_string(), "9223372036854775808".to_string())
+                .tokenize()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_imaginary() {
+        assert_eq!(
+            get_token_types_from_str("1+2.5i"),
+            vec![Int(1), Plus, TokenType::Imaginary(2.5), EndOfFile]
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(
+            get_token_types_from_str("1==2!=3<4>5<=6>=7"),
+            vec![
+                Int(1),
+                EqualEqual,
+                Int(2),
+                NotEqual,
+                Int(3),
+                LessThan,
+                Int(4),
+                GreaterThan,
+                Int(5),
+                LessThanEqual,
+                Int(6),
+                GreaterThanEqual,
+                Int(7),
+                EndOfFile,
+            ]
+        );
+    }
 }
@@ -0,0 +1,38 @@
+pub mod asmgen;
+pub mod ast;
+pub mod builtins;
+pub mod bytecode;
+pub mod cgen;
+pub mod compiler;
+pub mod context;
+pub mod elf;
+pub mod engine;
+pub mod errors;
+pub mod interpeter;
+pub mod ir;
+pub mod jit;
+pub mod lexer;
+pub mod limits;
+pub mod native;
+pub mod number;
+pub mod operators;
+pub mod optimizer;
+pub mod parser;
+pub mod passes;
+pub mod position;
+pub mod rustgen;
+pub mod ssa;
+pub mod symbols;
+#[cfg(test)]
+pub mod test_support;
+pub mod token;
+pub mod types;
+pub mod value;
+pub mod vm;
+pub mod x86;
+
+pub use engine::{Backend, Diagnostics, Engine, OutputBuffer};
+pub use errors::ErrorType;
+pub use limits::Limits;
+pub use native::{Arity, NativeFn, NativeFunction};
+pub use value::Value;
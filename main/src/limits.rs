@@ -0,0 +1,74 @@
+use std::fmt;
+use std::time::Duration;
+
+/// How much work a program may do before the interpreter stops it.
+/// `None` means no limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    max_steps: Option<u64>,
+    max_depth: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// No step or time limit. Calls may nest 1000 deep.
+    pub fn new() -> Self {
+        Self {
+            max_steps: None,
+            max_depth: Some(1000),
+            timeout: None,
+        }
+    }
+
+    /// Every visited node is one step
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_steps(&self) -> Option<u64> {
+        self.max_steps
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The limit a program ran into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Steps,
+    Depth,
+    Time,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "Step Limit"),
+            Limit::Depth => write!(f, "Call Depth Limit"),
+            Limit::Time => write!(f, "Time Limit"),
+        }
+    }
+}
@@ -1,15 +1,47 @@
-mod context;
-mod errors;
 mod finshell;
-mod interpeter;
-mod lexer;
-mod number;
-mod parser;
-mod position;
-mod symbols;
-mod token;
+
+use fin::{Backend, Engine};
+use std::env;
+use std::process;
+
+const USAGE: &str = "usage: finshell [--vm] [--disassemble] [--no-jit] [file]";
 
 fn main() {
-    println!("Starting Shell");
-    finshell::shell_loop();
+    let mut backend = Backend::Interpreter;
+    let mut disassemble = false;
+    let mut jit = true;
+    let mut file = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--vm" => backend = Backend::Vm,
+            "--disassemble" => disassemble = true,
+            "--no-jit" => jit = false,
+            _ if arg.starts_with("--") || file.is_some() => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let result = fin::interpeter::with_stack(move || {
+        let mut engine = Engine::new();
+        engine.set_backend(backend);
+        if !jit {
+            engine.set_jit_threshold(None);
+        }
+        match (file, disassemble) {
+            (file, true) => finshell::disassemble(&engine, file.as_deref()),
+            (Some(file), false) => finshell::run_file(&mut engine, &file),
+            (None, false) => finshell::shell_loop(&mut engine).map(|_| true),
+        }
+    });
+    match result {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("finshell: {}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,229 @@
+use crate::errors::ErrorType;
+use crate::interpeter::Interpeter;
+use crate::symbols::SymbolMap;
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// How many arguments a native function takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(count: usize) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    pub fn between(min: usize, max: usize) -> Self {
+        Self {
+            min,
+            max: Some(max),
+        }
+    }
+
+    pub fn at_least(min: usize) -> Self {
+        Self { min, max: None }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// A function written in Rust that programs can call like their own functions.
+/// Errors should be made with `Interpeter::native_error` so they point at the call.
+pub trait NativeFunction {
+    fn name(&self) -> &str;
+
+    fn arity(&self) -> Arity;
+
+    /// Called with as many arguments as `arity` accepts
+    fn call(&self, interpeter: &mut Interpeter, args: Vec<Value>) -> Result<Value, ErrorType>;
+}
+
+type NativeBody = dyn Fn(&mut Interpeter, Vec<Value>) -> Result<Value, ErrorType>;
+
+/// A native function made from a closure
+pub struct NativeFn {
+    name: String,
+    arity: Arity,
+    function: Box<NativeBody>,
+}
+
+impl NativeFn {
+    pub fn new<F>(name: &str, arity: Arity, function: F) -> Self
+    where
+        F: Fn(&mut Interpeter, Vec<Value>) -> Result<Value, ErrorType> + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }
+    }
+}
+
+impl NativeFunction for NativeFn {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn call(&self, interpeter: &mut Interpeter, args: Vec<Value>) -> Result<Value, ErrorType> {
+        (self.function)(interpeter, args)
+    }
+}
+
+/// A native function as a value
+#[derive(Clone)]
+pub struct Native(Rc<dyn NativeFunction>);
+
+impl Native {
+    pub fn new<F: NativeFunction + 'static>(function: F) -> Self {
+        Native(Rc::new(function))
+    }
+
+    pub fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    pub fn arity(&self) -> Arity {
+        self.0.arity()
+    }
+
+    pub fn call(&self, interpeter: &mut Interpeter, args: Vec<Value>) -> Result<Value, ErrorType> {
+        self.0.call(interpeter, args)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({})", self.name())
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The native functions to give to programs, by name
+#[derive(Clone, Default)]
+pub struct Registry {
+    functions: BTreeMap<String, Native>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            functions: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a function, replacing any function with the same name
+    pub fn register<F: NativeFunction + 'static>(&mut self, function: F) {
+        self.functions
+            .insert(function.name().to_string(), Native::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Native> {
+        self.functions.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.functions.keys().cloned().collect()
+    }
+
+    /// Makes every function visible to programs run with the symbol map
+    pub fn install(&self, symbol_map: &mut SymbolMap<Value>) {
+        for (name, function) in &self.functions {
+            symbol_map.set(name.clone(), Value::Native(function.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    struct Double;
+
+    impl NativeFunction for Double {
+        fn name(&self) -> &str {
+            "tupla"
+        }
+
+        fn arity(&self) -> Arity {
+            Arity::exactly(1)
+        }
+
+        fn call(&self, interpeter: &mut Interpeter, args: Vec<Value>) -> Result<Value, ErrorType> {
+            match &args[0] {
+                Value::Text(text) => Ok(Value::Text(text.repeat(2))),
+                _ => Err(interpeter.native_error("tupla expects teksti".to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_arity() {
+        assert!(Arity::exactly(2).accepts(2));
+        assert!(!Arity::exactly(2).accepts(1));
+        assert!(Arity::between(0, 1).accepts(0));
+        assert!(Arity::at_least(1).accepts(10));
+        assert!(!Arity::at_least(1).accepts(0));
+        assert_eq!(Arity::between(1, 2).to_string(), "1 to 2");
+        assert_eq!(Arity::at_least(1).to_string(), "at least 1");
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = Registry::new();
+        registry.register(Double);
+        registry.register(NativeFn::new("vakio", Arity::exactly(0), |_, _| {
+            Ok(Value::Text("a".to_string()))
+        }));
+        assert_eq!(registry.names(), vec!["tupla", "vakio"]);
+
+        let mut symbol_map = SymbolMap::new();
+        registry.install(&mut symbol_map);
+        let symbol_map = symbol_map.shared();
+        let run = |text: &str| {
+            let tokens = Lexer::new("<test>".to_string(), text.to_string()).tokenize()?;
+            let root = Parser::new(tokens).parse()?;
+            let mut context = Context::init("Test");
+            context.set_symbol_map(symbol_map.clone());
+            Interpeter::new().run(&root, context)
+        };
+        assert_eq!(run("tupla(vakio())").unwrap().to_string(), "aa");
+        match run("tupla(1)") {
+            Err(ErrorType::RunTimeError(e)) => assert!(e.as_string().contains("tupla(1)\n^^^^^")),
+            other => panic!("Expected runtime error, got {:?}", other),
+        }
+        assert!(run("tupla()").is_err());
+    }
+}
@@ -1,13 +1,132 @@
 use crate::context::Context;
-use crate::errors::RunTimeError;
-use num::{pow::pow, Zero};
-use std::{cmp, fmt, ops};
+use crate::errors::{DivisionByZeroError, ErrorType, RunTimeError};
+use num::complex::Complex64;
+use num::{BigInt, BigRational, One, ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
 
 use crate::position::Position;
 #[derive(Debug, Clone, PartialEq)]
 pub enum NumberType {
     Integer(Number<i64>),
     Float(Number<f64>),
+    BigInteger(Number<BigInt>),
+    Fraction(Number<BigRational>),
+    Complex(Number<Complex64>),
+}
+
+impl NumberType {
+    pub fn pos_start(&self) -> Option<Position> {
+        match self {
+            NumberType::Integer(num) => num.pos_start(),
+            NumberType::Float(num) => num.pos_start(),
+            NumberType::BigInteger(num) => num.pos_start(),
+            NumberType::Fraction(num) => num.pos_start(),
+            NumberType::Complex(num) => num.pos_start(),
+        }
+    }
+
+    pub fn pos_end(&self) -> Option<Position> {
+        match self {
+            NumberType::Integer(num) => num.pos_end(),
+            NumberType::Float(num) => num.pos_end(),
+            NumberType::BigInteger(num) => num.pos_end(),
+            NumberType::Fraction(num) => num.pos_end(),
+            NumberType::Complex(num) => num.pos_end(),
+        }
+    }
+
+    pub fn set_pos(&mut self, pos_start: Option<Position>, pos_end: Option<Position>) {
+        match self {
+            NumberType::Integer(num) => num.set_pos(pos_start, pos_end),
+            NumberType::Float(num) => num.set_pos(pos_start, pos_end),
+            NumberType::BigInteger(num) => num.set_pos(pos_start, pos_end),
+            NumberType::Fraction(num) => num.set_pos(pos_start, pos_end),
+            NumberType::Complex(num) => num.set_pos(pos_start, pos_end),
+        }
+    }
+
+    /// Converts mixed operands to the wider of the two types, so both sides match.
+    /// `kok` widens to `iso`, both integer types widen to `murto`,
+    /// and any number paired with a complex number becomes complex.
+    /// Every other pair is returned unchanged.
+    pub fn promote(left: NumberType, right: NumberType) -> (NumberType, NumberType) {
+        use NumberType::{BigInteger, Complex, Fraction, Integer};
+        match (left, right) {
+            (Complex(num1), Complex(num2)) => (Complex(num1), Complex(num2)),
+            (Complex(num1), num2) => (Complex(num1), Complex(num2.to_complex())),
+            (num1, Complex(num2)) => (Complex(num1.to_complex()), Complex(num2)),
+            (Integer(num1), BigInteger(num2)) => (BigInteger(num1.to_big()), BigInteger(num2)),
+            (BigInteger(num1), Integer(num2)) => (BigInteger(num1), BigInteger(num2.to_big())),
+            (Integer(num1), Fraction(num2)) => {
+                (Fraction(num1.to_big().to_fraction()), Fraction(num2))
+            }
+            (Fraction(num1), Integer(num2)) => {
+                (Fraction(num1), Fraction(num2.to_big().to_fraction()))
+            }
+            (BigInteger(num1), Fraction(num2)) => (Fraction(num1.to_fraction()), Fraction(num2)),
+            (Fraction(num1), BigInteger(num2)) => (Fraction(num1), Fraction(num2.to_fraction())),
+            pair => pair,
+        }
+    }
+
+    /// The value as a `liu`, rounding where needed
+    pub fn to_float(&self) -> Number<f64> {
+        match self {
+            NumberType::Integer(num) => num.map(|val| *val as f64),
+            NumberType::Float(num) => num.clone(),
+            NumberType::BigInteger(num) => num.map(|val| val.to_f64().unwrap_or(f64::NAN)),
+            NumberType::Fraction(num) => num.map(|val| val.to_f64().unwrap_or(f64::NAN)),
+            NumberType::Complex(num) => num.map(|val| val.re),
+        }
+    }
+
+    /// The value as a power, if it is a whole number
+    pub fn to_whole_exponent(&self) -> Result<Option<Number<i64>>, ErrorType> {
+        match self {
+            NumberType::Integer(num) => Ok(Some(num.clone())),
+            NumberType::BigInteger(num) => num.to_exponent().map(Some),
+            NumberType::Fraction(num) if num.value().is_integer() => {
+                num.map(|val| val.to_integer()).to_exponent().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn to_complex(&self) -> Number<Complex64> {
+        match self {
+            NumberType::Complex(num) => num.clone(),
+            num => num.to_float().map(|val| Complex64::new(*val, 0.0)),
+        }
+    }
+
+    /// Orders two numbers after promoting them to a common type.
+    /// Returns `None` if the types do not mix and `Some(None)` if the values are unordered,
+    /// which is the case for NaN and for complex numbers that are not equal.
+    pub fn compare(left: &NumberType, right: &NumberType) -> Option<Option<Ordering>> {
+        use NumberType::{BigInteger, Complex, Float, Fraction, Integer};
+        match NumberType::promote(left.clone(), right.clone()) {
+            (Integer(num1), Integer(num2)) => Some(num1.compare(&num2)),
+            (Float(num1), Float(num2)) => Some(num1.compare(&num2)),
+            (BigInteger(num1), BigInteger(num2)) => Some(num1.compare(&num2)),
+            (Fraction(num1), Fraction(num2)) => Some(num1.compare(&num2)),
+            (Complex(num1), Complex(num2)) => {
+                Some(Some(Ordering::Equal).filter(|_| num1.value() == num2.value()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_context(&mut self, context: Context) {
+        match self {
+            NumberType::Integer(num) => num.set_context(context),
+            NumberType::Float(num) => num.set_context(context),
+            NumberType::BigInteger(num) => num.set_context(context),
+            NumberType::Fraction(num) => num.set_context(context),
+            NumberType::Complex(num) => num.set_context(context),
+        }
+    }
 }
 
 impl fmt::Display for NumberType {
@@ -15,16 +134,141 @@ impl fmt::Display for NumberType {
         match self {
             NumberType::Integer(num) => write!(f, "{}", num.value()),
             NumberType::Float(num) => write!(f, "{}", num.value()),
+            NumberType::BigInteger(num) => write!(f, "{}", num.value()),
+            NumberType::Fraction(num) => write!(f, "{}", num.value()),
+            NumberType::Complex(num) => write!(f, "{}", num.value()),
         }
     }
 }
 
+/// Raw arithmetic on the values a `Number` can hold.
+/// Each operation returns `None` when the result does not fit in the type.
+pub trait Arithmetic: Sized {
+    fn add_checked(&self, other: &Self) -> Option<Self>;
+    fn sub_checked(&self, other: &Self) -> Option<Self>;
+    fn mult_checked(&self, other: &Self) -> Option<Self>;
+    fn div_checked(&self, other: &Self) -> Option<Self>;
+    fn neg_checked(&self) -> Option<Self>;
+    fn pow_checked(&self, exponent: usize) -> Option<Self>;
+
+    /// Whether a power would be too large to compute in reasonable time
+    fn pow_too_large(&self, _exponent: usize) -> bool {
+        false
+    }
+}
+
+// Largest power of an arbitrary precision number, in bits
+const MAX_POWER_BITS: u64 = 1 << 18;
+
+/// Estimates the bits of a power from the bits of its base
+fn power_too_large(bits: u64, exponent: usize) -> bool {
+    bits.saturating_sub(1).saturating_mul(exponent as u64) > MAX_POWER_BITS
+}
+
+impl Arithmetic for i64 {
+    fn add_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_add(*other)
+    }
+
+    fn sub_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_sub(*other)
+    }
+
+    fn mult_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_mul(*other)
+    }
+
+    fn div_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_div(*other)
+    }
+
+    fn neg_checked(&self) -> Option<Self> {
+        self.checked_neg()
+    }
+
+    fn pow_checked(&self, exponent: usize) -> Option<Self> {
+        num::checked_pow(*self, exponent)
+    }
+}
+
+// Floats go to infinity instead of overflowing
+impl Arithmetic for f64 {
+    fn add_checked(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn sub_checked(&self, other: &Self) -> Option<Self> {
+        Some(self - other)
+    }
+
+    fn mult_checked(&self, other: &Self) -> Option<Self> {
+        Some(self * other)
+    }
+
+    fn div_checked(&self, other: &Self) -> Option<Self> {
+        Some(self / other)
+    }
+
+    fn neg_checked(&self) -> Option<Self> {
+        Some(-self)
+    }
+
+    fn pow_checked(&self, exponent: usize) -> Option<Self> {
+        Some(num::pow(*self, exponent))
+    }
+}
+
+macro_rules! exact_arithmetic {
+    ($type:ty) => {
+        exact_arithmetic!($type, |_| 0);
+    };
+    ($type:ty, $bits:expr) => {
+        impl Arithmetic for $type {
+            fn add_checked(&self, other: &Self) -> Option<Self> {
+                Some(self + other)
+            }
+
+            fn sub_checked(&self, other: &Self) -> Option<Self> {
+                Some(self - other)
+            }
+
+            fn mult_checked(&self, other: &Self) -> Option<Self> {
+                Some(self * other)
+            }
+
+            fn div_checked(&self, other: &Self) -> Option<Self> {
+                Some(self / other)
+            }
+
+            fn neg_checked(&self) -> Option<Self> {
+                Some(-self)
+            }
+
+            fn pow_checked(&self, exponent: usize) -> Option<Self> {
+                Some(num::pow(self.clone(), exponent))
+            }
+
+            fn pow_too_large(&self, exponent: usize) -> bool {
+                let bits: fn(&$type) -> u64 = $bits;
+                power_too_large(bits(self), exponent)
+            }
+        }
+    };
+}
+
+// Arbitrary precision types never overflow
+exact_arithmetic!(BigInt, |val| val.bits());
+exact_arithmetic!(BigRational, |val| val.numer().bits() + val.denom().bits());
+// Complex numbers follow floats and go to infinity
+exact_arithmetic!(Complex64);
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Number<T> {
     value: T,
     pos_start: Option<Position>, // Should this be wrapped in Option
     pos_end: Option<Position>,   // Should this be wrapped in Option
-    context: Option<Context>,    // Should this be wrapped in Option
+    // Shared, since numbers are copied far more often than they fail
+    context: Option<Rc<Context>>,
 }
 
 impl<T> Number<T> {
@@ -47,7 +291,7 @@ impl<T> Number<T> {
             value,
             pos_start,
             pos_end,
-            context,
+            context: context.map(|context| Rc::new(context.detached())),
         }
     }
 
@@ -57,63 +301,225 @@ impl<T> Number<T> {
     {
         self.value.clone()
     }
+
+    pub fn pos_start(&self) -> Option<Position> {
+        self.pos_start.clone()
+    }
+
+    pub fn pos_end(&self) -> Option<Position> {
+        self.pos_end.clone()
+    }
+
     pub fn set_pos(&mut self, pos_start: Option<Position>, pos_end: Option<Position>) {
         self.pos_start = pos_start;
         self.pos_end = pos_end;
     }
 
-    pub fn set_context(&mut self, context: Context) {}
+    pub fn set_context(&mut self, context: Context) {
+        self.context = Some(Rc::new(context.detached()));
+    }
+
+    /// A number of another type at the same position and context
+    pub fn map<U>(&self, function: impl FnOnce(&T) -> U) -> Number<U> {
+        Number {
+            value: function(&self.value),
+            pos_start: self.pos_start.clone(),
+            pos_end: self.pos_end.clone(),
+            context: self.context.clone(),
+        }
+    }
+
+    // Errors are reported in the context the number was created in
+    fn runtime_error(
+        &self,
+        pos_start: Option<Position>,
+        pos_end: Option<Position>,
+        message: &str,
+    ) -> ErrorType {
+        ErrorType::RunTimeError(RunTimeError::new(
+            pos_start,
+            pos_end,
+            message.to_string(),
+            self.error_context(),
+        ))
+    }
+
+    // Where errors about this number happened
+    fn error_context(&self) -> Context {
+        match &self.context {
+            Some(context) => (**context).clone(),
+            None => Context::init("<unknown>"),
+        }
+    }
+
+    fn division_by_zero_error(
+        &self,
+        pos_start: Option<Position>,
+        pos_end: Option<Position>,
+    ) -> ErrorType {
+        ErrorType::DivisionByZeroError(DivisionByZeroError::new(
+            pos_start,
+            pos_end,
+            "Division by Zero".to_string(),
+            self.error_context(),
+        ))
+    }
 
-    pub fn add(&self, other: Number<T>) -> Self
+    fn overflow_error<U>(&self, other: &Number<U>) -> ErrorType {
+        self.runtime_error(
+            self.pos_start.clone(),
+            other.pos_end.clone(),
+            "kokonaisluvun ylivuoto",
+        )
+    }
+
+    pub fn add(&self, other: Number<T>) -> Result<Self, ErrorType>
     where
-        T: ops::Add<Output = T> + Clone,
+        T: Arithmetic,
     {
-        Self::new_no_pos(self.value.clone() + other.value)
+        match self.value.add_checked(&other.value) {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(&other)),
+        }
     }
 
-    pub fn sub(&self, other: Number<T>) -> Self
+    pub fn sub(&self, other: Number<T>) -> Result<Self, ErrorType>
     where
-        T: ops::Sub<Output = T> + Clone,
+        T: Arithmetic,
     {
-        Self::new_no_pos(self.value.clone() - other.value)
+        match self.value.sub_checked(&other.value) {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(&other)),
+        }
     }
 
-    pub fn mult(&self, other: Number<T>) -> Self
+    pub fn mult(&self, other: Number<T>) -> Result<Self, ErrorType>
     where
-        T: ops::Mul<Output = T> + Clone,
+        T: Arithmetic,
     {
-        Self::new_no_pos(self.value.clone() * other.value)
+        match self.value.mult_checked(&other.value) {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(&other)),
+        }
     }
 
-    pub fn pow(&self, other: Number<i64>) -> Result<Self, RunTimeError>
+    pub fn neg(&self) -> Result<Self, ErrorType>
     where
-        T: ops::Mul<Output = T> + Clone + num::One,
+        T: Arithmetic,
     {
-        if other.value.is_negative() {
-            return Err(RunTimeError::new(
-                other.pos_start.clone(),
+        match self.value.neg_checked() {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(self)),
+        }
+    }
+
+    /// Raises to a whole power. A negative power is the reciprocal of the positive one,
+    /// so whole number types have to be turned into fractions before using one.
+    pub fn pow(&self, other: Number<i64>) -> Result<Self, ErrorType>
+    where
+        T: Arithmetic + Zero + One,
+    {
+        let exponent = other.value.unsigned_abs() as usize;
+        if self.value.pow_too_large(exponent) {
+            return Err(self.runtime_error(
+                self.pos_start.clone(),
                 other.pos_end.clone(),
-                "Cant raise to Negative power".to_string(),
-                other.context.clone().unwrap(), // Blind unwrap!
+                "Power is too large",
             ));
         }
-        let val = pow::<T>(self.value.clone(), other.value as usize);
-        Ok(Self::new_no_pos(val))
+        let power = match self.value.pow_checked(exponent) {
+            Some(val) => val,
+            None => return Err(self.overflow_error(&other)),
+        };
+        if !other.value.is_negative() {
+            return Ok(Self::new_no_pos(power));
+        }
+        if power.is_zero() {
+            return Err(self.division_by_zero_error(self.pos_start.clone(), other.pos_end.clone()));
+        }
+        match T::one().div_checked(&power) {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(&other)),
+        }
     }
 
-    pub fn div(&self, other: Number<T>) -> Result<Self, RunTimeError>
+    pub fn compare(&self, other: &Number<T>) -> Option<Ordering>
     where
-        T: ops::Div<Output = T> + Clone + Zero,
+        T: PartialOrd,
+    {
+        self.value.partial_cmp(&other.value)
+    }
+
+    pub fn div(&self, other: Number<T>) -> Result<Self, ErrorType>
+    where
+        T: Arithmetic + Zero,
     {
         if Zero::is_zero(&other.value) {
-            return Err(RunTimeError::new(
-                other.pos_start.clone(),
+            return Err(self.division_by_zero_error(other.pos_start.clone(), other.pos_end.clone()));
+        }
+        match self.value.div_checked(&other.value) {
+            Some(val) => Ok(Self::new_no_pos(val)),
+            None => Err(self.overflow_error(&other)),
+        }
+    }
+}
+
+impl Number<i64> {
+    pub fn to_big(&self) -> Number<BigInt> {
+        self.map(|val| BigInt::from(*val))
+    }
+}
+
+impl Number<BigInt> {
+    pub fn to_fraction(&self) -> Number<BigRational> {
+        self.map(|val| BigRational::from_integer(val.clone()))
+    }
+
+    /// Builds the normalised fraction `self / denominator`
+    pub fn over(&self, denominator: Number<BigInt>) -> Result<Number<BigRational>, ErrorType> {
+        if denominator.value.is_zero() {
+            return Err(self.division_by_zero_error(
+                denominator.pos_start.clone(),
+                denominator.pos_end.clone(),
+            ));
+        }
+        Ok(self.map(|val| BigRational::new(val.clone(), denominator.value.clone())))
+    }
+
+    /// Exponents are limited to what fits in a `kok`
+    pub fn to_exponent(&self) -> Result<Number<i64>, ErrorType> {
+        match self.value.to_i64() {
+            Some(val) => Ok(self.map(|_| val)),
+            None => Err(self.runtime_error(
+                self.pos_start.clone(),
+                self.pos_end.clone(),
+                "Exponent is too large",
+            )),
+        }
+    }
+}
+
+impl Number<f64> {
+    /// Raises to any real power.
+    /// Negative numbers only have real roots for whole powers.
+    pub fn powf(&self, other: Number<f64>) -> Result<Self, ErrorType> {
+        if self.value.is_zero() && other.value < 0.0 {
+            return Err(self.division_by_zero_error(self.pos_start.clone(), other.pos_end.clone()));
+        }
+        if self.value < 0.0 && other.value.fract() != 0.0 {
+            return Err(self.runtime_error(
+                self.pos_start.clone(),
                 other.pos_end.clone(),
-                "Division by Zero".to_string(),
-                other.context.clone().unwrap(), // Warning! Blind Unwrap
+                "Cant raise a negative number to a fractional power, use a complex base",
             ));
         }
-        Ok(Self::new_no_pos(self.value.clone() / other.value))
+        Ok(Self::new_no_pos(self.value.powf(other.value)))
+    }
+}
+
+impl Number<Complex64> {
+    pub fn powc(&self, other: Number<Complex64>) -> Self {
+        self.map(|val| val.powc(other.value))
     }
 }
 
@@ -0,0 +1,350 @@
+use crate::context::Context;
+use crate::errors::{ErrorType, RunTimeError};
+use crate::number::NumberType::{self, BigInteger, Complex, Float, Fraction, Integer};
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+// What operators, indexing and fields do to values.
+// The interpreter and the virtual machine both use these, so they agree on results and errors.
+
+/// Applies a binary operator other than `ja` and `tai`.
+/// Results have no position, the caller gives them the position of the whole expression.
+pub fn binary(
+    left: Value,
+    optok: &Token,
+    right: Value,
+    context: &Context,
+) -> Result<Value, ErrorType> {
+    if is_comparison(&optok.type_()) {
+        return comparison(left, optok, right, context);
+    }
+
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => {
+            Ok(Value::Number(number_binop(left, optok, right, context)?))
+        }
+        (Value::Text(left), Value::Text(right)) if optok.type_() == TokenType::Plus => {
+            Ok(Value::Text(left + &right))
+        }
+        (Value::List(mut left), Value::List(right)) if optok.type_() == TokenType::Plus => {
+            left.extend(right);
+            Ok(Value::List(left))
+        }
+        (left, right) => Err(token_error(
+            optok,
+            format!(
+                "Cant use {} with {} and {}",
+                optok.type_().symbol(),
+                left.type_name(),
+                right.type_name()
+            ),
+            context,
+        )),
+    }
+}
+
+fn number_binop(
+    left: NumberType,
+    optok: &Token,
+    right: NumberType,
+    context: &Context,
+) -> Result<NumberType, ErrorType> {
+    let (left, right) = match optok.type_() {
+        TokenType::Pow => (left, right),
+        _ => NumberType::promote(left, right),
+    };
+
+    let result = match optok.type_() {
+        TokenType::Plus => match (left.clone(), right.clone()) {
+            (Integer(num1), Integer(num2)) => Integer(num1.add(num2)?),
+            (Float(num1), Float(num2)) => Float(num1.add(num2)?),
+            (BigInteger(num1), BigInteger(num2)) => BigInteger(num1.add(num2)?),
+            (Fraction(num1), Fraction(num2)) => Fraction(num1.add(num2)?),
+            (Complex(num1), Complex(num2)) => Complex(num1.add(num2)?),
+            _ => {
+                return Err(token_error(
+                    optok,
+                    format!("Cant add {} with {} due to different types", left, right),
+                    context,
+                ))
+            }
+        },
+        TokenType::Minus => match (left.clone(), right.clone()) {
+            (Integer(num1), Integer(num2)) => Integer(num1.sub(num2)?),
+            (Float(num1), Float(num2)) => Float(num1.sub(num2)?),
+            (BigInteger(num1), BigInteger(num2)) => BigInteger(num1.sub(num2)?),
+            (Fraction(num1), Fraction(num2)) => Fraction(num1.sub(num2)?),
+            (Complex(num1), Complex(num2)) => Complex(num1.sub(num2)?),
+            _ => {
+                return Err(token_error(
+                    optok,
+                    format!(
+                        "Cant subtract {} from {} due to different types",
+                        left, right
+                    ),
+                    context,
+                ))
+            }
+        },
+        TokenType::Multiply => match (left.clone(), right.clone()) {
+            (Integer(num1), Integer(num2)) => Integer(num1.mult(num2)?),
+            (Float(num1), Float(num2)) => Float(num1.mult(num2)?),
+            (BigInteger(num1), BigInteger(num2)) => BigInteger(num1.mult(num2)?),
+            (Fraction(num1), Fraction(num2)) => Fraction(num1.mult(num2)?),
+            (Complex(num1), Complex(num2)) => Complex(num1.mult(num2)?),
+            _ => {
+                return Err(token_error(
+                    optok,
+                    format!(
+                        "Cant Multiply {} with {} due to different types",
+                        left, right
+                    ),
+                    context,
+                ))
+            }
+        },
+        TokenType::Divide => match (left.clone(), right.clone()) {
+            (Integer(num1), Integer(num2)) => Integer(num1.div(num2)?),
+            (Float(num1), Float(num2)) => Float(num1.div(num2)?),
+            (BigInteger(num1), BigInteger(num2)) => BigInteger(num1.div(num2)?),
+            (Fraction(num1), Fraction(num2)) => Fraction(num1.div(num2)?),
+            (Complex(num1), Complex(num2)) => Complex(num1.div(num2)?),
+            _ => {
+                return Err(token_error(
+                    optok,
+                    format!("Cant Divide {} with {} due to different types", left, right),
+                    context,
+                ))
+            }
+        },
+
+        TokenType::Pow => power(left, right)?,
+        _ => {
+            return Err(token_error(
+                optok,
+                format!("Invalid operator token '{}'", optok),
+                context,
+            ))
+        }
+    };
+    Ok(result)
+}
+
+fn comparison(
+    left: Value,
+    optok: &Token,
+    right: Value,
+    context: &Context,
+) -> Result<Value, ErrorType> {
+    let equality = matches!(optok.type_(), TokenType::EqualEqual | TokenType::NotEqual);
+    let ordering = match (&left, &right) {
+        (Value::Number(num1), Value::Number(num2)) => {
+            // Complex numbers have no order, only equality
+            let complex = matches!(num1, Complex(_)) || matches!(num2, Complex(_));
+            match NumberType::compare(num1, num2) {
+                Some(ordering) if equality || !complex => ordering,
+                _ => {
+                    return Err(token_error(
+                        optok,
+                        format!(
+                            "Cant compare {} with {} due to different types",
+                            left, right
+                        ),
+                        context,
+                    ))
+                }
+            }
+        }
+        (Value::Text(text1), Value::Text(text2)) => Some(text1.cmp(text2)),
+        _ if equality => Some(Ordering::Equal).filter(|_| left.equals(&right)),
+        _ => {
+            return Err(token_error(
+                optok,
+                format!(
+                    "Cant compare {} with {}",
+                    left.type_name(),
+                    right.type_name()
+                ),
+                context,
+            ))
+        }
+    };
+    Ok(Value::Boolean(comparison_holds(&optok.type_(), ordering)))
+}
+
+/// Result types of `^`:
+/// - `kok` or `iso` to a non-negative whole power keeps its type, and is `iso` if either side is
+/// - `kok`, `iso` or `murto` to a negative whole power is an exact `murto`
+/// - `liu` to a whole power stays `liu`
+/// - any real number to a `liu` or non-whole `murto` power is a `liu`
+/// - a complex number on either side gives a complex number
+fn power(left: NumberType, right: NumberType) -> Result<NumberType, ErrorType> {
+    let exponent = right.to_whole_exponent()?;
+    if let (Complex(num1), Some(exponent)) = (&left, &exponent) {
+        return Ok(Complex(num1.pow(exponent.clone())?));
+    }
+    if let (Complex(_), _) | (_, Complex(_)) = (&left, &right) {
+        return Ok(Complex(left.to_complex().powc(right.to_complex())));
+    }
+
+    let exponent = match exponent {
+        Some(exponent) => exponent,
+        None => return Ok(Float(left.to_float().powf(right.to_float())?)),
+    };
+    let negative = exponent.value() < 0;
+    let result = match (left.clone(), right) {
+        (Integer(num1), BigInteger(_)) if !negative => BigInteger(num1.to_big().pow(exponent)?),
+        (Integer(num1), _) if !negative => Integer(num1.pow(exponent)?),
+        (Integer(num1), _) => Fraction(num1.to_big().to_fraction().pow(exponent)?),
+        (BigInteger(num1), _) if !negative => BigInteger(num1.pow(exponent)?),
+        (BigInteger(num1), _) => Fraction(num1.to_fraction().pow(exponent)?),
+        (Float(num1), _) => Float(num1.pow(exponent)?),
+        (Fraction(num1), _) => Fraction(num1.pow(exponent)?),
+        (Complex(num1), _) => Complex(num1.pow(exponent)?),
+    };
+    Ok(result)
+}
+
+/// Applies unary `+` or `-` to a number.
+/// The result starts at the operator and ends where the number did.
+pub fn unary(optok: &Token, value: Value, context: &Context) -> Result<Value, ErrorType> {
+    let number = match value {
+        Value::Number(number) => number,
+        value => {
+            return Err(token_error(
+                optok,
+                format!(
+                    "Cant use {} with {}",
+                    optok.type_().symbol(),
+                    value.type_name()
+                ),
+                context,
+            ))
+        }
+    };
+
+    let mut result = match optok.type_() {
+        TokenType::Minus => match number.clone() {
+            Integer(num) => Integer(num.neg()?),
+            Float(num) => Float(num.neg()?),
+            BigInteger(num) => BigInteger(num.neg()?),
+            Fraction(num) => Fraction(num.neg()?),
+            Complex(num) => Complex(num.neg()?),
+        },
+        TokenType::Plus => number.clone(),
+        _ => {
+            return Err(token_error(
+                optok,
+                format!("Invalid operator token '{}'", optok),
+                context,
+            ))
+        }
+    };
+
+    result.set_pos(optok.position_start(), number.pos_end());
+    Ok(Value::Number(result))
+}
+
+/// Looks up an item of a list or text by position, or of a record by name.
+/// Errors point at the index, which is between `pos_start` and `pos_end`.
+pub fn index(
+    collection: &Value,
+    index: &Value,
+    pos_start: Option<Position>,
+    pos_end: Option<Position>,
+    context: &Context,
+) -> Result<Value, ErrorType> {
+    let position = match index {
+        Value::Number(Integer(num)) => usize::try_from(num.value()).ok(),
+        _ => None,
+    };
+    let item = match (collection, index, position) {
+        (Value::List(values), _, Some(position)) => values.get(position).cloned(),
+        (Value::Text(text), _, Some(position)) => text
+            .chars()
+            .nth(position)
+            .map(|c| Value::Text(c.to_string())),
+        (Value::Record(fields), Value::Text(name), _) => fields.get(name).cloned(),
+        (Value::List(_) | Value::Text(_), Value::Number(Integer(_)), None) => None,
+        _ => {
+            return Err(ErrorType::RunTimeError(RunTimeError::new(
+                pos_start,
+                pos_end,
+                format!(
+                    "Cant index {} with {}",
+                    collection.type_name(),
+                    index.type_name()
+                ),
+                context.clone(),
+            )))
+        }
+    };
+    match item {
+        Some(item) => Ok(item),
+        None => Err(ErrorType::RunTimeError(RunTimeError::new(
+            pos_start,
+            pos_end,
+            format!("Index {} is out of range", index),
+            context.clone(),
+        ))),
+    }
+}
+
+/// Looks up a field of a record. Errors point at the field name.
+pub fn field(
+    record: &Value,
+    name_tok: &Token,
+    name: &str,
+    context: &Context,
+) -> Result<Value, ErrorType> {
+    let field = match record {
+        Value::Record(fields) => fields.get(name).cloned(),
+        _ => None,
+    };
+    match field {
+        Some(value) => Ok(value),
+        None => Err(token_error(
+            name_tok,
+            format!("{} has no field {}", record.type_name(), name),
+            context,
+        )),
+    }
+}
+
+fn token_error(token: &Token, message: String, context: &Context) -> ErrorType {
+    ErrorType::RunTimeError(RunTimeError::new(
+        token.position_start(),
+        token.position_end(),
+        message,
+        context.clone(),
+    ))
+}
+
+fn is_comparison(operator: &TokenType) -> bool {
+    matches!(
+        operator,
+        TokenType::EqualEqual
+            | TokenType::NotEqual
+            | TokenType::LessThan
+            | TokenType::GreaterThan
+            | TokenType::LessThanEqual
+            | TokenType::GreaterThanEqual
+    )
+}
+
+// Comparisons with NaN are unordered, and only '!=' holds for them
+fn comparison_holds(operator: &TokenType, ordering: Option<Ordering>) -> bool {
+    match (operator, ordering) {
+        (TokenType::NotEqual, ordering) => ordering != Some(Ordering::Equal),
+        (_, None) => false,
+        (TokenType::EqualEqual, Some(ordering)) => ordering == Ordering::Equal,
+        (TokenType::LessThan, Some(ordering)) => ordering == Ordering::Less,
+        (TokenType::GreaterThan, Some(ordering)) => ordering == Ordering::Greater,
+        (TokenType::LessThanEqual, Some(ordering)) => ordering != Ordering::Greater,
+        (TokenType::GreaterThanEqual, Some(ordering)) => ordering != Ordering::Less,
+        _ => false,
+    }
+}
@@ -0,0 +1,396 @@
+use crate::context::Context;
+use crate::errors::ErrorType;
+use crate::number::NumberType;
+use crate::operators;
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Work done on the syntax tree before a program runs, so it is not repeated every time
+// an expression is evaluated. Folded values keep the position of the expression they
+// replace, so later errors point at the same place.
+
+/// What kind of number an expression gives, if it gives one at all
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Kind {
+    // 'kok', 'iso' and 'murto'
+    Exact,
+    // 'liu', or an exact number
+    Real,
+    // Possibly also 'kompleksi'
+    Number,
+}
+
+/// Folds expressions of literals into literals, and drops identities like `x * 1` and `x + 0`
+/// where `x` is sure to be a real number.
+///
+/// Operators that would fail at runtime, like a constant `1 / 0`, are not folded. Their errors
+/// are returned instead, all of them, even for code that might never run.
+pub fn optimize(name: &str, node: &Node) -> Result<Node, Vec<ErrorType>> {
+    let mut optimizer = Optimizer {
+        scope: name.to_string(),
+        kinds: HashMap::new(),
+        errors: Vec::new(),
+    };
+    let node = optimizer.optimize(node);
+    match optimizer.errors.is_empty() {
+        true => Ok(node),
+        false => Err(optimizer.errors),
+    }
+}
+
+struct Optimizer {
+    // The function being optimized, or the program, for errors
+    scope: String,
+    // Parameters of the function with a number type that the function never sets
+    kinds: HashMap<String, Kind>,
+    errors: Vec<ErrorType>,
+}
+
+impl Optimizer {
+    fn optimize(&mut self, node: &Node) -> Node {
+        match node {
+            // Chains like 1 + 2 - 3 are as long as the input, so they are folded in a loop
+            Node::Binop(..) => {
+                let operations = node.binop_chain();
+                let pos_start = operations[0].0.pos_start();
+                let mut left = self.optimize(operations[0].0);
+                for (_, optok, right) in operations {
+                    let right = self.optimize(right);
+                    left = self.binop(left, optok, right, pos_start.clone());
+                }
+                left
+            }
+            Node::Unary(optok, node) => {
+                let node = self.optimize(node);
+                self.unary(optok, node)
+            }
+            Node::Value(_) | Node::VarAccessNode(_) => node.clone(),
+            Node::VarAssignNode(name, type_, node) => {
+                Node::VarAssignNode(name.clone(), type_.clone(), self.boxed(node))
+            }
+            Node::CallNode(name, args) => Node::CallNode(name.clone(), self.all(args)),
+            Node::ListNode(token, items) => Node::ListNode(token.clone(), self.all(items)),
+            Node::RecordNode(token, fields) => Node::RecordNode(
+                token.clone(),
+                fields
+                    .iter()
+                    .map(|(name, node)| (name.clone(), self.optimize(node)))
+                    .collect(),
+            ),
+            Node::IndexNode(node, bracket, index) => {
+                Node::IndexNode(self.boxed(node), bracket.clone(), self.boxed(index))
+            }
+            Node::FieldNode(node, name) => Node::FieldNode(self.boxed(node), name.clone()),
+            Node::FuncDefNode(name, params, return_type, body) => {
+                let body = self.function(name, params, body);
+                Node::FuncDefNode(name.clone(), params.clone(), return_type.clone(), body)
+            }
+            Node::IfNode(token, cases, else_case) => Node::IfNode(
+                token.clone(),
+                cases
+                    .iter()
+                    .map(|(condition, body)| (self.optimize(condition), self.optimize(body)))
+                    .collect(),
+                else_case.as_ref().map(|node| self.boxed(node)),
+            ),
+            Node::WhileNode(token, condition, body) => {
+                Node::WhileNode(token.clone(), self.boxed(condition), self.boxed(body))
+            }
+            Node::ReturnNode(token, node) => {
+                Node::ReturnNode(token.clone(), node.as_ref().map(|node| self.boxed(node)))
+            }
+            Node::StatementsNode(nodes) => Node::StatementsNode(self.all(nodes)),
+        }
+    }
+
+    fn boxed(&mut self, node: &Node) -> Box<Node> {
+        Box::new(self.optimize(node))
+    }
+
+    fn all(&mut self, nodes: &[Node]) -> Vec<Node> {
+        nodes.iter().map(|node| self.optimize(node)).collect()
+    }
+
+    fn function(
+        &mut self,
+        name: &Token,
+        params: &[(Token, Option<Token>)],
+        body: &Rc<Node>,
+    ) -> Rc<Node> {
+        let mut kinds = HashMap::new();
+        for (param, type_) in params {
+            let kind = match type_.as_ref().map(Token::type_) {
+                Some(TokenType::Identifier(type_)) if type_ == "kok" => Kind::Exact,
+                Some(TokenType::Identifier(type_)) if type_ == "liu" => Kind::Real,
+                _ => continue,
+            };
+            if let TokenType::Identifier(param) = param.type_() {
+                if !sets(body, &param) {
+                    kinds.insert(param, kind);
+                }
+            }
+        }
+
+        let scope = match name.type_() {
+            TokenType::Identifier(name) => name,
+            _ => self.scope.clone(),
+        };
+        let scope = std::mem::replace(&mut self.scope, scope);
+        let kinds = std::mem::replace(&mut self.kinds, kinds);
+        let body = Rc::new(self.optimize(body));
+        self.scope = scope;
+        self.kinds = kinds;
+        body
+    }
+
+    // `pos_start` is where the left side starts
+    fn binop(
+        &mut self,
+        left: Node,
+        optok: &Token,
+        right: Node,
+        pos_start: Option<Position>,
+    ) -> Node {
+        let pos_end = right.pos_end();
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            return match (truth(&left), truth(&right)) {
+                // The right side is never looked at
+                (Some(value), _) if (keyword == "ja") != value => left,
+                (Some(_), Some(_)) => right,
+                _ => Node::Binop(Box::new(left), optok.clone(), Box::new(right)),
+            };
+        }
+
+        if let (Some(left_value), Some(right_value)) = (constant(&left), constant(&right)) {
+            let context = Context::init(&self.scope);
+            match operators::binary(left_value, optok, right_value, &context) {
+                Ok(value) => {
+                    if let Some(node) = literal(value, pos_start, pos_end) {
+                        return node;
+                    }
+                }
+                Err(error) => self.error(error),
+            }
+            return Node::Binop(Box::new(left), optok.clone(), Box::new(right));
+        }
+
+        // Identities are only dropped where they could not have failed or changed the value.
+        // 'x + 0' is not dropped for 'liu' values, since -0.0 + 0 is 0.0.
+        let kind = |node: &Node| self.kind(node).filter(|kind| *kind <= Kind::Real);
+        match optok.type_() {
+            TokenType::Multiply if is_int(&right, 1) && kind(&left).is_some() => left,
+            TokenType::Multiply if is_int(&left, 1) && kind(&right).is_some() => right,
+            TokenType::Divide if is_int(&right, 1) && kind(&left).is_some() => left,
+            TokenType::Minus if is_int(&right, 0) && kind(&left).is_some() => left,
+            TokenType::Plus if is_int(&right, 0) && kind(&left) == Some(Kind::Exact) => left,
+            TokenType::Plus if is_int(&left, 0) && kind(&right) == Some(Kind::Exact) => right,
+            _ => Node::Binop(Box::new(left), optok.clone(), Box::new(right)),
+        }
+    }
+
+    fn unary(&mut self, optok: &Token, node: Node) -> Node {
+        let (pos_start, pos_end) = (optok.position_start(), node.pos_end());
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            return match truth(&node) {
+                Some(value) => literal(Value::Boolean(!value), pos_start, pos_end).unwrap(),
+                None => Node::Unary(optok.clone(), Box::new(node)),
+            };
+        }
+
+        if let Some(value) = constant(&node) {
+            let context = Context::init(&self.scope);
+            match operators::unary(optok, value, &context) {
+                Ok(value) => {
+                    if let Some(node) = literal(value, pos_start, pos_end) {
+                        return node;
+                    }
+                }
+                Err(error) => self.error(error),
+            }
+        }
+        Node::Unary(optok.clone(), Box::new(node))
+    }
+
+    // What kind of number the node gives, or `None` if it could give something else
+    fn kind(&self, node: &Node) -> Option<Kind> {
+        let number = |node: &Node| self.kind(node).unwrap_or(Kind::Number);
+        match node {
+            Node::Value(token) => match token.type_() {
+                TokenType::Int(_) | TokenType::BigInt(_) => Some(Kind::Exact),
+                TokenType::Float(_) => Some(Kind::Real),
+                TokenType::Imaginary(_) => Some(Kind::Number),
+                _ => None,
+            },
+            Node::VarAccessNode(token) => match token.type_() {
+                TokenType::Identifier(name) => self.kinds.get(&name).copied(),
+                _ => None,
+            },
+            // These fail for anything but numbers
+            Node::Unary(optok, node) if optok.type_() != TokenType::Keyword("ei".to_string()) => {
+                Some(number(node))
+            }
+            Node::Binop(..) => {
+                let operations = node.binop_chain();
+                let mut kind = self.kind(operations[0].0);
+                for (_, optok, right) in operations {
+                    kind = match optok.type_() {
+                        TokenType::Minus
+                        | TokenType::Multiply
+                        | TokenType::Divide
+                        | TokenType::Pow => Some(join(kind.unwrap_or(Kind::Number), number(right))),
+                        // Texts and lists can be added too
+                        TokenType::Plus => match (kind, self.kind(right)) {
+                            (Some(left), Some(right)) => Some(join(left, right)),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                }
+                kind
+            }
+            _ => None,
+        }
+    }
+
+    fn error(&mut self, error: ErrorType) {
+        self.errors.push(error.before_running());
+    }
+}
+
+fn join(kind1: Kind, kind2: Kind) -> Kind {
+    if kind1 > kind2 {
+        kind1
+    } else {
+        kind2
+    }
+}
+
+// Whether the function body sets the variable, which could give it a value of another type
+fn sets(node: &Node, name: &str) -> bool {
+    let named = |token: &Token| token.type_() == TokenType::Identifier(name.to_string());
+    match node {
+        Node::VarAssignNode(token, _, _) | Node::FuncDefNode(token, _, _, _) if named(token) => {
+            true
+        }
+        node => node.children().into_iter().any(|child| sets(child, name)),
+    }
+}
+
+fn constant(node: &Node) -> Option<Value> {
+    match node {
+        Node::Value(token) => Value::literal(token),
+        _ => None,
+    }
+}
+
+fn truth(node: &Node) -> Option<bool> {
+    match constant(node) {
+        Some(Value::Boolean(value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn is_int(node: &Node, expected: i64) -> bool {
+    matches!(node, Node::Value(token) if token.type_() == TokenType::Int(expected))
+}
+
+// The literal for a value. Fractions and complex numbers have none, so they are not folded.
+fn literal(value: Value, pos_start: Option<Position>, pos_end: Option<Position>) -> Option<Node> {
+    let type_ = match value {
+        Value::Number(NumberType::Integer(num)) => TokenType::Int(num.value()),
+        Value::Number(NumberType::Float(num)) => TokenType::Float(num.value()),
+        Value::Number(NumberType::BigInteger(num)) => TokenType::BigInt(num.value()),
+        Value::Text(text) => TokenType::String(text),
+        Value::Boolean(true) => TokenType::Keyword("tosi".to_string()),
+        Value::Boolean(false) => TokenType::Keyword("epätosi".to_string()),
+        _ => return None,
+    };
+    Some(Node::Value(Token::new(type_, pos_start, pos_end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn optimized(text: &str) -> Result<String, Vec<ErrorType>> {
+        let tokens = Lexer::new("test".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        let root = Parser::new(tokens).parse().unwrap();
+        optimize("Program", &root).map(|node| node.to_string())
+    }
+
+    fn same(text: &str) -> String {
+        let tokens = Lexer::new("test".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        Parser::new(tokens).parse().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_folding() {
+        let cases = [
+            ("4*(3-2)/(4-2)", "2"),
+            ("muut a = 2 ^ 10 + 1", "Int(1025)"),
+            ("0.5 * 3.0", "Float(1.5)"),
+            ("\"moi \" + \"maailma\"", "moi maailma"),
+            ("1 < 2 ja ei epätosi", "tosi"),
+            ("epätosi ja x", "epätosi"),
+            ("-(-3)", "3"),
+            ("2n ^ 64", "BigInt"),
+            // Only the operand, since the result is a 'murto'
+            ("2 ^ -1", "[Int(2), Pow, Int(-1)]"),
+        ];
+        for (text, expected) in cases {
+            assert!(optimized(text).unwrap().contains(expected), "{}", text);
+        }
+
+        // No literals for these, or the value depends on a variable
+        for text in ["murto(1, 2) + 1", "1 + 2i", "x + 1 + 2", "tosi ja x"] {
+            assert_eq!(optimized(text).unwrap(), same(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_identities() {
+        let text = "tominto f(n: kok, x: liu, s) { palata [n * 1, 1 * x, (n - s) / 1, n + 0, x + 0, s * 1, s + 0] }";
+        let expected = "tominto f(n: kok, x: liu, s) { palata [n * 1, 1 * x, (n - s) / 1, n + 0, x + 0, s * 1, s + 0] }"
+            .replace("n * 1", "n")
+            .replace("1 * x", "x")
+            .replace("n + 0", "n");
+        assert_eq!(optimized(text).unwrap(), same(&expected));
+
+        // A parameter the function sets could hold anything
+        let text = "tominto f(n: kok) { muut n = \"a\"; palata n * 1 }";
+        assert_eq!(optimized(text).unwrap(), same(text));
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        let errors =
+            optimized("muut a = 1 / 0\ntominto f() { palata 9223372036854775807 + 1 }\n\"a\" - 1")
+                .unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], ErrorType::DivisionByZeroError(_)));
+        let messages: Vec<String> = errors.iter().map(|e| e.as_string()).collect();
+        assert!(
+            messages[0].starts_with("DivisionByZero Error: Division by Zero, File test, line 1, col 13\n\nmuut a = 1 / 0\n"),
+            "{}",
+            messages[0]
+        );
+        assert!(
+            messages[1].contains("kokonaisluvun ylivuoto"),
+            "{}",
+            messages[1]
+        );
+        assert!(messages[1].contains("line 2"), "{}", messages[1]);
+        assert!(messages.iter().all(|m| !m.contains("Traceback")));
+        assert!(messages[2].contains("Cant use"), "{}", messages[2]);
+    }
+}
@@ -1,24 +1,41 @@
-use crate::errors::{DisallowedCharError, ErrorType, SyntaxError};
-use crate::lexer::Lexer;
+use crate::errors::{ErrorType, SyntaxError};
 use crate::position::Position;
 use crate::token::{
     Token,
     TokenType::{
-        self, Divide, Equal, Float, Identifier, Int, Keyword, LParen, Minus, Multiply, Plus, Pow,
-        RParen, String,
+        self, BigInt, Colon, Comma, Divide, Dot, EndOfFile, Equal, EqualEqual, Float, GreaterThan,
+        GreaterThanEqual, Identifier, Imaginary, Int, Keyword, LBrace, LBracket, LParen, LessThan,
+        LessThanEqual, Minus, Multiply, Newline, NotEqual, Plus, Pow, RBrace, RBracket, RParen,
+        String,
     },
 };
 use std::fmt;
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
+use std::rc::Rc;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
     Binop(Box<Node>, Token, Box<Node>),
     Value(Token),
     Unary(Token, Box<Node>),
     VarAccessNode(Token),
-    VarAssignNode(Token, Box<Node>),
+    // Name, type annotation and value
+    VarAssignNode(Token, Option<Token>, Box<Node>),
+    CallNode(Token, Vec<Node>),
+    // Opening bracket and items
+    ListNode(Token, Vec<Node>),
+    // Opening brace and fields
+    RecordNode(Token, Vec<(Token, Node)>),
+    // Collection, opening bracket and index
+    IndexNode(Box<Node>, Token, Box<Node>),
+    FieldNode(Box<Node>, Token),
+    // Name, parameters with their types, return type and body
+    FuncDefNode(Token, Vec<(Token, Option<Token>)>, Option<Token>, Rc<Node>),
+    // 'jos' keyword, conditions with their bodies, and the 'muuten' body
+    IfNode(Token, Vec<(Node, Node)>, Option<Box<Node>>),
+    WhileNode(Token, Box<Node>, Box<Node>),
+    ReturnNode(Token, Option<Box<Node>>),
+    StatementsNode(Vec<Node>),
 }
 
 impl Default for Node {
@@ -27,22 +44,170 @@ impl Default for Node {
     }
 }
 
+impl Node {
+    pub fn pos_start(&self) -> Option<Position> {
+        match self {
+            Node::Binop(..) => self.binop_chain()[0].0.pos_start(),
+            Node::Value(token) | Node::VarAccessNode(token) => token.position_start(),
+            Node::Unary(token, _)
+            | Node::VarAssignNode(token, _, _)
+            | Node::CallNode(token, _)
+            | Node::ListNode(token, _)
+            | Node::RecordNode(token, _)
+            | Node::FuncDefNode(token, _, _, _)
+            | Node::IfNode(token, _, _)
+            | Node::WhileNode(token, _, _)
+            | Node::ReturnNode(token, _) => token.position_start(),
+            Node::IndexNode(node, _, _) | Node::FieldNode(node, _) => node.pos_start(),
+            Node::StatementsNode(nodes) => nodes.first().and_then(|node| node.pos_start()),
+        }
+    }
+
+    /// End of the node, or of its header for nodes with a body
+    pub fn pos_end(&self) -> Option<Position> {
+        let token_end = |token: &Token| token.position_end().or_else(|| token.position_start());
+        match self {
+            Node::Binop(_, _, node)
+            | Node::Unary(_, node)
+            | Node::VarAssignNode(_, _, node)
+            | Node::IndexNode(_, _, node)
+            | Node::WhileNode(_, node, _)
+            | Node::ReturnNode(_, Some(node)) => node.pos_end(),
+            Node::Value(token)
+            | Node::VarAccessNode(token)
+            | Node::FieldNode(_, token)
+            | Node::FuncDefNode(token, _, _, _)
+            | Node::ReturnNode(token, None) => token_end(token),
+            Node::CallNode(token, nodes) | Node::ListNode(token, nodes) => match nodes.last() {
+                Some(node) => node.pos_end(),
+                None => token_end(token),
+            },
+            Node::RecordNode(token, fields) => match fields.last() {
+                Some((_, node)) => node.pos_end(),
+                None => token_end(token),
+            },
+            Node::IfNode(token, cases, _) => match cases.first() {
+                Some((condition, _)) => condition.pos_end(),
+                None => token_end(token),
+            },
+            Node::StatementsNode(nodes) => nodes.last().and_then(|node| node.pos_end()),
+        }
+    }
+
+    /// The operations of a chain like `1 + 2 - 3` as their left side, operator and right side,
+    /// innermost first. Chains can be as long as the input, so they are walked in a loop.
+    pub fn binop_chain(&self) -> Vec<(&Node, &Token, &Node)> {
+        let mut node = self;
+        let mut operations = Vec::new();
+        while let Node::Binop(left, optok, right) = node {
+            operations.push((&**left, optok, &**right));
+            node = left;
+        }
+        operations.reverse();
+        operations
+    }
+
+    /// The nodes directly inside this one. Function bodies are not, they run when called.
+    pub fn children(&self) -> Vec<&Node> {
+        match self {
+            // The operands of the whole chain, so walking them doesnt recurse once per operation
+            Node::Binop(..) => {
+                let operations = self.binop_chain();
+                let rights = operations.iter().map(|(_, _, right)| *right);
+                std::iter::once(operations[0].0).chain(rights).collect()
+            }
+            Node::Value(_) | Node::VarAccessNode(_) | Node::FuncDefNode(..) => vec![],
+            Node::Unary(_, node) | Node::VarAssignNode(_, _, node) | Node::FieldNode(node, _) => {
+                vec![node]
+            }
+            Node::CallNode(_, nodes) | Node::ListNode(_, nodes) | Node::StatementsNode(nodes) => {
+                nodes.iter().collect()
+            }
+            Node::RecordNode(_, fields) => fields.iter().map(|(_, node)| node).collect(),
+            Node::IndexNode(node, _, index) => vec![node, index],
+            Node::IfNode(_, cases, else_case) => {
+                let mut nodes: Vec<&Node> = Vec::new();
+                for (condition, body) in cases {
+                    nodes.push(condition);
+                    nodes.push(body);
+                }
+                nodes.extend(else_case.as_deref());
+                nodes
+            }
+            Node::WhileNode(_, condition, body) => vec![condition, body],
+            Node::ReturnNode(_, node) => node.as_deref().into_iter().collect(),
+        }
+    }
+}
+
+fn join(nodes: &[Node]) -> std::string::String {
+    let nodes: Vec<std::string::String> = nodes.iter().map(|n| n.to_string()).collect();
+    nodes.join(", ")
+}
+
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Node::Binop(left, op, right) => write!(f, "[{}, {}, {}]", left, op, right),
+            Node::Binop(..) => {
+                let operations = self.binop_chain();
+                write!(f, "{}{}", "[".repeat(operations.len()), operations[0].0)?;
+                for (_, op, right) in operations {
+                    write!(f, ", {}, {}]", op, right)?;
+                }
+                Ok(())
+            }
             Node::Value(val) => write!(f, "{}", val),
             Node::Unary(optok, node) => write!(f, "[{}, {}]", optok, node),
-            Node::VarAssignNode(optok, node) => write!(f, "[{}, {}]", optok, node),
+            Node::VarAssignNode(optok, _, node) => write!(f, "[{}, {}]", optok, node),
             Node::VarAccessNode(id) => write!(f, "{}", id),
+            Node::CallNode(name, args) => write!(f, "[{}, ({})]", name, join(args)),
+            Node::ListNode(_, items) => write!(f, "[List, ({})]", join(items)),
+            Node::RecordNode(_, fields) => {
+                let fields: Vec<std::string::String> = fields
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect();
+                write!(f, "[Record, ({})]", fields.join(", "))
+            }
+            Node::IndexNode(node, _, index) => write!(f, "[{}, Index, {}]", node, index),
+            Node::FieldNode(node, name) => write!(f, "[{}, Dot, {}]", node, name),
+            Node::FuncDefNode(name, params, _, body) => {
+                let params: Vec<std::string::String> =
+                    params.iter().map(|(p, _)| p.to_string()).collect();
+                write!(f, "[Function, {}, ({}), {}]", name, params.join(", "), body)
+            }
+            Node::IfNode(_, cases, else_case) => {
+                write!(f, "[If")?;
+                for (condition, body) in cases {
+                    write!(f, ", {} => {}", condition, body)?;
+                }
+                match else_case {
+                    Some(body) => write!(f, ", Else => {}]", body),
+                    None => write!(f, "]"),
+                }
+            }
+            Node::WhileNode(_, condition, body) => write!(f, "[While, {}, {}]", condition, body),
+            Node::ReturnNode(_, Some(node)) => write!(f, "[Return, {}]", node),
+            Node::ReturnNode(_, None) => write!(f, "[Return]"),
+            Node::StatementsNode(nodes) => {
+                let nodes: Vec<std::string::String> = nodes.iter().map(|n| n.to_string()).collect();
+                write!(f, "{}", nodes.join("; "))
+            }
         }
     }
 }
 
+/// How deep expressions may nest. The parser and the interpreter recurse once per level,
+/// so without a limit a long enough input would overflow the stack.
+pub const MAX_NESTING: usize = 1000;
+
 pub struct Parser {
     tokens: Vec<Token>,
     token_index: i64,
     current_token: Token,
+    // How deep the node being parsed will end up in the tree, with a chain like 1 + 2 - 3
+    // counting as one level since it is walked in a loop
+    depth: usize,
 }
 
 impl Parser {
@@ -51,6 +216,7 @@ impl Parser {
             tokens,
             token_index: -1,
             current_token: Token::default(),
+            depth: 0,
         };
         parser.advance();
         parser
@@ -62,8 +228,79 @@ impl Parser {
             self.current_token = self.tokens.get(self.token_index as usize).unwrap().clone();
         }
     }
+
+    fn skip_newlines(&mut self) {
+        while self.current_token.type_() == Newline {
+            self.advance();
+        }
+    }
+
+    // The first token after the current newlines, without moving
+    fn peek_past_newlines(&self) -> TokenType {
+        self.tokens[self.token_index as usize..]
+            .iter()
+            .map(|token| token.type_())
+            .find(|type_| *type_ != Newline)
+            .unwrap_or(EndOfFile)
+    }
+
+    // Goes one level deeper into the tree
+    fn nest(&mut self) -> Result<(), ErrorType> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(ErrorType::SyntaxError(SyntaxError::new(
+                self.current_token.position_start(),
+                self.current_token.position_end(),
+                format!("Expression is nested more than {} deep", MAX_NESTING),
+            )));
+        }
+        Ok(())
+    }
+
+    fn nested(
+        &mut self,
+        function: fn(&mut Parser) -> Result<Node, ErrorType>,
+    ) -> Result<Node, ErrorType> {
+        self.nest()?;
+        let node = function(self)?;
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.current_token.type_() == Keyword(keyword.to_string())
+    }
+
+    fn expect(&mut self, type_: TokenType, description: &str) -> Result<Token, ErrorType> {
+        let token = self.current_token.clone();
+        if token.type_() != type_ {
+            return Err(ErrorType::SyntaxError(SyntaxError::new(
+                token.position_start(),
+                token.position_end(),
+                format!("Expected {}", description),
+            )));
+        }
+        self.advance();
+        Ok(token)
+    }
+
+    fn expect_identifier(&mut self) -> Result<Token, ErrorType> {
+        let token = self.current_token.clone();
+        match token.type_() {
+            Identifier(_) => {
+                self.advance();
+                Ok(token)
+            }
+            t => Err(ErrorType::SyntaxError(SyntaxError::new(
+                token.position_start(),
+                token.position_end(),
+                format!("Expected Identifier. Found {:?}", t),
+            ))),
+        }
+    }
+
     pub fn parse(&mut self) -> Result<Node, ErrorType> {
-        let result = self.expression();
+        let result = self.statements();
         if result.is_ok() && self.current_token.type_() != TokenType::EndOfFile {
             return Err(ErrorType::SyntaxError(SyntaxError::new(
                 self.current_token.position_start(),
@@ -74,18 +311,69 @@ impl Parser {
         result
     }
 
+    fn statements(&mut self) -> Result<Node, ErrorType> {
+        let mut statements = Vec::new();
+        self.skip_newlines();
+        while !matches!(self.current_token.type_(), EndOfFile | RBrace) {
+            statements.push(self.statement()?);
+            if self.current_token.type_() != Newline {
+                break;
+            }
+            self.skip_newlines();
+        }
+        Ok(Node::StatementsNode(statements))
+    }
+
+    fn statement(&mut self) -> Result<Node, ErrorType> {
+        if self.is_keyword("palata") {
+            let token = self.current_token.clone();
+            self.advance();
+            if matches!(self.current_token.type_(), Newline | EndOfFile | RBrace) {
+                return Ok(Node::ReturnNode(token, None));
+            }
+            let expression = self.expression()?;
+            return Ok(Node::ReturnNode(token, Some(Box::new(expression))));
+        }
+        self.expression()
+    }
+
+    fn block(&mut self) -> Result<Node, ErrorType> {
+        self.expect(LBrace, "'{'")?;
+        let statements = self.statements()?;
+        self.expect(RBrace, "'}'")?;
+        Ok(statements)
+    }
+
+    // ': type' after a name, if there is one
+    fn type_annotation(&mut self) -> Result<Option<Token>, ErrorType> {
+        if self.current_token.type_() != Colon {
+            return Ok(None);
+        }
+        self.advance();
+        if self.is_keyword("tyhjä") {
+            let token = self.current_token.clone();
+            self.advance();
+            return Ok(Some(token));
+        }
+        self.expect_identifier().map(Some)
+    }
+
     fn atom(&mut self) -> Result<Node, ErrorType> {
         let token = self.current_token.clone();
         match token.type_() {
-            Int(_) | Float(_) => {
+            Int(_) | Float(_) | BigInt(_) | Imaginary(_) | String(_) => {
+                self.advance();
+                Ok(Node::Value(token))
+            }
+
+            Keyword(keyword) if matches!(keyword.as_str(), "tosi" | "epätosi" | "tyhjä") => {
                 self.advance();
                 Ok(Node::Value(token))
             }
 
             Identifier(_) => {
-                println!("Found Identifier");
                 self.advance();
-                return Ok(Node::VarAccessNode(token));
+                Ok(Node::VarAccessNode(token))
             }
 
             LParen => {
@@ -107,6 +395,25 @@ impl Parser {
                     }
                 }
             }
+
+            LBracket => {
+                self.advance();
+                let items = self.comma_separated(RBracket, Self::expression)?;
+                self.expect(RBracket, "',' or ']'")?;
+                Ok(Node::ListNode(token, items))
+            }
+
+            LBrace => {
+                self.advance();
+                let fields = self.comma_separated(RBrace, |parser| {
+                    let name = parser.expect_identifier()?;
+                    parser.expect(Colon, "':'")?;
+                    Ok((name, parser.expression()?))
+                })?;
+                self.expect(RBrace, "',' or '}'")?;
+                Ok(Node::RecordNode(token, fields))
+            }
+
             _ => Err(ErrorType::SyntaxError(SyntaxError::new(
                 token.position_start(),
                 token.position_end(),
@@ -115,25 +422,85 @@ impl Parser {
         }
     }
 
+    // Items up to the closing token, which is left for the caller
+    fn comma_separated<T>(
+        &mut self,
+        closing: TokenType,
+        item: fn(&mut Parser) -> Result<T, ErrorType>,
+    ) -> Result<Vec<T>, ErrorType> {
+        let mut items = Vec::new();
+        self.skip_newlines();
+        if self.current_token.type_() == closing {
+            return Ok(items);
+        }
+        items.push(item(self)?);
+        self.skip_newlines();
+        while self.current_token.type_() == Comma {
+            self.advance();
+            self.skip_newlines();
+            items.push(item(self)?);
+            self.skip_newlines();
+        }
+        Ok(items)
+    }
+
+    // Calls, indexing and field access after an atom: f(x), lista[0], tietue.nimi
+    fn call(&mut self) -> Result<Node, ErrorType> {
+        let name = self.current_token.clone();
+        let mut node = self.atom()?;
+        let mut levels = 0;
+        loop {
+            if let LParen | LBracket | Dot = self.current_token.type_() {
+                self.nest()?;
+                levels += 1;
+            }
+            match self.current_token.type_() {
+                LParen if matches!(node, Node::VarAccessNode(_)) => {
+                    self.advance();
+                    let arguments = self.comma_separated(RParen, Self::expression)?;
+                    self.expect(RParen, "',' or ')'")?;
+                    node = Node::CallNode(name.clone(), arguments);
+                }
+                LBracket => {
+                    let bracket = self.current_token.clone();
+                    self.advance();
+                    let index = self.expression()?;
+                    self.expect(RBracket, "']'")?;
+                    node = Node::IndexNode(Box::new(node), bracket, Box::new(index));
+                }
+                Dot => {
+                    self.advance();
+                    let field = self.expect_identifier()?;
+                    node = Node::FieldNode(Box::new(node), field);
+                }
+                _ => {
+                    self.depth -= levels;
+                    return Ok(node);
+                }
+            }
+        }
+    }
+
+    // The exponent is a factor, so powers are right associative and take a sign: 2^-3^2
     fn power(&mut self) -> Result<Node, ErrorType> {
-        let valid_operations = vec![Pow];
-        self.binary_operation(Self::atom, valid_operations)
+        let left = self.call()?;
+        if self.current_token.type_() != Pow {
+            return Ok(left);
+        }
+        let operation = self.current_token.clone();
+        self.advance();
+        let right = self.nested(Self::factor)?;
+        Ok(Node::Binop(Box::new(left), operation, Box::new(right)))
     }
 
     fn factor(&mut self) -> Result<Node, ErrorType> {
         //println!("{:?}", self.tokens);
         //println!("{:?} {:?}", self.token_index, self.current_token);
         let token = self.current_token.clone();
-        match token.type_() {
-            Plus | Minus => {
-                self.advance();
-                let result = self.factor();
-                match result {
-                    Ok(factor) => return Ok(Node::Unary(token, Box::new(factor))),
-                    Err(e) => return Err(e),
-                }
-            }
-            _ => (),
+        if let Plus | Minus = token.type_() {
+            self.advance();
+            let factor = self.nested(Self::factor)?;
+            return Ok(Node::Unary(token, Box::new(factor)));
         };
         self.power()
     }
@@ -144,51 +511,112 @@ impl Parser {
     }
 
     fn expression(&mut self) -> Result<Node, ErrorType> {
-        match &self.current_token.type_() {
-            Keyword(val) => {
-                if *val == "muut" {
-                    println!("Recognized Keyword 'muut'");
-                    self.advance();
-                    match &self.current_token.type_() {
-                        Identifier(_name) => {
-                            println!("Found Identifier");
-                            let variable_name = self.current_token.clone();
-                            self.advance();
-                            match &self.current_token.type_() {
-                                Equal => {
-                                    self.advance();
-                                    let expression = match self.expression() {
-                                        Ok(node) => node,
-                                        Err(e) => return Err(e),
-                                    };
-
-                                    return Ok(Node::VarAssignNode(
-                                        variable_name,
-                                        Box::new(expression),
-                                    ));
-                                }
-                                _ => {
-                                    return Err(ErrorType::SyntaxError(SyntaxError::new(
-                                        self.current_token.position_start(),
-                                        self.current_token.position_end(),
-                                        "Expected '='".to_string(),
-                                    )))
-                                }
-                            }
-                        }
-                        t => {
-                            return Err(ErrorType::SyntaxError(SyntaxError::new(
-                                self.current_token.position_start(),
-                                self.current_token.position_end(),
-                                format!("Expected Identifier. Found {:?}", t),
-                            )))
-                        }
-                    };
-                }
+        self.nested(Self::unnested_expression)
+    }
+
+    fn unnested_expression(&mut self) -> Result<Node, ErrorType> {
+        if self.is_keyword("muut") {
+            self.advance();
+            let variable_name = self.expect_identifier()?;
+            let type_ = self.type_annotation()?;
+            self.expect(Equal, "'='")?;
+            let expression = self.expression()?;
+            return Ok(Node::VarAssignNode(
+                variable_name,
+                type_,
+                Box::new(expression),
+            ));
+        }
+        if self.is_keyword("tominto") {
+            return self.function_definition();
+        }
+        if self.is_keyword("jos") {
+            return self.if_expression();
+        }
+        if self.is_keyword("kun") {
+            let keyword = self.current_token.clone();
+            self.advance();
+            let condition = self.expression()?;
+            let body = self.block()?;
+            return Ok(Node::WhileNode(
+                keyword,
+                Box::new(condition),
+                Box::new(body),
+            ));
+        }
+
+        self.or_expression()
+    }
+
+    fn function_definition(&mut self) -> Result<Node, ErrorType> {
+        self.advance();
+        let name = self.expect_identifier()?;
+        self.expect(LParen, "'('")?;
+        let params = self.comma_separated(RParen, |parser| {
+            let param = parser.expect_identifier()?;
+            Ok((param, parser.type_annotation()?))
+        })?;
+        self.expect(RParen, "',' or ')'")?;
+        let return_type = self.type_annotation()?;
+        let body = self.block()?;
+        Ok(Node::FuncDefNode(name, params, return_type, Rc::new(body)))
+    }
+
+    fn if_expression(&mut self) -> Result<Node, ErrorType> {
+        let keyword = self.current_token.clone();
+        let mut cases = Vec::new();
+        loop {
+            // At 'jos'
+            self.advance();
+            let condition = self.expression()?;
+            let body = self.block()?;
+            cases.push((condition, body));
+
+            if self.peek_past_newlines() != Keyword("muuten".to_string()) {
+                return Ok(Node::IfNode(keyword, cases, None));
+            }
+            self.skip_newlines();
+            self.advance();
+            if !self.is_keyword("jos") {
+                let else_case = self.block()?;
+                return Ok(Node::IfNode(keyword, cases, Some(Box::new(else_case))));
             }
-            _ => {}
         }
+    }
+
+    fn or_expression(&mut self) -> Result<Node, ErrorType> {
+        let valid_operations = vec![Keyword("tai".to_string())];
+        self.binary_operation(Self::and_expression, valid_operations)
+    }
+
+    fn and_expression(&mut self) -> Result<Node, ErrorType> {
+        let valid_operations = vec![Keyword("ja".to_string())];
+        self.binary_operation(Self::not_expression, valid_operations)
+    }
+
+    fn not_expression(&mut self) -> Result<Node, ErrorType> {
+        if self.is_keyword("ei") {
+            let token = self.current_token.clone();
+            self.advance();
+            let node = self.nested(Self::not_expression)?;
+            return Ok(Node::Unary(token, Box::new(node)));
+        }
+        self.comparison()
+    }
 
+    fn comparison(&mut self) -> Result<Node, ErrorType> {
+        let valid_operations = vec![
+            EqualEqual,
+            NotEqual,
+            LessThan,
+            GreaterThan,
+            LessThanEqual,
+            GreaterThanEqual,
+        ];
+        self.binary_operation(Self::arithmetic, valid_operations)
+    }
+
+    fn arithmetic(&mut self) -> Result<Node, ErrorType> {
         let valid_operations = vec![Plus, Minus];
         self.binary_operation(Self::term, valid_operations)
     }
@@ -198,26 +626,18 @@ impl Parser {
         function: fn(&mut Parser) -> Result<Node, ErrorType>,
         operation_tokens: Vec<TokenType>,
     ) -> Result<Node, ErrorType> {
-        let result = function(self);
-        let mut left = match result {
-            Ok(node) => node,
-            Err(e) => return Err(e),
-        };
-
+        let mut left = function(self)?;
         while operation_tokens.contains(&self.current_token.type_()) {
             let current = self.current_token.clone();
             self.advance();
-            let result = function(self);
-            let right = match result {
-                Ok(node) => node,
-                Err(e) => return Err(e),
-            };
+            let right = function(self)?;
             left = Node::Binop(Box::new(left), current, Box::new(right));
         }
         Ok(left)
     }
 }
 
+#[cfg(test)]
 fn print_ast(root: Node) {
     match root {
         Node::Binop(left, _op, right) => {
@@ -228,14 +648,39 @@ fn print_ast(root: Node) {
         Node::Unary(_op, node) => {
             print_ast(*node);
         }
-        Node::VarAssignNode(_op, node) => print_ast(*node),
+        Node::VarAssignNode(_op, _type, node) => print_ast(*node),
         Node::VarAccessNode(_) => {}
+        Node::CallNode(_name, args) | Node::ListNode(_name, args) => {
+            args.into_iter().for_each(print_ast)
+        }
+        Node::RecordNode(_, fields) => fields.into_iter().for_each(|(_, node)| print_ast(node)),
+        Node::IndexNode(node, _, index) => {
+            print_ast(*node);
+            print_ast(*index)
+        }
+        Node::FieldNode(node, _) => print_ast(*node),
+        Node::FuncDefNode(_, _, _, body) => print_ast((*body).clone()),
+        Node::IfNode(_, cases, else_case) => {
+            for (condition, body) in cases {
+                print_ast(condition);
+                print_ast(body)
+            }
+            else_case.into_iter().for_each(|body| print_ast(*body))
+        }
+        Node::WhileNode(_, condition, body) => {
+            print_ast(*condition);
+            print_ast(*body)
+        }
+        Node::ReturnNode(_, node) => node.into_iter().for_each(|node| print_ast(*node)),
+        Node::StatementsNode(nodes) => nodes.into_iter().for_each(print_ast),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::Lexer;
+
     fn get_tokens_from_str(s: &str) -> Vec<Token> {
         let mut lexer = Lexer::new("test".to_string(), s.to_string());
         let tokens = match lexer.tokenize() {
@@ -243,7 +688,7 @@ mod tests {
             Err(e) => panic!("Lexer failed to exctract tokens due to: {:?}", e),
         };
 
-        return tokens;
+        tokens
     }
 
     fn get_ast_from_string(s: &str) -> Node {
@@ -273,9 +718,85 @@ mod tests {
         assert_eq!(ast1_rep, ast2_rep);
     }
 
+    #[test]
+    fn test_call() {
+        let ast = get_ast_from_string("wrapping_add(1, 2*3)");
+        assert_eq!(
+            format!("{}", ast),
+            "[Identifier(\"wrapping_add\"), (Int(1), [Int(2), Multiply, Int(3)])]"
+        );
+        let ast = get_ast_from_string("f()");
+        assert_eq!(format!("{}", ast), "[Identifier(\"f\"), ()]");
+    }
+
     #[test]
     fn test_ast_print() {
         let ast = get_ast_from_string("3/1+2*4");
         print_ast(ast);
     }
+
+    #[test]
+    fn test_statements() {
+        let ast = get_ast_from_string("\nmuut a: kok = 1; muut b = a\n\n a + b\n");
+        match ast {
+            Node::StatementsNode(nodes) => assert_eq!(nodes.len(), 3),
+            other => panic!("Expected statements, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_collections() {
+        let ast = get_ast_from_string("[1, [\"a\"], {x: tosi, y: tyhjä}][1][0].z");
+        assert_eq!(
+            format!("{}", ast),
+            "[[[[List, (Int(1), [List, (String(\"a\"))], [Record, (Identifier(\"x\"): \
+             Keyword(\"tosi\"), Identifier(\"y\"): Keyword(\"tyhjä\"))])], Index, Int(1)], \
+             Index, Int(0)], Dot, Identifier(\"z\")]"
+        );
+    }
+
+    #[test]
+    fn test_function_and_control_flow() {
+        let text = "tominto f(n: kok, acc): kok {
+            jos n == 0 ja ei tosi {
+                palata acc
+            } muuten jos n < 0 {
+                palata 0
+            }
+            muuten {
+                kun n > 0 { muut n = n - 1 }
+                palata f(n - 1, acc + n)
+            }
+        }";
+        match get_ast_from_string(text) {
+            Node::StatementsNode(nodes) => match &nodes[..] {
+                [Node::FuncDefNode(name, params, Some(_), body)] => {
+                    assert_eq!(name.type_(), Identifier("f".to_string()));
+                    assert_eq!(params.len(), 2);
+                    assert!(params[0].1.is_some() && params[1].1.is_none());
+                    assert!(
+                        matches!(&**body, Node::StatementsNode(b) if matches!(&b[..], [Node::IfNode(_, cases, Some(_))] if cases.len() == 2))
+                    );
+                }
+                other => panic!("Expected a function, got {:?}", other),
+            },
+            other => panic!("Expected statements, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        for text in [
+            "muut = 5",
+            "[1, 2",
+            "f(1",
+            "{x 1}",
+            "jos tosi 1",
+            "tominto (x) {}",
+            "1 2",
+        ] {
+            let mut parser = Parser::new(get_tokens_from_str(text));
+            assert!(parser.parse().is_err(), "{}", text);
+        }
+    }
 }
@@ -0,0 +1,1135 @@
+use crate::ir::{
+    evaluate_binary, evaluate_unary, BinaryOp, BlockId, Const, Function, Inlined, Inst, Module,
+    Operand, Reg, Terminator, UnaryOp,
+};
+use crate::ssa::{self, Dominators};
+use std::collections::{HashMap, HashSet};
+
+// Optimisations of the IR. The first pass puts functions in SSA form and the others keep
+// them in it. No pass changes what a program prints or the error it ends with: values
+// are only computed at compile time when that does not fail, and instructions are only
+// moved out of loops when they cant fail.
+
+/// A change to every function of a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Puts functions in SSA form
+    Ssa,
+    /// Computes what can be computed at compile time, skips branches that cant be taken
+    /// and uses the values of copies instead of the copies
+    Constants,
+    /// Joins blocks that always follow each other, skips empty blocks and removes phis
+    /// that choose between the same values
+    Simplify,
+    /// Removes the instructions whose values are not used
+    DeadCode,
+    /// Uses the value of an earlier instruction that computes the same thing
+    Cse,
+    /// Moves instructions that compute the same value on every round of a loop to before
+    /// the loop
+    Licm,
+    /// Puts the bodies of small functions that are not recursive in place of calls to them
+    Inline,
+}
+
+pub const PASSES: [Pass; 7] = [
+    Pass::Ssa,
+    Pass::Constants,
+    Pass::Simplify,
+    Pass::DeadCode,
+    Pass::Cse,
+    Pass::Licm,
+    Pass::Inline,
+];
+
+/// The largest function, in instructions, that `Inline` puts in place of its calls unless
+/// told otherwise
+pub const INLINE_THRESHOLD: usize = 20;
+
+/// What `optimize` does to a module
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimizations {
+    level: u8,
+    inline_threshold: usize,
+}
+
+impl Optimizations {
+    /// The passes of `level`, inlining functions of up to `INLINE_THRESHOLD` instructions
+    pub fn new(level: u8) -> Self {
+        Self {
+            level,
+            inline_threshold: INLINE_THRESHOLD,
+        }
+    }
+
+    /// Functions with more instructions than this are not inlined, and 0 inlines none
+    pub fn with_inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = inline_threshold;
+        self
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn inline_threshold(&self) -> usize {
+        self.inline_threshold
+    }
+}
+
+impl Pass {
+    /// The name `--print-after` takes
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Ssa => "ssa",
+            Pass::Constants => "constprop",
+            Pass::Simplify => "simplify",
+            Pass::DeadCode => "dce",
+            Pass::Cse => "cse",
+            Pass::Licm => "licm",
+            Pass::Inline => "inline",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        PASSES.iter().copied().find(|pass| pass.name() == name)
+    }
+
+    /// Runs the pass on the functions of a module. Passes other than `Ssa` need functions
+    /// in SSA form.
+    pub fn run(self, module: &mut Module, optimizations: &Optimizations) {
+        let run: fn(&mut Function) = match self {
+            Pass::Ssa => ssa::construct,
+            Pass::Constants => constants,
+            Pass::Simplify => simplify,
+            Pass::DeadCode => dead_code,
+            Pass::Cse => common_subexpressions,
+            Pass::Licm => loop_invariants,
+            Pass::Inline => return inline(module, optimizations.inline_threshold),
+        };
+        for function in module.functions.iter_mut().chain(Some(&mut module.program)) {
+            run(function);
+        }
+    }
+}
+
+/// The passes of an optimisation level. Level 0 has none, level 1 computes constants and
+/// removes what is not needed, and level 2 also inlines small functions, reuses values
+/// and moves them out of loops.
+pub fn pipeline(level: u8) -> Vec<Pass> {
+    match level {
+        0 => Vec::new(),
+        1 => vec![Pass::Ssa, Pass::Constants, Pass::Simplify, Pass::DeadCode],
+        _ => vec![
+            Pass::Ssa,
+            Pass::Constants,
+            Pass::Simplify,
+            Pass::Inline,
+            Pass::Constants,
+            Pass::Simplify,
+            Pass::Cse,
+            Pass::Licm,
+            Pass::Constants,
+            Pass::Simplify,
+            Pass::DeadCode,
+        ],
+    }
+}
+
+/// Runs the passes of a level on a module, calling `after` with the module after each pass
+pub fn optimize(
+    module: &mut Module,
+    optimizations: &Optimizations,
+    mut after: impl FnMut(Pass, &Module),
+) {
+    for pass in pipeline(optimizations.level) {
+        pass.run(module, optimizations);
+        if let Err(e) = ssa::verify(module) {
+            panic!(
+                "The {} pass made invalid IR: {}\n{}",
+                pass.name(),
+                e,
+                module
+            );
+        }
+        after(pass, module);
+    }
+}
+
+// Whether two constants are the same value, telling 0.0 and -0.0 apart
+fn same(a: &Const, b: &Const) -> bool {
+    match (a, b) {
+        (Const::Float(a), Const::Float(b)) => a.to_bits() == b.to_bits(),
+        (a, b) => a == b,
+    }
+}
+
+fn same_operand(a: &Operand, b: &Operand) -> bool {
+    match (a, b) {
+        (Operand::Const(a), Operand::Const(b)) => same(a, b),
+        (a, b) => a == b,
+    }
+}
+
+// Removes the instructions of a function that set the given registers
+fn remove_setting(function: &mut Function, removed: &HashSet<Reg>) {
+    for block in &mut function.blocks {
+        block
+            .insts
+            .retain(|inst| !matches!(inst.dest(), Some(dest) if removed.contains(&dest)));
+    }
+}
+
+// Constants
+
+// What is known about a register: nothing yet, its value, or that it can have more than
+// one value
+#[derive(Debug, Clone)]
+enum Lattice {
+    Unknown,
+    Known(Const),
+    Varying,
+}
+
+impl Lattice {
+    fn same(&self, other: &Lattice) -> bool {
+        match (self, other) {
+            (Lattice::Known(a), Lattice::Known(b)) => same(a, b),
+            (Lattice::Unknown, Lattice::Unknown) | (Lattice::Varying, Lattice::Varying) => true,
+            _ => false,
+        }
+    }
+
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Unknown, value) | (value, Lattice::Unknown) => value,
+            (Lattice::Known(a), Lattice::Known(b)) if same(&a, &b) => Lattice::Known(a),
+            _ => Lattice::Varying,
+        }
+    }
+}
+
+// Sparse conditional constant propagation, from "Constant Propagation with Conditional
+// Branches" by Wegman and Zadeck, run over the blocks until nothing changes. Blocks are
+// only followed from branches that can be taken, so values from the others dont make
+// phis vary.
+fn constants(function: &mut Function) {
+    let order = function.reverse_postorder();
+    let mut values = vec![Lattice::Unknown; function.regs.len()];
+    for param in &function.params {
+        values[param.0] = Lattice::Varying;
+    }
+    let mut reached = vec![false; function.blocks.len()];
+    reached[0] = true;
+    let mut edges: HashSet<(BlockId, BlockId)> = HashSet::new();
+    let value = |values: &[Lattice], operand: &Operand| match operand {
+        Operand::Reg(reg) => values[reg.0].clone(),
+        Operand::Const(value) => Lattice::Known(value.clone()),
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order {
+            if !reached[block.0] {
+                continue;
+            }
+            for inst in &function.block(block).insts {
+                let new = match inst {
+                    Inst::Phi { incoming, .. } => incoming
+                        .iter()
+                        .filter(|(from, _)| edges.contains(&(*from, block)))
+                        .fold(Lattice::Unknown, |known, (_, operand)| {
+                            known.meet(value(&values, operand))
+                        }),
+                    Inst::Copy { value: operand, .. } => value(&values, operand),
+                    Inst::Binary {
+                        op, left, right, ..
+                    } => match (value(&values, left), value(&values, right)) {
+                        // Values that fail are left for the program to fail with
+                        (Lattice::Known(left), Lattice::Known(right)) => {
+                            match evaluate_binary(*op, &left, &right) {
+                                Ok(result) => Lattice::Known(result),
+                                Err(_) => Lattice::Varying,
+                            }
+                        }
+                        (Lattice::Varying, _) | (_, Lattice::Varying) => Lattice::Varying,
+                        _ => Lattice::Unknown,
+                    },
+                    Inst::Unary {
+                        op, value: operand, ..
+                    } => match value(&values, operand) {
+                        Lattice::Known(known) => match evaluate_unary(*op, &known) {
+                            Ok(result) => Lattice::Known(result),
+                            Err(_) => Lattice::Varying,
+                        },
+                        other => other,
+                    },
+                    Inst::Convert { value: operand, .. } => match value(&values, operand) {
+                        Lattice::Known(Const::Int(int)) => Lattice::Known(Const::Float(int as f64)),
+                        other => other,
+                    },
+                    Inst::Call { .. } | Inst::Load { .. } => Lattice::Varying,
+                    Inst::Store { .. } | Inst::Print { .. } | Inst::Enter { .. } => continue,
+                };
+                let dest = match inst.dest() {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+                if !values[dest.0].same(&new) {
+                    values[dest.0] = new;
+                    changed = true;
+                }
+            }
+            let targets = match &function.block(block).terminator {
+                Terminator::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => match value(&values, condition) {
+                    Lattice::Known(Const::Bool(true)) => vec![*then],
+                    Lattice::Known(Const::Bool(false)) => vec![*otherwise],
+                    Lattice::Varying => vec![*then, *otherwise],
+                    _ => Vec::new(),
+                },
+                terminator => terminator.successors(),
+            };
+            for target in targets {
+                changed |= edges.insert((block, target));
+                if !reached[target.0] {
+                    reached[target.0] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    // Known registers are replaced by their values, and copies by what they copy. The
+    // copy keeps the name of the variable if the copied register has none.
+    let mut replacements = HashMap::new();
+    for (i, known) in values.iter().enumerate() {
+        if let Lattice::Known(value) = known {
+            replacements.insert(Reg(i), Operand::Const(value.clone()));
+        }
+    }
+    for block in &function.blocks {
+        for inst in &block.insts {
+            if let Inst::Copy {
+                dest,
+                value: Operand::Reg(source),
+            } = inst
+            {
+                replacements.insert(*dest, Operand::Reg(*source));
+            }
+        }
+    }
+    for (dest, replacement) in &replacements {
+        if let Operand::Reg(source) = replacement {
+            if function.regs[source.0].name.is_none() {
+                function.regs[source.0].name = function.regs[dest.0].name.clone();
+            }
+        }
+    }
+    let removed: HashSet<Reg> = replacements.keys().copied().collect();
+    remove_setting(function, &removed);
+    function.replace_uses(&replacements);
+    for block in &mut function.blocks {
+        if let Terminator::Branch {
+            condition: Operand::Const(Const::Bool(condition)),
+            then,
+            otherwise,
+        } = block.terminator
+        {
+            block.terminator = Terminator::Jump(if condition { then } else { otherwise });
+        }
+    }
+    function.sort_blocks();
+    function.remove_unused_regs();
+}
+
+// Simplifying
+
+fn simplify(function: &mut Function) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        function.sort_blocks();
+
+        // Phis whose values are all the same, or the phi itself on a loop, are that value
+        let mut replacements = HashMap::new();
+        for block in &function.blocks {
+            for inst in &block.insts {
+                if let Inst::Phi { dest, incoming } = inst {
+                    let mut values = incoming
+                        .iter()
+                        .map(|(_, value)| value)
+                        .filter(|value| **value != Operand::Reg(*dest));
+                    let first = match values.next() {
+                        Some(first) => first,
+                        None => continue,
+                    };
+                    if values.all(|value| same_operand(value, first)) {
+                        replacements.insert(*dest, first.clone());
+                    }
+                }
+            }
+        }
+        if !replacements.is_empty() {
+            let removed: HashSet<Reg> = replacements.keys().copied().collect();
+            remove_setting(function, &removed);
+            function.replace_uses(&replacements);
+            changed = true;
+        }
+
+        for block in &mut function.blocks {
+            if let Terminator::Branch {
+                then, otherwise, ..
+            } = block.terminator
+            {
+                if then == otherwise {
+                    block.terminator = Terminator::Jump(then);
+                    changed = true;
+                }
+            }
+        }
+
+        // Blocks that only jump to a block that nothing else jumps to are joined with it
+        let predecessors = function.predecessors();
+        for i in 0..function.blocks.len() {
+            let next = match function.blocks[i].terminator {
+                Terminator::Jump(next) => next,
+                _ => continue,
+            };
+            if next.0 == i || next.0 == 0 || predecessors[next.0] != [BlockId(i)] {
+                continue;
+            }
+            let next_block = &function.blocks[next.0];
+            if next_block
+                .insts
+                .iter()
+                .any(|inst| matches!(inst, Inst::Phi { .. }))
+            {
+                continue;
+            }
+            let insts = std::mem::take(&mut function.blocks[next.0].insts);
+            let terminator = std::mem::replace(
+                &mut function.blocks[next.0].terminator,
+                Terminator::Return(None),
+            );
+            rename_predecessor(function, &terminator.successors(), next, BlockId(i));
+            function.blocks[i].insts.extend(insts);
+            function.blocks[i].terminator = terminator;
+            // Nothing jumps to the joined block now, so the predecessors are out of date
+            changed = true;
+            break;
+        }
+        if changed {
+            continue;
+        }
+
+        // Empty blocks that only jump are skipped
+        for i in 1..function.blocks.len() {
+            let target = match &function.blocks[i] {
+                block if block.insts.is_empty() => match block.terminator {
+                    Terminator::Jump(target) if target.0 != i => target,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let from = &predecessors[i];
+            let has_phis = function.blocks[target.0]
+                .insts
+                .iter()
+                .any(|inst| matches!(inst, Inst::Phi { .. }));
+            // Phis need a value for each block, so blocks that already jump to the target
+            // cant jump there twice
+            if has_phis
+                && from
+                    .iter()
+                    .any(|block| predecessors[target.0].contains(block))
+            {
+                continue;
+            }
+            for inst in &mut function.blocks[target.0].insts {
+                if let Inst::Phi { incoming, .. } = inst {
+                    let position = incoming
+                        .iter()
+                        .position(|(block, _)| block.0 == i)
+                        .expect("Phis have a value for each block before theirs");
+                    let (_, value) = incoming.remove(position);
+                    incoming.extend(from.iter().map(|block| (*block, value.clone())));
+                }
+            }
+            for block in from {
+                for successor in function.blocks[block.0].terminator.successors_mut() {
+                    if successor.0 == i {
+                        *successor = target;
+                    }
+                }
+            }
+            changed = true;
+            break;
+        }
+    }
+    function.remove_unused_regs();
+}
+
+// Phis of the blocks after a block that was joined with the one before it get their values
+// from that block
+fn rename_predecessor(function: &mut Function, blocks: &[BlockId], old: BlockId, new: BlockId) {
+    for block in blocks {
+        for inst in &mut function.block_mut(*block).insts {
+            if let Inst::Phi { incoming, .. } = inst {
+                for (from, _) in incoming.iter_mut() {
+                    if *from == old {
+                        *from = new;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Dead code
+
+fn dead_code(function: &mut Function) {
+    let mut sets: HashMap<Reg, (usize, usize)> = HashMap::new();
+    for (i, block) in function.blocks.iter().enumerate() {
+        for (j, inst) in block.insts.iter().enumerate() {
+            if let Some(dest) = inst.dest() {
+                sets.insert(dest, (i, j));
+            }
+        }
+    }
+    // Registers are used if instructions that do more than set them use them
+    let mut used = vec![false; function.regs.len()];
+    let mut work = Vec::new();
+    for block in &function.blocks {
+        for inst in &block.insts {
+            if inst.has_effects(function) {
+                work.extend(inst.operands().into_iter().cloned());
+            }
+        }
+        work.extend(block.terminator.operands().into_iter().cloned());
+    }
+    while let Some(operand) = work.pop() {
+        let reg = match operand {
+            Operand::Reg(reg) if !used[reg.0] => reg,
+            _ => continue,
+        };
+        used[reg.0] = true;
+        if let Some((block, index)) = sets.get(&reg) {
+            let inst = &function.blocks[*block].insts[*index];
+            work.extend(inst.operands().into_iter().cloned());
+        }
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    let mut kept = Vec::with_capacity(blocks.len());
+    for mut block in blocks {
+        block.insts.retain(|inst| match inst.dest() {
+            Some(dest) => used[dest.0] || inst.has_effects(function),
+            None => true,
+        });
+        for inst in &mut block.insts {
+            if let Inst::Call { dest, .. } = inst {
+                if matches!(dest, Some(reg) if !used[reg.0]) {
+                    *dest = None;
+                }
+            }
+        }
+        kept.push(block);
+    }
+    function.blocks = kept;
+    function.remove_unused_regs();
+}
+
+// Common subexpressions
+
+// An operand that can be hashed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Value {
+    Reg(Reg),
+    Int(i64),
+    Float(u64),
+    Bool(bool),
+    Text(String),
+}
+
+impl From<&Operand> for Value {
+    fn from(operand: &Operand) -> Self {
+        match operand {
+            Operand::Reg(reg) => Value::Reg(*reg),
+            Operand::Const(Const::Int(value)) => Value::Int(*value),
+            Operand::Const(Const::Float(value)) => Value::Float(value.to_bits()),
+            Operand::Const(Const::Bool(value)) => Value::Bool(*value),
+            Operand::Const(Const::Text(text)) => Value::Text(text.clone()),
+        }
+    }
+}
+
+// What an instruction computes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Expression {
+    Binary(BinaryOp, Value, Value),
+    Unary(UnaryOp, Value),
+    Convert(Value),
+}
+
+impl Expression {
+    fn of(inst: &Inst) -> Option<(Expression, Reg)> {
+        match inst {
+            Inst::Binary {
+                dest,
+                op,
+                left,
+                right,
+                ..
+            } => Some((Expression::Binary(*op, left.into(), right.into()), *dest)),
+            Inst::Unary {
+                dest, op, value, ..
+            } => Some((Expression::Unary(*op, value.into()), *dest)),
+            Inst::Convert { dest, value } => Some((Expression::Convert(value.into()), *dest)),
+            _ => None,
+        }
+    }
+}
+
+// Values are reused in the blocks their instruction dominates. Instructions that can fail
+// are reused too: when an earlier one with the same values fails, the later one is never
+// reached.
+fn common_subexpressions(function: &mut Function) {
+    let dominators = Dominators::new(function);
+    let children = dominators.children();
+    let mut replacements = HashMap::new();
+    let mut available = HashMap::new();
+    reuse(
+        function,
+        BlockId(0),
+        &children,
+        &mut available,
+        &mut replacements,
+    );
+    let removed: HashSet<Reg> = replacements.keys().copied().collect();
+    remove_setting(function, &removed);
+    function.replace_uses(&replacements);
+    function.remove_unused_regs();
+}
+
+fn reuse(
+    function: &mut Function,
+    block: BlockId,
+    children: &[Vec<BlockId>],
+    available: &mut HashMap<Expression, Reg>,
+    replacements: &mut HashMap<Reg, Operand>,
+) {
+    let mut added = Vec::new();
+    for inst in &mut function.block_mut(block).insts {
+        if matches!(inst, Inst::Phi { .. }) {
+            continue;
+        }
+        // Values the instruction uses may have been replaced already
+        for operand in inst.operands_mut() {
+            if let Operand::Reg(reg) = operand {
+                if let Some(replacement) = replacements.get(reg) {
+                    *operand = replacement.clone();
+                }
+            }
+        }
+        let (expression, dest) = match Expression::of(inst) {
+            Some(expression) => expression,
+            None => continue,
+        };
+        match available.get(&expression) {
+            Some(earlier) => {
+                replacements.insert(dest, Operand::Reg(*earlier));
+            }
+            None => {
+                available.insert(expression.clone(), dest);
+                added.push(expression);
+            }
+        }
+    }
+    for child in &children[block.0] {
+        reuse(function, *child, children, available, replacements);
+    }
+    for expression in added {
+        available.remove(&expression);
+    }
+}
+
+// Loop invariants
+
+// Instructions are moved to the block before the loop, which must be the only block
+// outside the loop that jumps to its start and must always jump there. The lowering
+// makes such a block for every `kun`.
+fn loop_invariants(function: &mut Function) {
+    let dominators = Dominators::new(function);
+    let predecessors = function.predecessors();
+
+    // The blocks of each loop, found from the blocks that jump back to its start
+    let mut loops: HashMap<BlockId, HashSet<BlockId>> = HashMap::new();
+    for &block in dominators.order() {
+        for start in function.block(block).terminator.successors() {
+            if !dominators.dominates(start, block) {
+                continue;
+            }
+            let body = loops
+                .entry(start)
+                .or_insert_with(|| Some(start).into_iter().collect());
+            let mut work = vec![block];
+            while let Some(current) = work.pop() {
+                if body.insert(current) {
+                    work.extend(predecessors[current.0].iter().copied());
+                }
+            }
+        }
+    }
+    // Inner loops first, so their invariants can leave the outer loops too
+    let mut loops: Vec<(BlockId, HashSet<BlockId>)> = loops.into_iter().collect();
+    loops.sort_by_key(|(start, body)| (body.len(), start.0));
+
+    let mut sets: HashMap<Reg, BlockId> = HashMap::new();
+    for (i, block) in function.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            if let Some(dest) = inst.dest() {
+                sets.insert(dest, BlockId(i));
+            }
+        }
+    }
+    for (start, body) in loops {
+        let outside: Vec<BlockId> = predecessors[start.0]
+            .iter()
+            .filter(|block| !body.contains(block))
+            .copied()
+            .collect();
+        let before = match outside.as_slice() {
+            [before] if function.block(*before).terminator == Terminator::Jump(start) => *before,
+            _ => continue,
+        };
+        let calls = body.iter().any(|block| {
+            function
+                .block(*block)
+                .insts
+                .iter()
+                .any(|inst| matches!(inst, Inst::Call { .. }))
+        });
+        let stored: HashSet<usize> = body
+            .iter()
+            .flat_map(|block| &function.block(*block).insts)
+            .filter_map(|inst| match inst {
+                Inst::Store { global, .. } => Some(*global),
+                _ => None,
+            })
+            .collect();
+        let order: Vec<BlockId> = dominators
+            .order()
+            .iter()
+            .filter(|block| body.contains(block))
+            .copied()
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order {
+                let mut i = 0;
+                while i < function.block(block).insts.len() {
+                    let inst = &function.block(block).insts[i];
+                    let movable = match inst {
+                        Inst::Binary { .. }
+                        | Inst::Unary { .. }
+                        | Inst::Convert { .. }
+                        | Inst::Copy { .. } => !inst.can_fail(function),
+                        Inst::Load { global, .. } => !calls && !stored.contains(global),
+                        _ => false,
+                    };
+                    let invariant = inst.operands().iter().all(|operand| match operand {
+                        Operand::Reg(reg) => match sets.get(reg) {
+                            Some(set) => !body.contains(set),
+                            // Parameters are set before any loop
+                            None => true,
+                        },
+                        Operand::Const(_) => true,
+                    });
+                    if !(movable && invariant) {
+                        i += 1;
+                        continue;
+                    }
+                    let inst = function.block_mut(block).insts.remove(i);
+                    let dest = inst.dest().expect("Moved instructions set registers");
+                    sets.insert(dest, before);
+                    function.block_mut(before).insts.push(inst);
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+// Inlining
+
+// Calls are replaced by the bodies of the functions they call when those are small and cant
+// call themselves, directly or through other functions. Functions get the bodies of the
+// functions they call after those got theirs, so a body has its own calls inlined already.
+// Instructions from a body keep the call for their tracebacks, and calls from it count the
+// inlined call towards the call depth limit. The body starts with an `Enter` that fails
+// where the call would have failed when calls nest too deep.
+fn inline(module: &mut Module, threshold: usize) {
+    let calls: Vec<Vec<usize>> = module.functions.iter().map(callees).collect();
+    let recursive: Vec<bool> = (0..calls.len())
+        .map(|start| {
+            let mut seen = vec![false; calls.len()];
+            let mut work = calls[start].clone();
+            while let Some(next) = work.pop() {
+                if next == start {
+                    return true;
+                }
+                if !std::mem::replace(&mut seen[next], true) {
+                    work.extend(&calls[next]);
+                }
+            }
+            false
+        })
+        .collect();
+    let mut order = Vec::with_capacity(calls.len());
+    let mut visited = vec![false; calls.len()];
+    for function in 0..calls.len() {
+        postorder(function, &calls, &mut visited, &mut order);
+    }
+
+    let inline = |functions: &[Function], function: &mut Function| {
+        inline_calls(function, functions, |callee| {
+            !recursive[callee] && functions[callee].size() <= threshold
+        })
+    };
+    for index in order {
+        // A function that is not recursive is not inlined into itself
+        let mut function = std::mem::replace(&mut module.functions[index], Function::new("", None));
+        inline(&module.functions, &mut function);
+        module.functions[index] = function;
+    }
+    inline(&module.functions, &mut module.program);
+}
+
+// The functions a function calls
+fn callees(function: &Function) -> Vec<usize> {
+    function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.insts)
+        .filter_map(|inst| match inst {
+            Inst::Call { function, .. } => Some(*function),
+            _ => None,
+        })
+        .collect()
+}
+
+// Functions after the functions they call, except when they call each other
+fn postorder(function: usize, calls: &[Vec<usize>], visited: &mut [bool], order: &mut Vec<usize>) {
+    if std::mem::replace(&mut visited[function], true) {
+        return;
+    }
+    for callee in &calls[function] {
+        postorder(*callee, calls, visited, order);
+    }
+    order.push(function);
+}
+
+fn inline_calls(function: &mut Function, functions: &[Function], inlined: impl Fn(usize) -> bool) {
+    let mut block = 0;
+    while block < function.blocks.len() {
+        let call = function.blocks[block].insts.iter().position(
+            |inst| matches!(inst, Inst::Call { function: callee, .. } if inlined(*callee)),
+        );
+        match call {
+            Some(index) => inline_call(function, BlockId(block), index, functions),
+            None => block += 1,
+        }
+    }
+    function.sort_blocks();
+}
+
+// Replaces the call at `index` of `block` with the body of the function it calls. The
+// instructions after the call move to a new block, which the returns of the body jump to.
+fn inline_call(function: &mut Function, block: BlockId, index: usize, functions: &[Function]) {
+    let rest = function.block_mut(block).insts.split_off(index + 1);
+    let (dest, callee, args, call) = match function.block_mut(block).insts.pop() {
+        Some(Inst::Call {
+            dest,
+            function: callee,
+            args,
+            span,
+            mut inlined,
+            ..
+        }) => {
+            inlined.push(Inlined {
+                function: callee,
+                args: args.clone(),
+                span,
+            });
+            (dest, &functions[callee], args, inlined)
+        }
+        _ => panic!("Only calls are inlined"),
+    };
+    let after = function.new_block();
+    let terminator = std::mem::replace(
+        &mut function.block_mut(block).terminator,
+        Terminator::Return(None),
+    );
+    rename_predecessor(function, &terminator.successors(), block, after);
+    function.block_mut(after).insts = rest;
+    function.block_mut(after).terminator = terminator;
+
+    // The body gets registers and blocks of its own
+    let regs: Vec<Reg> = callee
+        .regs
+        .iter()
+        .map(|info| function.new_reg(info.type_.clone(), info.name.clone()))
+        .collect();
+    let first = function.blocks.len();
+    let mut returned = Vec::new();
+    for (i, body) in callee.blocks.iter().enumerate() {
+        let mut body = body.clone();
+        for inst in &mut body.insts {
+            let fails = inst.can_fail(callee);
+            if let Some(dest) = inst.dest_mut() {
+                *dest = regs[dest.0];
+            }
+            for operand in inst.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    *reg = regs[reg.0];
+                }
+            }
+            if let Inst::Phi { incoming, .. } = inst {
+                for (from, _) in incoming.iter_mut() {
+                    *from = BlockId(first + from.0);
+                }
+            }
+            // The call goes before the calls the instruction was inlined from in the body
+            match inst.inlined_mut() {
+                Some(inlined) if fails => {
+                    inlined.splice(0..0, call.iter().cloned());
+                }
+                _ => (),
+            }
+        }
+        for target in body.terminator.successors_mut() {
+            *target = BlockId(first + target.0);
+        }
+        for operand in body.terminator.operands_mut() {
+            if let Operand::Reg(reg) = operand {
+                *reg = regs[reg.0];
+            }
+        }
+        if let Terminator::Return(value) = body.terminator {
+            returned.extend(value.map(|value| (BlockId(first + i), value)));
+            body.terminator = Terminator::Jump(after);
+        }
+        function.blocks.push(body);
+    }
+
+    function
+        .block_mut(block)
+        .insts
+        .push(Inst::Enter { inlined: call });
+    for (param, arg) in callee.params.iter().zip(args) {
+        function.block_mut(block).insts.push(Inst::Copy {
+            dest: regs[param.0],
+            value: arg,
+        });
+    }
+    function.block_mut(block).terminator = Terminator::Jump(BlockId(first));
+    if let Some(dest) = dest {
+        let phi = Inst::Phi {
+            dest,
+            incoming: returned,
+        };
+        function.block_mut(after).insts.insert(0, phi);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::tests::{assert_same, lower_text, PROGRAMS};
+
+    fn optimized(text: &str, level: u8) -> Module {
+        let mut module = lower_text(text);
+        optimize(&mut module, &Optimizations::new(level), |_, _| ());
+        module
+    }
+
+    #[test]
+    fn test_same_as_interpeter() {
+        let programs = [
+            "tominto f(a: kok, b: kok): kok { palata (a + b) * (a + b) - (a + b) }; tulosta(f(3, 4))",
+            "tominto f(n: kok, x: liu): liu { muut s = 0.0; muut i = 0; kun i < n { muut s = s + x * 2.0; muut i = i + 1 }; palata s }; tulosta(f(10, 1.5))",
+            "muut a = 2; muut b = a * 3; jos b > 5 { tulosta(b) } muuten { tulosta(0) }",
+            "muut k = 3; tominto f(n: kok): kok { muut s = 0; kun n > 0 { muut s = s + k; muut n = n - 1 }; palata s }; tulosta(f(4))",
+            "muut i = 0; kun i < 3 { muut j = 0; kun j < 2 { tulosta(i * 10 + j, liu(i) * 0.5); muut j = j + 1 }; muut i = i + 1 }",
+            "muut x = 0.0; muut y = -x; tulosta(x, y, 1.0 / (0.0 - y) > 0.0)",
+            "muut n = 0.0 / 1.0 * 0.0; muut z = n / 1.0; muut y = z; tulosta(y == z, y != z)",
+            "tominto f(n: kok): kok { jos n > 2 { palata 1 / (n - 3) }; palata n }; tulosta(f(1), f(2)); f(3)",
+        ];
+        for level in 1..=2 {
+            for text in PROGRAMS.iter().chain(&programs) {
+                assert_same(text, move |mut module| {
+                    optimize(&mut module, &Optimizations::new(level), |_, _| ());
+                    module
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let module = optimized(
+            "muut a = 2; muut b = a * 3; jos b > 5 { tulosta(b) } muuten { tulosta(0) }",
+            1,
+        );
+        assert_eq!(
+            module.to_string(),
+            "program {\nb0:\n    print 6\n    ret\n}\n"
+        );
+
+        // Values that fail stay for the program to fail with
+        let module = optimized(
+            "muut a = 9223372036854775807; tulosta(1); tulosta(a + 1)",
+            1,
+        );
+        let expected = "program {
+b0:
+    print 1
+    %0: kok = add 9223372036854775807, 1
+    print %0
+    ret
+}
+";
+        assert_eq!(module.to_string(), expected);
+    }
+
+    #[test]
+    fn test_loops() {
+        let text = "tominto f(n: kok, x: liu): liu {
+    muut s = 0.0
+    muut i = 0
+    kun i < n {
+        muut s = s + (x * 2.0 + x * 2.0)
+        muut i = i + 1
+    }
+    palata s
+}";
+        let expected = "fn f(%n: kok, %x: liu) -> liu {
+b0:
+    %0: liu = mul %x, 2.0
+    %1: liu = add %0, %0
+    jmp b1
+b1:          ; from b0, b2
+    %i.1: kok = phi [b0: 0], [b2: %i.2]
+    %s.1: liu = phi [b0: 0.0], [b2: %s.2]
+    %2: totuus = lt %i.1, %n
+    br %2, b2, b3
+b2:          ; from b1
+    %s.2: liu = add %s.1, %1
+    %i.2: kok = add %i.1, 1
+    jmp b1
+b3:          ; from b1
+    ret %s.1
+}
+
+program {
+b0:
+    ret
+}
+";
+        assert_eq!(optimized(text, 2).to_string(), expected);
+    }
+
+    #[test]
+    fn test_inline() {
+        let text = "tominto neliö(x: kok): kok { palata x * x }
+tominto summa(a: kok, b: kok): kok { palata neliö(a) + neliö(b) }
+tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }
+tominto f(n: kok): kok { palata summa(n, n + 1) + fib(n) }";
+        let module = optimized(text, 2);
+        let expected = "fn f(%n: kok) -> kok {
+b0:
+    %b: kok = add %n, 1
+    enter summa(%n, %b)
+    enter summa(%n, %b) > neliö(%n)
+    %0: kok = mul %n, %n    ; in summa(%n, %b) > neliö(%n)
+    enter summa(%n, %b) > neliö(%b)
+    %1: kok = mul %b, %b    ; in summa(%n, %b) > neliö(%b)
+    %2: kok = add %0, %1    ; in summa(%n, %b)
+    %3: kok = call fib(%n)
+    %4: kok = add %2, %3
+    ret %4
+}
+";
+        assert!(module.to_string().contains(expected), "{}", module);
+        // Recursive functions keep their calls
+        assert_eq!(callees(&module.functions[2]), [2, 2]);
+
+        // Functions larger than the threshold are called
+        let mut module = lower_text(text);
+        optimize(
+            &mut module,
+            &Optimizations::new(2).with_inline_threshold(3),
+            |_, _| (),
+        );
+        assert_eq!(callees(&module.functions[1]), Vec::<usize>::new());
+        assert_eq!(callees(&module.functions[3]), [1, 2]);
+        let mut module = lower_text(text);
+        optimize(
+            &mut module,
+            &Optimizations::new(2).with_inline_threshold(0),
+            |_, _| (),
+        );
+        assert_eq!(callees(&module.functions[1]), [0, 0]);
+
+        // Errors in inlined bodies have the tracebacks of the calls
+        for text in [
+            "tominto jaa(a: kok, b: kok): kok { palata a / b }; tominto f(n: kok): kok { palata jaa(10, n - 1) + 1 }; tulosta(f(3)); f(1)",
+            "tominto neliö(x: kok): kok { palata x * x }; tominto f(n: kok): kok { palata neliö(n) + neliö(n * 2) }; tulosta(f(3)); f(2000000000)",
+            "tominto g(x: liu): liu { jos x > 1.0 { palata x ^ 0.5 }; palata 1.0 / x }; tominto f(x: liu, y: liu): liu { palata g(x) + g(y) }; tulosta(f(4.0, 0.5)); f(2.0, 0.0)",
+            "tominto f(n: kok): kok { palata 1 + f(n + 1) }; tominto g(n: kok): kok { palata f(n) }; tominto h(n: kok): kok { palata g(n) * 2 }; h(0)",
+            "tominto apu(n: kok): kok { palata n + 1 }; tominto f(n: kok): kok { palata 1 + f(apu(n)) }; f(0)",
+        ] {
+            assert_same(text, |mut module| {
+                optimize(&mut module, &Optimizations::new(2), |_, _| ());
+                assert!(!module.to_string().contains("call g"), "{}", module);
+                module
+            });
+        }
+    }
+
+    #[test]
+    fn test_print_after() {
+        let mut module = lower_text("muut a = 1; tulosta(a + 1)");
+        let mut printed = Vec::new();
+        optimize(&mut module, &Optimizations::new(2), |pass, module| {
+            printed.push(format!("{}: {}", pass.name(), module.program.size()))
+        });
+        assert_eq!(
+            printed,
+            [
+                "ssa: 4",
+                "constprop: 2",
+                "simplify: 2",
+                "inline: 2",
+                "constprop: 2",
+                "simplify: 2",
+                "cse: 2",
+                "licm: 2",
+                "constprop: 2",
+                "simplify: 2",
+                "dce: 2"
+            ]
+        );
+        for pass in PASSES {
+            assert_eq!(Pass::from_name(pass.name()), Some(pass));
+        }
+        assert_eq!(Pass::from_name("inlining"), None);
+    }
+}
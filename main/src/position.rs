@@ -1,51 +1,58 @@
+use std::rc::Rc;
+
+// Every token and many values have positions, so they are shared instead of copied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position(Rc<Location>);
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct Position {
+struct Location {
     index: i64,
     line: i64,
     column: i64,
-    file_name: String,
-    file_text: String,
+    file_name: Rc<str>,
+    file_text: Rc<str>,
 }
 
 impl Position {
     pub fn new(index: i64, line: i64, column: i64, file_name: String, file_text: String) -> Self {
-        Self {
+        Self(Rc::new(Location {
             index,
             line,
             column,
-            file_name,
-            file_text,
-        }
+            file_name: Rc::from(file_name),
+            file_text: Rc::from(file_text),
+        }))
     }
 
     pub fn advance(&mut self, current_char: Option<char>) {
-        self.index += 1;
-        self.column += 1;
+        let location = Rc::make_mut(&mut self.0);
+        location.index += 1;
+        location.column += 1;
 
         if let Some(c) = current_char {
             if c == '\n' {
-                self.line += 1;
-                self.column = 0;
+                location.line += 1;
+                location.column = 0;
             }
         }
     }
 
     pub fn index(&self) -> i64 {
-        self.index
+        self.0.index
     }
 
     pub fn line(&self) -> i64 {
-        self.line
+        self.0.line
     }
 
     pub fn column(&self) -> i64 {
-        self.column
+        self.0.column
     }
 
     pub fn file_name(&self) -> String {
-        self.file_name.clone()
+        self.0.file_name.to_string()
     }
     pub fn file_text(&self) -> String {
-        self.file_text.clone()
+        self.0.file_text.to_string()
     }
 }
@@ -0,0 +1,2024 @@
+use crate::ast::{self, identifier_name};
+use crate::builtins;
+use crate::errors::{ErrorType, TypeError};
+use crate::parser::Node;
+use crate::token::{Token, TokenType};
+use crate::types::Type;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Translates syntax trees to Rust source that reads like it was written by hand, for
+// porting programs of kok, liu, totuus and teksti values. Like in the assembly backend the
+// types are known when compiling, and functions annotate their parameters and results.
+//
+// Arithmetic on kok stays checked: where the interpreter fails, the Rust program prints the
+// same error and exits with status 1. Calls are counted to stop at the interpreter's depth
+// limit, except for the tail calls of functions to themselves, which the interpreter runs
+// in the same frame. Helpers for the operations Rust does differently are added at
+// the end of the program, when it uses them.
+//
+// Variables are function wide in fin and block scoped in Rust, so every variable is
+// declared in the innermost block around all of its uses, with `let` where it is first
+// set if that is a statement of the block. Variables that might be read before they are
+// set are `Option`s, and reading one that is not set fails like in the interpreter.
+
+// Names of the helpers, which variables and functions of the program cant have
+const HELPERS: [&str; 9] = [
+    "Call",
+    "divide",
+    "divide_float",
+    "fail",
+    "overflow",
+    "power",
+    "power_float",
+    "power_real",
+    "totuus",
+];
+
+const HELPER_CODE: [&str; 9] = [
+    "// Calls that have not returned yet, which nest at most 1000 deep like in the interpreter
+static DEPTH: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+struct Call;
+
+impl Call {
+    fn enter() -> Call {
+        if DEPTH.fetch_add(1, std::sync::atomic::Ordering::Relaxed) >= 1000 {
+            fail(\"Call Depth Limit Error\", \"Calls nested more than 1000 deep\");
+        }
+        Call
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        DEPTH.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}",
+    "fn divide(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        fail(\"DivisionByZero Error\", \"Division by Zero\");
+    }
+    a.checked_div(b).unwrap_or_else(overflow)
+}",
+    "fn divide_float(a: f64, b: f64) -> f64 {
+    if b == 0.0 {
+        fail(\"DivisionByZero Error\", \"Division by Zero\");
+    }
+    a / b
+}",
+    "fn fail(error: &str, message: &str) -> ! {
+    eprintln!(\"{error}: {message}\");
+    std::process::exit(1);
+}",
+    "fn overflow() -> i64 {
+    fail(\"Runtime Error\", \"kokonaisluvun ylivuoto\")
+}",
+    "fn power(mut base: i64, exponent: i64) -> i64 {
+    if exponent < 0 {
+        if base == 0 {
+            fail(\"DivisionByZero Error\", \"Division by Zero\");
+        }
+        fail(\"Runtime Error\", \"murto numbers are not supported in Rust programs\");
+    }
+    let mut exponent = exponent as u64;
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(base).unwrap_or_else(overflow);
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base).unwrap_or_else(overflow);
+        }
+    }
+    result
+}",
+    "fn power_float(mut base: f64, exponent: i64) -> f64 {
+    let mut magnitude = exponent.unsigned_abs();
+    let mut result = 1.0;
+    if magnitude > 0 {
+        while magnitude & 1 == 0 {
+            base *= base;
+            magnitude >>= 1;
+        }
+        result = base;
+        while magnitude > 1 {
+            magnitude >>= 1;
+            base *= base;
+            if magnitude & 1 == 1 {
+                result *= base;
+            }
+        }
+    }
+    if exponent >= 0 {
+        return result;
+    }
+    if result == 0.0 {
+        fail(\"DivisionByZero Error\", \"Division by Zero\");
+    }
+    1.0 / result
+}",
+    "fn power_real(base: f64, exponent: f64) -> f64 {
+    if base == 0.0 && exponent < 0.0 {
+        fail(\"DivisionByZero Error\", \"Division by Zero\");
+    }
+    if base < 0.0 && exponent.fract() != 0.0 {
+        fail(
+            \"Runtime Error\",
+            \"Cant raise a negative number to a fractional power, use a complex base\",
+        );
+    }
+    base.powf(exponent)
+}",
+    "fn totuus(value: bool) -> &'static str {
+    if value {
+        \"tosi\"
+    } else {
+        \"epätosi\"
+    }
+}",
+];
+
+// Words Rust reserves, which are written as raw identifiers
+const KEYWORDS: [&str; 50] = [
+    "as",
+    "async",
+    "await",
+    "break",
+    "const",
+    "continue",
+    "dyn",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "for",
+    "if",
+    "impl",
+    "in",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "move",
+    "mut",
+    "pub",
+    "ref",
+    "return",
+    "static",
+    "struct",
+    "trait",
+    "true",
+    "type",
+    "unsafe",
+    "use",
+    "where",
+    "while",
+    "abstract",
+    "become",
+    "box",
+    "do",
+    "final",
+    "gen",
+    "macro",
+    "override",
+    "priv",
+    "try",
+    "typeof",
+    "unsized",
+    "virtual",
+    "yield",
+    "union",
+    "macro_rules",
+];
+
+// How tightly Rust expressions bind, to know where they need parentheses.
+// Casts are in parentheses wherever they are operands, since `x as i64 < y` reads as
+// a generic type.
+const CAST: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const COMPARE: u8 = 3;
+const ADD: u8 = 4;
+const MULTIPLY: u8 = 5;
+const UNARY: u8 = 6;
+const POSTFIX: u8 = 7;
+
+/// Generates a Rust program, to be compiled with `rustc`.
+///
+/// Programs can only use kok, liu, totuus and teksti values. Functions are defined at the
+/// top of the program, annotate the types of their parameters and of their result if they
+/// have one, and only use their own variables.
+pub fn generate(file_name: &str, node: &Node) -> Result<String, ErrorType> {
+    let statements = match node {
+        Node::StatementsNode(nodes) => nodes.as_slice(),
+        node => std::slice::from_ref(node),
+    };
+    let mut generator = Generator::default();
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, params, returns, _) = statement {
+            generator.declare(name_tok, params, returns)?;
+        }
+    }
+    let main = generator.program(statements)?;
+    let mut functions = Vec::new();
+    for statement in statements {
+        if let Node::FuncDefNode(name_tok, _, _, body) = statement {
+            functions.push(generator.function(name_tok, body)?);
+        }
+    }
+
+    let mut rust = format!(
+        "// Generated by finc from {}\n",
+        file_name.replace('\n', " ")
+    );
+    for function in functions {
+        rust.push('\n');
+        rust.push_str(&function);
+    }
+    rust.push('\n');
+    rust.push_str(&main);
+    for (name, code) in HELPERS.iter().zip(HELPER_CODE) {
+        if generator.helpers.contains(name) {
+            writeln!(rust, "\n{}", code).ok();
+        }
+    }
+    Ok(rust)
+}
+
+/// Compiles a generated program with the compiler in `RUSTC`, or `rustc`
+pub fn build(rust_source: &str, output: &Path) -> io::Result<()> {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let build = BUILDS.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("finc-rust-{}-{}", process::id(), build));
+    fs::create_dir_all(&dir)?;
+    let result = compile_in(&dir, rust_source, output);
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+fn compile_in(dir: &Path, rust_source: &str, output: &Path) -> io::Result<()> {
+    let source = dir.join("main.rs");
+    fs::write(&source, rust_source)?;
+
+    let compiler = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let result = Command::new(&compiler)
+        .args(["--edition", "2021", "-C", "opt-level=2", "-o"])
+        .arg(output)
+        .arg(&source)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cant run {}: {}", compiler, e)))?;
+    if !result.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed:\n{}",
+            compiler,
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(())
+}
+
+// A function of the program, known before code is generated for any of them
+struct Function {
+    name: String,
+    params: Vec<(String, Type)>,
+    // `None` when the function returns nothing
+    returns: Option<Type>,
+    // Whether the program has defined it by the code being generated
+    defined: bool,
+}
+
+// The Rust code of an expression
+struct Expr {
+    code: String,
+    type_: Type,
+    // How tightly the code binds
+    precedence: u8,
+    // A number literal, whose Rust type is only known from where it is used
+    literal: bool,
+    // A variable, which is moved if it is used as a value
+    place: bool,
+}
+
+impl Expr {
+    fn new(code: String, type_: Type, precedence: u8) -> Self {
+        Expr {
+            code,
+            type_,
+            precedence,
+            literal: false,
+            place: false,
+        }
+    }
+
+    // The code where it binds at least as tightly as `precedence`
+    fn at(&self, precedence: u8) -> String {
+        match self.precedence >= precedence {
+            true => self.code.clone(),
+            false => format!("({})", self.code),
+        }
+    }
+
+    // The code as the receiver of a method call
+    fn receiver(&self) -> String {
+        if !self.literal {
+            return self.at(POSTFIX);
+        }
+        let suffix = match self.type_ {
+            Type::Float => "_f64",
+            _ => "_i64",
+        };
+        let code = match self.code.ends_with(suffix) {
+            true => self.code.clone(),
+            false => format!("{}{}", self.code, suffix),
+        };
+        match code.starts_with('-') {
+            true => format!("({})", code),
+            false => code,
+        }
+    }
+
+    // The code as a value to keep, cloning texts in variables
+    fn owned(&self) -> String {
+        match self.place && self.type_ == Type::Text {
+            true => format!("{}.clone()", self.code),
+            false => self.code.clone(),
+        }
+    }
+}
+
+// A block of statements, and the blocks around it
+struct Block {
+    parent: Option<usize>,
+    is_loop: bool,
+    // The if the block is a case of, by the order of the ifs
+    case_of: Option<usize>,
+}
+
+// Where a variable is used in a function, in the order the code uses it
+struct Use {
+    block: usize,
+    // The assignment node when the variable is set
+    assignment: Option<*const Node>,
+    statement: bool,
+    // The statement the use is in, in each block around it
+    path: Vec<usize>,
+}
+
+// Where the variables of a function are declared
+#[derive(Default)]
+struct Declarations {
+    // Assignments that declare their variable with `let`, and whether it is `mut`
+    at: HashMap<*const Node, bool>,
+    // Variables declared without a value before the statement of a block that first uses
+    // them, by block
+    deferred: HashMap<usize, Vec<(String, bool, usize)>>,
+    // Parameters that are set again
+    mutable_params: Vec<String>,
+    // Deferred variables that might be read before they are set. They are `Option`s, and
+    // reading one that is not set fails like in the interpreter.
+    optional: Vec<String>,
+}
+
+// Finds the blocks of a function and the uses of its variables, in code order
+#[derive(Default)]
+struct Analysis {
+    blocks: Vec<Block>,
+    uses: Vec<(String, Use)>,
+    ifs: usize,
+    path: Vec<usize>,
+    // Variables set on every path to the code being looked at
+    set: Vec<String>,
+    // Variables read where they might not be set
+    unset_reads: Vec<String>,
+}
+
+impl Analysis {
+    fn block(
+        &mut self,
+        nodes: &[Node],
+        parent: Option<usize>,
+        is_loop: bool,
+        case_of: Option<usize>,
+    ) {
+        self.blocks.push(Block {
+            parent,
+            is_loop,
+            case_of,
+        });
+        let block = self.blocks.len() - 1;
+        for (i, statement) in nodes.iter().enumerate() {
+            self.path.push(i);
+            self.node(statement, block, true);
+            self.path.pop();
+        }
+    }
+
+    fn node(&mut self, node: &Node, block: usize, statement: bool) {
+        match node {
+            Node::VarAccessNode(token) => {
+                if let TokenType::Identifier(name) = token.type_() {
+                    if !self.set.contains(&name) {
+                        ast::push_unique(&mut self.unset_reads, name.clone());
+                    }
+                    self.uses.push((
+                        name,
+                        Use {
+                            block,
+                            assignment: None,
+                            statement: false,
+                            path: self.path.clone(),
+                        },
+                    ));
+                }
+            }
+            Node::VarAssignNode(token, _, value) => {
+                self.node(value, block, false);
+                if let TokenType::Identifier(name) = token.type_() {
+                    ast::push_unique(&mut self.set, name.clone());
+                    self.uses.push((
+                        name,
+                        Use {
+                            block,
+                            assignment: Some(node as *const Node),
+                            statement,
+                            path: self.path.clone(),
+                        },
+                    ));
+                }
+            }
+            Node::IfNode(_, cases, else_case) => {
+                self.ifs += 1;
+                let if_ = Some(self.ifs);
+                let mut set = None;
+                for (condition, body) in cases {
+                    self.node(condition, block, false);
+                    let before = self.set.clone();
+                    self.block(statements(body), Some(block), false, if_);
+                    self.case_end(&mut set, before, !ast::ends_with_return(body));
+                }
+                let before = self.set.clone();
+                match else_case {
+                    Some(body) => {
+                        self.block(statements(body), Some(block), false, if_);
+                        self.case_end(&mut set, before, !ast::ends_with_return(body));
+                    }
+                    None => self.case_end(&mut set, before, true),
+                }
+                if let Some(set) = set {
+                    self.set = set;
+                }
+            }
+            Node::WhileNode(_, condition, body) => {
+                self.node(condition, block, false);
+                // The body might not run
+                let set = self.set.clone();
+                self.block(statements(body), Some(block), true, None);
+                self.set = set;
+            }
+            Node::StatementsNode(nodes) => {
+                for node in nodes {
+                    self.node(node, block, statement);
+                }
+            }
+            // Functions have their own variables
+            Node::FuncDefNode(..) => (),
+            node => {
+                for child in node.children() {
+                    self.node(child, block, false);
+                }
+            }
+        }
+    }
+
+    // Goes back to before a case of an if, keeping the variables set by every case so far
+    // that goes on after the if
+    fn case_end(&mut self, set: &mut Option<Vec<String>>, before: Vec<String>, goes_on: bool) {
+        let after = std::mem::replace(&mut self.set, before);
+        if !goes_on {
+            return;
+        }
+        *set = Some(match set.take() {
+            Some(set) => set
+                .into_iter()
+                .filter(|name| after.contains(name))
+                .collect(),
+            None => after,
+        });
+    }
+
+    fn depth(&self, mut block: usize) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = self.blocks[block].parent {
+            block = parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    // The innermost block around two blocks
+    fn common(&self, mut a: usize, mut b: usize) -> usize {
+        let (mut depth_a, mut depth_b) = (self.depth(a), self.depth(b));
+        while depth_a > depth_b {
+            a = self.blocks[a].parent.unwrap_or(0);
+            depth_a -= 1;
+        }
+        while depth_b > depth_a {
+            b = self.blocks[b].parent.unwrap_or(0);
+            depth_b -= 1;
+        }
+        while a != b {
+            a = self.blocks[a].parent.unwrap_or(0);
+            b = self.blocks[b].parent.unwrap_or(0);
+        }
+        a
+    }
+
+    // The block inside `outer` that `block` is in
+    fn child_of(&self, mut block: usize, outer: usize) -> Option<usize> {
+        while let Some(parent) = self.blocks[block].parent {
+            if parent == outer {
+                return Some(block);
+            }
+            block = parent;
+        }
+        None
+    }
+
+    // Whether code in two blocks is in different cases of one if, so only one runs
+    fn exclusive(&self, a: usize, b: usize) -> bool {
+        let common = self.common(a, b);
+        match (self.child_of(a, common), self.child_of(b, common)) {
+            (Some(a), Some(b)) => {
+                a != b
+                    && self.blocks[a].case_of.is_some()
+                    && self.blocks[a].case_of == self.blocks[b].case_of
+            }
+            _ => false,
+        }
+    }
+
+    // Whether a loop inside `outer` runs code of `block` more than once
+    fn loops_within(&self, mut block: usize, outer: usize) -> bool {
+        while block != outer {
+            if self.blocks[block].is_loop {
+                return true;
+            }
+            block = self.blocks[block].parent.unwrap_or(outer);
+        }
+        false
+    }
+
+    fn declarations(&self, params: &[(String, Type)]) -> Declarations {
+        let mut declarations = Declarations::default();
+        let mut names: Vec<&String> = Vec::new();
+        for (name, _) in &self.uses {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        for name in names {
+            let uses: Vec<&Use> = self
+                .uses
+                .iter()
+                .filter(|(used, _)| used == name)
+                .map(|(_, use_)| use_)
+                .collect();
+            let assignments: Vec<&&Use> = uses
+                .iter()
+                .filter(|use_| use_.assignment.is_some())
+                .collect();
+            if params.iter().any(|(param, _)| param == name) {
+                if !assignments.is_empty() {
+                    declarations.mutable_params.push(name.clone());
+                }
+                continue;
+            }
+            if assignments.is_empty() {
+                continue;
+            }
+            let block = uses
+                .iter()
+                .fold(uses[0].block, |block, use_| self.common(block, use_.block));
+            let first = uses[0];
+            match (first.assignment, first.statement && first.block == block) {
+                (Some(node), true) => {
+                    declarations.at.insert(node, assignments.len() > 1);
+                }
+                _ if self.unset_reads.contains(name) => {
+                    declarations.optional.push(name.clone());
+                    declarations.deferred.entry(block).or_default().push((
+                        name.clone(),
+                        true,
+                        first.path[self.depth(block)],
+                    ));
+                }
+                _ => {
+                    // Variables set once on every path can be declared without `mut`
+                    let mutable = assignments
+                        .iter()
+                        .any(|use_| self.loops_within(use_.block, block))
+                        || assignments.iter().enumerate().any(|(i, a)| {
+                            assignments[i + 1..]
+                                .iter()
+                                .any(|b| !self.exclusive(a.block, b.block))
+                        });
+                    declarations.deferred.entry(block).or_default().push((
+                        name.clone(),
+                        mutable,
+                        first.path[self.depth(block)],
+                    ));
+                }
+            }
+        }
+        declarations
+    }
+}
+
+// The statements of a block
+fn statements(node: &Node) -> &[Node] {
+    match node {
+        Node::StatementsNode(nodes) => nodes,
+        node => std::slice::from_ref(node),
+    }
+}
+
+// The code of the program or of one function
+struct Body {
+    function: Option<usize>,
+    // Types of the variables, once they are set
+    variables: HashMap<String, Type>,
+    declarations: Declarations,
+    // Blocks are numbered in the order they are generated, like the analysis found them
+    blocks: usize,
+}
+
+#[derive(Default)]
+struct Generator {
+    functions: Vec<Function>,
+    function_ids: HashMap<String, usize>,
+    // Variables of the program, which functions cant use
+    globals: Vec<String>,
+    body: Option<Body>,
+    helpers: BTreeSet<&'static str>,
+}
+
+impl Generator {
+    fn body(&mut self) -> &mut Body {
+        self.body.as_mut().expect("Code is generated inside a body")
+    }
+
+    fn current(&self) -> &Body {
+        self.body.as_ref().expect("Code is generated inside a body")
+    }
+
+    fn helper(&mut self, name: &'static str) -> &'static str {
+        self.helpers.insert(name);
+        // Every helper but totuus can end the program
+        if name != "totuus" {
+            self.helpers.insert("fail");
+        }
+        name
+    }
+
+    fn overflow(&mut self) -> &'static str {
+        self.helper("overflow")
+    }
+
+    // Expressions
+
+    fn expression(&mut self, node: &Node) -> Result<Expr, ErrorType> {
+        match node {
+            Node::Value(token) => self.value(token),
+            Node::VarAccessNode(token) => {
+                let name = identifier_name(token)?;
+                let type_ = self.lookup(token, &name)?;
+                if self.current().declarations.optional.contains(&name) {
+                    let value = match type_ == Type::Text {
+                        true => format!("{}.clone()", rust_name(&name)),
+                        false => rust_name(&name),
+                    };
+                    let code = format!(
+                        "{}.unwrap_or_else(|| {}(\"Runtime Error\", \"{} is not defined\"))",
+                        value,
+                        self.helper("fail"),
+                        name
+                    );
+                    return Ok(Expr::new(code, type_, POSTFIX));
+                }
+                let mut expr = Expr::new(rust_name(&name), type_, POSTFIX);
+                expr.place = true;
+                Ok(expr)
+            }
+            Node::Binop(..) => self.binops(node),
+            Node::Unary(optok, operand) => self.unary(optok, operand),
+            Node::CallNode(name_tok, args) => {
+                let expr = self.call(name_tok, args)?;
+                if expr.type_ == Type::Nil {
+                    return Err(type_error(
+                        name_tok,
+                        format!("{} returns nothing", identifier_name(name_tok)?),
+                    ));
+                }
+                Ok(expr)
+            }
+            Node::VarAssignNode(..) => {
+                Err(unsupported_node(node, "assignments inside expressions"))
+            }
+            Node::ListNode(..) => Err(unsupported_node(node, "lista values")),
+            Node::RecordNode(..) => Err(unsupported_node(node, "tietue values")),
+            Node::IndexNode(..) => Err(unsupported_node(node, "indexes")),
+            Node::FieldNode(..) => Err(unsupported_node(node, "fields")),
+            Node::FuncDefNode(..) => Err(unsupported_node(node, "functions inside other code")),
+            Node::IfNode(..) | Node::WhileNode(..) | Node::ReturnNode(..) => {
+                Err(unsupported_node(node, "statements as values"))
+            }
+            Node::StatementsNode(_) => Err(unsupported_node(node, "statements as values")),
+        }
+    }
+
+    fn value(&mut self, token: &Token) -> Result<Expr, ErrorType> {
+        match token.type_() {
+            TokenType::Int(value) => {
+                // Literals that dont fit an i32 need their type where Rust would guess one
+                let mut expr = match i32::try_from(value) {
+                    Ok(_) => Expr::new(value.to_string(), Type::Integer, POSTFIX),
+                    Err(_) => Expr::new(format!("{}_i64", value), Type::Integer, POSTFIX),
+                };
+                expr.literal = true;
+                Ok(expr)
+            }
+            TokenType::Float(value) => {
+                let mut expr = Expr::new(format!("{:?}", value), Type::Float, POSTFIX);
+                expr.literal = true;
+                Ok(expr)
+            }
+            TokenType::String(text) => Ok(Expr::new(
+                format!("String::from({:?})", text),
+                Type::Text,
+                POSTFIX,
+            )),
+            TokenType::Keyword(keyword) if keyword == "tosi" || keyword == "epätosi" => Ok(
+                Expr::new((keyword == "tosi").to_string(), Type::Boolean, POSTFIX),
+            ),
+            TokenType::Keyword(keyword) if keyword == "tyhjä" => {
+                Err(unsupported(token, "tyhjä values"))
+            }
+            TokenType::BigInt(_) => Err(unsupported(token, "iso numbers")),
+            TokenType::Imaginary(_) => Err(unsupported(token, "kompleksi numbers")),
+            other => Err(type_error(
+                token,
+                format!(
+                    "Non Value Token {:?} found inside generate value function",
+                    other
+                ),
+            )),
+        }
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are generated in a loop
+    fn binops(&mut self, node: &Node) -> Result<Expr, ErrorType> {
+        let operations = node.binop_chain();
+        let mut texts = operations
+            .iter()
+            .take_while(|(_, optok, _)| optok.type_() == TokenType::Plus)
+            .count();
+        if !self.joins_texts(&operations[..texts])? {
+            texts = 0;
+        }
+        let mut left = match texts {
+            0 => self.expression(operations[0].0)?,
+            _ => self.concatenation(&operations[..texts])?,
+        };
+        for (left_node, optok, right) in &operations[texts..] {
+            left = self.binop(left, left_node, optok, right)?;
+        }
+        Ok(left)
+    }
+
+    // `left` is the generated `left_node`
+    fn binop(
+        &mut self,
+        left: Expr,
+        left_node: &Node,
+        optok: &Token,
+        right_node: &Node,
+    ) -> Result<Expr, ErrorType> {
+        if let TokenType::Keyword(keyword) = optok.type_() {
+            let left = self.truth(left, left_node)?;
+            let right = self.condition(right_node)?;
+            let (operator, precedence) = match keyword == "ja" {
+                true => ("&&", AND),
+                false => ("||", OR),
+            };
+            return Ok(Expr::new(
+                format!(
+                    "{} {} {}",
+                    left.at(precedence),
+                    operator,
+                    right.at(precedence + 1)
+                ),
+                Type::Boolean,
+                precedence,
+            ));
+        }
+
+        let operator = optok.type_();
+        let right = self.expression(right_node)?;
+        let mismatch = || {
+            type_error(
+                optok,
+                format!(
+                    "Cant use {} with {} and {}",
+                    optok.type_().symbol(),
+                    left.type_,
+                    right.type_
+                ),
+            )
+        };
+
+        let comparison = match operator {
+            TokenType::EqualEqual => Some("=="),
+            TokenType::NotEqual => Some("!="),
+            TokenType::LessThan => Some("<"),
+            TokenType::GreaterThan => Some(">"),
+            TokenType::LessThanEqual => Some("<="),
+            TokenType::GreaterThanEqual => Some(">="),
+            _ => None,
+        };
+        if let Some(comparison) = comparison {
+            let ordered = matches!(left.type_, Type::Integer | Type::Float | Type::Text);
+            if left.type_ != right.type_ || !(ordered || comparison == "==" || comparison == "!=") {
+                return Err(mismatch());
+            }
+            return Ok(Expr::new(
+                format!("{} {} {}", left.at(ADD), comparison, right.at(ADD)),
+                Type::Boolean,
+                COMPARE,
+            ));
+        }
+
+        match (&operator, &left.type_, &right.type_) {
+            (TokenType::Pow, Type::Integer, Type::Integer) => {
+                let power = self.helper("power");
+                self.overflow();
+                Ok(Expr::new(
+                    format!("{}({}, {})", power, left.code, right.code),
+                    Type::Integer,
+                    POSTFIX,
+                ))
+            }
+            (TokenType::Pow, Type::Float, Type::Integer) => {
+                let power = self.helper("power_float");
+                Ok(Expr::new(
+                    format!("{}({}, {})", power, left.code, right.code),
+                    Type::Float,
+                    POSTFIX,
+                ))
+            }
+            (TokenType::Pow, Type::Integer | Type::Float, Type::Float) => {
+                let power = self.helper("power_real");
+                let base = match left.type_ {
+                    Type::Integer => format!("{} as f64", left.at(UNARY)),
+                    _ => left.code.clone(),
+                };
+                Ok(Expr::new(
+                    format!("{}({}, {})", power, base, right.code),
+                    Type::Float,
+                    POSTFIX,
+                ))
+            }
+            (TokenType::Divide, Type::Integer, Type::Integer) => {
+                let divide = self.helper("divide");
+                self.overflow();
+                Ok(Expr::new(
+                    format!("{}({}, {})", divide, left.code, right.code),
+                    Type::Integer,
+                    POSTFIX,
+                ))
+            }
+            (TokenType::Divide, Type::Float, Type::Float) => {
+                let divide = self.helper("divide_float");
+                Ok(Expr::new(
+                    format!("{}({}, {})", divide, left.code, right.code),
+                    Type::Float,
+                    POSTFIX,
+                ))
+            }
+            (
+                TokenType::Plus | TokenType::Minus | TokenType::Multiply,
+                Type::Integer,
+                Type::Integer,
+            ) => {
+                let method = match operator {
+                    TokenType::Plus => "checked_add",
+                    TokenType::Minus => "checked_sub",
+                    _ => "checked_mul",
+                };
+                let overflow = self.overflow();
+                Ok(Expr::new(
+                    format!(
+                        "{}.{}({}).unwrap_or_else({})",
+                        left.receiver(),
+                        method,
+                        right.code,
+                        overflow
+                    ),
+                    Type::Integer,
+                    POSTFIX,
+                ))
+            }
+            (
+                TokenType::Plus | TokenType::Minus | TokenType::Multiply,
+                Type::Float,
+                Type::Float,
+            ) => {
+                let (symbol, precedence) = match operator {
+                    TokenType::Plus => ("+", ADD),
+                    TokenType::Minus => ("-", ADD),
+                    _ => ("*", MULTIPLY),
+                };
+                Ok(Expr::new(
+                    format!(
+                        "{} {} {}",
+                        left.at(precedence),
+                        symbol,
+                        right.at(precedence + 1)
+                    ),
+                    Type::Float,
+                    precedence,
+                ))
+            }
+            _ => Err(mismatch()),
+        }
+    }
+
+    // Texts joined with '+' become one `format!`
+    fn concatenation(&mut self, operations: &[(&Node, &Token, &Node)]) -> Result<Expr, ErrorType> {
+        let mut parts = Vec::new();
+        self.joined_parts(operations, &mut parts)?;
+        let (format, args) = format_parts(&parts);
+        Ok(Expr::new(
+            format!("format!({}{})", format, args),
+            Type::Text,
+            POSTFIX,
+        ))
+    }
+
+    // Whether a chain only joins texts with '+'
+    fn joins_texts(&mut self, operations: &[(&Node, &Token, &Node)]) -> Result<bool, ErrorType> {
+        let plus = operations
+            .iter()
+            .all(|(_, optok, _)| optok.type_() == TokenType::Plus);
+        match operations.first() {
+            Some((first, _, _)) if plus => Ok(self.type_of(first)? == Type::Text),
+            _ => Ok(false),
+        }
+    }
+
+    // Adds the parts of texts joined with '+' to a format string
+    fn joined_parts(
+        &mut self,
+        operations: &[(&Node, &Token, &Node)],
+        parts: &mut Vec<Part>,
+    ) -> Result<(), ErrorType> {
+        parts.push(self.part(operations[0].0)?);
+        for (_, _, right) in operations {
+            let start = parts.len();
+            self.parts(right, parts)?;
+            if let Some(part) = parts[start..].iter().find(|part| part.type_ != Type::Text) {
+                return Err(node_error(
+                    right,
+                    format!("Cant add {} to teksti", part.type_),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the parts of a value to a format string, with texts joined with '+' taken apart
+    fn parts(&mut self, node: &Node, parts: &mut Vec<Part>) -> Result<(), ErrorType> {
+        let operations = node.binop_chain();
+        if self.joins_texts(&operations)? {
+            return self.joined_parts(&operations, parts);
+        }
+        let part = self.part(node)?;
+        parts.push(part);
+        Ok(())
+    }
+
+    // A value inside a format string
+    fn part(&mut self, node: &Node) -> Result<Part, ErrorType> {
+        if let Node::Value(token) = node {
+            if let TokenType::String(text) = token.type_() {
+                return Ok(Part::text(text));
+            }
+        }
+        let expr = self.expression(node)?;
+        let arg = match expr.type_ {
+            Type::Boolean => format!("{}({})", self.helper("totuus"), expr.code),
+            _ => expr.code,
+        };
+        Ok(Part {
+            type_: expr.type_,
+            text: None,
+            arg,
+        })
+    }
+
+    fn unary(&mut self, optok: &Token, operand: &Node) -> Result<Expr, ErrorType> {
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            let operand = self.condition(operand)?;
+            return Ok(Expr::new(
+                format!("!{}", operand.at(UNARY)),
+                Type::Boolean,
+                UNARY,
+            ));
+        }
+
+        let expr = self.expression(operand)?;
+        match (optok.type_(), &expr.type_) {
+            (TokenType::Plus, Type::Integer | Type::Float) => Ok(expr),
+            // Literals cant overflow, as the smallest kok has no literal
+            (TokenType::Minus, Type::Integer | Type::Float) if expr.literal => {
+                let mut negated = Expr::new(format!("-{}", expr.code), expr.type_, UNARY);
+                negated.literal = true;
+                Ok(negated)
+            }
+            (TokenType::Minus, Type::Integer) => {
+                let overflow = self.overflow();
+                Ok(Expr::new(
+                    format!(
+                        "{}.checked_neg().unwrap_or_else({})",
+                        expr.receiver(),
+                        overflow
+                    ),
+                    Type::Integer,
+                    POSTFIX,
+                ))
+            }
+            (TokenType::Minus, Type::Float) => Ok(Expr::new(
+                format!("-{}", expr.at(UNARY)),
+                Type::Float,
+                UNARY,
+            )),
+            (operator, _) => Err(type_error(
+                optok,
+                format!("Cant use {} with {}", operator.symbol(), expr.type_),
+            )),
+        }
+    }
+
+    fn condition(&mut self, node: &Node) -> Result<Expr, ErrorType> {
+        let expr = self.expression(node)?;
+        self.truth(expr, node)
+    }
+
+    // Checks that `expr`, generated from `node`, is a condition
+    fn truth(&self, expr: Expr, node: &Node) -> Result<Expr, ErrorType> {
+        if expr.type_ != Type::Boolean {
+            return Err(node_error(
+                node,
+                format!("Condition must be totuus, found {}", expr.type_),
+            ));
+        }
+        Ok(expr)
+    }
+
+    // The type of an expression, without generating it
+    fn type_of(&mut self, node: &Node) -> Result<Type, ErrorType> {
+        let helpers = self.helpers.clone();
+        let type_ = self.expression(node).map(|expr| expr.type_);
+        self.helpers = helpers;
+        type_
+    }
+
+    fn lookup(&self, token: &Token, name: &str) -> Result<Type, ErrorType> {
+        if let Some(type_) = self.current().variables.get(name) {
+            return Ok(type_.clone());
+        }
+        if self.function_ids.contains_key(name) {
+            return Err(unsupported(token, "functions as values"));
+        }
+        if self.current().function.is_some() && self.globals.iter().any(|global| global == name) {
+            return Err(unsupported(token, "global variables in functions"));
+        }
+        Err(type_error(token, format!("{} is not defined", name)))
+    }
+
+    // Calls
+
+    // Generates a call, typed tyhjä when it returns nothing
+    fn call(&mut self, name_tok: &Token, args: &[Node]) -> Result<Expr, ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if let Some(index) = self.function_ids.get(&name) {
+            return self.call_function(*index, name_tok, args);
+        }
+        let expect_args = |count: usize| match args.len() == count {
+            true => Ok(()),
+            false => Err(type_error(
+                name_tok,
+                format!("{} expects {} arguments, got {}", name, count, args.len()),
+            )),
+        };
+        let expr = match name.as_str() {
+            "tulosta" => {
+                let mut parts = Vec::with_capacity(args.len());
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        parts.push(Part::text(" ".to_string()));
+                    }
+                    self.parts(arg, &mut parts)?;
+                }
+                let code = match parts.is_empty() {
+                    true => "println!()".to_string(),
+                    false => {
+                        let (format, args) = format_parts(&parts);
+                        format!("println!({}{})", format, args)
+                    }
+                };
+                Expr::new(code, Type::Nil, POSTFIX)
+            }
+            "str" => {
+                expect_args(1)?;
+                let expr = self.expression(&args[0])?;
+                let code = match expr.type_ {
+                    Type::Text => expr.owned(),
+                    Type::Boolean => {
+                        format!("{}({}).to_string()", self.helper("totuus"), expr.code)
+                    }
+                    _ => format!("{}.to_string()", expr.receiver()),
+                };
+                Expr::new(code, Type::Text, POSTFIX)
+            }
+            "liu" => {
+                expect_args(1)?;
+                let expr = self.expression(&args[0])?;
+                match expr.type_ {
+                    Type::Float => expr,
+                    Type::Integer => {
+                        Expr::new(format!("{} as f64", expr.at(UNARY)), Type::Float, CAST)
+                    }
+                    type_ => {
+                        return Err(node_error(
+                            &args[0],
+                            format!("liu expects a number, got {}", type_),
+                        ))
+                    }
+                }
+            }
+            "pituus" => {
+                expect_args(1)?;
+                let expr = self.expression(&args[0])?;
+                if expr.type_ != Type::Text {
+                    return Err(node_error(
+                        &args[0],
+                        format!("pituus expects teksti, got {}", expr.type_),
+                    ));
+                }
+                Expr::new(
+                    format!("{}.chars().count() as i64", expr.at(POSTFIX)),
+                    Type::Integer,
+                    CAST,
+                )
+            }
+            "abs" => {
+                expect_args(1)?;
+                let expr = self.expression(&args[0])?;
+                match expr.type_ {
+                    Type::Integer => {
+                        let overflow = self.overflow();
+                        Expr::new(
+                            format!(
+                                "{}.checked_abs().unwrap_or_else({})",
+                                expr.receiver(),
+                                overflow
+                            ),
+                            Type::Integer,
+                            POSTFIX,
+                        )
+                    }
+                    Type::Float => {
+                        Expr::new(format!("{}.abs()", expr.receiver()), Type::Float, POSTFIX)
+                    }
+                    type_ => {
+                        return Err(node_error(
+                            &args[0],
+                            format!("abs expects a number, got {}", type_),
+                        ))
+                    }
+                }
+            }
+            "min" | "max" if !args.is_empty() => {
+                let mut result = self.expression(&args[0])?;
+                for arg in args {
+                    if result.type_ != Type::Integer {
+                        return Err(unsupported(
+                            name_tok,
+                            &format!("{} of other numbers than kok", name),
+                        ));
+                    }
+                    if std::ptr::eq(arg, &args[0]) {
+                        continue;
+                    }
+                    let expr = self.expression(arg)?;
+                    if expr.type_ != Type::Integer {
+                        return Err(unsupported(
+                            name_tok,
+                            &format!("{} of other numbers than kok", name),
+                        ));
+                    }
+                    result = Expr::new(
+                        format!("{}.{}({})", result.receiver(), name, expr.code),
+                        Type::Integer,
+                        POSTFIX,
+                    );
+                }
+                result
+            }
+            _ if builtins::prelude().get(&name).is_some() => {
+                return Err(unsupported(name_tok, &format!("calls to {}", name)))
+            }
+            _ => {
+                let type_ = self.lookup(name_tok, &name)?;
+                return Err(type_error(
+                    name_tok,
+                    format!("{} is a {}, not a function", name, type_),
+                ));
+            }
+        };
+        Ok(expr)
+    }
+
+    fn call_function(
+        &mut self,
+        index: usize,
+        name_tok: &Token,
+        args: &[Node],
+    ) -> Result<Expr, ErrorType> {
+        // Functions can call the ones defined after them, but the program cant
+        if self.current().function.is_none() && !self.functions[index].defined {
+            return Err(type_error(
+                name_tok,
+                format!("{} is not defined", self.functions[index].name),
+            ));
+        }
+        let function = &self.functions[index];
+        if args.len() != function.params.len() {
+            return Err(type_error(
+                name_tok,
+                format!(
+                    "{} expects {} arguments, got {}",
+                    function.name,
+                    function.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let params = function.params.clone();
+        let mut codes = Vec::with_capacity(args.len());
+        for (arg, (param, expected)) in args.iter().zip(params) {
+            let expr = self.expression(arg)?;
+            if expr.type_ != expected {
+                return Err(node_error(
+                    arg,
+                    format!(
+                        "{} expects {} for {}, got {}",
+                        self.functions[index].name, expected, param, expr.type_
+                    ),
+                ));
+            }
+            codes.push(expr.owned());
+        }
+        let function = &self.functions[index];
+        let code = format!("{}({})", rust_name(&function.name), codes.join(", "));
+        let type_ = function.returns.clone().unwrap_or(Type::Nil);
+        Ok(Expr::new(code, type_, POSTFIX))
+    }
+
+    // Statements
+
+    // Generates the statements of a block, declaring the variables that live in it. The
+    // last statement is in tail position when the block's value is the function's.
+    fn block(&mut self, nodes: &[Node], indent: usize, tail: bool) -> Result<String, ErrorType> {
+        let block = self.current().blocks;
+        self.body().blocks += 1;
+        let top = block == 0 && self.current().function.is_none();
+        let mut codes = Vec::with_capacity(nodes.len());
+        for (i, statement) in nodes.iter().enumerate() {
+            let mut code = String::new();
+            match statement {
+                // Functions are defined where the program defines them
+                Node::FuncDefNode(name_tok, ..) if top => {
+                    let index = self.function_ids[&identifier_name(name_tok)?];
+                    self.functions[index].defined = true;
+                }
+                statement => {
+                    let tail = tail && i == nodes.len() - 1;
+                    self.statement(statement, &mut code, indent, tail)?;
+                }
+            }
+            codes.push(code);
+        }
+
+        // Declarations go in once the types of the variables are known
+        let deferred = self
+            .current()
+            .declarations
+            .deferred
+            .get(&block)
+            .cloned()
+            .unwrap_or_default();
+        for (name, mutable, index) in deferred.into_iter().rev() {
+            // Variables that are never set are not declared, and fail where they are used
+            if let Some(type_) = self.current().variables.get(&name) {
+                let declaration = match self.current().declarations.optional.contains(&name) {
+                    true => format!(
+                        "let mut {}: Option<{}> = None;",
+                        rust_name(&name),
+                        rust_type(type_)
+                    ),
+                    false => format!(
+                        "let {}{}: {};",
+                        if mutable { "mut " } else { "" },
+                        rust_name(&name),
+                        rust_type(type_)
+                    ),
+                };
+                let mut code = String::new();
+                line(&mut code, indent, &declaration);
+                codes[index].insert_str(0, &code);
+            }
+        }
+        Ok(codes.concat())
+    }
+
+    fn statement(
+        &mut self,
+        node: &Node,
+        code: &mut String,
+        indent: usize,
+        tail: bool,
+    ) -> Result<(), ErrorType> {
+        match node {
+            Node::StatementsNode(nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    self.statement(node, code, indent, tail && i == nodes.len() - 1)?;
+                }
+            }
+            Node::VarAssignNode(token, annotation, value) => {
+                self.assign(node, token, annotation, value, code, indent)?
+            }
+            Node::IfNode(_, cases, else_case) => {
+                // An if with an else is the value of the function in tail position
+                let tail = tail && else_case.is_some();
+                for (i, (condition, body)) in cases.iter().enumerate() {
+                    let condition = self.condition(condition)?;
+                    let body = self.block(statements(body), indent + 1, tail)?;
+                    let keyword = if i == 0 { "if" } else { "} else if" };
+                    line(code, indent, &format!("{} {} {{", keyword, condition.code));
+                    code.push_str(&body);
+                }
+                if let Some(body) = else_case {
+                    let body = self.block(statements(body), indent + 1, tail)?;
+                    line(code, indent, "} else {");
+                    code.push_str(&body);
+                }
+                line(code, indent, "}");
+            }
+            Node::WhileNode(_, condition, body) => {
+                let condition = self.condition(condition)?;
+                let body = self.block(statements(body), indent + 1, false)?;
+                match condition.code.as_str() {
+                    "true" => line(code, indent, "loop {"),
+                    _ => line(code, indent, &format!("while {} {{", condition.code)),
+                }
+                code.push_str(&body);
+                line(code, indent, "}");
+            }
+            Node::ReturnNode(token, value) => {
+                self.ret(token, value.as_deref(), code, indent, tail)?
+            }
+            Node::CallNode(name_tok, args) => {
+                let expr = self.call(name_tok, args)?;
+                line(code, indent, &format!("{};", expr.code));
+            }
+            Node::FuncDefNode(..) => {
+                return Err(unsupported_node(node, "functions inside other code"))
+            }
+            node => {
+                let expr = self.expression(node)?;
+                line(code, indent, &format!("let _ = {};", expr.code));
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(
+        &mut self,
+        node: &Node,
+        token: &Token,
+        annotation: &Option<Token>,
+        value: &Node,
+        code: &mut String,
+        indent: usize,
+    ) -> Result<(), ErrorType> {
+        let name = identifier_name(token)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(token, "variables named like functions"));
+        }
+        let expr = self.expression(value)?;
+        if expr.type_ == Type::Nil {
+            return Err(node_error(value, "The value returns nothing".to_string()));
+        }
+        let annotated = match annotation {
+            Some(annotation) => {
+                let expected = annotated_type(annotation)?;
+                if expected != expr.type_ {
+                    return Err(type_error(
+                        token,
+                        format!("{} is {}, not {}", name, expected, expr.type_),
+                    ));
+                }
+                true
+            }
+            None => false,
+        };
+
+        // A Rust variable has one type
+        let known = self
+            .body()
+            .variables
+            .entry(name.clone())
+            .or_insert_with(|| expr.type_.clone())
+            .clone();
+        if known != expr.type_ {
+            return Err(type_error(
+                token,
+                format!("{} is {}, not {}", name, known, expr.type_),
+            ));
+        }
+
+        let value = expr.owned();
+        let declaration = self
+            .current()
+            .declarations
+            .at
+            .get(&(node as *const Node))
+            .copied();
+        let statement = match declaration {
+            Some(mutable) => {
+                let type_ = match annotated || expr.literal {
+                    true => format!(": {}", rust_type(&expr.type_)),
+                    false => String::new(),
+                };
+                format!(
+                    "let {}{}{} = {};",
+                    if mutable { "mut " } else { "" },
+                    rust_name(&name),
+                    type_,
+                    value
+                )
+            }
+            None if self.current().declarations.optional.contains(&name) => {
+                format!("{} = Some({});", rust_name(&name), value)
+            }
+            None => format!("{} = {};", rust_name(&name), value),
+        };
+        line(code, indent, &statement);
+        Ok(())
+    }
+
+    fn ret(
+        &mut self,
+        token: &Token,
+        value: Option<&Node>,
+        code: &mut String,
+        indent: usize,
+        tail: bool,
+    ) -> Result<(), ErrorType> {
+        let index = match self.current().function {
+            Some(index) => index,
+            None => {
+                // Returning from main ends the program
+                if let Some(value) = value {
+                    self.statement(value, code, indent, false)?;
+                }
+                line(code, indent, "return;");
+                return Ok(());
+            }
+        };
+
+        let name = self.functions[index].name.clone();
+        // A tail call to the function itself does not nest
+        if let Some(Node::CallNode(name_tok, args)) = value {
+            if identifier_name(name_tok)? == name
+                && args.len() == self.functions[index].params.len()
+            {
+                line(code, indent, "drop(_call);");
+            }
+        }
+        match (value, self.functions[index].returns.clone()) {
+            (None, None) if tail => (),
+            (None, None) => line(code, indent, "return;"),
+            (Some(value), Some(returns)) => {
+                let expr = self.expression(value)?;
+                if expr.type_ != returns {
+                    return Err(node_error(
+                        value,
+                        format!("{} returns {}, not {}", name, returns, expr.type_),
+                    ));
+                }
+                match tail {
+                    true => line(code, indent, &expr.code),
+                    false => line(code, indent, &format!("return {};", expr.code)),
+                }
+            }
+            (Some(Node::CallNode(name_tok, args)), None) => {
+                let expr = self.call(name_tok, args)?;
+                if expr.type_ != Type::Nil {
+                    return Err(unsupported(
+                        token,
+                        "values returned from functions without a return type",
+                    ));
+                }
+                line(code, indent, &format!("{};", expr.code));
+                if !tail {
+                    line(code, indent, "return;");
+                }
+            }
+            (Some(_), None) => {
+                return Err(unsupported(
+                    token,
+                    "values returned from functions without a return type",
+                ))
+            }
+            (None, Some(returns)) => {
+                return Err(type_error(
+                    token,
+                    format!("{} returns {}, not tyhjä", name, returns),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Functions and the program
+
+    fn declare(
+        &mut self,
+        name_tok: &Token,
+        params: &[(Token, Option<Token>)],
+        returns: &Option<Token>,
+    ) -> Result<(), ErrorType> {
+        let name = identifier_name(name_tok)?;
+        if self.function_ids.contains_key(&name) {
+            return Err(unsupported(name_tok, "functions defined more than once"));
+        }
+        let mut typed_params: Vec<(String, Type)> = Vec::with_capacity(params.len());
+        for (param, annotation) in params {
+            let type_ = match annotation {
+                Some(annotation) => annotated_type(annotation)?,
+                None => {
+                    return Err(unsupported(
+                        param,
+                        "parameters without kok, liu, totuus or teksti types",
+                    ))
+                }
+            };
+            let param_name = identifier_name(param)?;
+            if typed_params.iter().any(|(other, _)| *other == param_name) {
+                return Err(unsupported(param, "parameters with the same name"));
+            }
+            typed_params.push((param_name, type_));
+        }
+        let returns = match returns {
+            Some(token) if token.type_() == TokenType::Keyword("tyhjä".to_string()) => None,
+            Some(token) => Some(annotated_type(token)?),
+            None => None,
+        };
+        self.functions.push(Function {
+            name: name.clone(),
+            params: typed_params,
+            returns,
+            defined: false,
+        });
+        self.function_ids.insert(name, self.functions.len() - 1);
+        Ok(())
+    }
+
+    fn program(&mut self, statements: &[Node]) -> Result<String, ErrorType> {
+        let mut analysis = Analysis::default();
+        analysis.block(statements, None, false, None);
+        self.globals = analysis.uses.iter().map(|(name, _)| name.clone()).collect();
+        let declarations = analysis.declarations(&[]);
+        self.body = Some(Body {
+            function: None,
+            variables: HashMap::new(),
+            declarations,
+            blocks: 0,
+        });
+
+        let code = self.block(statements, 1, false)?;
+        self.body = None;
+        Ok(format!("fn main() {{\n{}}}\n", code))
+    }
+
+    fn function(&mut self, name_tok: &Token, body: &Node) -> Result<String, ErrorType> {
+        let index = self.function_ids[&identifier_name(name_tok)?];
+        let params = self.functions[index].params.clone();
+        for (param, _) in &params {
+            if self.function_ids.contains_key(param) {
+                return Err(unsupported(name_tok, "parameters named like functions"));
+            }
+        }
+        let mut analysis = Analysis {
+            set: params.iter().map(|(param, _)| param.clone()).collect(),
+            ..Analysis::default()
+        };
+        analysis.block(statements(body), None, false, None);
+        let declarations = analysis.declarations(&params);
+        let mut signature = Vec::with_capacity(params.len());
+        for (param, type_) in &params {
+            let mutable = declarations.mutable_params.contains(param);
+            signature.push(format!(
+                "{}{}: {}",
+                if mutable { "mut " } else { "" },
+                rust_name(param),
+                rust_type(type_)
+            ));
+        }
+        self.body = Some(Body {
+            function: Some(index),
+            variables: params.iter().cloned().collect(),
+            declarations,
+            blocks: 0,
+        });
+        let mut code = String::new();
+        line(
+            &mut code,
+            1,
+            &format!("let _call = {}::enter();", self.helper("Call")),
+        );
+        code.push_str(&self.block(statements(body), 1, true)?);
+        self.body = None;
+
+        let function = &self.functions[index];
+        let returns = match &function.returns {
+            Some(type_) => format!(" -> {}", rust_type(type_)),
+            None => String::new(),
+        };
+        Ok(format!(
+            "fn {}({}){} {{\n{}}}\n",
+            rust_name(&function.name),
+            signature.join(", "),
+            returns,
+            code
+        ))
+    }
+}
+
+// A value in a format string: a text literal, or an argument
+struct Part {
+    type_: Type,
+    text: Option<String>,
+    arg: String,
+}
+
+impl Part {
+    fn text(text: String) -> Self {
+        Part {
+            type_: Type::Text,
+            text: Some(text),
+            arg: String::new(),
+        }
+    }
+}
+
+// The format string and arguments of parts. Variables are captured by the format string.
+fn format_parts(parts: &[Part]) -> (String, String) {
+    let mut format = String::new();
+    let mut args = String::new();
+    for part in parts {
+        match &part.text {
+            Some(text) => format.push_str(&text.replace('{', "{{").replace('}', "}}")),
+            None if is_plain_identifier(&part.arg) => {
+                write!(format, "{{{}}}", part.arg).ok();
+            }
+            None => {
+                format.push_str("{}");
+                write!(args, ", {}", part.arg).ok();
+            }
+        }
+    }
+    (format!("{:?}", format), args)
+}
+
+// Whether format strings can capture the code as a variable
+fn is_plain_identifier(code: &str) -> bool {
+    let mut chars = code.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&code)
+}
+
+fn line(code: &mut String, indent: usize, text: &str) {
+    for _ in 0..indent {
+        code.push_str("    ");
+    }
+    code.push_str(text);
+    code.push('\n');
+}
+
+fn rust_type(type_: &Type) -> &'static str {
+    match type_ {
+        Type::Integer => "i64",
+        Type::Float => "f64",
+        Type::Boolean => "bool",
+        _ => "String",
+    }
+}
+
+// The name of a variable or function in Rust. Keywords are raw identifiers, and names
+// that Rust, the helpers or the call counting use get an underscore.
+fn rust_name(name: &str) -> String {
+    match name {
+        "self" | "Self" | "super" | "crate" | "main" | "_" | "_call" | "DEPTH" => {
+            format!("{}_", name)
+        }
+        name if HELPERS.contains(&name) => format!("{}_", name),
+        name if KEYWORDS.contains(&name) => format!("r#{}", name),
+        name => name.to_string(),
+    }
+}
+
+fn annotated_type(token: &Token) -> Result<Type, ErrorType> {
+    let name = match token.type_() {
+        TokenType::Identifier(name) | TokenType::Keyword(name) => name,
+        _ => String::new(),
+    };
+    match Type::from_name(&name) {
+        Some(type_ @ (Type::Integer | Type::Float | Type::Boolean | Type::Text)) => Ok(type_),
+        Some(type_) => Err(unsupported(token, &format!("{} values", type_))),
+        None => Err(type_error(token, format!("Unknown type {}", name))),
+    }
+}
+
+fn unsupported(token: &Token, what: &str) -> ErrorType {
+    type_error(
+        token,
+        format!("{} are not supported in Rust programs", what),
+    )
+}
+
+fn unsupported_node(node: &Node, what: &str) -> ErrorType {
+    node_error(node, format!("{} are not supported in Rust programs", what))
+}
+
+fn type_error(token: &Token, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(
+        token.position_start(),
+        token.position_end(),
+        message,
+    ))
+}
+
+fn node_error(node: &Node, message: String) -> ErrorType {
+    ErrorType::TypeError(TypeError::new(node.pos_start(), node.pos_end(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::test_support::{self, Target};
+
+    const RUST: Target = Target {
+        name: "rust",
+        generate: Engine::to_rust,
+        build,
+    };
+
+    // The program prints the error of the interpreter, without the traceback
+    fn assert_same_error(text: &str, message: &str) {
+        let (stdout, stderr) = RUST.compile_and_run(text);
+        let (expected_stdout, expected_stderr) = test_support::interpret(text);
+        assert_eq!(stdout, expected_stdout, "{}", text);
+        assert!(stderr.contains(message), "{}: {}", text, stderr);
+        assert!(
+            expected_stderr
+                .lines()
+                .any(|line| line == stderr.trim_end()),
+            "{}: {}\n{}",
+            text,
+            stderr,
+            expected_stderr
+        );
+    }
+
+    #[test]
+    fn test_same_as_interpeter() {
+        for text in [
+            "tulosta(1 + 2 * 3, 7 / 2, -7 / 2, 2 ^ 10, 2.0 ^ -2, 1.5 * 4.0, 0.1 + 0.2, 1.0 / 3.0)",
+            "tulosta(1000000000000000000000.0 * 1.0, 1.0 / 10000000.0, -0.0, liu(3), 0.000000000000000000000000000123 * 1.0)",
+            "tulosta(\"äö {}\", tosi, 1 < 2, 1.5 >= 2.5, 2 == 2, tosi != epätosi, ei tosi, 0.0 / 1.0 == -0.0)",
+            "muut n = 0.0 / 1.0 * 0.0; muut x = n / 1.0; tulosta(x < x, x == x, x != x, x >= 1.0)",
+            "tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; tulosta(fib(20))",
+            "tominto f(n: kok, s: kok): kok { kun n > 0 { muut s = s + n; muut n = n - 1 }; palata s }; tulosta(f(100000, 0))",
+            "tominto f(a: liu, b: kok, c: totuus, d: liu): liu { jos c { palata a * liu(b) + d }; palata d }; tulosta(f(1.5, 2, tosi, 0.25), f(1.5, 2, epätosi, 0.25))",
+            "tominto näytä(x: kok) { tulosta(\"x on\", x) }; näytä(3); näytä(-3)",
+            "jos 1 > 2 { tulosta(1) } muuten jos 1 == 1 ja ei epätosi { tulosta(2) } muuten { tulosta(3) }",
+            "tominto t(x: kok): totuus { tulosta(x); palata x > 1 }; tulosta(t(1) ja t(2), t(2) tai t(3), t(1) tai t(2) ja t(3))",
+            "muut a = 1; muut b = 2; tulosta(((a + b) * (a - b) + (a * b - (b - a) * (a + b))) * ((a + 1) * (b + 1) - (a + b) * (a - b)))",
+            "muut x = 1.5; tulosta(((x + x) * (x - 1.0) + (x * x - (x - 0.5) * (x + x))) * ((x + 1.0) * (x + 2.0) - (x + x) * (x - 2.0)))",
+            "muut a = 3; tulosta(a ^ 3, (-a) ^ 3, a ^ 0, 2.0 ^ 10, 0.5 ^ -2, -a, +a, -(1.5 * liu(a)))",
+            "muut a = 2.0; muut b = 3; tulosta(a ^ 0.5, b ^ 1.5, a ^ -3, (-a) ^ 3.0, abs(-b), abs(-a), min(b, 1, 2), max(b, 7))",
+            "palata 1; tulosta(2)",
+            "tominto f(n: kok): kok { kun tosi { jos n > 0 { palata f(n - 1) }; palata 7 } }; tulosta(f(50000))",
+            "muut nimi = \"maailma\"; muut t = \"hei \" + nimi + \"!\"; tulosta(t, pituus(t), t == \"hei maailma!\", nimi < t)",
+            "muut s = \"\"; muut i = 0; kun i < 5 { muut s = s + str(i) + \",\"; muut i = i + 1 }; tulosta(s + str(tosi) + str(1.5))",
+            "muut i = 0; kun tosi { muut i = i + 1; jos i > 3 { palata } }; tulosta(i)",
+            "jos 1 < 2 { muut y = 1 } muuten { muut y = 2 }; tulosta(y); muut y = y + 1; tulosta(y)",
+            "tominto f(x: kok): kok { jos x > 0 { palata 1 } muuten jos x < 0 { palata -1 } muuten { palata 0 } }; tulosta(f(5), f(-5), f(0))",
+            "tominto tervehdi(nimi: teksti): teksti { palata \"hei \" + nimi }; muut n = \"a\"; tulosta(tervehdi(n), tervehdi(n))",
+            "muut fn = 1; muut loop = 2; muut main = 3; muut divide = 4; muut self = 5; tulosta(fn + loop + main + divide + self)",
+            "tominto f(n: kok): kok { palata n * 2 }; muut i = 0; kun i < 5000 { muut x = f(i); muut i = i + 1 }; tulosta(x)",
+            "muut n = 3; muut s = \"abc\"; tulosta(liu(n) < 4.0, pituus(s) < 4, liu(n) * 2.0, -liu(n), pituus(s) > 2, 2.0 ^ liu(n), liu(n) ^ 0.5)",
+            "tominto f(n: kok): kok { jos n > 0 { muut r = n } muuten { palata 0 }; palata r }; tulosta(f(3), f(0))",
+            "muut i = 0; kun i < 3 { muut t = \"kierros \" + str(i); muut i = i + 1 }; tulosta(t, t + \"!\")",
+        ] {
+            RUST.assert_same(text);
+        }
+    }
+
+    #[test]
+    fn test_same_errors() {
+        for (text, message) in [
+            (
+                "tulosta(1); muut a = 9223372036854775807; a + 1",
+                "kokonaisluvun ylivuoto",
+            ),
+            (
+                "muut a = 0; tulosta(\"ennen\"); 1 / a",
+                "Division by Zero",
+            ),
+            (
+                "muut a = -9223372036854775807 - 1; tulosta(a / -1)",
+                "kokonaisluvun ylivuoto",
+            ),
+            (
+                "muut a = -9223372036854775807 - 1; -a",
+                "kokonaisluvun ylivuoto",
+            ),
+            ("muut a = 0.0; 1.0 / a", "Division by Zero"),
+            (
+                "tominto f(n: kok): kok { palata 10 / n }; tominto g(n: kok): kok { palata f(n - 1) }; g(1)",
+                "Division by Zero",
+            ),
+            ("muut a = 0; a ^ -2", "Division by Zero"),
+            ("muut a = 2; a ^ 63", "kokonaisluvun ylivuoto"),
+            ("muut a = 0.0; a ^ -1", "Division by Zero"),
+            (
+                "muut a = -2.0; a ^ 0.5",
+                "Cant raise a negative number to a fractional power",
+            ),
+            (
+                "tominto f(n: kok): kok { jos n > 0 { palata 1 + f(n - 1) }; palata 0 }; tulosta(f(999)); f(1000)",
+                "Calls nested more than 1000 deep",
+            ),
+            (
+                "muut x = 5; jos x > 10 { muut y = 1 }; tulosta(y)",
+                "y is not defined",
+            ),
+            (
+                "tominto f(n: kok): kok { jos n > 0 { muut r = 1 }; palata r }; tulosta(f(1)); f(0)",
+                "r is not defined",
+            ),
+        ] {
+            assert_same_error(text, message);
+        }
+    }
+
+    #[test]
+    fn test_readable_output() {
+        let text = "tominto fib(n: kok): kok {
+    jos n < 2 {
+        palata n
+    }
+    palata fib(n - 1) + fib(n - 2)
+}
+
+muut i = 0
+kun i < 10 {
+    tulosta(\"fib\", i, \"=\", fib(i))
+    muut i = i + 1
+}
+jos i > 5 {
+    muut nimi = \"iso\"
+} muuten {
+    muut nimi = \"pieni\"
+}
+tulosta(\"i on \" + nimi)";
+        let expected = "// Generated by finc from fib.fin
+
+fn fib(n: i64) -> i64 {
+    let _call = Call::enter();
+    if n < 2 {
+        return n;
+    }
+    fib(n.checked_sub(1).unwrap_or_else(overflow)).checked_add(fib(n.checked_sub(2).unwrap_or_else(overflow))).unwrap_or_else(overflow)
+}
+
+fn main() {
+    let mut i: i64 = 0;
+    while i < 10 {
+        println!(\"fib {i} = {}\", fib(i));
+        i = i.checked_add(1).unwrap_or_else(overflow);
+    }
+    let nimi: String;
+    if i > 5 {
+        nimi = String::from(\"iso\");
+    } else {
+        nimi = String::from(\"pieni\");
+    }
+    println!(\"i on {nimi}\");
+}
+
+// Calls that have not returned yet, which nest at most 1000 deep like in the interpreter
+static DEPTH: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+struct Call;
+
+impl Call {
+    fn enter() -> Call {
+        if DEPTH.fetch_add(1, std::sync::atomic::Ordering::Relaxed) >= 1000 {
+            fail(\"Call Depth Limit Error\", \"Calls nested more than 1000 deep\");
+        }
+        Call
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        DEPTH.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn fail(error: &str, message: &str) -> ! {
+    eprintln!(\"{error}: {message}\");
+    std::process::exit(1);
+}
+
+fn overflow() -> i64 {
+    fail(\"Runtime Error\", \"kokonaisluvun ylivuoto\")
+}
+";
+        assert_eq!(Engine::new().to_rust("fib.fin", text).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for (text, message) in [
+            ("muut a = 2i", "kompleksi numbers are not supported"),
+            ("muut a = [1]", "lista values are not supported"),
+            (
+                "tominto f(x) { palata x }",
+                "parameters without kok, liu, totuus or teksti types",
+            ),
+            (
+                "tominto f() { 1 }; muut g = f",
+                "functions as values are not supported",
+            ),
+            (
+                "muut x = 1; tominto f(): kok { palata x }; tulosta(f())",
+                "global variables in functions are not supported",
+            ),
+            ("tulosta(syöte())", "calls to syöte are not supported"),
+        ] {
+            let error = RUST.unsupported_message(text);
+            assert!(error.contains(message), "{}: {}", text, error);
+        }
+
+        let (_, error) = RUST.compile_and_run("muut a = 2; a ^ -1");
+        assert!(
+            error.contains("murto numbers are not supported"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_fibonacci_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examplez/src/fibonacci.fin");
+        RUST.assert_same(&fs::read_to_string(path).unwrap());
+    }
+}
@@ -0,0 +1,489 @@
+use crate::ir::{self, BlockId, Function, Inst, Module, Operand, Reg};
+use std::collections::HashMap;
+
+// Static single assignment form. Registers that are set more than once are split into a
+// register for each place that sets them, and phis join the registers that reach a block
+// on different paths. Phis only go where the variable is still used, at the dominance
+// frontiers of the blocks that set it.
+//
+// Registers that are used before they are set on some path were set to zero at the start
+// by the lowering, so every path to a phi has a value for it.
+
+/// The dominator tree of a function. A block dominates another if every path from the
+/// start to the other goes through it.
+pub struct Dominators {
+    // The closest block that dominates each block, the start for the start, and `None`
+    // for blocks that cant be reached
+    idom: Vec<Option<BlockId>>,
+    // The blocks that can be reached, in reverse postorder
+    order: Vec<BlockId>,
+}
+
+impl Dominators {
+    // "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and Kennedy
+    pub fn new(function: &Function) -> Self {
+        let order = function.reverse_postorder();
+        let mut position = vec![usize::MAX; function.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[block.0] = i;
+        }
+        let predecessors = function.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; function.blocks.len()];
+        idom[0] = Some(BlockId(0));
+
+        let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+            while a != b {
+                while position[a.0] > position[b.0] {
+                    a = idom[a.0].expect("Processed blocks have dominators");
+                }
+                while position[b.0] > position[a.0] {
+                    b = idom[b.0].expect("Processed blocks have dominators");
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in order.iter().skip(1) {
+                let mut new_idom = None;
+                for &from in &predecessors[block.0] {
+                    if idom[from.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        Some(other) => intersect(&idom, from, other),
+                        None => from,
+                    });
+                }
+                if new_idom.is_some() && idom[block.0] != new_idom {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        Dominators { idom, order }
+    }
+
+    /// The closest block that dominates a block other than itself
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        match block.0 {
+            0 => None,
+            _ => self.idom[block.0],
+        }
+    }
+
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(up) => b = up,
+                None => return false,
+            }
+        }
+    }
+
+    /// The blocks each block is the closest dominator of, in reverse postorder
+    pub fn children(&self) -> Vec<Vec<BlockId>> {
+        let mut children = vec![Vec::new(); self.idom.len()];
+        for &block in &self.order {
+            if let Some(parent) = self.idom(block) {
+                children[parent.0].push(block);
+            }
+        }
+        children
+    }
+
+    /// The blocks that can be reached, in reverse postorder
+    pub fn order(&self) -> &[BlockId] {
+        &self.order
+    }
+
+    /// Where the blocks each block dominates stop: the blocks it does not strictly
+    /// dominate that one of them jumps to
+    pub fn frontiers(&self, function: &Function) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![Vec::new(); function.blocks.len()];
+        let predecessors = function.predecessors();
+        for &block in &self.order {
+            if predecessors[block.0].len() < 2 {
+                continue;
+            }
+            let idom = self.idom(block);
+            for &from in &predecessors[block.0] {
+                if self.idom[from.0].is_none() {
+                    continue;
+                }
+                let mut runner = Some(from);
+                while let Some(current) = runner {
+                    if Some(current) == idom {
+                        break;
+                    }
+                    if !frontiers[current.0].contains(&block) {
+                        frontiers[current.0].push(block);
+                    }
+                    runner = self.idom(current);
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+/// The registers used at the start of each block before they are set. Phis set their
+/// registers at the start of their block, and use their values at the end of the blocks
+/// the values come from.
+pub fn live_in(function: &Function) -> Vec<Vec<bool>> {
+    let count = function.regs.len();
+    let mut live = vec![vec![false; count]; function.blocks.len()];
+    let mut order = function.reverse_postorder();
+    order.reverse();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order {
+            let mut current = vec![false; count];
+            for successor in function.block(block).terminator.successors() {
+                for (i, is_live) in live[successor.0].iter().enumerate() {
+                    current[i] |= is_live;
+                }
+                for inst in &function.block(successor).insts {
+                    if let Inst::Phi { incoming, .. } = inst {
+                        for (from, value) in incoming {
+                            if let (true, Operand::Reg(reg)) = (*from == block, value) {
+                                current[reg.0] = true;
+                            }
+                        }
+                    }
+                }
+            }
+            let body = function.block(block);
+            for operand in body.terminator.operands() {
+                if let Operand::Reg(reg) = operand {
+                    current[reg.0] = true;
+                }
+            }
+            for inst in body.insts.iter().rev() {
+                if let Some(dest) = inst.dest() {
+                    current[dest.0] = false;
+                }
+                if let Inst::Phi { .. } = inst {
+                    continue;
+                }
+                for operand in inst.operands() {
+                    if let Operand::Reg(reg) = operand {
+                        current[reg.0] = true;
+                    }
+                }
+            }
+            if live[block.0] != current {
+                live[block.0] = current;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+/// Puts a function in SSA form. Registers set in more than one place get a register for
+/// each, with the name of the variable.
+pub fn construct(function: &mut Function) {
+    function.sort_blocks();
+    let mut sets = vec![Vec::new(); function.regs.len()];
+    for param in &function.params {
+        sets[param.0].push(BlockId(0));
+    }
+    for (i, block) in function.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            if let Some(dest) = inst.dest() {
+                sets[dest.0].push(BlockId(i));
+            }
+        }
+    }
+    let split: Vec<bool> = sets.iter().map(|blocks| blocks.len() > 1).collect();
+
+    let dominators = Dominators::new(function);
+    let frontiers = dominators.frontiers(function);
+    let live = live_in(function);
+    let predecessors = function.predecessors();
+    for (reg, blocks) in sets.iter().enumerate() {
+        if !split[reg] {
+            continue;
+        }
+        let mut has_phi = vec![false; function.blocks.len()];
+        let mut work = blocks.clone();
+        while let Some(block) = work.pop() {
+            for &frontier in &frontiers[block.0] {
+                if has_phi[frontier.0] || !live[frontier.0][reg] {
+                    continue;
+                }
+                has_phi[frontier.0] = true;
+                // The values are renamed when the blocks they come from are
+                let incoming = predecessors[frontier.0]
+                    .iter()
+                    .map(|from| (*from, Operand::Reg(Reg(reg))))
+                    .collect();
+                let phi = Inst::Phi {
+                    dest: Reg(reg),
+                    incoming,
+                };
+                function.block_mut(frontier).insts.insert(0, phi);
+                if !blocks.contains(&frontier) {
+                    work.push(frontier);
+                }
+            }
+        }
+    }
+
+    let mut renaming = Renaming {
+        split,
+        stacks: vec![Vec::new(); function.regs.len()],
+        children: dominators.children(),
+    };
+    for param in function.params.clone() {
+        renaming.stacks[param.0].push(param);
+    }
+    renaming.block(function, BlockId(0));
+    function.remove_unused_regs();
+}
+
+struct Renaming {
+    split: Vec<bool>,
+    // The registers that hold each split register where the renaming is
+    stacks: Vec<Vec<Reg>>,
+    children: Vec<Vec<BlockId>>,
+}
+
+impl Renaming {
+    fn current(&self, operand: &mut Operand) {
+        if let Operand::Reg(reg) = operand {
+            if self.split[reg.0] {
+                *reg = *self.stacks[reg.0]
+                    .last()
+                    .expect("Registers are set on every path to their uses");
+            }
+        }
+    }
+
+    fn block(&mut self, function: &mut Function, block: BlockId) {
+        let mut pushed = Vec::new();
+        let count = function.block(block).insts.len();
+        for i in 0..count {
+            let inst = &mut function.block_mut(block).insts[i];
+            if !matches!(inst, Inst::Phi { .. }) {
+                for operand in inst.operands_mut() {
+                    self.current(operand);
+                }
+            }
+            let dest = match inst.dest() {
+                Some(dest) if self.split[dest.0] => dest,
+                _ => continue,
+            };
+            let info = function.regs[dest.0].clone();
+            let new = function.new_reg(info.type_, info.name);
+            if let Some(reg) = function.block_mut(block).insts[i].dest_mut() {
+                *reg = new;
+            }
+            self.stacks[dest.0].push(new);
+            pushed.push(dest);
+        }
+        for operand in function.block_mut(block).terminator.operands_mut() {
+            self.current(operand);
+        }
+        for successor in function.block(block).terminator.successors() {
+            for inst in &mut function.block_mut(successor).insts {
+                if let Inst::Phi { incoming, .. } = inst {
+                    for (from, value) in incoming.iter_mut() {
+                        if *from == block {
+                            self.current(value);
+                        }
+                    }
+                }
+            }
+        }
+        for child in self.children[block.0].clone() {
+            self.block(function, child);
+        }
+        for reg in pushed {
+            self.stacks[reg.0].pop();
+        }
+    }
+}
+
+/// Checks that a module is well formed and in SSA form: every register is set once, in a
+/// block that dominates its uses
+pub fn verify(module: &Module) -> Result<(), String> {
+    ir::verify(module)?;
+    for function in module.functions.iter().chain(Some(&module.program)) {
+        verify_function(function).map_err(|e| format!("{}: {}", function.name, e))?;
+    }
+    Ok(())
+}
+
+fn verify_function(function: &Function) -> Result<(), String> {
+    let dominators = Dominators::new(function);
+    let names = function.reg_names();
+    // Where each register is set: the block and the index of the instruction, with
+    // parameters before the first
+    let mut sets: HashMap<Reg, (BlockId, Option<usize>)> = HashMap::new();
+    for param in &function.params {
+        sets.insert(*param, (BlockId(0), None));
+    }
+    for (i, block) in function.blocks.iter().enumerate() {
+        for (j, inst) in block.insts.iter().enumerate() {
+            if let Some(dest) = inst.dest() {
+                if sets.insert(dest, (BlockId(i), Some(j))).is_some() {
+                    return Err(format!("{} is set more than once", names[dest.0]));
+                }
+            }
+        }
+    }
+    let check = |reg: &Reg, block: BlockId, index: usize| match sets.get(reg) {
+        Some((set_block, set_index)) => {
+            let before = match set_index {
+                Some(set_index) => *set_block != block || *set_index < index,
+                None => true,
+            };
+            match before && dominators.dominates(*set_block, block) {
+                true => Ok(()),
+                false => Err(format!(
+                    "{}: {} is used where it may not be set",
+                    block, names[reg.0]
+                )),
+            }
+        }
+        None => Err(format!("{}: {} is never set", block, names[reg.0])),
+    };
+    for &block in dominators.order() {
+        let body = function.block(block);
+        for (i, inst) in body.insts.iter().enumerate() {
+            match inst {
+                Inst::Phi { incoming, .. } => {
+                    for (from, value) in incoming {
+                        if let Operand::Reg(reg) = value {
+                            check(reg, *from, usize::MAX)?;
+                        }
+                    }
+                }
+                inst => {
+                    for operand in inst.operands() {
+                        if let Operand::Reg(reg) = operand {
+                            check(reg, block, i)?;
+                        }
+                    }
+                }
+            }
+        }
+        for operand in body.terminator.operands() {
+            if let Operand::Reg(reg) = operand {
+                check(reg, block, usize::MAX)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::tests::{assert_same, lower_text, PROGRAMS};
+
+    fn to_ssa(mut module: Module) -> Module {
+        for function in module.functions.iter_mut().chain(Some(&mut module.program)) {
+            construct(function);
+        }
+        module
+    }
+
+    #[test]
+    fn test_dominators() {
+        let module =
+            lower_text("muut i = 0; kun i < 3 { jos i == 1 { tulosta(i) }; muut i = i + 1 }");
+        let function = &module.program;
+        let dominators = Dominators::new(function);
+        let idoms: Vec<Option<usize>> = (0..function.blocks.len())
+            .map(|block| dominators.idom(BlockId(block)).map(|idom| idom.0))
+            .collect();
+        // The start, the condition of the loop, its body, the two cases of the jos, where
+        // they meet, and the end of the loop
+        assert_eq!(
+            idoms,
+            [None, Some(0), Some(1), Some(2), Some(2), Some(2), Some(1)]
+        );
+        let frontiers = dominators.frontiers(function);
+        assert_eq!(frontiers[3], [BlockId(5)]);
+        assert_eq!(frontiers[4], [BlockId(5)]);
+        assert_eq!(frontiers[5], [BlockId(1)]);
+        assert!(dominators.dominates(BlockId(1), BlockId(5)));
+        assert!(!dominators.dominates(BlockId(3), BlockId(5)));
+    }
+
+    #[test]
+    fn test_phis() {
+        let module = to_ssa(lower_text(
+            "muut i = 0; muut t = 0; kun i < 3 { jos i == 1 { muut t = i }; muut i = i + 1 }; tulosta(i, t)",
+        ));
+        let expected = "program {
+b0:
+    %i.1: kok = copy 0
+    %t.1: kok = copy 0
+    jmp b1
+b1:          ; from b0, b5
+    %t.2: kok = phi [b0: %t.1], [b5: %t.4]
+    %i.2: kok = phi [b0: %i.1], [b5: %i.3]
+    %0: totuus = lt %i.2, 3
+    br %0, b2, b6
+b2:          ; from b1
+    %1: totuus = eq %i.2, 1
+    br %1, b3, b4
+b3:          ; from b2
+    %t.3: kok = copy %i.2
+    jmp b5
+b4:          ; from b2
+    jmp b5
+b5:          ; from b3, b4
+    %t.4: kok = phi [b3: %t.3], [b4: %t.2]
+    %2: kok = add %i.2, 1
+    %i.3: kok = copy %2
+    jmp b1
+b6:          ; from b1
+    print %i.2, %t.2
+    ret
+}
+";
+        assert_eq!(module.to_string(), expected);
+        assert_eq!(verify(&module), Ok(()));
+    }
+
+    #[test]
+    fn test_same_as_interpeter() {
+        for text in PROGRAMS {
+            assert_same(text, |module| {
+                let module = to_ssa(module);
+                verify(&module).unwrap();
+                module
+            });
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut module = lower_text("muut i = 0; kun i < 3 { muut i = i + 1 }");
+        assert_eq!(
+            verify(&module),
+            Err("program: %i is set more than once".to_string())
+        );
+        module = to_ssa(module);
+        assert_eq!(verify(&module), Ok(()));
+        // The value of the loop used before the loop
+        let phi = match &module.program.blocks[1].insts[0] {
+            Inst::Phi { dest, .. } => *dest,
+            inst => panic!("{:?}", inst),
+        };
+        module.program.blocks[0].insts.push(Inst::Print {
+            values: vec![Operand::Reg(phi)],
+        });
+        assert!(verify(&module).is_err());
+    }
+}
@@ -1,9 +1,14 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A symbol map that can be shared between contexts, and by functions that captured it
+pub type SharedSymbolMap<V> = Rc<RefCell<SymbolMap<V>>>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SymbolMap<V> {
     symbols: HashMap<String, V>,
-    parent: Option<Box<SymbolMap<V>>>,
+    parent: Option<SharedSymbolMap<V>>,
 }
 
 impl<V> SymbolMap<V> {
@@ -14,15 +19,52 @@ impl<V> SymbolMap<V> {
         }
     }
 
-    pub fn get(&self, key: String) -> Option<&V> {
-        self.symbols.get(&key)
+    pub fn with_parent(parent: SharedSymbolMap<V>) -> Self {
+        Self {
+            symbols: HashMap::<String, V>::new(),
+            parent: Some(parent),
+        }
+    }
+
+    pub fn shared(self) -> SharedSymbolMap<V> {
+        Rc::new(RefCell::new(self))
+    }
+
+    /// Looks the symbol up here first, then in the parent maps
+    pub fn get(&self, key: &str) -> Option<V>
+    where
+        V: Clone,
+    {
+        match self.symbols.get(key) {
+            Some(value) => Some(value.clone()),
+            None => match &self.parent {
+                Some(parent) => parent.borrow().get(key),
+                None => None,
+            },
+        }
     }
 
     pub fn set(&mut self, key: String, value: V) -> Option<V> {
         self.symbols.insert(key, value)
     }
 
+    /// Like `set`, but only allocates the key when the symbol is new
+    pub fn assign(&mut self, key: &str, value: V) {
+        match self.symbols.get_mut(key) {
+            Some(slot) => *slot = value,
+            None => {
+                self.symbols.insert(key.to_string(), value);
+            }
+        }
+    }
+
     pub fn remove(&mut self, key: String) -> Option<V> {
         self.symbols.remove(&key)
     }
 }
+
+impl<V> Default for SymbolMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,81 @@
+use crate::engine::{Diagnostics, Engine, OutputBuffer};
+use crate::interpeter;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Helpers for the tests of the backends, which check that the programs they build do
+// what the interpreter does. Programs read "Olle" as their input.
+
+const INPUT: &str = "Olle\n";
+
+/// What the interpreter prints to stdout and stderr
+pub fn interpret(text: &str) -> (String, String) {
+    let text = text.to_string();
+    interpeter::with_stack(move || {
+        let mut engine = Engine::new();
+        let output = OutputBuffer::new();
+        engine.set_output(output.clone());
+        engine.set_input(io::Cursor::new(INPUT));
+        let errors = match engine.eval_file("test.fin", &text) {
+            Ok(_) => String::new(),
+            Err(diagnostics) => format!("{}\n", diagnostics),
+        };
+        (output.contents(), errors)
+    })
+}
+
+/// A backend that builds executables
+pub struct Target {
+    pub name: &'static str,
+    pub generate: fn(&Engine, &str, &str) -> Result<String, Diagnostics>,
+    pub build: fn(&str, &Path) -> io::Result<()>,
+}
+
+impl Target {
+    /// What the built program prints to stdout and stderr
+    pub fn compile_and_run(&self, text: &str) -> (String, String) {
+        let source = (self.generate)(&Engine::new(), "test.fin", text).unwrap();
+        let dir = env::temp_dir().join(format!("finc-{}-test-{}", self.name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        static PROGRAMS: AtomicUsize = AtomicUsize::new(0);
+        let program = PROGRAMS.fetch_add(1, Ordering::Relaxed);
+        let binary: PathBuf = dir.join(format!("program{}", program));
+        (self.build)(&source, &binary).unwrap();
+
+        let mut child = Command::new(&binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            // Programs that end without reading close the pipe
+            stdin.write_all(INPUT.as_bytes()).ok();
+        }
+        let output = child.wait_with_output().unwrap();
+        fs::remove_file(&binary).ok();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert_eq!(output.status.success(), stderr.is_empty(), "{}", stderr);
+        if !output.status.success() {
+            assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        }
+        (stdout, stderr)
+    }
+
+    pub fn assert_same(&self, text: &str) {
+        assert_eq!(self.compile_and_run(text), interpret(text), "{}", text);
+    }
+
+    /// Why the backend cant compile a program
+    pub fn unsupported_message(&self, text: &str) -> String {
+        (self.generate)(&Engine::new(), "test.fin", text)
+            .unwrap_err()
+            .to_string()
+    }
+}
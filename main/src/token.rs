@@ -1,11 +1,13 @@
-use crate::errors::{self, DisallowedCharError};
 use crate::position::Position;
+use num::BigInt;
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     Int(i64),
     Float(f64),
+    BigInt(BigInt),
+    Imaginary(f64),
     Plus,
     Minus,
     Multiply,
@@ -19,10 +21,42 @@ pub enum TokenType {
     Identifier(String),
     Keyword(String),
     Equal,
+    Comma,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Colon,
+    Dot,
+    Newline, // Or ';'
+    EqualEqual,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    LessThanEqual,
+    GreaterThanEqual,
 }
 
-pub enum Keyword {
-    Func(),
+impl TokenType {
+    /// How the token is written, for messages about operators
+    pub fn symbol(&self) -> String {
+        let symbol = match self {
+            TokenType::Plus => "+",
+            TokenType::Minus => "-",
+            TokenType::Multiply => "*",
+            TokenType::Divide => "/",
+            TokenType::Pow => "^",
+            TokenType::EqualEqual => "==",
+            TokenType::NotEqual => "!=",
+            TokenType::LessThan => "<",
+            TokenType::GreaterThan => ">",
+            TokenType::LessThanEqual => "<=",
+            TokenType::GreaterThanEqual => ">=",
+            TokenType::Keyword(keyword) => keyword,
+            other => return format!("{:?}", other),
+        };
+        symbol.to_string()
+    }
 }
 
 impl Default for TokenType {
@@ -31,23 +65,13 @@ impl Default for TokenType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Token {
     type_: TokenType, // Also holds values
     position_start: Option<Position>,
     position_end: Option<Position>,
 }
 
-impl Default for Token {
-    fn default() -> Self {
-        Self {
-            type_: TokenType::default(),
-            position_start: None,
-            position_end: None,
-        }
-    }
-}
-
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self.type_)
@@ -0,0 +1,965 @@
+use crate::errors::{ErrorType, TypeError};
+use crate::number::NumberType;
+use crate::parser::Node;
+use crate::position::Position;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The type of a value, as far as it can be known before a program runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Integer,
+    Float,
+    BigInteger,
+    Fraction,
+    Complex,
+    /// A real number whose type is only known when the program runs, like a power of a `kok`.
+    /// Annotations dont accept it, as it may not be what they say.
+    Number,
+    Boolean,
+    Text,
+    List,
+    Record,
+    Nil,
+    /// A function, with its signature when it is known
+    Function(Option<Box<Signature>>),
+    /// Anything. Values that are not annotated can be used anywhere.
+    Any,
+}
+
+/// Parameters and return type of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<(String, Type)>,
+    pub returns: Type,
+}
+
+impl Type {
+    /// The type a name in an annotation stands for
+    pub fn from_name(name: &str) -> Option<Type> {
+        let type_ = match name {
+            "kok" => Type::Integer,
+            "liu" => Type::Float,
+            "iso" => Type::BigInteger,
+            "murto" => Type::Fraction,
+            "kompleksi" => Type::Complex,
+            "totuus" => Type::Boolean,
+            "teksti" => Type::Text,
+            "lista" => Type::List,
+            "tietue" => Type::Record,
+            "tyhjä" => Type::Nil,
+            "tominto" => Type::Function(None),
+            _ => return None,
+        };
+        Some(type_)
+    }
+
+    pub fn of(value: &Value) -> Type {
+        match value {
+            Value::Number(NumberType::Integer(_)) => Type::Integer,
+            Value::Number(NumberType::Float(_)) => Type::Float,
+            Value::Number(NumberType::BigInteger(_)) => Type::BigInteger,
+            Value::Number(NumberType::Fraction(_)) => Type::Fraction,
+            Value::Number(NumberType::Complex(_)) => Type::Complex,
+            Value::Boolean(_) => Type::Boolean,
+            Value::Text(_) => Type::Text,
+            Value::Function(_) | Value::Native(_) => Type::Function(None),
+            Value::List(_) => Type::List,
+            Value::Record(_) => Type::Record,
+            Value::Nil => Type::Nil,
+        }
+    }
+
+    /// Whether a value of type `other` can be used where this type is expected
+    pub fn accepts(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Function(None), Type::Function(_))
+            | (Type::Function(_), Type::Function(None)) => true,
+            (expected, actual) => expected == actual,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Type::Integer
+                | Type::Float
+                | Type::BigInteger
+                | Type::Fraction
+                | Type::Complex
+                | Type::Number
+        )
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Integer => "kok",
+            Type::Float => "liu",
+            Type::BigInteger => "iso",
+            Type::Fraction => "murto",
+            Type::Complex => "kompleksi",
+            Type::Number => "luku",
+            Type::Boolean => "totuus",
+            Type::Text => "teksti",
+            Type::List => "lista",
+            Type::Record => "tietue",
+            Type::Nil => "tyhjä",
+            Type::Function(None) => "tominto",
+            Type::Function(Some(signature)) => return write!(f, "{}", signature),
+            Type::Any => "mikä tahansa",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|(name, type_)| match type_ {
+                Type::Any => name.clone(),
+                type_ => format!("{}: {}", name, type_),
+            })
+            .collect();
+        match &self.returns {
+            Type::Any => write!(f, "tominto({})", params.join(", ")),
+            returns => write!(f, "tominto({}): {}", params.join(", "), returns),
+        }
+    }
+}
+
+// A variable the checker knows about
+#[derive(Debug, Clone)]
+struct Binding {
+    type_: Type,
+    // Annotated variables only take values of their type
+    declared: bool,
+    // Where an inferred type came from: the value of the variable, or the 'palata'
+    // that gave the return type of a function
+    origin: Option<Position>,
+}
+
+// A function being checked
+#[derive(Debug, Clone)]
+struct Returns {
+    function: String,
+    // The annotated return type
+    declared: Option<Type>,
+    // Types of the values returned so far, and where they were returned
+    found: Vec<(Type, Option<Position>)>,
+}
+
+/// Checks types before a program runs: annotated declarations, function signatures,
+/// call arguments and returns, and the operators used on values of known types.
+///
+/// Variables that are not annotated take the type of their value, and functions the type
+/// of what they return. Setting them to a value of another type later is an error,
+/// except for 'tyhjä' and values of unknown type, which leave the variable untyped.
+///
+/// The checker remembers the globals of the programs it accepted, for the next ones.
+#[derive(Debug, Clone, Default)]
+pub struct Checker {
+    // From the globals to the function being checked
+    scopes: Vec<HashMap<String, Binding>>,
+    // The functions being checked, innermost last
+    returns: Vec<Returns>,
+    errors: Vec<ErrorType>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            ..Self::default()
+        }
+    }
+
+    /// Checks a whole program and returns the type of its value, or all of its type errors.
+    /// The globals it defines are only remembered if there are none.
+    pub fn check(&mut self, node: &Node) -> Result<Type, Vec<ErrorType>> {
+        let globals = self.scopes.clone();
+        let type_ = self.synth(node);
+        let errors = std::mem::take(&mut self.errors);
+        if errors.is_empty() {
+            return Ok(type_);
+        }
+        self.scopes = globals;
+        self.returns.clear();
+        Err(errors)
+    }
+
+    /// Tells the checker about a global set from outside of programs
+    pub fn set_global(&mut self, name: &str, type_: Type) {
+        let binding = self.binding(type_, false, None);
+        self.scopes[0].insert(name.to_string(), binding);
+    }
+
+    /// The type of a global, if the checker knows the global
+    pub fn global(&self, name: &str) -> Option<Type> {
+        self.scopes[0]
+            .get(name)
+            .map(|binding| binding.type_.clone())
+    }
+
+    fn synth(&mut self, node: &Node) -> Type {
+        match node {
+            Node::Value(token) => match token.type_() {
+                TokenType::Int(_) => Type::Integer,
+                TokenType::Float(_) => Type::Float,
+                TokenType::BigInt(_) => Type::BigInteger,
+                TokenType::Imaginary(_) => Type::Complex,
+                TokenType::String(_) => Type::Text,
+                TokenType::Keyword(keyword) if keyword == "tyhjä" => Type::Nil,
+                TokenType::Keyword(_) => Type::Boolean,
+                _ => Type::Any,
+            },
+            Node::VarAccessNode(token) => self
+                .lookup(&name(token))
+                .map(|binding| binding.type_.clone())
+                .unwrap_or(Type::Any),
+            Node::Binop(..) => self.binops(node),
+            Node::Unary(optok, node) => self.unary(optok, node),
+            Node::VarAssignNode(token, annotation, value) => {
+                self.assign(token, annotation.as_ref(), value)
+            }
+            Node::CallNode(token, args) => self.call(node, token, args),
+            Node::ListNode(_, items) => {
+                for item in items {
+                    self.synth(item);
+                }
+                Type::List
+            }
+            Node::RecordNode(_, fields) => {
+                for (_, value) in fields {
+                    self.synth(value);
+                }
+                Type::Record
+            }
+            Node::IndexNode(collection, _, index) => self.index(collection, index),
+            Node::FieldNode(record, field) => {
+                let type_ = self.synth(record);
+                if !Type::Record.accepts(&type_) {
+                    let message = format!("{} has no field {}", type_, name(field));
+                    self.error(node, self.explained(message, &[record]));
+                }
+                Type::Any
+            }
+            Node::FuncDefNode(token, params, returns, body) => {
+                self.function(token, params, returns.as_ref(), body)
+            }
+            Node::IfNode(_, cases, else_case) => {
+                let mut types = Vec::new();
+                for (condition, body) in cases {
+                    self.condition(condition);
+                    types.push(self.synth(body));
+                }
+                match else_case {
+                    Some(body) => types.push(self.synth(body)),
+                    None => types.push(Type::Nil),
+                }
+                match types.iter().all(|type_| *type_ == types[0]) {
+                    true => types.swap_remove(0),
+                    false => Type::Any,
+                }
+            }
+            Node::WhileNode(_, condition, body) => {
+                self.condition(condition);
+                self.synth(body);
+                Type::Nil
+            }
+            Node::ReturnNode(_, value) => {
+                let type_ = match value {
+                    Some(value) => self.synth(value),
+                    None => Type::Nil,
+                };
+                self.returned(node, value.as_deref(), type_);
+                Type::Any
+            }
+            Node::StatementsNode(nodes) => {
+                let mut type_ = Type::Nil;
+                for node in nodes {
+                    type_ = self.synth(node);
+                }
+                type_
+            }
+        }
+    }
+
+    fn assign(&mut self, token: &Token, annotation: Option<&Token>, value: &Node) -> Type {
+        let type_ = self.synth(value);
+        let name = name(token);
+        let previous = self
+            .scopes
+            .last()
+            .and_then(|scope| scope.get(&name))
+            .cloned();
+        let binding = match (annotation, previous) {
+            (Some(annotation), _) => {
+                let declared = self.annotation(annotation);
+                self.expect_declared(&name, &declared, value, &type_);
+                self.binding(declared, true, None)
+            }
+            // Setting a variable again keeps the type it was declared with
+            (None, Some(previous)) if previous.declared => {
+                self.expect_declared(&name, &previous.type_, value, &type_);
+                previous
+            }
+            (None, Some(previous)) => match (&previous.type_, &type_) {
+                (Type::Any, _) => previous,
+                (_, Type::Any) | (_, Type::Nil) => self.binding(Type::Any, false, None),
+                (inferred, type_) if inferred.accepts(type_) => previous,
+                (inferred, type_) => {
+                    let message = format!(
+                        "{} is {}{}, not {}",
+                        name,
+                        inferred,
+                        from(previous.origin.as_ref()),
+                        type_
+                    );
+                    self.error(value, self.explained(message, &[value]));
+                    previous
+                }
+            },
+            (None, _) => self.inferred(type_.clone(), value.pos_start()),
+        };
+        self.define(name, binding);
+        type_
+    }
+
+    fn expect_declared(&mut self, name: &str, declared: &Type, value: &Node, type_: &Type) {
+        if !declared.accepts(type_) {
+            let message = format!("{} is declared {}, not {}", name, declared, type_);
+            self.error(value, self.explained(message, &[value]));
+        }
+    }
+
+    // The binding for a variable that was not annotated. Only values of known types,
+    // other than 'tyhjä', give it a type.
+    fn inferred(&self, type_: Type, origin: Option<Position>) -> Binding {
+        match type_ {
+            Type::Any | Type::Nil => self.binding(Type::Any, false, None),
+            type_ => self.binding(type_, false, origin),
+        }
+    }
+
+    fn binding(&self, type_: Type, declared: bool, origin: Option<Position>) -> Binding {
+        Binding {
+            type_,
+            declared,
+            origin,
+        }
+    }
+
+    // Checks a returned value against the annotated return type of the function,
+    // or against the other values it returns
+    fn returned(&mut self, node: &Node, value: Option<&Node>, type_: Type) {
+        let returns = match self.returns.last() {
+            Some(returns) => returns.clone(),
+            None => return,
+        };
+        let values: Vec<&Node> = value.into_iter().collect();
+        match &returns.declared {
+            Some(declared) if !declared.accepts(&type_) => {
+                let message = format!("{} returns {}, not {}", returns.function, declared, type_);
+                self.error(node, self.explained(message, &values));
+            }
+            Some(_) => (),
+            None => {
+                let earlier = returns.found.iter().find(|(found, _)| {
+                    !matches!(found, Type::Any | Type::Nil)
+                        && !matches!(type_, Type::Any | Type::Nil)
+                        && !found.accepts(&type_)
+                });
+                if let Some((found, origin)) = earlier {
+                    let message = format!(
+                        "{} returns {}{}, not {}",
+                        returns.function,
+                        found,
+                        from(origin.as_ref()),
+                        type_
+                    );
+                    self.error(node, self.explained(message, &values));
+                }
+                let position = node.pos_start();
+                if let Some(returns) = self.returns.last_mut() {
+                    returns.found.push((type_, position));
+                }
+            }
+        }
+    }
+
+    fn function(
+        &mut self,
+        token: &Token,
+        params: &[(Token, Option<Token>)],
+        returns: Option<&Token>,
+        body: &Node,
+    ) -> Type {
+        let params: Vec<(String, Type)> = params
+            .iter()
+            .map(|(param, annotation)| {
+                let type_ = match annotation {
+                    Some(annotation) => self.annotation(annotation),
+                    None => Type::Any,
+                };
+                (name(param), type_)
+            })
+            .collect();
+        let declared = returns.map(|annotation| self.annotation(annotation));
+        let function = name(token);
+        // Defined first, so the function can call itself. Until its body is checked,
+        // it is not known what it returns.
+        let signature = Signature {
+            params: params.clone(),
+            returns: declared.clone().unwrap_or(Type::Any),
+        };
+        let binding = self.binding(Type::Function(Some(Box::new(signature))), false, None);
+        self.define(function.clone(), binding);
+
+        let mut scope = HashMap::new();
+        for (param, param_type) in &params {
+            let declared = *param_type != Type::Any;
+            let binding = self.binding(param_type.clone(), declared, None);
+            scope.insert(param.clone(), binding);
+        }
+        self.scopes.push(scope);
+        self.returns.push(Returns {
+            function: function.clone(),
+            declared: declared.clone(),
+            found: Vec::new(),
+        });
+        self.synth(body);
+        let mut returns = self.returns.pop().expect("The function was pushed");
+        self.scopes.pop();
+
+        // Functions that end without 'palata' return 'tyhjä'
+        let ends = !always_returns(body);
+        let (returns, origin) = match declared {
+            Some(declared) => {
+                if ends && !Type::Nil.accepts(&declared) {
+                    self.token_error(
+                        token,
+                        format!(
+                            "{} returns {}, but can end without palata and return tyhjä",
+                            function, declared
+                        ),
+                    );
+                }
+                (declared, None)
+            }
+            None => {
+                if ends {
+                    returns.found.push((Type::Nil, None));
+                }
+                let (first, origin) = returns.found.swap_remove(0);
+                match returns.found.iter().all(|(type_, _)| *type_ == first) {
+                    true => (first, origin),
+                    false => (Type::Any, None),
+                }
+            }
+        };
+        let type_ = Type::Function(Some(Box::new(Signature { params, returns })));
+        let binding = self.binding(type_.clone(), false, origin);
+        self.define(function, binding);
+        type_
+    }
+
+    fn call(&mut self, node: &Node, token: &Token, args: &[Node]) -> Type {
+        let arg_types: Vec<Type> = args.iter().map(|arg| self.synth(arg)).collect();
+        let function = name(token);
+        let signature = match self.lookup(&function).map(|binding| binding.type_.clone()) {
+            Some(Type::Function(Some(signature))) => *signature,
+            Some(Type::Function(None)) | Some(Type::Any) | None => return Type::Any,
+            Some(type_) => {
+                self.token_error(
+                    token,
+                    format!("{} is a {}, not a function", function, type_),
+                );
+                return Type::Any;
+            }
+        };
+
+        if signature.params.len() != args.len() {
+            self.error(
+                node,
+                format!(
+                    "{} expects {} arguments, got {}",
+                    function,
+                    signature.params.len(),
+                    args.len()
+                ),
+            );
+        } else {
+            for ((param, expected), (arg, type_)) in
+                signature.params.iter().zip(args.iter().zip(arg_types))
+            {
+                if !expected.accepts(&type_) {
+                    let message = format!(
+                        "{} expects {} to be {}, not {}",
+                        function, param, expected, type_
+                    );
+                    self.error(arg, self.explained(message, &[arg]));
+                }
+            }
+        }
+        signature.returns
+    }
+
+    // Chains like 1 + 2 - 3 are as long as the input, so they are checked in a loop
+    fn binops(&mut self, node: &Node) -> Type {
+        let operations = node.binop_chain();
+        let mut type_ = self.synth(operations[0].0);
+        for (left, optok, right) in operations {
+            type_ = self.binop(left, type_, optok, right);
+        }
+        type_
+    }
+
+    fn binop(&mut self, left: &Node, left_type: Type, optok: &Token, right: &Node) -> Type {
+        let right_type = self.synth(right);
+        let operator = optok.type_();
+        if let TokenType::Keyword(_) = operator {
+            self.expect_condition(left, &left_type);
+            self.expect_condition(right, &right_type);
+            return Type::Boolean;
+        }
+        if left_type == Type::Any || right_type == Type::Any {
+            return match is_comparison(&operator) {
+                true => Type::Boolean,
+                false => Type::Any,
+            };
+        }
+
+        let result = match operator {
+            TokenType::EqualEqual | TokenType::NotEqual => {
+                match left_type.is_number() && right_type.is_number() {
+                    true => promote(&left_type, &right_type).map(|_| Type::Boolean),
+                    false => Some(Type::Boolean),
+                }
+            }
+            TokenType::LessThan
+            | TokenType::GreaterThan
+            | TokenType::LessThanEqual
+            | TokenType::GreaterThanEqual => match (&left_type, &right_type) {
+                (Type::Text, Type::Text) => Some(Type::Boolean),
+                (Type::Complex, _) | (_, Type::Complex) => None,
+                (left, right) => promote(left, right).map(|_| Type::Boolean),
+            },
+            TokenType::Pow => power(&left_type, &right_type, right),
+            TokenType::Plus => match (&left_type, &right_type) {
+                (Type::Text, Type::Text) => Some(Type::Text),
+                (Type::List, Type::List) => Some(Type::List),
+                (left, right) => promote(left, right),
+            },
+            _ => promote(&left_type, &right_type),
+        };
+        match result {
+            Some(type_) => type_,
+            None => {
+                let message = match is_comparison(&operator) {
+                    true => format!("Cant compare {} with {}", left_type, right_type),
+                    false => format!(
+                        "Cant use {} with {} and {}",
+                        operator.symbol(),
+                        left_type,
+                        right_type
+                    ),
+                };
+                self.token_error(optok, self.explained(message, &[left, right]));
+                Type::Any
+            }
+        }
+    }
+
+    fn unary(&mut self, optok: &Token, node: &Node) -> Type {
+        let type_ = self.synth(node);
+        if optok.type_() == TokenType::Keyword("ei".to_string()) {
+            self.expect_condition(node, &type_);
+            return Type::Boolean;
+        }
+        if type_ != Type::Any && !type_.is_number() {
+            let message = format!("Cant use {} with {}", optok.type_().symbol(), type_);
+            self.token_error(optok, self.explained(message, &[node]));
+            return Type::Any;
+        }
+        type_
+    }
+
+    fn index(&mut self, collection: &Node, index: &Node) -> Type {
+        let collection_type = self.synth(collection);
+        let index_type = self.synth(index);
+        let allowed = match &collection_type {
+            Type::List | Type::Text => Type::Integer.accepts(&index_type),
+            Type::Record => Type::Text.accepts(&index_type),
+            Type::Any => true,
+            _ => false,
+        };
+        if !allowed {
+            let message = format!("Cant index {} with {}", collection_type, index_type);
+            self.error(index, self.explained(message, &[collection, index]));
+        }
+        match collection_type {
+            Type::Text => Type::Text,
+            _ => Type::Any,
+        }
+    }
+
+    fn condition(&mut self, node: &Node) {
+        let type_ = self.synth(node);
+        self.expect_condition(node, &type_);
+    }
+
+    fn expect_condition(&mut self, node: &Node, type_: &Type) {
+        if !Type::Boolean.accepts(type_) {
+            let message = format!("Condition must be totuus, found {}", type_);
+            self.error(node, self.explained(message, &[node]));
+        }
+    }
+
+    fn annotation(&mut self, token: &Token) -> Type {
+        let name = match token.type_() {
+            TokenType::Identifier(name) | TokenType::Keyword(name) => name,
+            _ => String::new(),
+        };
+        match Type::from_name(&name) {
+            Some(type_) => type_,
+            None => {
+                self.token_error(token, format!("Unknown type {}", name));
+                Type::Any
+            }
+        }
+    }
+
+    // Adds where the inferred types of variables and function results among the nodes came from
+    fn explained(&self, message: String, nodes: &[&Node]) -> String {
+        let mut message = message;
+        for node in nodes {
+            let (token, what) = match node {
+                Node::VarAccessNode(token) => (token, "is"),
+                Node::CallNode(token, _) => (token, "returns"),
+                _ => continue,
+            };
+            let binding = match self.lookup(&name(token)) {
+                Some(binding) if binding.origin.is_some() => binding,
+                _ => continue,
+            };
+            let type_ = match (&binding.type_, what) {
+                (Type::Function(Some(signature)), "returns") => signature.returns.clone(),
+                (type_, _) => type_.clone(),
+            };
+            message.push_str(&format!(
+                ". {} {} {}{}",
+                name(token),
+                what,
+                type_,
+                from(binding.origin.as_ref())
+            ));
+        }
+        message
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn define(&mut self, name: String, binding: Binding) {
+        self.scopes
+            .last_mut()
+            .expect("There is a global scope")
+            .insert(name, binding);
+    }
+
+    fn error(&mut self, node: &Node, message: String) {
+        self.errors.push(ErrorType::TypeError(TypeError::new(
+            node.pos_start(),
+            node.pos_end(),
+            message,
+        )));
+    }
+
+    fn token_error(&mut self, token: &Token, message: String) {
+        self.errors.push(ErrorType::TypeError(TypeError::new(
+            token.position_start(),
+            token.position_end(),
+            message,
+        )));
+    }
+}
+
+// Where an inferred type came from, for messages
+fn from(origin: Option<&Position>) -> String {
+    match origin {
+        Some(pos) => format!(" from line {}, col {}", pos.line() + 1, pos.column()),
+        None => String::new(),
+    }
+}
+
+fn name(token: &Token) -> String {
+    match token.type_() {
+        TokenType::Identifier(name) => name,
+        other => format!("{:?}", other),
+    }
+}
+
+fn is_comparison(operator: &TokenType) -> bool {
+    matches!(
+        operator,
+        TokenType::EqualEqual
+            | TokenType::NotEqual
+            | TokenType::LessThan
+            | TokenType::GreaterThan
+            | TokenType::LessThanEqual
+            | TokenType::GreaterThanEqual
+    )
+}
+
+// The type both sides of arithmetic are turned into, like `NumberType::promote` does
+fn promote(left: &Type, right: &Type) -> Option<Type> {
+    if !left.is_number() || !right.is_number() {
+        return None;
+    }
+    let exact = |type_: &Type| matches!(type_, Type::Integer | Type::BigInteger | Type::Fraction);
+    match (left, right) {
+        (left, right) if left == right => Some(left.clone()),
+        (Type::Complex, _) | (_, Type::Complex) => Some(Type::Complex),
+        (Type::Number, _) | (_, Type::Number) => Some(Type::Number),
+        (Type::Fraction, other) | (other, Type::Fraction) if exact(other) => Some(Type::Fraction),
+        (Type::BigInteger, Type::Integer) | (Type::Integer, Type::BigInteger) => {
+            Some(Type::BigInteger)
+        }
+        _ => None,
+    }
+}
+
+// See `operators::power`. Exact numbers to whole powers keep their type, unless the power is
+// negative, which gives a `murto`. Only literals are known not to be negative.
+fn power(left: &Type, right: &Type, exponent: &Node) -> Option<Type> {
+    if !left.is_number() || !right.is_number() {
+        return None;
+    }
+    let natural = match exponent {
+        Node::Value(token) => matches!(token.type_(), TokenType::Int(_) | TokenType::BigInt(_)),
+        _ => false,
+    };
+    match (left, right) {
+        (Type::Complex, _) | (_, Type::Complex) => Some(Type::Complex),
+        (Type::Float, _) | (_, Type::Float) => Some(Type::Float),
+        (Type::Fraction, Type::Integer | Type::BigInteger) => Some(Type::Fraction),
+        (Type::Integer, Type::Integer) if natural => Some(Type::Integer),
+        (Type::Integer | Type::BigInteger, Type::Integer | Type::BigInteger) if natural => {
+            Some(Type::BigInteger)
+        }
+        // Negative powers, and powers that are a `murto`, which may not be whole
+        _ => Some(Type::Number),
+    }
+}
+
+// Whether running the node always ends in 'palata'
+fn always_returns(node: &Node) -> bool {
+    match node {
+        Node::ReturnNode(..) => true,
+        Node::StatementsNode(nodes) => nodes.iter().any(always_returns),
+        Node::IfNode(_, cases, Some(else_case)) => {
+            cases.iter().all(|(_, body)| always_returns(body)) && always_returns(else_case)
+        }
+        // Only 'palata' leaves 'kun tosi'
+        Node::WhileNode(_, condition, _) => {
+            matches!(&**condition, Node::Value(token) if token.type_() == TokenType::Keyword("tosi".to_string()))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn check(text: &str) -> Vec<String> {
+        let tokens = Lexer::new("test".to_string(), text.to_string())
+            .tokenize()
+            .unwrap();
+        let root = Parser::new(tokens).parse().unwrap();
+        match Checker::new().check(&root) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.as_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_well_typed() {
+        for text in [
+            "muut a: kok = 1; muut b: liu = 2.5; muut c: teksti = \"c\"",
+            "muut a: kok = 1; muut a = a + 2",
+            "muut x = [\"a\"][0]; muut y: kok = x",
+            "muut x = 1; muut x = tyhjä; muut x = \"a\"",
+            "tominto fib(n: kok): kok { jos n < 2 { palata n }; palata fib(n - 1) + fib(n - 2) }; muut f: kok = fib(10)",
+            "tominto f(n: kok, s: teksti): teksti { jos n > 0 { palata s } muuten { palata \"\" } }",
+            "tominto f(): kok { kun tosi { palata 1 } }",
+            "tominto f(x): tyhjä { tulosta(x) }; f(1)",
+            "muut l: lista = [1] + [2]; muut t: totuus = 1 < 2 ja ei epätosi",
+            "muut a: iso = 2n + 1; muut m: murto = murto(1, 2) + 1",
+            "muut a: kok = 2 ^ 10; muut b: iso = 2n ^ 3; muut c: murto = murto(1, 2) ^ -2",
+            "muut g = tominto h(x) { palata x }; g(1) + g(\"a\")",
+        ] {
+            assert_eq!(check(text), Vec::<String>::new(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let cases = [
+            ("muut a: kok = \"a\"", "a is declared kok, not teksti"),
+            (
+                "muut a: kok = 1; muut a = 2.5",
+                "a is declared kok, not liu",
+            ),
+            ("muut a: numero = 1", "Unknown type numero"),
+            (
+                "tominto f(n: kok): kok { palata n }; f(\"a\")",
+                "f expects n to be kok, not teksti",
+            ),
+            (
+                "tominto f(n: kok) { palata n }; f(1, 2)",
+                "f expects 1 arguments, got 2",
+            ),
+            (
+                "tominto f(s: teksti): kok { palata s }",
+                "f returns kok, not teksti",
+            ),
+            (
+                "tominto f(n: kok): kok { jos n > 0 { palata n } }",
+                "can end without palata",
+            ),
+            (
+                "tominto f(n: kok): teksti { palata \"\" }; muut x: kok = f(1)",
+                "x is declared kok, not teksti",
+            ),
+            ("muut a: kok = 1; a + 1.5", "Cant use + with kok and liu"),
+            (
+                "muut a: teksti = \"a\"; jos a { 1 }",
+                "Condition must be totuus, found teksti",
+            ),
+            ("muut a: kok = 1; a()", "a is a kok, not a function"),
+            ("muut a: kok = 1; a[0]", "Cant index kok with kok"),
+            ("muut a: kok = 1; a.x", "kok has no field x"),
+            ("1 < 2i", "Cant compare kok with kompleksi"),
+            ("muut w: kok = 2 ^ -1", "w is declared kok, not luku"),
+            (
+                "tominto f(a: kok): kok { palata a ^ -1 }",
+                "f returns kok, not luku",
+            ),
+        ];
+        for (text, expected) in cases {
+            let errors = check(text);
+            assert_eq!(errors.len(), 1, "{}: {:?}", text, errors);
+            assert!(errors[0].contains(expected), "{}: {}", text, errors[0]);
+            assert!(errors[0].starts_with("Type Error"), "{}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_inferred_errors() {
+        let cases = [
+            (
+                "muut x = 5; muut x = 1; x + \"a\"",
+                "Cant use + with kok and teksti. x is kok from line 1, col 9",
+            ),
+            (
+                "muut x = 5\nmuut x = \"viisi\"",
+                "x is kok from line 1, col 9, not teksti",
+            ),
+            (
+                "tominto f(n) { jos n { palata 1 }; palata \"a\" }",
+                "f returns kok from line 1, col 23, not teksti",
+            ),
+            (
+                "tominto f() { palata 1.5 }; muut x: kok = f()",
+                "x is declared kok, not liu. f returns liu from line 1, col 14",
+            ),
+            (
+                "tominto f() { tulosta(1) }; jos f() { 1 }",
+                "Condition must be totuus, found tyhjä",
+            ),
+            (
+                "muut l = [1]; l.a",
+                "lista has no field a. l is lista from line 1, col 9",
+            ),
+        ];
+        for (text, message) in cases {
+            let errors = check(text);
+            assert_eq!(errors.len(), 1, "{}: {:?}", text, errors);
+            assert!(errors[0].contains(message), "{}: {}", text, errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_inferred_types() {
+        let mut checker = Checker::new();
+        let mut type_of = |text: &str| {
+            let tokens = Lexer::new("test".to_string(), text.to_string())
+                .tokenize()
+                .unwrap();
+            let root = Parser::new(tokens).parse().unwrap();
+            checker.check(&root).unwrap().to_string()
+        };
+        assert_eq!(type_of("muut x = 2 * 3"), "kok");
+        assert_eq!(type_of("x < 2"), "totuus");
+        assert_eq!(
+            type_of("tominto f(a, b: liu) { palata b * 2.0 }"),
+            "tominto(a, b: liu): liu"
+        );
+        assert_eq!(type_of("tominto g() { tulosta(1) }"), "tominto(): tyhjä");
+        assert_eq!(type_of("tominto h(n) { jos n { palata 1 } }"), "tominto(n)");
+        assert_eq!(type_of("f(1, 2.0) + 1.0"), "liu");
+        assert_eq!(type_of("muut y = x; y"), "kok");
+        assert_eq!(type_of("x ^ y + 1"), "luku");
+    }
+
+    #[test]
+    fn test_all_errors_with_spans() {
+        let text = "muut a: kok = \"a\"\ntominto f(n: kok): kok { palata 1.5 }\nf(tosi)";
+        let errors = check(text);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].contains("line 1, col 14"), "{}", errors[0]);
+        assert!(errors[1].contains("line 2, col 25"), "{}", errors[1]);
+        assert!(errors[2].contains("line 3, col 2"), "{}", errors[2]);
+    }
+
+    #[test]
+    fn test_globals_are_remembered() {
+        let parse = |text: &str| {
+            let tokens = Lexer::new("test".to_string(), text.to_string())
+                .tokenize()
+                .unwrap();
+            Parser::new(tokens).parse().unwrap()
+        };
+        let mut checker = Checker::new();
+        checker
+            .check(&parse("tominto f(n: kok): kok { palata n }"))
+            .unwrap();
+        assert!(checker.check(&parse("f(\"a\")")).is_err());
+
+        // Inferred types hold in the programs after too
+        checker.check(&parse("muut x = 5")).unwrap();
+        assert!(checker.check(&parse("muut x = \"a\"")).is_err());
+        checker.check(&parse("muut x = 6")).unwrap();
+
+        // Nothing from a rejected program is kept
+        assert!(checker
+            .check(&parse("muut b: teksti = 1; muut f = 1"))
+            .is_err());
+        assert_eq!(checker.global("b"), None);
+        assert_eq!(
+            checker.global("f").unwrap().to_string(),
+            "tominto(n: kok): kok"
+        );
+    }
+}
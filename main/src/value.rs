@@ -0,0 +1,247 @@
+use crate::context::Context;
+use crate::native::Native;
+use crate::number::{Number, NumberType};
+use crate::parser::Node;
+use crate::position::Position;
+use crate::symbols::{SharedSymbolMap, SymbolMap};
+use crate::token::{Token, TokenType};
+use num::complex::Complex64;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+/// Everything a program can compute, store in a variable or pass to a function
+// Numbers carry their positions and context, which makes them larger than the rest
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(NumberType),
+    Boolean(bool),
+    Text(String),
+    Function(Function),
+    Native(Native),
+    List(Vec<Value>),
+    Record(BTreeMap<String, Value>),
+    Nil,
+}
+
+impl Value {
+    /// The name of the type as written in programs
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(NumberType::Integer(_)) => "kok",
+            Value::Number(NumberType::Float(_)) => "liu",
+            Value::Number(NumberType::BigInteger(_)) => "iso",
+            Value::Number(NumberType::Fraction(_)) => "murto",
+            Value::Number(NumberType::Complex(_)) => "kompleksi",
+            Value::Boolean(_) => "totuus",
+            Value::Text(_) => "teksti",
+            Value::Function(_) | Value::Native(_) => "tominto",
+            Value::List(_) => "lista",
+            Value::Record(_) => "tietue",
+            Value::Nil => "tyhjä",
+        }
+    }
+
+    /// The value a literal token stands for, at the position of the token
+    pub fn literal(token: &Token) -> Option<Value> {
+        let (pos_start, pos_end) = (token.position_start(), token.position_end());
+        let number = match token.type_() {
+            TokenType::Int(val) => NumberType::Integer(Number::new(val, pos_start, pos_end, None)),
+            TokenType::Float(val) => NumberType::Float(Number::new(val, pos_start, pos_end, None)),
+            TokenType::Imaginary(val) => NumberType::Complex(Number::new(
+                Complex64::new(0.0, val),
+                pos_start,
+                pos_end,
+                None,
+            )),
+            TokenType::BigInt(val) => {
+                NumberType::BigInteger(Number::new(val, pos_start, pos_end, None))
+            }
+            TokenType::String(text) => return Some(Value::Text(text)),
+            TokenType::Keyword(keyword) => {
+                return match keyword.as_str() {
+                    "tosi" => Some(Value::Boolean(true)),
+                    "epätosi" => Some(Value::Boolean(false)),
+                    "tyhjä" => Some(Value::Nil),
+                    _ => None,
+                }
+            }
+            _ => return None,
+        };
+        Some(Value::Number(number))
+    }
+
+    /// Only numbers remember where they were computed
+    pub fn set_pos(&mut self, pos_start: Option<Position>, pos_end: Option<Position>) {
+        if let Value::Number(num) = self {
+            num.set_pos(pos_start, pos_end)
+        }
+    }
+
+    pub fn set_context(&mut self, context: Context) {
+        if let Value::Number(num) = self {
+            num.set_context(context)
+        }
+    }
+
+    /// Structural equality. Numbers are equal if they have the same value after promotion.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(num1), Value::Number(num2)) => {
+                matches!(NumberType::compare(num1, num2), Some(Some(Ordering::Equal)))
+            }
+            (Value::Boolean(b1), Value::Boolean(b2)) => b1 == b2,
+            (Value::Text(t1), Value::Text(t2)) => t1 == t2,
+            (Value::Function(f1), Value::Function(f2)) => f1 == f2,
+            (Value::Native(f1), Value::Native(f2)) => f1 == f2,
+            (Value::List(l1), Value::List(l2)) => {
+                l1.len() == l2.len() && l1.iter().zip(l2).all(|(v1, v2)| v1.equals(v2))
+            }
+            (Value::Record(r1), Value::Record(r2)) => {
+                r1.len() == r2.len()
+                    && r1
+                        .iter()
+                        .zip(r2)
+                        .all(|((k1, v1), (k2, v2))| k1 == k2 && v1.equals(v2))
+            }
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        }
+    }
+
+    /// Texts are quoted when shown inside lists, records and tracebacks
+    pub fn representation(&self) -> String {
+        match self {
+            Value::Text(text) => format!("{:?}", text),
+            value => value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(num) => write!(f, "{}", num),
+            Value::Boolean(true) => write!(f, "tosi"),
+            Value::Boolean(false) => write!(f, "epätosi"),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Function(function) => write!(f, "<tominto {}>", function.name()),
+            Value::Native(function) => write!(f, "<tominto {}>", function.name()),
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.representation()).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Value::Record(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, v)| format!("{}: {}", name, v.representation()))
+                    .collect();
+                write!(f, "{{{}}}", fields.join(", "))
+            }
+            Value::Nil => write!(f, "tyhjä"),
+        }
+    }
+}
+
+/// A function defined with `tominto`.
+/// It keeps the symbol map it was defined in, so it can see the variables around it.
+pub struct Function {
+    name: String,
+    params: Vec<Token>,
+    body: Rc<Node>,
+    closure: Closure,
+}
+
+// The copy of a function stored in the map it was defined in only holds a weak reference,
+// so the map and the function dont keep each other alive
+enum Closure {
+    Strong(SharedSymbolMap<Value>),
+    Weak(Weak<RefCell<SymbolMap<Value>>>),
+}
+
+impl Function {
+    pub fn new(
+        name: String,
+        params: Vec<Token>,
+        body: Rc<Node>,
+        closure: SharedSymbolMap<Value>,
+    ) -> Self {
+        Self {
+            name,
+            params,
+            body,
+            closure: Closure::Strong(closure),
+        }
+    }
+
+    /// The copy to store in the symbol map the function closes over
+    pub fn stored(&self) -> Self {
+        let closure = match &self.closure {
+            Closure::Strong(closure) => Closure::Weak(Rc::downgrade(closure)),
+            Closure::Weak(closure) => Closure::Weak(closure.clone()),
+        };
+        Self {
+            name: self.name.clone(),
+            params: self.params.clone(),
+            body: self.body.clone(),
+            closure,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn params(&self) -> Vec<Token> {
+        self.params.clone()
+    }
+
+    pub fn body(&self) -> Rc<Node> {
+        self.body.clone()
+    }
+
+    pub fn closure(&self) -> SharedSymbolMap<Value> {
+        match &self.closure {
+            Closure::Strong(closure) => closure.clone(),
+            Closure::Weak(closure) => closure
+                .upgrade()
+                .expect("a stored function outlived its symbol map"),
+        }
+    }
+
+    fn closure_ptr(&self) -> *const RefCell<SymbolMap<Value>> {
+        match &self.closure {
+            Closure::Strong(closure) => Rc::as_ptr(closure),
+            Closure::Weak(closure) => closure.as_ptr(),
+        }
+    }
+}
+
+// A stored copy can only be read through the map it points to, so the map is alive
+// and copies taken out of it hold it strongly again
+impl Clone for Function {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            params: self.params.clone(),
+            body: self.body.clone(),
+            closure: Closure::Strong(self.closure()),
+        }
+    }
+}
+
+// The closure usually contains the function itself, so neither of these may look inside it
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function({})", self.name)
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.body, &other.body) && self.closure_ptr() == other.closure_ptr()
+    }
+}
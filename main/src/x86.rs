@@ -0,0 +1,1225 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// Assembles the x86-64 assembly of the assembly backend and its runtime into machine code,
+// so programs can be built without an external assembler. The source is in Intel syntax
+// without prefixes, as the GNU assembler reads it, and only the instructions and
+// directives the backend and fin_runtime.s use are known.
+//
+// Jumps and calls always use 32-bit offsets, so every instruction has its size as soon as
+// it is read. Offsets within a section are filled in at the end, and the rest are left as
+// relocations for the ELF writer.
+
+/// Indexes of the sections of an object
+pub const TEXT: usize = 0;
+pub const RODATA: usize = 1;
+pub const DATA: usize = 2;
+pub const BSS: usize = 3;
+
+/// The names of the sections of an object, by index
+pub const SECTION_NAMES: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+/// Machine code and data assembled from one source file
+#[derive(Debug)]
+pub struct Object {
+    /// The text, read only data, data and bss sections, in that order
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug)]
+pub struct Section {
+    /// The bytes of the section, empty for the bss section
+    pub data: Vec<u8>,
+    pub size: u64,
+    pub align: u64,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    /// The section the symbol is defined in, or `None` when another object defines it
+    pub section: Option<usize>,
+    pub offset: u64,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    /// The 32-bit offset from the place to the symbol
+    Pc32,
+    /// The 64-bit address of the symbol
+    Abs64,
+}
+
+/// A place to fill in with the address of a symbol, once it is known
+#[derive(Debug)]
+pub struct Relocation {
+    pub section: usize,
+    pub offset: u64,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+/// Whether a symbol is only known in the file that defines it, like the GNU assembler's
+/// labels that start with `.L`
+pub fn is_local_label(name: &str) -> bool {
+    name.starts_with(".L")
+}
+
+/// Assembles a source file. Errors name the line that could not be assembled.
+pub fn assemble(source: &str) -> Result<Object, String> {
+    let mut assembler = Assembler::new();
+    for (number, line) in source.lines().enumerate() {
+        assembler
+            .line(line)
+            .map_err(|e| format!("line {}: {}: {}", number + 1, e, line.trim()))?;
+    }
+    assembler.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegisterKind {
+    General,
+    Xmm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Register {
+    number: u8,
+    // Bytes of general purpose registers, 8 for xmm registers
+    size: u8,
+    kind: RegisterKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Memory {
+    size: Option<u8>,
+    base: Option<u8>,
+    // The index register and its scale
+    index: Option<(u8, u8)>,
+    displacement: i64,
+    // A symbol the address is relative to, through rip
+    symbol: Option<String>,
+    rip: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Memory(Memory),
+    Symbol(String),
+}
+
+impl Operand {
+    fn size(&self) -> Option<u8> {
+        match self {
+            Operand::Register(register) => Some(register.size),
+            Operand::Memory(memory) => memory.size,
+            _ => None,
+        }
+    }
+
+    fn is_xmm(&self) -> bool {
+        matches!(self, Operand::Register(register) if register.kind == RegisterKind::Xmm)
+    }
+
+    fn is_general(&self) -> bool {
+        matches!(self, Operand::Register(register) if register.kind == RegisterKind::General)
+    }
+}
+
+const REGISTERS_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REGISTERS_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGISTERS_8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+fn register(name: &str) -> Option<Register> {
+    let general = |number: usize, size| Register {
+        number: number as u8,
+        size,
+        kind: RegisterKind::General,
+    };
+    if let Some(number) = REGISTERS_64.iter().position(|r| *r == name) {
+        return Some(general(number, 8));
+    }
+    if let Some(number) = REGISTERS_32.iter().position(|r| *r == name) {
+        return Some(general(number, 4));
+    }
+    if let Some(number) = REGISTERS_8.iter().position(|r| *r == name) {
+        return Some(general(number, 1));
+    }
+    let number: u8 = name.strip_prefix("xmm")?.parse().ok()?;
+    match number < 16 {
+        true => Some(Register {
+            number,
+            size: 8,
+            kind: RegisterKind::Xmm,
+        }),
+        false => None,
+    }
+}
+
+// The condition codes of jcc and setcc, by their suffix
+fn condition(suffix: &str) -> Option<u8> {
+    let code = match suffix {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" | "pe" => 10,
+        "np" | "po" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None,
+    };
+    Some(code)
+}
+
+// The /digit of the arithmetic instructions that share their encodings
+fn arithmetic(mnemonic: &str) -> Option<u8> {
+    let digit = match mnemonic {
+        "add" => 0,
+        "or" => 1,
+        "adc" => 2,
+        "sbb" => 3,
+        "and" => 4,
+        "sub" => 5,
+        "xor" => 6,
+        "cmp" => 7,
+        _ => return None,
+    };
+    Some(digit)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+    match negative {
+        true if magnitude <= 1 << 63 => Some((magnitude as i64).wrapping_neg()),
+        true => None,
+        false => Some(magnitude as i64),
+    }
+}
+
+fn is_symbol(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+fn operand(text: &str) -> Result<Operand, String> {
+    let text = text.trim();
+    if let Some(open) = text.find('[') {
+        let size = match text[..open].trim() {
+            "" => None,
+            "byte ptr" => Some(1),
+            "dword ptr" => Some(4),
+            "qword ptr" => Some(8),
+            other => return Err(format!("unknown operand size {}", other)),
+        };
+        let inside = text[open + 1..]
+            .strip_suffix(']')
+            .ok_or_else(|| format!("expected ] in {}", text))?;
+        return memory(size, inside).map(Operand::Memory);
+    }
+    if let Some(register) = register(text) {
+        return Ok(Operand::Register(register));
+    }
+    if let Some(value) = number(text) {
+        return Ok(Operand::Immediate(value));
+    }
+    match is_symbol(text) {
+        true => Ok(Operand::Symbol(text.to_string())),
+        false => Err(format!("cant read operand {}", text)),
+    }
+}
+
+// An address like `rbx + rcx*8 - 8` or `rip + name`
+fn memory(size: Option<u8>, text: &str) -> Result<Memory, String> {
+    let mut memory = Memory {
+        size,
+        base: None,
+        index: None,
+        displacement: 0,
+        symbol: None,
+        rip: false,
+    };
+    let mut sign = 1;
+    let spaced = text.replace('+', " + ").replace('-', " - ");
+    for term in spaced.split_whitespace() {
+        match term {
+            "+" => sign = 1,
+            "-" => sign = -1,
+            "rip" => memory.rip = true,
+            term => {
+                let (name, scale) = match term.split_once('*') {
+                    Some((name, scale)) => (name, Some(scale)),
+                    None => (term, None),
+                };
+                match (register(name), scale) {
+                    (Some(register), _) if register.size != 8 || sign < 0 => {
+                        return Err(format!("cant address with {}", term))
+                    }
+                    (Some(register), Some(scale)) => {
+                        let scale = match scale {
+                            "1" | "2" | "4" | "8" => scale.parse().unwrap_or(1),
+                            _ => return Err(format!("cant scale by {}", scale)),
+                        };
+                        memory.index = Some((register.number, scale));
+                    }
+                    (Some(register), None) if memory.base.is_none() => {
+                        memory.base = Some(register.number)
+                    }
+                    (Some(register), None) => memory.index = Some((register.number, 1)),
+                    (None, None) if number(term).is_some() => {
+                        memory.displacement += sign * number(term).unwrap_or(0)
+                    }
+                    (None, None) if is_symbol(term) && sign > 0 => {
+                        memory.symbol = Some(term.to_string())
+                    }
+                    _ => return Err(format!("cant read address {}", text)),
+                }
+            }
+        }
+    }
+    if memory.rip && (memory.base.is_some() || memory.index.is_some()) {
+        return Err(format!("cant address with rip and registers: {}", text));
+    }
+    if memory.symbol.is_some() && !memory.rip {
+        return Err(format!("symbols are only addressed through rip: {}", text));
+    }
+    if matches!(memory.index, Some((4, _))) {
+        return Err("rsp cant be an index".to_string());
+    }
+    if memory.base.is_none() && !memory.rip {
+        return Err(format!("addresses need a base register: {}", text));
+    }
+    Ok(memory)
+}
+
+// Splits operands at the commas between them
+fn operands(text: &str) -> Result<Vec<Operand>, String> {
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    text.split(',').map(operand).collect()
+}
+
+// The text of an .asciz directive, with the GNU assembler's escapes
+fn string_literal(text: &str) -> Result<Vec<u8>, String> {
+    let inside = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a string, got {}", text))?;
+    let bytes = inside.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            result.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let escape = *bytes.get(i + 1).ok_or("string ends with \\")?;
+        i += 2;
+        let byte = match escape {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'b' => 8,
+            b'f' => 12,
+            b'0'..=b'7' => {
+                let mut value = u32::from(escape - b'0');
+                for _ in 0..2 {
+                    match bytes.get(i) {
+                        Some(digit @ b'0'..=b'7') => {
+                            value = value * 8 + u32::from(digit - b'0');
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                value as u8
+            }
+            other => other,
+        };
+        result.push(byte);
+    }
+    Ok(result)
+}
+
+// Removes a comment, unless the '#' is in a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// Where a memory or register operand goes in the ModRM byte
+struct Encoding<'a> {
+    // A mandatory prefix of SSE instructions, before the REX prefix
+    prefix: Option<u8>,
+    wide: bool,
+    opcode: &'a [u8],
+    // The register, or the /digit extending the opcode
+    reg: u8,
+    rm: &'a Operand,
+    immediate: &'a [u8],
+}
+
+struct Assembler {
+    sections: Vec<Section>,
+    section: usize,
+    symbols: Vec<Symbol>,
+    symbol_ids: HashMap<String, usize>,
+    globals: Vec<String>,
+    relocations: Vec<Relocation>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let section = |align| Section {
+            data: Vec::new(),
+            size: 0,
+            align,
+        };
+        Assembler {
+            sections: vec![section(16), section(1), section(1), section(1)],
+            section: TEXT,
+            symbols: Vec::new(),
+            symbol_ids: HashMap::new(),
+            globals: Vec::new(),
+            relocations: Vec::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let mut line = strip_comment(line).trim();
+        // Labels, maybe followed by a statement
+        while let Some(colon) = line.find(':') {
+            let name = &line[..colon];
+            if !is_symbol(name) {
+                break;
+            }
+            self.define(name)?;
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (mnemonic, rest) = match line.split_once(char::is_whitespace) {
+            Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+            None => (line, ""),
+        };
+        match mnemonic.strip_prefix('.') {
+            Some(directive) => self.directive(directive, rest),
+            None => self.instruction(mnemonic, &operands(rest)?),
+        }
+    }
+
+    fn define(&mut self, name: &str) -> Result<(), String> {
+        let offset = self.sections[self.section].size;
+        let section = self.section;
+        let id = self.symbol_id(name);
+        let symbol = &mut self.symbols[id];
+        if symbol.section.is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        symbol.section = Some(section);
+        symbol.offset = offset;
+        Ok(())
+    }
+
+    fn symbol_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.symbol_ids.get(name) {
+            return *id;
+        }
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            section: None,
+            offset: 0,
+            global: false,
+        });
+        self.symbol_ids
+            .insert(name.to_string(), self.symbols.len() - 1);
+        self.symbols.len() - 1
+    }
+
+    fn directive(&mut self, directive: &str, rest: &str) -> Result<(), String> {
+        match directive {
+            "intel_syntax" if rest == "noprefix" => (),
+            "text" => self.section = TEXT,
+            "data" => self.section = DATA,
+            "bss" => self.section = BSS,
+            "section" => {
+                self.section = SECTION_NAMES
+                    .iter()
+                    .position(|name| *name == rest)
+                    .ok_or_else(|| format!("unknown section {}", rest))?
+            }
+            "globl" | "global" => {
+                self.symbol_id(rest);
+                self.globals.push(rest.to_string());
+            }
+            "balign" => {
+                let align = rest
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|align| align.is_power_of_two())
+                    .ok_or_else(|| format!("cant align to {}", rest))?;
+                let section = &mut self.sections[self.section];
+                section.align = section.align.max(align);
+                let padding = (align - section.size % align) % align;
+                let fill = if self.section == TEXT { 0x90 } else { 0 };
+                self.data(&vec![fill; padding as usize])?;
+            }
+            "zero" => {
+                let count = rest
+                    .parse::<usize>()
+                    .map_err(|_| format!("cant fill {} bytes", rest))?;
+                self.data(&vec![0; count])?;
+            }
+            "asciz" => {
+                let mut bytes = string_literal(rest)?;
+                bytes.push(0);
+                self.data(&bytes)?;
+            }
+            "quad" => {
+                for value in rest.split(',') {
+                    let value = value.trim();
+                    match number(value) {
+                        Some(value) => self.data(&value.to_le_bytes())?,
+                        None if is_symbol(value) => {
+                            self.relocation(RelocationKind::Abs64, value, 0);
+                            self.data(&[0; 8])?;
+                        }
+                        None => return Err(format!("cant read {}", value)),
+                    }
+                }
+            }
+            _ => return Err(format!("unknown directive .{}", directive)),
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let section = &mut self.sections[self.section];
+        if self.section == BSS {
+            match bytes.iter().all(|byte| *byte == 0) {
+                true => section.size += bytes.len() as u64,
+                false => return Err("the bss section only has zeros".to_string()),
+            }
+        } else {
+            section.data.extend_from_slice(bytes);
+            section.size = section.data.len() as u64;
+        }
+        Ok(())
+    }
+
+    // A relocation at the end of the current section
+    fn relocation(&mut self, kind: RelocationKind, symbol: &str, addend: i64) {
+        self.symbol_id(symbol);
+        self.relocations.push(Relocation {
+            section: self.section,
+            offset: self.sections[self.section].size,
+            kind,
+            symbol: symbol.to_string(),
+            addend,
+        });
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[Operand]) -> Result<(), String> {
+        if self.section == BSS {
+            return Err("the bss section only has zeros".to_string());
+        }
+        use Operand::*;
+        match (mnemonic, operands) {
+            ("ret", []) => self.data(&[0xc3]),
+            ("leave", []) => self.data(&[0xc9]),
+            ("cqo", []) => self.data(&[0x48, 0x99]),
+            ("syscall", []) => self.data(&[0x0f, 0x05]),
+            ("clc", []) => self.data(&[0xf8]),
+            ("push" | "pop", [Register(register)]) if register.size == 8 => {
+                let opcode = if mnemonic == "push" { 0x50 } else { 0x58 };
+                let mut bytes = Vec::new();
+                if register.number >= 8 {
+                    bytes.push(0x41);
+                }
+                bytes.push(opcode + (register.number & 7));
+                self.data(&bytes)
+            }
+            ("call" | "jmp", [Symbol(symbol)]) => {
+                let opcode = if mnemonic == "call" { 0xe8 } else { 0xe9 };
+                self.data(&[opcode])?;
+                self.relocation(RelocationKind::Pc32, symbol, -4);
+                self.data(&[0; 4])
+            }
+            ("call", [rm @ Register(register)]) if register.size == 8 => self.encode(Encoding {
+                prefix: None,
+                wide: false,
+                opcode: &[0xff],
+                reg: 2,
+                rm,
+                immediate: &[],
+            }),
+            (_, [Symbol(symbol)]) if mnemonic.starts_with('j') => {
+                let code = condition(&mnemonic[1..])
+                    .ok_or_else(|| format!("unknown instruction {}", mnemonic))?;
+                self.data(&[0x0f, 0x80 + code])?;
+                self.relocation(RelocationKind::Pc32, symbol, -4);
+                self.data(&[0; 4])
+            }
+            (_, [rm]) if mnemonic.starts_with("set") && condition(&mnemonic[3..]).is_some() => {
+                let code = condition(&mnemonic[3..]).unwrap_or(0);
+                self.sized(rm, 1)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: false,
+                    opcode: &[0x0f, 0x90 + code],
+                    reg: 0,
+                    rm,
+                    immediate: &[],
+                })
+            }
+            ("mov", [destination, source]) => self.mov(destination, source),
+            ("movabs", [Register(register), Immediate(value)]) if register.size == 8 => {
+                self.mov_immediate(register, *value)
+            }
+            ("lea", [Register(register), source @ Memory(_)]) if register.size == 8 => {
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: true,
+                    opcode: &[0x8d],
+                    reg: register.number,
+                    rm: source,
+                    immediate: &[],
+                })
+            }
+            ("test", [rm, source]) => self.test(rm, source),
+            ("imul", [Register(register), rm]) if register.size >= 4 => {
+                self.sized(rm, register.size)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: register.size == 8,
+                    opcode: &[0x0f, 0xaf],
+                    reg: register.number,
+                    rm,
+                    immediate: &[],
+                })
+            }
+            ("imul", [Register(register), rm, Immediate(value)]) if register.size >= 4 => {
+                self.sized(rm, register.size)?;
+                let (opcode, immediate) = match i8::try_from(*value) {
+                    Ok(value) => (0x6b, vec![value as u8]),
+                    Err(_) => (0x69, immediate32(*value)?),
+                };
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: register.size == 8,
+                    opcode: &[opcode],
+                    reg: register.number,
+                    rm,
+                    immediate: &immediate,
+                })
+            }
+            ("bsr", [Register(register), rm]) if register.size >= 4 => {
+                self.sized(rm, register.size)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: register.size == 8,
+                    opcode: &[0x0f, 0xbd],
+                    reg: register.number,
+                    rm,
+                    immediate: &[],
+                })
+            }
+            ("movzx", [Register(register), rm]) if register.size >= 4 => {
+                self.sized(rm, 1)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: register.size == 8,
+                    opcode: &[0x0f, 0xb6],
+                    reg: register.number,
+                    rm,
+                    immediate: &[],
+                })
+            }
+            ("not" | "neg" | "mul" | "div" | "idiv" | "inc" | "dec", [rm]) => {
+                let (opcode, digit) = match mnemonic {
+                    "not" => (0xf7, 2),
+                    "neg" => (0xf7, 3),
+                    "mul" => (0xf7, 4),
+                    "div" => (0xf7, 6),
+                    "idiv" => (0xf7, 7),
+                    "inc" => (0xff, 0),
+                    _ => (0xff, 1),
+                };
+                let size = self.size_of(rm, None)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[if size == 1 { opcode - 1 } else { opcode }],
+                    reg: digit,
+                    rm,
+                    immediate: &[],
+                })
+            }
+            ("shl" | "shr" | "sar", [rm, Immediate(count)]) => {
+                let digit = match mnemonic {
+                    "shl" => 4,
+                    "shr" => 5,
+                    _ => 7,
+                };
+                let count = u8::try_from(*count).map_err(|_| "cant shift that far")?;
+                let size = self.size_of(rm, None)?;
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[if size == 1 { 0xc0 } else { 0xc1 }],
+                    reg: digit,
+                    rm,
+                    immediate: &[count],
+                })
+            }
+            (_, [destination, source]) if arithmetic(mnemonic).is_some() => {
+                self.arithmetic(arithmetic(mnemonic).unwrap_or(0), destination, source)
+            }
+            (_, [destination, source]) => self.sse(mnemonic, destination, source),
+            _ => Err(format!("cant assemble {} with these operands", mnemonic)),
+        }
+    }
+
+    // The size of an operation on an operand, which another operand can tell
+    fn size_of(&self, operand: &Operand, other: Option<&Operand>) -> Result<u8, String> {
+        let size = match (operand.size(), other.and_then(Operand::size)) {
+            (Some(size), Some(other)) if size != other => {
+                return Err("operands have different sizes".to_string())
+            }
+            (Some(size), _) | (None, Some(size)) => size,
+            (None, None) => return Err("the size of the operation is unknown".to_string()),
+        };
+        match size {
+            1 | 4 | 8 => Ok(size),
+            _ => Err(format!("cant operate on {} bytes", size)),
+        }
+    }
+
+    fn sized(&self, operand: &Operand, size: u8) -> Result<(), String> {
+        match operand.size() {
+            Some(actual) if actual != size => Err(format!("expected a {} byte operand", size)),
+            _ => Ok(()),
+        }
+    }
+
+    fn arithmetic(
+        &mut self,
+        digit: u8,
+        destination: &Operand,
+        source: &Operand,
+    ) -> Result<(), String> {
+        let size = self.size_of(destination, Some(source))?;
+        let byte = size == 1;
+        match (destination, source) {
+            (rm, Operand::Register(register)) if !matches!(rm, Operand::Immediate(_)) => self
+                .encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[digit * 8 + if byte { 0 } else { 1 }],
+                    reg: register.number,
+                    rm,
+                    immediate: &[],
+                }),
+            (Operand::Register(register), rm @ Operand::Memory(_)) => self.encode(Encoding {
+                prefix: None,
+                wide: size == 8,
+                opcode: &[digit * 8 + if byte { 2 } else { 3 }],
+                reg: register.number,
+                rm,
+                immediate: &[],
+            }),
+            (rm, Operand::Immediate(value)) => {
+                let (opcode, immediate) = match (byte, i8::try_from(*value)) {
+                    (true, _) => (0x80, vec![immediate8(*value)?]),
+                    (false, Ok(value)) => (0x83, vec![value as u8]),
+                    (false, Err(_)) => (0x81, immediate32(*value)?),
+                };
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[opcode],
+                    reg: digit,
+                    rm,
+                    immediate: &immediate,
+                })
+            }
+            _ => Err("cant use these operands".to_string()),
+        }
+    }
+
+    fn mov(&mut self, destination: &Operand, source: &Operand) -> Result<(), String> {
+        if destination.is_xmm() || source.is_xmm() {
+            return Err("use movsd or movq for xmm registers".to_string());
+        }
+        let size = self.size_of(destination, Some(source))?;
+        let byte = size == 1;
+        match (destination, source) {
+            (Operand::Register(register), Operand::Immediate(value)) if size == 8 => {
+                match i32::try_from(*value) {
+                    // Sign extended from 32 bits
+                    Ok(_) => self.encode(Encoding {
+                        prefix: None,
+                        wide: true,
+                        opcode: &[0xc7],
+                        reg: 0,
+                        rm: destination,
+                        immediate: &immediate32(*value)?,
+                    }),
+                    Err(_) => self.mov_immediate(register, *value),
+                }
+            }
+            (Operand::Register(register), Operand::Immediate(value)) => {
+                let mut bytes = Vec::new();
+                if register.number >= 8 || (byte && register.number >= 4) {
+                    bytes.push(0x40 | (register.number >> 3));
+                }
+                match byte {
+                    true => {
+                        bytes.push(0xb0 + (register.number & 7));
+                        bytes.push(immediate8(*value)?);
+                    }
+                    false => {
+                        bytes.push(0xb8 + (register.number & 7));
+                        let value = u32::try_from(*value)
+                            .or_else(|_| i32::try_from(*value).map(|value| value as u32))
+                            .map_err(|_| format!("{} does not fit in 32 bits", value))?;
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                self.data(&bytes)
+            }
+            (rm @ Operand::Memory(_), Operand::Immediate(value)) => {
+                let immediate = match byte {
+                    true => vec![immediate8(*value)?],
+                    false => immediate32(*value)?,
+                };
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[if byte { 0xc6 } else { 0xc7 }],
+                    reg: 0,
+                    rm,
+                    immediate: &immediate,
+                })
+            }
+            (rm, Operand::Register(register)) => self.encode(Encoding {
+                prefix: None,
+                wide: size == 8,
+                opcode: &[if byte { 0x88 } else { 0x89 }],
+                reg: register.number,
+                rm,
+                immediate: &[],
+            }),
+            (Operand::Register(register), rm @ Operand::Memory(_)) => self.encode(Encoding {
+                prefix: None,
+                wide: size == 8,
+                opcode: &[if byte { 0x8a } else { 0x8b }],
+                reg: register.number,
+                rm,
+                immediate: &[],
+            }),
+            _ => Err("cant use these operands".to_string()),
+        }
+    }
+
+    // A full 64-bit immediate
+    fn mov_immediate(&mut self, register: &Register, value: i64) -> Result<(), String> {
+        let mut bytes = vec![0x48 | (register.number >> 3), 0xb8 + (register.number & 7)];
+        bytes.extend_from_slice(&value.to_le_bytes());
+        self.data(&bytes)
+    }
+
+    fn test(&mut self, rm: &Operand, source: &Operand) -> Result<(), String> {
+        let size = self.size_of(rm, Some(source))?;
+        let byte = size == 1;
+        match source {
+            Operand::Register(register) => self.encode(Encoding {
+                prefix: None,
+                wide: size == 8,
+                opcode: &[if byte { 0x84 } else { 0x85 }],
+                reg: register.number,
+                rm,
+                immediate: &[],
+            }),
+            Operand::Immediate(value) => {
+                let immediate = match byte {
+                    true => vec![immediate8(*value)?],
+                    false => immediate32(*value)?,
+                };
+                self.encode(Encoding {
+                    prefix: None,
+                    wide: size == 8,
+                    opcode: &[if byte { 0xf6 } else { 0xf7 }],
+                    reg: 0,
+                    rm,
+                    immediate: &immediate,
+                })
+            }
+            _ => Err("cant use these operands".to_string()),
+        }
+    }
+
+    // Instructions on floats in xmm registers
+    fn sse(
+        &mut self,
+        mnemonic: &str,
+        destination: &Operand,
+        source: &Operand,
+    ) -> Result<(), String> {
+        let unknown = || format!("cant assemble {} with these operands", mnemonic);
+        let xmm = |operand: &Operand| match operand {
+            Operand::Register(register) if register.kind == RegisterKind::Xmm => {
+                Some(register.number)
+            }
+            _ => None,
+        };
+        let float_source = source.is_xmm() || matches!(source, Operand::Memory(_));
+        let (prefix, wide, opcode, reg, rm) = match mnemonic {
+            "movsd" if xmm(destination).is_some() && float_source => {
+                (0xf2, false, 0x10, xmm(destination), source)
+            }
+            "movsd" if matches!(destination, Operand::Memory(_)) => {
+                (0xf2, false, 0x11, xmm(source), destination)
+            }
+            "addsd" | "mulsd" | "subsd" | "divsd" | "ucomisd" | "xorpd" if float_source => {
+                let (prefix, opcode) = match mnemonic {
+                    "addsd" => (0xf2, 0x58),
+                    "mulsd" => (0xf2, 0x59),
+                    "subsd" => (0xf2, 0x5c),
+                    "divsd" => (0xf2, 0x5e),
+                    "ucomisd" => (0x66, 0x2e),
+                    _ => (0x66, 0x57),
+                };
+                (prefix, false, opcode, xmm(destination), source)
+            }
+            "cvtsi2sd" if !source.is_xmm() => {
+                self.sized(source, 8)?;
+                (0xf2, true, 0x2a, xmm(destination), source)
+            }
+            "movq" if source.is_general() => (0x66, true, 0x6e, xmm(destination), source),
+            "movq" if destination.is_general() => (0x66, true, 0x7e, xmm(source), destination),
+            _ => return Err(unknown()),
+        };
+        let reg = reg.ok_or_else(unknown)?;
+        if let Operand::Memory(memory) = rm {
+            if memory.size.is_some_and(|size| size != 8) {
+                return Err("expected a qword operand".to_string());
+            }
+        }
+        self.encode(Encoding {
+            prefix: Some(prefix),
+            wide,
+            opcode: &[0x0f, opcode],
+            reg,
+            rm,
+            immediate: &[],
+        })
+    }
+
+    // Writes an instruction with a ModRM byte, and what follows it
+    fn encode(&mut self, encoding: Encoding) -> Result<(), String> {
+        let Encoding {
+            prefix,
+            wide,
+            opcode,
+            reg,
+            rm,
+            immediate,
+        } = encoding;
+        let mut bytes = Vec::new();
+        if let Some(prefix) = prefix {
+            bytes.push(prefix);
+        }
+
+        let mut rex = (wide as u8) << 3 | (reg >> 3 & 1) << 2;
+        // Spl, bpl, sil and dil need a REX prefix, or they would be ah, ch, dh and bh
+        let mut needs_rex = false;
+        let mut modrm = Vec::new();
+        // The displacement through rip, and where it is in `modrm`
+        let mut rip_symbol = None;
+        match rm {
+            Operand::Register(register) => {
+                rex |= register.number >> 3;
+                needs_rex = register.size == 1 && (4..8).contains(&register.number);
+                modrm.push(0xc0 | (reg & 7) << 3 | (register.number & 7));
+            }
+            Operand::Memory(memory) if memory.rip => {
+                modrm.push((reg & 7) << 3 | 5);
+                let displacement =
+                    i32::try_from(memory.displacement).map_err(|_| "displacement is too large")?;
+                match &memory.symbol {
+                    Some(symbol) => {
+                        rip_symbol = Some((symbol.clone(), modrm.len(), memory.displacement));
+                        modrm.extend_from_slice(&[0; 4]);
+                    }
+                    None => modrm.extend_from_slice(&displacement.to_le_bytes()),
+                }
+            }
+            Operand::Memory(memory) => {
+                let base = memory.base.ok_or("addresses need a base register")?;
+                let displacement =
+                    i32::try_from(memory.displacement).map_err(|_| "displacement is too large")?;
+                // Rbp and r13 as bases always have a displacement
+                let mode = match (displacement, i8::try_from(displacement)) {
+                    (0, _) if base & 7 != 5 => 0,
+                    (_, Ok(_)) => 1,
+                    _ => 2,
+                };
+                rex |= base >> 3;
+                match memory.index {
+                    Some((index, scale)) => {
+                        rex |= (index >> 3) << 1;
+                        modrm.push(mode << 6 | (reg & 7) << 3 | 4);
+                        modrm.push(
+                            (scale.trailing_zeros() as u8) << 6 | (index & 7) << 3 | (base & 7),
+                        );
+                    }
+                    // Rsp and r12 as bases need a SIB byte
+                    None if base & 7 == 4 => {
+                        modrm.push(mode << 6 | (reg & 7) << 3 | 4);
+                        modrm.push(0x24);
+                    }
+                    None => modrm.push(mode << 6 | (reg & 7) << 3 | (base & 7)),
+                }
+                match mode {
+                    1 => modrm.push(displacement as u8),
+                    2 => modrm.extend_from_slice(&displacement.to_le_bytes()),
+                    _ => (),
+                }
+            }
+            _ => return Err("expected a register or memory operand".to_string()),
+        }
+        if rex != 0 || needs_rex {
+            bytes.push(0x40 | rex);
+        }
+        bytes.extend_from_slice(opcode);
+
+        let start = self.sections[self.section].size + bytes.len() as u64;
+        if let Some((symbol, at, displacement)) = rip_symbol {
+            // Rip points after the instruction, past the immediate
+            let to_end = (modrm.len() - at + immediate.len()) as i64;
+            self.symbol_id(&symbol);
+            self.relocations.push(Relocation {
+                section: self.section,
+                offset: start + at as u64,
+                kind: RelocationKind::Pc32,
+                symbol,
+                addend: displacement - to_end,
+            });
+        }
+        bytes.extend_from_slice(&modrm);
+        bytes.extend_from_slice(immediate);
+        self.data(&bytes)
+    }
+
+    // Fills in offsets to symbols in the same section, and marks the global symbols
+    fn finish(mut self) -> Result<Object, String> {
+        for name in &self.globals {
+            let id = self.symbol_ids[name];
+            self.symbols[id].global = true;
+        }
+        let mut relocations = Vec::new();
+        for relocation in self.relocations {
+            let symbol = &self.symbols[self.symbol_ids[&relocation.symbol]];
+            if symbol.section.is_none() && is_local_label(&symbol.name) {
+                return Err(format!("{} is not defined", symbol.name));
+            }
+            if relocation.kind == RelocationKind::Pc32 && symbol.section == Some(relocation.section)
+            {
+                let value = symbol.offset as i64 + relocation.addend - relocation.offset as i64;
+                let value = i32::try_from(value).map_err(|_| "jump is too far")?;
+                let at = relocation.offset as usize;
+                self.sections[relocation.section].data[at..at + 4]
+                    .copy_from_slice(&value.to_le_bytes());
+            } else {
+                relocations.push(relocation);
+            }
+        }
+        // Symbols that are only used are defined in other objects
+        for symbol in &mut self.symbols {
+            if symbol.section.is_none() {
+                symbol.global = true;
+            }
+        }
+        Ok(Object {
+            sections: self.sections,
+            symbols: self.symbols,
+            relocations,
+        })
+    }
+}
+
+fn immediate8(value: i64) -> Result<u8, String> {
+    i8::try_from(value)
+        .map(|value| value as u8)
+        .or_else(|_| u8::try_from(value))
+        .map_err(|_| format!("{} does not fit in 8 bits", value))
+}
+
+fn immediate32(value: i64) -> Result<Vec<u8>, String> {
+    i32::try_from(value)
+        .map(|value| value.to_le_bytes().to_vec())
+        .map_err(|_| format!("{} does not fit in 32 bits", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(source: &str) -> Vec<u8> {
+        let object = assemble(source).unwrap();
+        object.sections[TEXT].data.clone()
+    }
+
+    #[test]
+    fn test_encodings() {
+        // As the GNU assembler encodes them
+        for (source, bytes) in [
+            (
+                "mov qword ptr [rbp - 40], r12",
+                &[0x4c, 0x89, 0x65, 0xd8][..],
+            ),
+            (
+                "mov rax, qword ptr [rcx + rbx*8 - 8]",
+                &[0x48, 0x8b, 0x44, 0xd9, 0xf8],
+            ),
+            ("add byte ptr [rsp + rcx], 1", &[0x80, 0x04, 0x0c, 0x01]),
+            (
+                "cmp qword ptr [r12 + 8], 0",
+                &[0x49, 0x83, 0x7c, 0x24, 0x08, 0x00],
+            ),
+            (
+                "mov r13, -1075",
+                &[0x49, 0xc7, 0xc5, 0xcd, 0xfb, 0xff, 0xff],
+            ),
+            (
+                "movabs r12, 18014398509481984",
+                &[0x49, 0xbc, 0, 0, 0, 0, 0, 0, 0x40, 0],
+            ),
+            ("movzx r12d, r12b", &[0x45, 0x0f, 0xb6, 0xe4]),
+            ("setl sil", &[0x40, 0x0f, 0x9c, 0xc6]),
+            (
+                "imul rcx, rcx, 1292913986",
+                &[0x48, 0x69, 0xc9, 0x42, 0x4d, 0x10, 0x4d],
+            ),
+            (
+                "movsd xmm8, qword ptr [r13 + 16]",
+                &[0xf2, 0x45, 0x0f, 0x10, 0x45, 0x10],
+            ),
+            ("cvtsi2sd xmm9, r10", &[0xf2, 0x4d, 0x0f, 0x2a, 0xca]),
+            ("movq rax, xmm0", &[0x66, 0x48, 0x0f, 0x7e, 0xc0]),
+            ("ucomisd xmm15, xmm1", &[0x66, 0x44, 0x0f, 0x2e, 0xf9]),
+            ("push r15", &[0x41, 0x57]),
+            ("call rax", &[0xff, 0xd0]),
+            ("call r11", &[0x41, 0xff, 0xd3]),
+            ("shr rbx, 52", &[0x48, 0xc1, 0xeb, 0x34]),
+        ] {
+            assert_eq!(text(source), bytes, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_jumps_and_relocations() {
+        let object = assemble(
+            ".globl f\nf:\n    jmp .Lend\n    call g\n.Lend:\n    cmp qword ptr [rip + x], 1000\n    ret\n    .data\nx:\n    .quad f",
+        )
+        .unwrap();
+        let text = &object.sections[TEXT].data;
+        // The jump goes past the call
+        assert_eq!(&text[..5], &[0xe9, 5, 0, 0, 0]);
+        assert!(object.symbol("f").unwrap().global);
+        assert!(object.symbol("g").unwrap().section.is_none());
+
+        let relocations: Vec<_> = object
+            .relocations
+            .iter()
+            .map(|r| (r.section, r.offset, r.kind, r.symbol.as_str(), r.addend))
+            .collect();
+        assert_eq!(
+            relocations,
+            [
+                (TEXT, 6, RelocationKind::Pc32, "g", -4),
+                // The immediate follows the displacement
+                (TEXT, 13, RelocationKind::Pc32, "x", -8),
+                (DATA, 0, RelocationKind::Abs64, "f", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let object = assemble(
+            "    .section .rodata\na:\n    .asciz \"\\303\\244 # \\\"\\n\" # comment\n    .bss\nb:\n    .zero 4096",
+        )
+        .unwrap();
+        assert_eq!(object.sections[RODATA].data, "ä # \"\n\0".as_bytes());
+        assert_eq!(object.sections[BSS].size, 4096);
+        assert!(object.sections[BSS].data.is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        for (source, message) in [
+            ("    jmp .Lnowhere", ".Lnowhere is not defined"),
+            ("    frob rax", "line 1: cant assemble frob"),
+            ("a:\na:", "line 2: a is defined twice"),
+            ("    mov eax, rbx", "operands have different sizes"),
+            ("    mov qword ptr [rax], 1 << 40", "cant read operand"),
+            ("    .bss\n    ret", "the bss section only has zeros"),
+        ] {
+            let error = assemble(source).unwrap_err();
+            assert!(error.contains(message), "{}: {}", source, error);
+        }
+    }
+}